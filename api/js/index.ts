@@ -1,6 +1,7 @@
 import { default as wasmbin } from "../pkg-wasm-bindgen/local_first_bg.wasm"
 import wbindgen from "../pkg-wasm-bindgen/local_first.js"
 import { Api, Causal, Cursor, Doc, Sdk } from "./bindings"
+import { subscribe } from "./subscribe"
 
 let API: Api;
 
@@ -39,6 +40,80 @@ class LocalFirst {
   proxy<T extends object>(doc: Doc): T {
     return mkProxy<T>(doc)
   }
+
+  wrap(doc: Doc): DocWrapper {
+    return new DocWrapper(doc)
+  }
+
+  // Returns and clears the pending invitations.
+  async invites(): Promise<Invite[]> {
+    const invites = Array.from(await this.sdk.invites())
+    return invites.map(([doc, schema, inviter, message, perm]) => ({
+      doc,
+      schema,
+      inviter,
+      message: message || undefined,
+      permission: perm < 0 ? undefined : PERMISSIONS[perm],
+    }))
+  }
+
+  // Calls `cb` whenever an invitation arrives. Returns a function to unsubscribe.
+  subscribeInvites(cb: () => void): () => void {
+    return subscribe(this.sdk.subscribeInvites(), cb)
+  }
+}
+
+const PERMISSIONS = ["read", "write", "control", "own"] as const
+type Permission = typeof PERMISSIONS[number]
+
+interface Invite {
+  doc: string
+  schema: string
+  inviter: string
+  message?: string
+  permission?: Permission
+}
+
+// Policy claim as returned by `DocWrapper.policies`.
+interface Claim {
+  id: string
+  peer: string
+  statement: string
+}
+
+class DocWrapper {
+  constructor(public doc: Doc) { }
+
+  proxy<T extends object>(): T {
+    return mkProxy<T>(this.doc)
+  }
+
+  invitePeer(peer: string, message?: string) {
+    if (message) {
+      this.doc.invitePeerWithMessage(peer, message)
+    } else {
+      this.doc.invitePeer(peer)
+    }
+  }
+
+  // Gives `peer` a permission on the document. Requires control permission, or own
+  // permission to give control or own permission.
+  setPermission(peer: string, perm: Permission) {
+    const causal = this.doc.createCursor().sayCan(peer, PERMISSIONS.indexOf(perm))
+    this.doc.applyCausal(causal)
+  }
+
+  // Returns the policy claims on the document root.
+  policies(): Claim[] {
+    return Array.from(this.doc.createCursor().policies())
+      .map(([id, peer, statement]) => ({ id, peer, statement }))
+  }
+
+  // Revokes a policy claim returned by `policies`.
+  revoke(claim: string) {
+    const causal = this.doc.createCursor().revoke(claim)
+    this.doc.applyCausal(causal)
+  }
 }
 
 const traverse = (cursor: Cursor, p: any) => {
@@ -70,8 +145,12 @@ const get = <T>(doc: Doc, cursor_?: Cursor) => (target: T, p: string | symbol, r
         { return Array.from(cursor.regU64s())[0] }
       case "Reg<i64>":
         { return Array.from(cursor.regI64s())[0] }
+      case "Reg<f64>":
+        { return Array.from(cursor.regF64s())[0] }
       case "Reg<string>":
         { return Array.from(cursor.regStrs())[0] }
+      case "Reg<bytes>":
+        { return Array.from(cursor.regBytes())[0] }
       default: { return undefined; }
     }
   } else {
@@ -164,8 +243,12 @@ const setPrimitiveValue = (cursor: Cursor, value: any): Causal => {
       return cursor.regAssignU64(BigInt(value))
     case "Reg<i64>":
       return cursor.regAssignI64(BigInt(value))
+    case "Reg<f64>":
+      return cursor.regAssignF64(Number(value))
     case "Reg<string>":
       return cursor.regAssignStr(value.toString())
+    case "Reg<bytes>":
+      return cursor.regAssignBytes(Uint8Array.from(value))
     default: {
       throw new Error(`Unknown type: ${cursor.typeOf()}; value: ${value}`)
 
@@ -272,8 +355,12 @@ const mkProxy = <T extends object>(doc: Doc, cursor_?: Cursor): T => {
             return Array.from(cursor.regU64s())[0]
           case "Reg<i64>":
             return Array.from(cursor.regI64s())[0]
+          case "Reg<f64>":
+            return Array.from(cursor.regF64s())[0]
           case "Reg<string>":
             return Array.from(cursor.regStrs())[0]
+          case "Reg<bytes>":
+            return Array.from(cursor.regBytes())[0]
         }
       } else {
         // return new object if not at a leaf
@@ -320,4 +407,6 @@ const mkProxy = <T extends object>(doc: Doc, cursor_?: Cursor): T => {
 //}
 //start();
 export default LocalFirst;
+export { DocWrapper };
+export type { Claim, Invite, Permission };
 export * from './bindings'
\ No newline at end of file
@@ -0,0 +1,44 @@
+import { subscribe } from "./subscribe"
+
+// A stream whose items are emitted by hand.
+const manualStream = () => {
+  const pending: ((item: IteratorResult<number>) => void)[] = []
+  let returned = false
+  const stream: AsyncIterable<number> = {
+    [Symbol.asyncIterator]: () => ({
+      next: () => new Promise<IteratorResult<number>>((resolve) => pending.push(resolve)),
+      return: async () => {
+        returned = true
+        return { done: true, value: undefined }
+      },
+    }),
+  }
+  const emit = () => pending.shift()?.({ done: false, value: 0 })
+  return { stream, emit, returned: () => returned }
+}
+
+const tick = () => new Promise((resolve) => setTimeout(resolve, 0))
+
+test("calls the callback for every item", async () => {
+  const { stream, emit } = manualStream()
+  let calls = 0
+  subscribe(stream, () => calls++)
+  emit()
+  await tick()
+  emit()
+  await tick()
+  expect(calls).toBe(2)
+})
+
+test("closes the stream when unsubscribing", async () => {
+  const { stream, emit, returned } = manualStream()
+  let calls = 0
+  const unsubscribe = subscribe(stream, () => calls++)
+  emit()
+  await tick()
+  unsubscribe()
+  expect(returned()).toBe(true)
+  emit()
+  await tick()
+  expect(calls).toBe(1)
+})
@@ -0,0 +1,22 @@
+// Calls `cb` for every item of `stream` and returns a function to unsubscribe. Unsubscribing
+// closes the stream right away instead of when the next item arrives.
+export const subscribe = (stream: AsyncIterable<unknown>, cb: () => void): (() => void) => {
+  const iter = stream[Symbol.asyncIterator]()
+  let subscribed = true
+  const listen = async () => {
+    while (subscribed) {
+      const { done } = await iter.next()
+      if (done || !subscribed) {
+        break
+      }
+      cb()
+    }
+  }
+  listen()
+  return () => {
+    if (subscribed) {
+      subscribed = false
+      iter.return?.()
+    }
+  }
+}
@@ -4,7 +4,7 @@ ffi_gen_macro::ffi_gen!("api/tlfs.rsh");
 
 use anyhow::Result;
 use futures::{Stream, StreamExt};
-use tlfs::Permission;
+use tlfs::{AddressScore, PeerSource, Permission};
 use tlfs_crdt::ArchivedSchema;
 
 pub struct Sdk(tlfs::Sdk);
@@ -17,6 +17,29 @@ pub async fn create_persistent(path: String, package: Vec<u8>) -> Result<Sdk> {
     Ok(Sdk(sdk))
 }
 
+pub struct StorageOptions(tlfs::StoragePath);
+
+impl StorageOptions {
+    pub fn path(path: &str) -> Self {
+        Self(tlfs::StoragePath::Path(path.into()))
+    }
+
+    pub fn container(container: &str, path: &str) -> Self {
+        Self(tlfs::StoragePath::Container {
+            container: container.into(),
+            path: path.into(),
+        })
+    }
+}
+
+pub async fn create_persistent_with(options: Box<StorageOptions>, package: Vec<u8>) -> Result<Sdk> {
+    #[cfg(target_family = "wasm")]
+    let sdk = tlfs::Sdk::browser(&options.0.resolve()?.to_string_lossy(), &package).await?;
+    #[cfg(not(target_family = "wasm"))]
+    let sdk = tlfs::Sdk::persistent(&options.0, &package).await?;
+    Ok(Sdk(sdk))
+}
+
 pub async fn create_memory(package: Vec<u8>) -> Result<Sdk> {
     Ok(Sdk(tlfs::Sdk::memory(&package).await?))
 }
@@ -36,6 +59,50 @@ impl Sdk {
         Ok(())
     }
 
+    pub fn add_external_address(&self, addr: &str, score: Option<u32>) -> Result<()> {
+        let score = score
+            .map(AddressScore::Finite)
+            .unwrap_or(AddressScore::Infinite);
+        self.0.add_external_address(addr.parse()?, score);
+        Ok(())
+    }
+
+    pub async fn external_addresses(&self) -> Vec<(String, i64)> {
+        self.0
+            .external_addresses()
+            .await
+            .into_iter()
+            .map(|(addr, score)| {
+                let score = match score {
+                    AddressScore::Finite(score) => score as i64,
+                    AddressScore::Infinite => -1,
+                };
+                (addr.to_string(), score)
+            })
+            .collect()
+    }
+
+    pub async fn peer_addresses(&self, peer_id: &str) -> Result<Vec<(String, String)>> {
+        let peer = peer_id.parse()?;
+        let mut peers = self.0.peers().await;
+        let addrs = peers
+            .remove(&peer)
+            .map(|entry| entry.addresses)
+            .unwrap_or_default();
+        Ok(addrs
+            .into_iter()
+            .map(|(addr, source)| {
+                let source = match source {
+                    PeerSource::Mdns => "mdns",
+                    PeerSource::Manual => "manual",
+                    PeerSource::Relay => "relay",
+                    PeerSource::Connection => "connection",
+                };
+                (addr.to_string(), source.to_string())
+            })
+            .collect())
+    }
+
     pub async fn addresses(&self) -> Vec<String> {
         self.0
             .addresses()
@@ -79,6 +146,16 @@ impl Sdk {
         self.0.docs(schema).map(|id| Ok(id?.to_string())).collect()
     }
 
+    pub fn all_docs(&self) -> Result<Vec<(String, String, u32)>> {
+        self.0
+            .all_docs()
+            .map(|res| {
+                let (id, schema, version) = res?;
+                Ok((id.to_string(), schema, version))
+            })
+            .collect()
+    }
+
     pub fn subscribe_docs(&self) -> impl Stream<Item = i32> {
         self.0.subscribe_docs().map(|_| 0)
     }
@@ -99,18 +176,69 @@ impl Sdk {
         self.0.remove_doc(&doc_id.parse()?)
     }
 
-    pub async fn invites(&self) -> Vec<(String, String)> {
+    pub fn redeem_invite_token(&self, token: Vec<u8>) -> Result<Doc> {
+        Ok(Doc(self.0.redeem_invite_token(&token)?))
+    }
+
+    pub async fn invites(&self) -> Vec<(String, String, String, String, i32)> {
         self.0
             .invites()
             .await
             .into_iter()
-            .map(|inv| (inv.doc.to_string(), inv.schema))
+            .map(|inv| {
+                (
+                    inv.doc.to_string(),
+                    inv.schema,
+                    inv.inviter.to_string(),
+                    inv.message.unwrap_or_default(),
+                    inv.permission.map(|perm| perm as i32).unwrap_or(-1),
+                )
+            })
             .collect()
     }
 
     pub fn subscribe_invites(&self) -> impl Stream<Item = i32> {
         self.0.subscribe_invites().map(|_| 0)
     }
+
+    pub async fn remote_changes(&self) -> Vec<String> {
+        self.0
+            .remote_changes()
+            .await
+            .into_iter()
+            .map(|doc| doc.to_string())
+            .collect()
+    }
+
+    pub fn subscribe_remote_changes(&self) -> impl Stream<Item = i32> {
+        self.0.subscribe_remote_changes().map(|_| 0)
+    }
+
+    pub async fn sync_once(&self) -> Result<()> {
+        self.0.sync_once().await
+    }
+
+    pub async fn barrier(&self) -> Result<()> {
+        self.0.barrier().await
+    }
+
+    pub fn journal_len(&self) -> Result<u32> {
+        Ok(self.0.journal()?.len() as u32)
+    }
+
+    pub fn clear_journal(&self) -> Result<()> {
+        self.0.clear_journal()
+    }
+
+    pub fn add_attachment(&self, doc_id: &str, data: Vec<u8>) -> Result<String> {
+        let hash = self.0.add_attachment(&doc_id.parse()?, &data)?;
+        Ok(hash.to_hex().to_string())
+    }
+
+    pub async fn attachment(&self, doc_id: &str, hash: &str) -> Result<Vec<u8>> {
+        let hash = tlfs::Hash::from_hex(hash)?;
+        self.0.attachment(&doc_id.parse()?, hash).await
+    }
 }
 
 pub struct Doc(tlfs::Doc);
@@ -124,6 +252,27 @@ impl Doc {
         Cursor(self.0.cursor())
     }
 
+    pub fn create_cursor_at(&self, pointer: &str) -> Result<Cursor> {
+        Ok(Cursor(self.0.cursor_at(pointer)?))
+    }
+
+    pub fn get_value(&self, pointer: &str) -> Result<Value> {
+        Value::read(&self.0.cursor_at(pointer)?)
+    }
+
+    pub fn schema_name(&self) -> Result<String> {
+        self.0.schema_name()
+    }
+
+    pub fn schema_version(&self) -> Result<u32> {
+        self.0.schema_version()
+    }
+
+    pub fn schema_hash(&self) -> Result<String> {
+        let hash = self.0.schema_hash()?;
+        Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
     pub fn apply_causal(&self, causal: Box<Causal>) -> Result<()> {
         self.0.apply(causal.0)
     }
@@ -131,6 +280,38 @@ impl Doc {
     pub fn invite_peer(&self, peer: String) -> Result<()> {
         self.0.invite(peer.parse()?)
     }
+
+    pub fn invite_peer_with_message(&self, peer: String, message: String) -> Result<()> {
+        self.0.invite_with_message(peer.parse()?, message)
+    }
+
+    pub fn create_invite_token(&self, perm: u8, expiry: u64) -> Result<Vec<u8>> {
+        self.0.create_invite_token(parse_perm(perm)?, expiry)
+    }
+
+    pub fn revoke_invite_token(&self, token: Vec<u8>) -> Result<()> {
+        self.0.revoke_invite_token(&token)
+    }
+
+    pub fn scan(&self, token: Vec<u8>, limit: u32) -> ScanPage {
+        let token = Some(&token[..]).filter(|token| !token.is_empty());
+        let page = self
+            .0
+            .scan(tlfs::PathBuf::new().as_path(), token, limit as usize);
+        ScanPage(page)
+    }
+}
+
+pub struct ScanPage(tlfs::ScanPage);
+
+impl ScanPage {
+    pub fn paths(&self) -> Vec<String> {
+        self.0.paths.iter().map(|path| path.to_string()).collect()
+    }
+
+    pub fn token(&self) -> Vec<u8> {
+        self.0.token.clone().unwrap_or_default()
+    }
 }
 
 #[derive(Clone)]
@@ -149,6 +330,15 @@ fn type_of(schema: &ArchivedSchema, max_depth: u8, depth: u8) -> String {
         }
         ArchivedSchema::Array(vs) => format!("Array<{}>", type_of(vs, max_depth, depth + 1)),
         ArchivedSchema::Struct(_) => "Struct<_>".into(),
+        ArchivedSchema::Set(ty) => format!("Set<{}>", ty),
+        ArchivedSchema::Dynamic => "Dynamic".into(),
+        ArchivedSchema::Local(vs) => format!("Local<{}>", type_of(vs, max_depth, depth)),
+        ArchivedSchema::MergeReg(ty, merge) => format!("Reg<{}> merge({})", ty, merge),
+        ArchivedSchema::CheckedReg(ty, invariant) => format!("Reg<{}> check({})", ty, invariant),
+        ArchivedSchema::LwwReg(ty) => format!("LwwReg<{}>", ty),
+        ArchivedSchema::Encrypted(ty) => format!("Encrypted<{}>", ty),
+        ArchivedSchema::Counter => "Counter".into(),
+        ArchivedSchema::Text => "Text".into(),
     }
 }
 
@@ -158,10 +348,28 @@ impl<'a> Cursor<'a> {
         type_of(self.0.schema(), 8, 0)
     }
 
+    pub fn path_string(&self) -> Result<String> {
+        self.0.path_string()
+    }
+
+    pub fn value(&self) -> Result<Value> {
+        Value::read(&self.0)
+    }
+
+    pub fn pointer(&mut self, pointer: &str) -> Result<()> {
+        self.0.pointer(pointer)?;
+        Ok(())
+    }
+
     pub fn points_at_value(&self) -> bool {
         matches!(
             self.0.schema(),
-            ArchivedSchema::Flag | ArchivedSchema::Reg(_)
+            ArchivedSchema::Flag
+                | ArchivedSchema::Reg(_)
+                | ArchivedSchema::MergeReg(_, _)
+                | ArchivedSchema::CheckedReg(_, _)
+                | ArchivedSchema::LwwReg(_)
+                | ArchivedSchema::Encrypted(_)
         )
     }
 
@@ -170,16 +378,27 @@ impl<'a> Cursor<'a> {
             Some(match self.0.schema() {
                 ArchivedSchema::Null => "null".into(),
                 ArchivedSchema::Flag => "bool".into(),
-                ArchivedSchema::Reg(ty) => match ty {
+                ArchivedSchema::Reg(ty)
+                | ArchivedSchema::MergeReg(ty, _)
+                | ArchivedSchema::CheckedReg(ty, _)
+                | ArchivedSchema::LwwReg(ty)
+                | ArchivedSchema::Encrypted(ty) => match ty {
                     tlfs::PrimitiveKind::Bool => "Reg<bool>",
                     tlfs::PrimitiveKind::U64 => "Reg<u64>",
                     tlfs::PrimitiveKind::I64 => "Reg<i64>",
+                    tlfs::PrimitiveKind::F64 => "Reg<f64>",
                     tlfs::PrimitiveKind::Str => "Reg<string>",
+                    tlfs::PrimitiveKind::Bytes => "Reg<bytes>",
                 }
                 .into(),
                 ArchivedSchema::Table(_, _)
                 | ArchivedSchema::Array(_)
-                | ArchivedSchema::Struct(_) => unreachable!(),
+                | ArchivedSchema::Struct(_)
+                | ArchivedSchema::Set(_)
+                | ArchivedSchema::Counter
+                | ArchivedSchema::Text
+                | ArchivedSchema::Dynamic
+                | ArchivedSchema::Local(_) => unreachable!(),
             })
         } else {
             None
@@ -226,10 +445,18 @@ impl<'a> Cursor<'a> {
         self.0.i64s()?.collect()
     }
 
+    pub fn reg_f64s(&self) -> Result<Vec<f64>> {
+        self.0.f64s()?.collect()
+    }
+
     pub fn reg_strs(&self) -> Result<Vec<String>> {
         self.0.strs()?.collect()
     }
 
+    pub fn reg_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        self.0.bytes()?.collect()
+    }
+
     pub fn reg_assign_bool(&self, value: bool) -> Result<Causal> {
         Ok(Causal(self.0.assign_bool(value)?))
     }
@@ -242,10 +469,38 @@ impl<'a> Cursor<'a> {
         Ok(Causal(self.0.assign_i64(value)?))
     }
 
+    pub fn reg_assign_f64(&self, value: f64) -> Result<Causal> {
+        Ok(Causal(self.0.assign_f64(value)?))
+    }
+
     pub fn reg_assign_str(&self, value: &str) -> Result<Causal> {
         Ok(Causal(self.0.assign_str(value)?))
     }
 
+    pub fn reg_assign_bytes(&self, value: Vec<u8>) -> Result<Causal> {
+        Ok(Causal(self.0.assign_bytes(&value)?))
+    }
+
+    pub fn counter_value(&self) -> Result<i64> {
+        self.0.counter_value()
+    }
+
+    pub fn counter_increment(&self, delta: i64) -> Result<Causal> {
+        Ok(Causal(self.0.increment(delta)?))
+    }
+
+    pub fn text_value(&self) -> Result<String> {
+        self.0.text()
+    }
+
+    pub fn text_insert(&self, index: usize, value: &str) -> Result<Causal> {
+        Ok(Causal(self.0.insert_str(index, value)?))
+    }
+
+    pub fn text_delete(&self, index: usize, len: usize) -> Result<Causal> {
+        Ok(Causal(self.0.delete_range(index, len)?))
+    }
+
     pub fn struct_field(&mut self, field: &str) -> Result<()> {
         self.0.field(field)?;
         Ok(())
@@ -329,13 +584,239 @@ impl<'a> Cursor<'a> {
         Ok(Causal(self.0.say_can_if(actor.0, perm, cond.0)?))
     }
 
-    // TODO: revoke
+    pub fn say_can_group(&self, group: &str, perm: u8) -> Result<Causal> {
+        let perm = parse_perm(perm)?;
+        Ok(Causal(self.0.say_can_group(&group.parse()?, perm)?))
+    }
+
+    pub fn say_readers_can(&self, field: &str, perm: u8) -> Result<Causal> {
+        let perm = parse_perm(perm)?;
+        Ok(Causal(self.0.say_readers_can(field, perm)?))
+    }
+
+    pub fn policies(&self) -> Vec<(String, String, String)> {
+        self.0
+            .policies()
+            .map(|(claim, peer, policy)| {
+                (claim.to_string(), peer.to_string(), format!("{:?}", policy))
+            })
+            .collect()
+    }
+
+    pub fn revoke(&self, claim: &str) -> Result<Causal> {
+        Ok(Causal(self.0.revoke(claim.parse()?)?))
+    }
 
     pub fn subscribe(&self) -> impl Stream<Item = i32> {
         self.0.subscribe().map(|_batch| 0)
     }
 }
 
+/// Value read recursively from a [`Cursor`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    Map(Vec<(String, Value)>),
+    List(Vec<Value>),
+    Struct(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Reads the value the cursor points to. Registers with concurrent values become a list.
+    fn read(cursor: &tlfs::Cursor) -> Result<Self> {
+        Ok(match cursor.schema() {
+            ArchivedSchema::Null => Self::Null,
+            ArchivedSchema::Flag => Self::Bool(cursor.enabled()?),
+            ArchivedSchema::Reg(kind)
+            | ArchivedSchema::CheckedReg(kind, _)
+            | ArchivedSchema::LwwReg(kind)
+            | ArchivedSchema::Encrypted(kind) => {
+                let mut values = Self::reg(cursor, *kind)?;
+                match values.len() {
+                    0 => Self::Null,
+                    1 => values.pop().unwrap(),
+                    _ => Self::List(values),
+                }
+            }
+            ArchivedSchema::MergeReg(kind, _) => match kind {
+                tlfs::PrimitiveKind::Bool => cursor.merged_bool()?.map(Self::Bool),
+                tlfs::PrimitiveKind::U64 => cursor.merged_u64()?.map(Self::U64),
+                tlfs::PrimitiveKind::I64 => cursor.merged_i64()?.map(Self::I64),
+                tlfs::PrimitiveKind::F64 => cursor.merged_f64()?.map(Self::F64),
+                tlfs::PrimitiveKind::Str => cursor.merged_str()?.map(Self::Str),
+                tlfs::PrimitiveKind::Bytes => cursor.merged_bytes()?.map(Self::Bytes),
+            }
+            .unwrap_or(Self::Null),
+            ArchivedSchema::Table(kind, _) => {
+                let mut entries = vec![];
+                match kind {
+                    tlfs::PrimitiveKind::Bool => {
+                        for key in cursor.keys_bool()? {
+                            entries.push((key.to_string(), Self::read(&cursor.at_key_bool(key)?)?));
+                        }
+                    }
+                    tlfs::PrimitiveKind::U64 => {
+                        for key in cursor.keys_u64()? {
+                            entries.push((key.to_string(), Self::read(&cursor.at_key_u64(key)?)?));
+                        }
+                    }
+                    tlfs::PrimitiveKind::I64 => {
+                        for key in cursor.keys_i64()? {
+                            entries.push((key.to_string(), Self::read(&cursor.at_key_i64(key)?)?));
+                        }
+                    }
+                    tlfs::PrimitiveKind::Str => {
+                        for key in cursor.keys_str()? {
+                            let value = Self::read(&cursor.at_key_str(&key)?)?;
+                            entries.push((key, value));
+                        }
+                    }
+                    tlfs::PrimitiveKind::F64 => anyhow::bail!("can't read a Table<f64, _>"),
+                    tlfs::PrimitiveKind::Bytes => anyhow::bail!("can't read a Table<bytes, _>"),
+                }
+                Self::Map(entries)
+            }
+            ArchivedSchema::Array(_) => Self::List(
+                cursor
+                    .iter_array()?
+                    .map(|element| Self::read(&element))
+                    .collect::<Result<_>>()?,
+            ),
+            ArchivedSchema::Struct(fields) => Self::Struct(
+                fields
+                    .keys()
+                    .map(|field| Ok((field.to_string(), Self::read(&cursor.at_field(field)?)?)))
+                    .collect::<Result<_>>()?,
+            ),
+            ArchivedSchema::Set(kind) => Self::List(match kind {
+                tlfs::PrimitiveKind::Bool => cursor.elements_bool()?.map(Self::Bool).collect(),
+                tlfs::PrimitiveKind::U64 => cursor.elements_u64()?.map(Self::U64).collect(),
+                tlfs::PrimitiveKind::I64 => cursor.elements_i64()?.map(Self::I64).collect(),
+                tlfs::PrimitiveKind::F64 => cursor.elements_f64()?.map(Self::F64).collect(),
+                tlfs::PrimitiveKind::Str => cursor.elements_str()?.map(Self::Str).collect(),
+                tlfs::PrimitiveKind::Bytes => cursor.elements_bytes()?.map(Self::Bytes).collect(),
+            }),
+            ArchivedSchema::Counter => Self::I64(cursor.counter_value()?),
+            ArchivedSchema::Text => Self::Str(cursor.text()?),
+            ArchivedSchema::Dynamic | ArchivedSchema::Local(_) => {
+                anyhow::bail!("can't read {:?}", cursor.schema())
+            }
+        })
+    }
+
+    fn reg(cursor: &tlfs::Cursor, kind: tlfs::PrimitiveKind) -> Result<Vec<Self>> {
+        match kind {
+            tlfs::PrimitiveKind::Bool => cursor.bools()?.map(|v| Ok(Self::Bool(v?))).collect(),
+            tlfs::PrimitiveKind::U64 => cursor.u64s()?.map(|v| Ok(Self::U64(v?))).collect(),
+            tlfs::PrimitiveKind::I64 => cursor.i64s()?.map(|v| Ok(Self::I64(v?))).collect(),
+            tlfs::PrimitiveKind::F64 => cursor.f64s()?.map(|v| Ok(Self::F64(v?))).collect(),
+            tlfs::PrimitiveKind::Str => cursor.strs()?.map(|v| Ok(Self::Str(v?))).collect(),
+            tlfs::PrimitiveKind::Bytes => cursor.bytes()?.map(|v| Ok(Self::Bytes(v?))).collect(),
+        }
+    }
+
+    pub fn kind(&self) -> String {
+        match self {
+            Self::Null => "null",
+            Self::Bool(_) => "bool",
+            Self::U64(_) => "u64",
+            Self::I64(_) => "i64",
+            Self::F64(_) => "f64",
+            Self::Str(_) => "string",
+            Self::Bytes(_) => "bytes",
+            Self::Map(_) => "map",
+            Self::List(_) => "list",
+            Self::Struct(_) => "struct",
+        }
+        .into()
+    }
+
+    pub fn as_bool(&self) -> Result<bool> {
+        match self {
+            Self::Bool(b) => Ok(*b),
+            _ => anyhow::bail!("not a bool"),
+        }
+    }
+
+    pub fn as_u64(&self) -> Result<u64> {
+        match self {
+            Self::U64(n) => Ok(*n),
+            _ => anyhow::bail!("not an u64"),
+        }
+    }
+
+    pub fn as_i64(&self) -> Result<i64> {
+        match self {
+            Self::I64(n) => Ok(*n),
+            _ => anyhow::bail!("not an i64"),
+        }
+    }
+
+    pub fn as_f64(&self) -> Result<f64> {
+        match self {
+            Self::F64(n) => Ok(*n),
+            _ => anyhow::bail!("not a f64"),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<String> {
+        match self {
+            Self::Str(s) => Ok(s.clone()),
+            _ => anyhow::bail!("not a string"),
+        }
+    }
+
+    pub fn as_bytes(&self) -> Result<Vec<u8>> {
+        match self {
+            Self::Bytes(b) => Ok(b.clone()),
+            _ => anyhow::bail!("not bytes"),
+        }
+    }
+
+    fn entries(&self) -> Result<&[(String, Value)]> {
+        match self {
+            Self::Map(entries) | Self::Struct(entries) => Ok(entries),
+            _ => anyhow::bail!("not a map or struct"),
+        }
+    }
+
+    pub fn keys(&self) -> Result<Vec<String>> {
+        Ok(self.entries()?.iter().map(|(k, _)| k.clone()).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Value> {
+        self.entries()?
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .ok_or_else(|| anyhow::anyhow!("missing key {}", key))
+    }
+
+    pub fn length(&self) -> Result<u32> {
+        match self {
+            Self::List(values) => Ok(values.len() as u32),
+            Self::Map(entries) | Self::Struct(entries) => Ok(entries.len() as u32),
+            _ => anyhow::bail!("not a list, map or struct"),
+        }
+    }
+
+    pub fn index(&self, index: usize) -> Result<Value> {
+        match self {
+            Self::List(values) => values
+                .get(index)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("index {} out of bounds", index)),
+            _ => anyhow::bail!("not a list"),
+        }
+    }
+}
+
 pub struct Causal(tlfs::Causal);
 
 impl Causal {
@@ -367,6 +848,10 @@ impl Actor {
         Self(tlfs::Actor::Anonymous)
     }
 
+    pub fn group(id: &str) -> Result<Self> {
+        Ok(Self(tlfs::Actor::Group(id.parse()?)))
+    }
+
     pub fn unbound() -> Self {
         Self(tlfs::Actor::Unbound)
     }
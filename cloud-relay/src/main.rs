@@ -1,13 +1,18 @@
 use std::{
+    collections::HashSet,
     fs,
     io::{self, BufReader},
+    net::SocketAddr,
+    num::NonZeroU64,
     path::PathBuf,
+    sync::Arc,
     time::Duration,
 };
 
 use anyhow::Context;
 use clap::Parser;
 use libp2p::{
+    bandwidth::{BandwidthLogging, BandwidthSinks},
     core::{
         either::EitherTransport, muxing::StreamMuxerBox, transport::upgrade, transport::OrTransport,
     },
@@ -19,7 +24,7 @@ use libp2p::{
     pnet::{PnetConfig, PreSharedKey},
     relay::{self, Relay},
     rendezvous,
-    swarm::SwarmBuilder,
+    swarm::{SwarmBuilder, SwarmEvent},
     tcp::TokioTcpConfig,
     websocket::{self, WsConfig},
     yamux::YamuxConfig,
@@ -29,6 +34,7 @@ use tracing::*;
 use tracing_subscriber::fmt;
 
 mod acme;
+mod metrics;
 
 #[derive(Parser)]
 struct Opts {
@@ -50,6 +56,12 @@ struct Opts {
     tls_email: Option<String>,
     #[clap(long)]
     wss: bool,
+    #[clap(long)]
+    /// Address to serve prometheus metrics on, for example `127.0.0.1:9090`.
+    metrics: Option<SocketAddr>,
+    #[clap(long, default_value = "60")]
+    /// Interval in seconds between logging relay stats. Must not be zero.
+    stats_interval: NonZeroU64,
 }
 
 #[tokio::main]
@@ -88,20 +100,29 @@ async fn main() -> anyhow::Result<()> {
             let private_key = websocket::tls::PrivateKey::new(der);
 
             let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(&opts.tls_cert)?))
-                .map_err(|_| anyhow::anyhow!("Reading TLS cert chain"))?
+                .map_err(|_| anyhow::anyhow!("Reading TLS cert chain"))?;
+            let not_after = metrics::cert_not_after(certs.first().context("Empty cert chain")?)?;
+            let certs = certs
                 .into_iter()
                 .map(websocket::tls::Certificate::new)
                 .collect();
-            Some((private_key, certs))
+            Some((private_key, certs, not_after))
         } else {
             anyhow::bail!("Please provide `cert,` `private_key`, `domain`, and `email` options");
         }
     } else {
         None
     };
-    let mut swarm = build_swarm(kp, None, Duration::from_secs(10), tls)
+    let cert_not_after = tls.as_ref().map(|(_, _, not_after)| *not_after);
+    let tls = tls.map(|(private_key, certs, _)| (private_key, certs));
+    let (mut swarm, bandwidth) = build_swarm(kp, None, Duration::from_secs(10), tls)
         .await
         .context("Creating libp2p swarm")?;
+    let metrics = Arc::new(metrics::Metrics::new(bandwidth, cert_not_after));
+    if let Some(addr) = opts.metrics {
+        let server = metrics::serve(metrics.clone(), addr).context("Binding metrics server")?;
+        tokio::spawn(server);
+    }
 
     let ws_or_wss = if opts.wss {
         Protocol::Wss("/".into())
@@ -115,10 +136,42 @@ async fn main() -> anyhow::Result<()> {
         swarm.listen_on(tcp)?;
         swarm.listen_on(ws)?;
     }
-    while let Some(event) = swarm.next().await {
-        debug!("Swarm {:?}", event);
+    let mut registrations = HashSet::new();
+    let mut stats = tokio::time::interval(Duration::from_secs(opts.stats_interval.get()));
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => {
+                debug!("Swarm {:?}", event);
+                match event {
+                    SwarmEvent::Behaviour(Event::Rendezvous(event)) => {
+                        match event {
+                            rendezvous::server::Event::PeerRegistered { peer, registration } => {
+                                registrations.insert((peer, registration.namespace));
+                            }
+                            rendezvous::server::Event::PeerUnregistered { peer, namespace } => {
+                                registrations.remove(&(peer, namespace));
+                            }
+                            rendezvous::server::Event::RegistrationExpired(registration) => {
+                                registrations.remove(&(
+                                    registration.record.peer_id(),
+                                    registration.namespace,
+                                ));
+                            }
+                            _ => {}
+                        }
+                        metrics.set_registrations(registrations.len() as u64);
+                    }
+                    SwarmEvent::ConnectionEstablished { .. }
+                    | SwarmEvent::ConnectionClosed { .. } => {
+                        let established = swarm.network_info().connection_counters().num_established();
+                        metrics.set_connections(established as u64);
+                    }
+                    _ => {}
+                }
+            }
+            _ = stats.tick() => metrics.log(),
+        }
     }
-    Ok(())
 }
 #[derive(Debug)]
 #[allow(clippy::large_enum_variant)]
@@ -156,7 +209,7 @@ async fn build_swarm(
     psk: Option<PreSharedKey>,
     upgrade_timeout: Duration,
     tls: Option<(websocket::tls::PrivateKey, Vec<websocket::tls::Certificate>)>,
-) -> anyhow::Result<Swarm<Behaviour>> {
+) -> anyhow::Result<(Swarm<Behaviour>, Arc<BandwidthSinks>)> {
     let tcp_base = {
         let tcp = TokioTcpConfig::new().nodelay(true);
         TokioDnsConfig::system(tcp).context("Creating TokioDnsConfig")?
@@ -181,6 +234,7 @@ async fn build_swarm(
         ),
         None => EitherTransport::Right(base_transport),
     };
+    let (base_transport, bandwidth) = BandwidthLogging::new(base_transport);
     // TODO: think about idle timeout for relayed connections
     let (transport, relay_behaviour) =
         relay::new_transport_and_behaviour(Default::default(), base_transport);
@@ -198,7 +252,7 @@ async fn build_swarm(
         .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
         .boxed();
 
-    Ok(SwarmBuilder::new(
+    let swarm = SwarmBuilder::new(
         transport,
         Behaviour {
             relay: relay_behaviour,
@@ -219,5 +273,6 @@ async fn build_swarm(
     .executor(Box::new(|f| {
         tokio::spawn(f);
     }))
-    .build())
+    .build();
+    Ok((swarm, bandwidth))
 }
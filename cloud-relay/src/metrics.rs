@@ -0,0 +1,143 @@
+use libp2p::bandwidth::BandwidthSinks;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::*;
+use warp::Filter;
+
+/// Warn in the periodic stats when the certificate expires within this duration.
+const CERT_EXPIRY_WARNING: Duration = Duration::from_secs(60 * 60 * 24 * 14);
+
+/// Relay health metrics, exported in the prometheus text format.
+pub(crate) struct Metrics {
+    connections: AtomicU64,
+    registrations: AtomicU64,
+    bandwidth: Arc<BandwidthSinks>,
+    /// Expiry of the certificate loaded at startup, which is served until the relay restarts.
+    cert_not_after: Option<i64>,
+}
+
+impl Metrics {
+    pub fn new(bandwidth: Arc<BandwidthSinks>, cert_not_after: Option<i64>) -> Self {
+        Self {
+            connections: AtomicU64::new(0),
+            registrations: AtomicU64::new(0),
+            bandwidth,
+            cert_not_after,
+        }
+    }
+
+    pub fn set_connections(&self, n: u64) {
+        self.connections.store(n, Ordering::Relaxed);
+    }
+
+    pub fn set_registrations(&self, n: u64) {
+        self.registrations.store(n, Ordering::Relaxed);
+    }
+
+    /// Seconds until the TLS certificate expires.
+    fn cert_ttl(&self) -> Option<i64> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64;
+        self.cert_not_after.map(|not_after| not_after - now)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(
+            out,
+            "# HELP relay_connections Number of established connections."
+        )
+        .ok();
+        writeln!(out, "# TYPE relay_connections gauge").ok();
+        writeln!(
+            out,
+            "relay_connections {}",
+            self.connections.load(Ordering::Relaxed)
+        )
+        .ok();
+        writeln!(
+            out,
+            "# HELP relay_transferred_bytes_total Bytes transferred including relayed traffic."
+        )
+        .ok();
+        writeln!(out, "# TYPE relay_transferred_bytes_total counter").ok();
+        writeln!(
+            out,
+            "relay_transferred_bytes_total{{direction=\"inbound\"}} {}",
+            self.bandwidth.total_inbound()
+        )
+        .ok();
+        writeln!(
+            out,
+            "relay_transferred_bytes_total{{direction=\"outbound\"}} {}",
+            self.bandwidth.total_outbound()
+        )
+        .ok();
+        writeln!(
+            out,
+            "# HELP relay_rendezvous_registrations Number of active rendezvous registrations."
+        )
+        .ok();
+        writeln!(out, "# TYPE relay_rendezvous_registrations gauge").ok();
+        writeln!(
+            out,
+            "relay_rendezvous_registrations {}",
+            self.registrations.load(Ordering::Relaxed)
+        )
+        .ok();
+        if let Some(not_after) = self.cert_not_after {
+            writeln!(
+                out,
+                "# HELP relay_tls_cert_expiry_timestamp_seconds Expiry of the TLS certificate loaded at startup."
+            )
+            .ok();
+            writeln!(out, "# TYPE relay_tls_cert_expiry_timestamp_seconds gauge").ok();
+            writeln!(out, "relay_tls_cert_expiry_timestamp_seconds {}", not_after).ok();
+        }
+        out
+    }
+
+    /// Logs the current metrics as structured fields.
+    pub fn log(&self) {
+        info!(
+            connections = self.connections.load(Ordering::Relaxed),
+            inbound_bytes = self.bandwidth.total_inbound(),
+            outbound_bytes = self.bandwidth.total_outbound(),
+            registrations = self.registrations.load(Ordering::Relaxed),
+            cert_ttl_secs = ?self.cert_ttl(),
+            "Relay stats"
+        );
+        if let Some(ttl) = self.cert_ttl() {
+            if ttl < CERT_EXPIRY_WARNING.as_secs() as i64 {
+                warn!(cert_ttl_secs = ttl, "TLS certificate is about to expire");
+            }
+        }
+    }
+}
+
+/// Binds `addr` and returns the server answering `GET /metrics`. Fails if `addr` can't be
+/// bound.
+pub(crate) fn serve(
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+) -> anyhow::Result<impl Future<Output = ()>> {
+    let route = warp::get()
+        .and(warp::path!("metrics"))
+        .map(move || metrics.render());
+    let (addr, server) = warp::serve(route).try_bind_ephemeral(addr)?;
+    info!("Serving metrics on http://{}/metrics", addr);
+    Ok(server)
+}
+
+/// Returns the expiry of the first certificate of a chain as a unix timestamp.
+pub(crate) fn cert_not_after(der: &[u8]) -> anyhow::Result<i64> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der)
+        .map_err(|err| anyhow::anyhow!("Parsing TLS cert: {}", err))?;
+    Ok(cert.validity().not_after.timestamp())
+}
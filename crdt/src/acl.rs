@@ -1,14 +1,17 @@
+use crate::crypto::Keypair;
 use crate::dotset::Dot;
 use crate::id::{DocId, PeerId};
 use crate::path::{Path, PathBuf};
-use crate::radixdb::{BlobMap, Diff, Storage};
+use crate::radixdb::{BlobMap, BlobSet, Diff, Flusher};
 use crate::util::Ref;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use bytecheck::CheckBytes;
 use crepe::crepe;
+use ed25519_dalek::{PublicKey, Signature, Verifier};
 use futures::stream::BoxStream;
+use parking_lot::RwLock;
 use rkyv::{Archive, Deserialize, Serialize};
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 
 /// Permission type.
@@ -58,6 +61,8 @@ pub enum Actor {
     /// Unbound public key which will be bound by the condition
     /// in a conditional statement.
     Unbound,
+    /// Any peer with permission on the root of a group document.
+    Group(DocId),
 }
 
 impl Actor {
@@ -85,6 +90,7 @@ impl std::fmt::Debug for Actor {
             Self::Peer(p) => write!(f, "{:?}", p),
             Self::Anonymous => write!(f, "Anonymous"),
             Self::Unbound => write!(f, "Unbound"),
+            Self::Group(g) => write!(f, "Group({:?})", g),
         }
     }
 }
@@ -115,6 +121,192 @@ impl Can {
     }
 }
 
+/// Offline verifiable capability token. Grants an actor a permission on a path until the
+/// token expires, as long as the issuer is allowed to delegate it.
+///
+/// Tokens are only known to the replicas they were presented to, so they never authorize
+/// changes. A replica accepting a token serves the document to the holder. Replicas that can
+/// delegate the permission themselves record it as a policy statement, see
+/// [`Doc::redeem_token`](crate::Doc::redeem_token).
+#[derive(Clone, Debug, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct Token {
+    can: Can,
+    /// Expiry in seconds since the unix epoch.
+    expiry: u64,
+    issuer: PeerId,
+    sig: [u8; 64],
+}
+
+impl Token {
+    pub(crate) fn new(key: &Keypair, can: Can, expiry: u64) -> Self {
+        let sig = key.sign(&Self::message(&can, expiry)).to_bytes();
+        Self {
+            can,
+            expiry,
+            issuer: key.peer_id(),
+            sig,
+        }
+    }
+
+    fn message(can: &Can, expiry: u64) -> Vec<u8> {
+        Ref::archive(&(can.clone(), expiry)).as_bytes().to_vec()
+    }
+
+    /// Returns the document the token grants access to.
+    pub fn doc(&self) -> Result<DocId> {
+        self.can
+            .path
+            .as_path()
+            .first()
+            .and_then(|doc| doc.doc())
+            .ok_or_else(|| anyhow!("token doesn't name a document"))
+    }
+
+    /// Returns the path the permission is granted on.
+    pub fn path(&self) -> Path<'_> {
+        self.can.path.as_path()
+    }
+
+    /// Returns the actor the token was issued to.
+    pub fn actor(&self) -> Actor {
+        self.can.actor
+    }
+
+    /// Returns the granted permission.
+    pub fn perm(&self) -> Permission {
+        self.can.perm
+    }
+
+    /// Returns the expiry in seconds since the unix epoch.
+    pub fn expiry(&self) -> u64 {
+        self.expiry
+    }
+
+    /// Returns if the token expired.
+    pub fn is_expired(&self) -> bool {
+        self.expiry <= crate::util::now()
+    }
+
+    /// Returns the peer that minted the token.
+    pub fn issuer(&self) -> PeerId {
+        self.issuer
+    }
+
+    /// Returns an identifier of the token.
+    pub fn id(&self) -> [u8; 32] {
+        blake3::hash(&self.sig).into()
+    }
+
+    pub(crate) fn verify(&self) -> Result<()> {
+        self.doc()?;
+        let pubkey = PublicKey::from_bytes(self.issuer.as_ref())?;
+        let sig = Signature::from_bytes(&self.sig)?;
+        pubkey.verify(&Self::message(&self.can, self.expiry), &sig)?;
+        Ok(())
+    }
+
+    pub(crate) fn grants(&self, peer: PeerId, perm: Permission, path: Path, now: u64) -> bool {
+        let actor = match self.can.actor {
+            Actor::Peer(actor) => actor == peer,
+            Actor::Anonymous => true,
+            Actor::Unbound | Actor::Group(_) => false,
+        };
+        actor
+            && self.expiry > now
+            && self.can.perm >= perm
+            && self.can.path.as_path().is_ancestor(path)
+    }
+}
+
+/// Offline invitation to a document, for example shared as a QR code. Carries a capability
+/// [`Token`] granting anyone redeeming it a permission on the document, together with the
+/// schema needed to add the document.
+#[derive(Clone, Debug, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct InviteToken {
+    token: Token,
+    schema: String,
+    hash: [u8; 32],
+    sig: [u8; 64],
+}
+
+impl InviteToken {
+    pub(crate) fn new(key: &Keypair, token: Token, schema: String, hash: [u8; 32]) -> Self {
+        let sig = key.sign(&Self::message(&token, &schema, &hash)).to_bytes();
+        Self {
+            token,
+            schema,
+            hash,
+            sig,
+        }
+    }
+
+    fn message(token: &Token, schema: &str, hash: &[u8; 32]) -> Vec<u8> {
+        Ref::archive(&(token.clone(), schema.to_string(), *hash))
+            .as_bytes()
+            .to_vec()
+    }
+
+    /// Decodes an invite token and checks it's signatures.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let invite: Self = Ref::checked(bytes)?.to_owned()?;
+        if invite.token.expiry == u64::MAX {
+            return Err(anyhow!("invite token doesn't expire"));
+        }
+        invite.token.verify()?;
+        let pubkey = PublicKey::from_bytes(invite.token.issuer.as_ref())?;
+        let sig = Signature::from_bytes(&invite.sig)?;
+        pubkey.verify(
+            &Self::message(&invite.token, &invite.schema, &invite.hash),
+            &sig,
+        )?;
+        Ok(invite)
+    }
+
+    /// Encodes the invite token.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Ref::archive(self).as_bytes().to_vec()
+    }
+
+    /// Returns the document the invite is for.
+    pub fn doc(&self) -> Result<DocId> {
+        self.token.doc()
+    }
+
+    /// Returns the name of the document's schema.
+    pub fn schema(&self) -> &str {
+        &self.schema
+    }
+
+    /// Returns the hash of the document's schema.
+    pub fn hash(&self) -> [u8; 32] {
+        self.hash
+    }
+
+    /// Returns the granted permission.
+    pub fn perm(&self) -> Permission {
+        self.token.perm()
+    }
+
+    /// Returns the expiry in seconds since the unix epoch.
+    pub fn expiry(&self) -> u64 {
+        self.token.expiry()
+    }
+
+    /// Returns the peer that created the invite.
+    pub fn issuer(&self) -> PeerId {
+        self.token.issuer()
+    }
+
+    /// Returns the capability token granting the permission.
+    pub fn token(&self) -> &Token {
+        &self.token
+    }
+}
+
 /// Policy statement.
 #[derive(Clone, Debug, Eq, Hash, PartialEq, Ord, PartialOrd, Archive, Deserialize, Serialize)]
 #[archive_attr(derive(Debug, Eq, Hash, PartialEq, Ord, PartialOrd, CheckBytes))]
@@ -127,6 +319,84 @@ pub enum Policy {
     Revokes(Dot),
 }
 
+/// Reason a peer holds a permission on a path.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Grant {
+    /// The peer is the local authority of the document.
+    LocalAuthority,
+    /// Rule derived from the policy statement `id`. Group and conditional statements are
+    /// resolved to the `actor` and `path` the rule applies to.
+    Rule {
+        /// Dot of the statement.
+        id: Dot,
+        /// Actor the rule applies to, either the peer or [`Actor::Anonymous`].
+        actor: Actor,
+        /// Granted permission.
+        perm: Permission,
+        /// Path the permission is granted on.
+        path: PathBuf,
+        /// Issuer and policy of the statement, `None` if it isn't in the store.
+        statement: Option<(PeerId, Policy)>,
+    },
+    /// Capability token presented to this replica.
+    Token(Token),
+}
+
+impl Grant {
+    /// Returns the granted permission.
+    pub fn perm(&self) -> Permission {
+        match self {
+            Self::LocalAuthority => Permission::Own,
+            Self::Rule { perm, .. } => *perm,
+            Self::Token(token) => token.perm(),
+        }
+    }
+}
+
+/// Reason a policy statement naming a peer doesn't grant it a permission.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum NotGranted {
+    /// The statement `id` was revoked by the statement `by`.
+    Revoked {
+        /// Dot of the statement.
+        id: Dot,
+        /// Dot of the revocation.
+        by: Dot,
+    },
+    /// The condition of the statement `id` isn't met.
+    ConditionNotMet {
+        /// Dot of the statement.
+        id: Dot,
+        /// Condition of the statement.
+        cond: Can,
+    },
+    /// The issuer of the statement `id` can't grant permissions on the path.
+    IssuerNotAuthorized {
+        /// Dot of the statement.
+        id: Dot,
+        /// Issuer of the statement.
+        issuer: PeerId,
+    },
+}
+
+/// Permissions of a peer on a path and the grants they are derived from.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EffectivePermissions {
+    /// Strongest permission held, `None` if the peer has no access.
+    pub perm: Option<Permission>,
+    /// Grants applying to the path.
+    pub grants: Vec<Grant>,
+    /// Statements naming the peer on the path that don't grant it a permission.
+    pub not_granted: Vec<NotGranted>,
+}
+
+impl EffectivePermissions {
+    /// Returns if the peer has `perm`.
+    pub fn can(&self, perm: Permission) -> bool {
+        self.perm.map(|p| p >= perm).unwrap_or_default()
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 struct CanRef<'a> {
     actor: Actor,
@@ -161,6 +431,12 @@ impl<'a> CanRef<'a> {
         other.perm <= self.perm() && self.path().is_ancestor(other.path())
     }
 
+    fn is_member_of(self, group: DocId) -> bool {
+        matches!(self.actor, Actor::Peer(_))
+            && self.root() == group
+            && self.path.child().map(|p| p.is_empty()).unwrap_or_default()
+    }
+
     fn bind(self, rule: CanRef<'a>) -> Self {
         Self {
             actor: rule.actor,
@@ -240,6 +516,13 @@ crepe! {
         Authorized(_, _, auth),
         (auth.implies(cond));
 
+    // group membership
+    Authorized(id, peer, can.bind(member)) <-
+        Authorized(id, peer, can),
+        let Actor::Group(group) = can.actor(),
+        Authorized(_, _, member),
+        (member.is_member_of(group));
+
     // local authority
     Authorized(id, peer, can) <-
         DerivedCan(id, peer, can),
@@ -293,16 +576,75 @@ impl Rule {
     }
 }
 
+/// Maximum number of capability tokens kept by a replica.
+const MAX_TOKENS: usize = 1024;
+
+/// Acl rules derived from the policy plus the capability tokens presented to this replica.
+/// Tokens are kept in memory only since they expire, and only grant read access.
 #[derive(Clone)]
-pub struct Acl(BlobMap);
+pub struct Acl(BlobMap, Arc<RwLock<Vec<Token>>>);
 
 impl Acl {
     pub fn new(tree: BlobMap) -> Self {
-        Self(tree)
+        Self(tree, Default::default())
+    }
+
+    pub fn load(storage: Flusher, name: &str) -> Result<Self> {
+        Ok(Self::new(BlobMap::load(storage, name)?))
+    }
+
+    /// Adds a capability token after checking it's signature and expiry. Whether the issuer is
+    /// allowed to delegate the permission is checked when the token is used, so that tokens can
+    /// be added before the policy of the document was synced.
+    pub fn add_token(&self, token: Token) -> Result<()> {
+        token.verify()?;
+        let now = crate::util::now();
+        if token.expiry <= now {
+            return Err(anyhow!("token expired"));
+        }
+        let mut tokens = self.1.write();
+        tokens.retain(|t| t.expiry > now);
+        if tokens.contains(&token) {
+            return Ok(());
+        }
+        if tokens.len() >= MAX_TOKENS {
+            // make room by dropping the token expiring first.
+            let (ix, _) = tokens
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, t)| t.expiry)
+                .unwrap();
+            tokens.swap_remove(ix);
+        }
+        tokens.push(token);
+        Ok(())
     }
 
-    pub fn load(storage: Arc<dyn Storage>, name: &str) -> Result<Self> {
-        Ok(Self(BlobMap::load(storage, name)?))
+    fn can_delegate(&self, token: &Token) -> Result<bool> {
+        self.delegates(token.issuer, token)
+    }
+
+    /// Returns if `peer` can delegate the permission granted by `token`.
+    fn delegates(&self, peer: PeerId, token: &Token) -> Result<bool> {
+        let perm = if token.can.perm.controllable() {
+            Permission::Control
+        } else {
+            Permission::Own
+        };
+        self.can_rules(peer, perm, token.can.path.as_path())
+    }
+
+    /// Returns if `peer` can revoke `token`, which requires being it's issuer or being allowed
+    /// to delegate it's permission.
+    pub fn can_revoke(&self, peer: PeerId, token: &Token) -> Result<bool> {
+        Ok(token.issuer == peer || self.delegates(peer, token)?)
+    }
+
+    /// Drops the tokens with `id` that `peer` can revoke.
+    pub(crate) fn revoke_token(&self, id: &[u8; 32], peer: PeerId) {
+        self.1
+            .write()
+            .retain(|token| token.id() != *id || !self.can_revoke(peer, token).unwrap_or(false));
     }
 
     pub fn active_peer(&self, peer: &PeerId) -> bool {
@@ -327,17 +669,31 @@ impl Acl {
         self.0.watch_prefix(path)
     }
 
-    fn add_rule(&self, id: Dot, actor: Actor, perm: Permission, path: Path) -> Result<()> {
-        let peer = match actor {
-            Actor::Peer(peer) => peer,
-            _ => PeerId::new([0; 32]),
-        };
-        let mut prefix = PathBuf::new();
-        prefix.doc(&path.first().unwrap().doc().unwrap());
-        prefix.peer(&peer);
-        prefix.extend(path.child().unwrap());
-        self.0
-            .insert_archived(prefix.as_path(), &Rule::new(id, perm))?;
+    /// Returns the changes of the rules of `doc`, each relative to the previous one.
+    pub fn subscribe_changes(&self, doc: &DocId) -> BoxStream<'static, Diff<u8, Arc<[u8]>>> {
+        let mut path = PathBuf::new();
+        path.doc(doc);
+        self.0.watch_prefix_changes(path)
+    }
+
+    /// Returns the changes of the rules of all documents, each relative to the previous one.
+    pub fn subscribe_all(&self) -> BoxStream<'static, Diff<u8, Arc<[u8]>>> {
+        self.0.watch_prefix_changes([])
+    }
+
+    /// Replaces the rules with `rules`. Every statement authorizing an actor is kept as a
+    /// separate rule, so revoking one of them keeps the permissions granted by the others.
+    fn set_rules(&self, rules: BTreeMap<PathBuf, Rule>) -> Result<()> {
+        for (key, _) in self.0.iter() {
+            if !rules.contains_key(&key[..]) {
+                self.0.remove(&key)?;
+            }
+        }
+        for (key, rule) in rules {
+            if self.0.get(key.as_path())?.is_none() {
+                self.0.insert_archived(key.as_path(), &rule)?;
+            }
+        }
         Ok(())
     }
 
@@ -355,16 +711,79 @@ impl Acl {
         prefix.doc(doc);
         prefix.peer(peer);
         for (k, v) in self.0.scan_prefix(prefix) {
-            let p = Path::new(&k);
+            let (_, rule_path) = rule_key_path(Path::new(&k));
             let rule = Ref::<Rule>::new(v.clone());
-            if p.child().unwrap().child().unwrap().is_ancestor(path) && rule.as_ref().perm >= perm {
+            if rule_path.is_ancestor(path) && rule.as_ref().perm >= perm {
                 return Ok(true);
             }
         }
         Ok(false)
     }
 
+    /// Returns if the policy gives `peer` the permission on `path`. Capability tokens are
+    /// ignored, so all replicas with the same policy come to the same decision.
     pub fn can(&self, peer: PeerId, perm: Permission, path: Path) -> Result<bool> {
+        self.can_rules(peer, perm, path)
+    }
+
+    /// Returns if `peer` can read `path` because of the policy or a capability token
+    /// presented to this replica.
+    pub fn can_read(&self, peer: PeerId, path: Path) -> Result<bool> {
+        if self.can_rules(peer, Permission::Read, path)? {
+            return Ok(true);
+        }
+        let now = crate::util::now();
+        for token in self.1.read().iter() {
+            if token.grants(peer, Permission::Read, path, now) && self.can_delegate(token)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the grants giving `peer` a permission on `path`.
+    pub fn grants(&self, peer: PeerId, path: Path) -> Result<Vec<Grant>> {
+        let (doc, rest) = path.split_first().unwrap();
+        let doc = doc.doc().unwrap();
+        let mut grants = vec![];
+        if peer == doc.into() {
+            grants.push(Grant::LocalAuthority);
+        }
+        for (actor, id) in [
+            (Actor::Peer(peer), peer),
+            (Actor::Anonymous, PeerId::new([0; 32])),
+        ] {
+            let mut prefix = PathBuf::new();
+            prefix.doc(&doc);
+            prefix.peer(&id);
+            for (k, v) in self.0.scan_prefix(prefix) {
+                let (_, rule_path) = rule_key_path(Path::new(&k));
+                if !rule_path.is_ancestor(rest) {
+                    continue;
+                }
+                let rule = Ref::<Rule>::new(v.clone());
+                let mut path = PathBuf::new();
+                path.doc(&doc);
+                path.extend(rule_path);
+                grants.push(Grant::Rule {
+                    id: rule.as_ref().id,
+                    actor,
+                    perm: rule.as_ref().perm,
+                    path,
+                    statement: None,
+                });
+            }
+        }
+        let now = crate::util::now();
+        for token in self.1.read().iter() {
+            if token.grants(peer, Permission::Read, path, now) && self.can_delegate(token)? {
+                grants.push(Grant::Token(token.clone()));
+            }
+        }
+        Ok(grants)
+    }
+
+    fn can_rules(&self, peer: PeerId, perm: Permission, path: Path) -> Result<bool> {
         let (doc, path) = path.split_first().unwrap();
         let doc = doc.doc().unwrap();
         if peer == doc.into() {
@@ -380,6 +799,22 @@ impl Acl {
     }
 }
 
+/// Key of the rule derived from statement `id` authorizing `peer` on `path`.
+fn rule_key(id: Dot, peer: &PeerId, path: Path) -> PathBuf {
+    let mut key = PathBuf::new();
+    key.doc(&path.first().unwrap().doc().unwrap());
+    key.peer(peer);
+    key.extend(path.child().unwrap());
+    key.dot(&id);
+    key
+}
+
+/// Returns the peer and the document relative path of a rule key.
+pub(crate) fn rule_key_path(key: Path) -> (PeerId, Path) {
+    let (peer, path) = key.child().unwrap().split_first().unwrap();
+    (peer.peer().unwrap(), path.parent().unwrap())
+}
+
 struct AclDebug<'a>(&'a BlobMap);
 
 impl<'a> std::fmt::Debug for AclDebug<'a> {
@@ -400,28 +835,120 @@ impl std::fmt::Debug for Acl {
     }
 }
 
+/// Key marking that the persisted policy is complete. It can't be confused with a policy path
+/// since paths start with a doc segment.
+const POLICY_COMPLETE: &[u8] = b"complete";
+
+/// Marks the persisted policy `paths` as incomplete. Called before policy changes are written
+/// to the store, so that the policy is rebuilt on startup if the engine didn't persist them.
+pub(crate) fn invalidate_policy(paths: &BlobSet) -> Result<()> {
+    if paths.contains(POLICY_COMPLETE) {
+        paths.remove(POLICY_COMPLETE);
+        paths.flush()?;
+    }
+    Ok(())
+}
+
 pub struct Engine {
     policy: BTreeSet<Says>,
+    /// Policy paths known to the engine, persisted so that they don't need to be collected
+    /// from the store on startup.
+    paths: BlobSet,
     acl: Acl,
 }
 
 impl Engine {
-    pub fn new(acl: Acl) -> Result<Self> {
-        Ok(Self {
-            policy: Default::default(),
-            acl,
-        })
+    pub fn new(acl: Acl, paths: BlobSet) -> Result<Self> {
+        // rules used to be keyed without their statement, they are derived from the policy
+        // again.
+        let legacy = acl
+            .0
+            .iter()
+            .any(|(key, _)| Path::new(&key).last().and_then(|seg| seg.dot()).is_none());
+        if legacy {
+            for (key, _) in acl.0.iter() {
+                acl.0.remove(&key)?;
+            }
+            invalidate_policy(&paths)?;
+        }
+        let policy = paths
+            .keys()
+            .filter(|key| key.as_ref() != POLICY_COMPLETE)
+            .filter_map(|key| Self::says(Path::new(&key)))
+            .collect();
+        Ok(Self { policy, paths, acl })
+    }
+
+    /// Returns `true` if the persisted policy contains every policy path of the store. Otherwise
+    /// the policy needs to be rebuilt from the store.
+    pub fn is_complete(&self) -> bool {
+        self.paths.contains(POLICY_COMPLETE)
+    }
+
+    /// Marks the persisted policy as complete.
+    pub fn set_complete(&self) -> Result<()> {
+        if !self.is_complete() {
+            self.paths.insert(POLICY_COMPLETE);
+            self.paths.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the policy with the policy paths in `store`, revoking the rules of persisted
+    /// policies that aren't in the store anymore, and marks it as complete.
+    pub fn rebuild(&mut self, store: impl IntoIterator<Item = Vec<u8>>) -> Result<()> {
+        let store = store
+            .into_iter()
+            .filter(|key| Self::says(Path::new(key)).is_some())
+            .collect::<BTreeSet<_>>();
+        let stale = self
+            .paths
+            .keys()
+            .filter(|key| key.as_ref() != POLICY_COMPLETE && !store.contains(key.as_ref()))
+            .map(|key| key.to_vec())
+            .collect::<Vec<_>>();
+        for key in stale {
+            if !self.remove_policy(Path::new(&key))? {
+                self.paths.remove(&key);
+            }
+        }
+        for key in &store {
+            self.add_policy(Path::new(key));
+        }
+        self.update_acl()?;
+        self.set_complete()
     }
 
     pub fn active_peer(&self, peer: &PeerId) -> bool {
         self.acl.active_peer(peer)
     }
 
-    pub fn add_policy(&mut self, path: Path) {
-        self._add_policy(path);
+    /// Adds a policy path. Returns `true` if it is a policy that wasn't known yet.
+    pub fn add_policy(&mut self, path: Path) -> bool {
+        if let Some(says) = Self::says(path) {
+            if self.policy.insert(says) {
+                self.paths.insert(path);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Removes a policy path and the rules derived from it. Returns `true` if the policy was
+    /// known.
+    pub fn remove_policy(&mut self, path: Path) -> Result<bool> {
+        if let Some(says) = Self::says(path) {
+            if self.policy.remove(&says) {
+                self.paths.remove(path);
+                self.acl
+                    .revoke_rules(std::iter::once(path.dot()).collect())?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
-    fn _add_policy(&mut self, path: Path) -> Option<()> {
+    fn says(path: Path) -> Option<Says> {
         let dot = path.dot();
         // schema.doc.(primitive|str)*.policy.peer.sig
         let (path, _) = path.split_last()?;
@@ -437,8 +964,7 @@ impl Engine {
             }
             Policy::Revokes(dot) => Says::Revokes(peer, dot),
         };
-        self.policy.insert(says);
-        None
+        Some(says)
     }
 
     pub fn update_acl(&self) -> Result<()> {
@@ -446,12 +972,24 @@ impl Engine {
         runtime.extend(self.policy.iter().map(Input));
         let (authorized, revoked) = runtime.run();
         let revoked: BTreeSet<Dot> = revoked.into_iter().map(|r| r.0).collect();
+        let mut rules = BTreeMap::<PathBuf, Rule>::new();
         for Authorized(id, _, CanRef { actor, perm, path }) in authorized.into_iter() {
-            if !revoked.contains(&id) {
-                self.acl.add_rule(id, actor, perm, path)?;
+            // revoked statements don't authorize anyone, group statements are applied through
+            // their members.
+            if revoked.contains(&id) || matches!(actor, Actor::Group(_)) {
+                continue;
             }
+            let peer = match actor {
+                Actor::Peer(peer) => peer,
+                _ => PeerId::new([0; 32]),
+            };
+            let rule = rules
+                .entry(rule_key(id, &peer, path))
+                .or_insert_with(|| Rule::new(id, perm));
+            rule.perm = rule.perm.max(perm);
         }
-        self.acl.revoke_rules(revoked)?;
+        self.acl.set_rules(rules)?;
+        self.paths.flush()?;
         Ok(())
     }
 }
@@ -459,7 +997,7 @@ impl Engine {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Backend, Keypair};
+    use crate::{Backend, Durability, Keypair, MemStorage};
     use std::pin::Pin;
     use Permission::*;
 
@@ -516,6 +1054,89 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_effective_permissions() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc1 = fut.await;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = fut.await;
+
+        let perms = doc2.cursor().effective_permissions(&b)?;
+        assert_eq!(perms.perm, None);
+        assert!(perms.grants.is_empty());
+
+        let cond = doc1.cursor().cond(Actor::Peer(b), Read);
+        let op = doc2.cursor().say_can_if(Actor::Peer(b), Write, cond)?;
+        doc2.apply(&op)?;
+        let cond_id = op.store.iter().next().unwrap().as_path().dot();
+        Pin::new(&mut sdk).await?;
+
+        let perms = doc2.cursor().effective_permissions(&b)?;
+        assert_eq!(perms.perm, None);
+        match &perms.not_granted[..] {
+            [NotGranted::ConditionNotMet { id, cond }] => {
+                assert_eq!(*id, cond_id);
+                assert_eq!(
+                    cond.to_string(),
+                    doc1.cursor().cond(Actor::Peer(b), Read).to_string()
+                );
+            }
+            not_granted => panic!("unexpected {:?}", not_granted),
+        }
+
+        let op = doc1.cursor().say_can(Some(b), Write)?;
+        doc1.apply(&op)?;
+        let grant_id = op.store.iter().next().unwrap().as_path().dot();
+        Pin::new(&mut sdk).await?;
+
+        let perms = doc2.cursor().effective_permissions(&b)?;
+        assert!(perms.not_granted.is_empty());
+        assert!(perms.can(Write));
+        assert!(!perms.can(Control));
+        assert_eq!(perms.grants.len(), 1);
+        match &perms.grants[0] {
+            Grant::Rule {
+                actor,
+                perm,
+                statement: Some((issuer, Policy::CanIf(_, _, cond))),
+                ..
+            } => {
+                assert_eq!(*actor, Actor::Peer(b));
+                assert_eq!(*perm, Write);
+                assert_eq!(*issuer, a);
+                assert_eq!(
+                    cond.to_string(),
+                    doc1.cursor().cond(Actor::Peer(b), Read).to_string()
+                );
+            }
+            grant => panic!("unexpected grant {:?}", grant),
+        }
+
+        let perms = doc2.cursor().effective_permissions(&a)?;
+        assert_eq!(perms.perm, Some(Own));
+
+        let op = doc1.cursor().revoke(grant_id)?;
+        doc1.apply(&op)?;
+        let revoke_id = op.store.iter().next().unwrap().as_path().dot();
+        Pin::new(&mut sdk).await?;
+
+        let perms = doc1.cursor().effective_permissions(&b)?;
+        assert_eq!(perms.perm, None);
+        assert_eq!(
+            perms.not_granted,
+            vec![NotGranted::Revoked {
+                id: grant_id,
+                by: revoke_id
+            }]
+        );
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_says_if_unbound() -> Result<()> {
         let mut sdk = Backend::test("acl {}")?;
@@ -598,13 +1219,110 @@ mod tests {
         Pin::new(&mut sdk).await?;
         assert!(doc.cursor().can(&b, Write)?);
 
+        let claim = op.store.iter().next().unwrap().as_path().dot();
+        let cursor = doc.cursor();
+        let policies = cursor.policies().collect::<Vec<_>>();
+        assert!(policies.contains(&(claim, a, Policy::Can(Actor::Peer(b), Write))));
+
+        let op = doc.cursor().revoke(claim)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().can(&b, Write)?);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_revoke_keeps_other_statements() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let read = doc.cursor().say_can(Some(b), Read)?;
+        doc.apply(&read)?;
+        let write = doc.cursor().say_can(Some(b), Write)?;
+        doc.apply(&write)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Write)?);
+        assert_eq!(doc.cursor().effective_permissions(&b)?.grants.len(), 2);
+
+        // revoking the stronger statement keeps the weaker one.
+        let claim = write.store.iter().next().unwrap().as_path().dot();
+        doc.apply(&doc.cursor().revoke(claim)?)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().can(&b, Write)?);
+        assert!(doc.cursor().can(&b, Read)?);
+        assert_eq!(doc.cursor().effective_permissions(&b)?.grants.len(), 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_persisted_policy() -> Result<()> {
+        let package = Ref::archive(&tlfsc::compile_lenses("acl {}")?);
+        let storage = Arc::new(MemStorage::default());
+        let mut sdk = Backend::new(storage.clone(), package.as_bytes())?;
+        sdk.set_durability(Durability::Manual);
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let op = doc.cursor().say_can(Some(b), Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        sdk.sync().await?;
+        drop(doc);
+        drop(sdk);
+
+        let mut sdk = Backend::new(storage, package.as_bytes())?;
+        let doc = sdk.frontend().docs().next().unwrap()?;
+        let doc = sdk.frontend().doc(doc)?;
+        assert!(doc.cursor().can(&b, Write)?);
+
+        // revoking requires the engine to know the persisted policy.
         let op = doc
             .cursor()
             .revoke(op.store.iter().next().unwrap().as_path().dot())?;
         doc.apply(&op)?;
         Pin::new(&mut sdk).await?;
         assert!(!doc.cursor().can(&b, Write)?);
+        Ok(())
+    }
 
+    #[async_std::test]
+    async fn test_rebuild_policy() -> Result<()> {
+        let package = Ref::archive(&tlfsc::compile_lenses("acl {}")?);
+        let storage = Arc::new(MemStorage::default());
+        let mut sdk = Backend::new(storage.clone(), package.as_bytes())?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let op = doc.cursor().say_can(Some(b), Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Write)?);
+        sdk.sync().await?;
+        drop(doc);
+        drop(sdk);
+
+        // the statement was removed from the store, but the engine didn't persist the removal.
+        let flusher = Flusher::new(storage.clone());
+        let store = BlobSet::load(flusher.clone(), "store")?;
+        invalidate_policy(&BlobSet::load(flusher.clone(), "policy")?)?;
+        store.remove(op.store.iter().next().unwrap().as_path());
+        store.flush()?;
+        flusher.sync().await?;
+
+        let sdk = Backend::new(storage, package.as_bytes())?;
+        let doc = sdk.frontend().docs().next().unwrap()?;
+        let doc = sdk.frontend().doc(doc)?;
+        assert!(doc.cursor().can(&a, Own)?);
+        assert!(!doc.cursor().can(&b, Write)?);
         Ok(())
     }
 
@@ -632,4 +1350,308 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_token() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let c = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let now = crate::util::now();
+
+        let bdoc = sdk.frontend().doc_as(*doc.id(), &b)?;
+        assert!(bdoc.cursor().mint_token(Some(c), Read, now + 60).is_err());
+
+        let expired = doc.cursor().mint_token(Some(b), Write, now)?;
+        assert!(sdk.frontend().add_token(expired).is_err());
+
+        let token = doc.cursor().mint_token(Some(b), Write, now + 60)?;
+        assert_eq!(token.doc()?, *doc.id());
+        let token = Ref::archive(&token).to_owned()?;
+        assert!(!doc.cursor().can_read(&b)?);
+        sdk.frontend().add_token(token.clone())?;
+        // tokens only grant read access on the replicas they were presented to.
+        assert!(doc.cursor().can_read(&b)?);
+        assert!(!doc.cursor().can(&b, Read)?);
+        assert!(!doc.cursor().can(&b, Write)?);
+        assert!(!doc.cursor().can_read(&c)?);
+
+        // replicas that can delegate the permission turn it into a policy statement.
+        assert!(doc.redeem_token(&token, &c)?.is_none());
+        assert!(bdoc.redeem_token(&token, &b)?.is_none());
+        let causal = doc.redeem_token(&token, &b)?.unwrap();
+        doc.apply(&causal)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Write)?);
+        assert!(!doc.cursor().can(&b, Control)?);
+        assert!(doc.redeem_token(&token, &b)?.is_none());
+
+        // a token issued by a peer that can't delegate the permission is ignored.
+        let forged = Token::new(
+            &sdk.frontend().keypair(&b)?,
+            Can::new(Actor::Peer(c), Read, doc.cursor().path().to_owned()),
+            now + 60,
+        );
+        sdk.frontend().add_token(forged.clone())?;
+        assert!(!doc.cursor().can_read(&c)?);
+        assert!(doc.redeem_token(&forged, &c)?.is_none());
+
+        let token = doc.cursor().mint_token(None, Read, now + 60)?;
+        sdk.frontend().add_token(token)?;
+        assert!(doc.cursor().can_read(&c)?);
+        assert!(!doc.cursor().can(&c, Read)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_token_capacity() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let now = crate::util::now();
+        let peers = (0..=MAX_TOKENS)
+            .map(|_| Keypair::generate().peer_id())
+            .collect::<Vec<_>>();
+        for (i, peer) in peers.iter().enumerate() {
+            let token = doc
+                .cursor()
+                .mint_token(Some(*peer), Read, now + 60 + i as u64)?;
+            sdk.frontend().add_token(token)?;
+        }
+        assert!(!doc.cursor().can_read(&peers[0])?);
+        assert!(doc.cursor().can_read(&peers[1])?);
+        assert!(doc.cursor().can_read(&peers[MAX_TOKENS])?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invite_token() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let now = crate::util::now();
+        let bdoc = sdk.frontend().doc_as(*doc.id(), &b)?;
+        assert!(bdoc.create_invite_token(Read, now + 60).is_err());
+        assert!(doc.create_invite_token(Write, u64::MAX).is_err());
+
+        let bytes = doc.create_invite_token(Write, now + 60)?.to_bytes();
+        let invite = InviteToken::from_bytes(&bytes)?;
+        assert_eq!(invite.doc()?, *doc.id());
+        assert_eq!(invite.schema(), "acl");
+        assert_eq!(
+            invite.hash(),
+            <[u8; 32]>::from(doc.schema()?.as_ref().hash())
+        );
+        assert_eq!(invite.perm(), Write);
+        assert_eq!(invite.expiry(), now + 60);
+        assert_eq!(invite.issuer(), a);
+
+        let mut tampered = bytes.clone();
+        let pos = tampered.windows(3).position(|w| w == b"acl").unwrap();
+        tampered[pos] = b'b';
+        assert!(InviteToken::from_bytes(&tampered).is_err());
+
+        assert!(!doc.cursor().can(&b, Write)?);
+        sdk.frontend().add_token(invite.token().clone())?;
+        assert!(doc.cursor().can_read(&b)?);
+        doc.apply(&doc.redeem_token(invite.token(), &b)?.unwrap())?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Write)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_revoke_invite_token() -> Result<()> {
+        let mut sdk = Backend::test("acl {}")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let c = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.apply(&doc.cursor().say_can(Some(b), Write)?)?;
+        Pin::new(&mut sdk).await?;
+
+        let invite = doc.create_invite_token(Read, crate::util::now() + 60)?;
+        sdk.frontend().add_token(invite.token().clone())?;
+        assert!(doc.cursor().can_read(&c)?);
+
+        let bdoc = sdk.frontend().doc_as(*doc.id(), &b)?;
+        assert!(bdoc.revoke_token(invite.token()).is_err());
+        doc.apply(&doc.revoke_token(invite.token())?)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().can_read(&c)?);
+        assert!(sdk.frontend().add_token(invite.token().clone()).is_err());
+        assert!(doc.redeem_token(invite.token(), &c)?.is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_group() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"acl {
+            0.1.0 {
+                .: Struct
+                .admins: EWFlag
+            }
+        }"#,
+        )?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let c = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let group = fut.await;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let op = doc.cursor().say_can_group(group.id(), Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().can(&b, Write)?);
+
+        let op = group.cursor().say_can(Some(b), Read)?;
+        group.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Write)?);
+        assert!(!doc.cursor().can(&b, Control)?);
+        assert!(!doc.cursor().can(&c, Read)?);
+
+        let op = group.cursor().field("admins")?.say_can(Some(c), Read)?;
+        group.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().can(&c, Read)?);
+
+        let op = group.cursor().say_can(Some(c), Read)?;
+        group.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&c, Write)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_field_level_sharing() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"acl {
+            0.1.0 {
+                .: Struct
+                .contacts: EWFlag
+                .notes: EWFlag
+                .comments: EWFlag
+            }
+        }"#,
+        )?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let c = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "acl", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let op = doc.cursor().field("contacts")?.say_can(Some(b), Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().field("contacts")?.can(&b, Write)?);
+        assert!(!doc.cursor().field("notes")?.can(&b, Read)?);
+        assert!(!doc.cursor().can(&b, Read)?);
+
+        let op = doc.cursor().say_readers_can("comments", Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(!doc.cursor().field("comments")?.can(&c, Write)?);
+
+        let op = doc.cursor().say_can(Some(c), Read)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().field("comments")?.can(&c, Write)?);
+        assert!(!doc.cursor().field("notes")?.can(&c, Write)?);
+        assert!(!doc.cursor().field("comments")?.can(&b, Write)?);
+
+        let bdoc = sdk.frontend().doc_as(*doc.id(), &b)?;
+        assert!(bdoc.cursor().say_readers_can("notes", Write).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_anonymous_write_quota() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"inbox {
+            0.1.0 {
+                .: Struct
+                .comments: Table<u64>
+                .comments.{}: MVReg<String>
+            }
+        }"#,
+        )?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk.frontend().create_doc(a, "inbox", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let op = doc.cursor().field("comments")?.say_can(None, Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().field("comments")?.can(&b, Write)?);
+        assert!(!doc.cursor().can(&b, Write)?);
+
+        let mut prefix = PathBuf::new();
+        prefix.prim_str("comments");
+        doc.set_quota(prefix.as_path(), 2);
+
+        let bdoc = sdk.frontend().doc_as(*doc.id(), &b)?;
+        for i in 0..3 {
+            let op = bdoc
+                .cursor()
+                .field("comments")?
+                .key_u64(i)?
+                .assign_str("spam")?;
+            doc.apply(&op)?;
+        }
+        assert_eq!(doc.cursor().field("comments")?.keys_u64()?.count(), 2);
+
+        for i in 3..6 {
+            let op = doc
+                .cursor()
+                .field("comments")?
+                .key_u64(i)?
+                .assign_str("reply")?;
+            doc.apply(&op)?;
+        }
+        assert_eq!(doc.cursor().field("comments")?.keys_u64()?.count(), 5);
+
+        // quotas aren't replicated, so they don't apply to remote changes.
+        let hash = doc.schema()?.as_ref().hash();
+        let op = bdoc
+            .cursor()
+            .field("comments")?
+            .key_u64(2)?
+            .assign_str("spam")?;
+        sdk.join(&b, doc.id(), &hash, op)?;
+        assert_eq!(doc.cursor().field("comments")?.keys_u64()?.count(), 6);
+
+        // but they count towards the quota of local writes.
+        let op = bdoc
+            .cursor()
+            .field("comments")?
+            .key_u64(6)?
+            .assign_str("spam")?;
+        doc.apply(&op)?;
+        assert_eq!(doc.cursor().field("comments")?.keys_u64()?.count(), 6);
+
+        doc.remove_quota(prefix.as_path());
+        doc.apply(&op)?;
+        assert_eq!(doc.cursor().field("comments")?.keys_u64()?.count(), 7);
+        Ok(())
+    }
 }
@@ -0,0 +1,139 @@
+use crate::id::DocId;
+use crate::radixdb::{BlobMap, BlobSet, Flusher};
+use crate::registry::Hash;
+use anyhow::{anyhow, Result};
+use std::convert::TryInto;
+use std::sync::Arc;
+
+/// Content addressed store of files too large for a [`PrimitiveKind::Bytes`] register.
+///
+/// Documents reference attachments by their [`struct@Hash`], usually stored in a
+/// `MVReg<Bytes>`. Attachments aren't part of the document, so they are only transferred
+/// when a peer reads them. Every attachment is recorded with the documents it was added to
+/// or fetched for, and is only served to peers that can read one of them. Attachments are
+/// never removed automatically.
+///
+/// [`PrimitiveKind::Bytes`]: crate::PrimitiveKind::Bytes
+#[derive(Clone)]
+pub struct Attachments {
+    tree: BlobMap,
+    /// Hash followed by the id of a document the attachment belongs to.
+    docs: BlobSet,
+}
+
+impl Attachments {
+    pub(crate) fn load(flusher: Flusher) -> Result<Self> {
+        Ok(Self {
+            tree: BlobMap::load(flusher.clone(), "attachments")?,
+            docs: BlobSet::load(flusher, "attachment_docs")?,
+        })
+    }
+
+    /// Stores `data` as an attachment of `doc` and returns it's hash.
+    pub fn insert(&self, doc: &DocId, data: &[u8]) -> Result<Hash> {
+        let hash = blake3::hash(data);
+        self.insert_verified(doc, &hash, data)?;
+        Ok(hash)
+    }
+
+    /// Stores `data` received from a peer as an attachment of `doc`. Fails if `data` doesn't
+    /// match `hash`.
+    pub fn insert_verified(&self, doc: &DocId, hash: &Hash, data: &[u8]) -> Result<()> {
+        if blake3::hash(data) != *hash {
+            return Err(anyhow!("attachment doesn't match {}", hash));
+        }
+        if !self.contains(hash)? {
+            self.tree.insert(hash.as_bytes(), data)?;
+        }
+        self.docs.insert(doc_key(hash, doc));
+        Ok(())
+    }
+
+    /// Returns if the attachment with `hash` belongs to `doc`.
+    pub fn is_attached(&self, doc: &DocId, hash: &Hash) -> bool {
+        self.docs.contains(doc_key(hash, doc))
+    }
+
+    /// Returns the documents the attachment with `hash` belongs to.
+    pub fn docs(&self, hash: &Hash) -> Vec<DocId> {
+        self.docs
+            .scan_prefix(hash.as_bytes())
+            .filter_map(|key| Some(DocId::new(key[32..].try_into().ok()?)))
+            .collect()
+    }
+
+    /// Returns the attachment with `hash` if it is stored locally.
+    pub fn get(&self, hash: &Hash) -> Result<Option<Arc<[u8]>>> {
+        self.tree.get(hash.as_bytes())
+    }
+
+    /// Returns if the attachment with `hash` is stored locally.
+    pub fn contains(&self, hash: &Hash) -> Result<bool> {
+        Ok(self.get(hash)?.is_some())
+    }
+
+    /// Removes the attachment with `hash` from the local store.
+    pub fn remove(&self, hash: &Hash) -> Result<()> {
+        for doc in self.docs(hash) {
+            self.docs.remove(doc_key(hash, &doc));
+        }
+        self.tree.remove(hash.as_bytes())
+    }
+
+    /// Returns the hashes and sizes of the locally stored attachments.
+    pub fn list(&self) -> Vec<(Hash, usize)> {
+        self.tree
+            .iter()
+            .filter_map(|(k, v)| {
+                let hash: [u8; 32] = k[..].try_into().ok()?;
+                Some((hash.into(), v.len()))
+            })
+            .collect()
+    }
+}
+
+fn doc_key(hash: &Hash, doc: &DocId) -> [u8; 64] {
+    let mut key = [0; 64];
+    key[..32].copy_from_slice(hash.as_bytes());
+    key[32..].copy_from_slice(doc.as_ref());
+    key
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Backend;
+
+    #[test]
+    fn test_attachments() -> Result<()> {
+        let sdk = Backend::test("todoapp { 0.1.0 { .: Struct } }")?;
+        let attachments = sdk.frontend().attachments().clone();
+        let (doc, doc2) = (DocId::new([1; 32]), DocId::new([2; 32]));
+        let data = vec![7; 1 << 20];
+        let hash = attachments.insert(&doc, &data)?;
+        assert_eq!(hash, blake3::hash(&data));
+        assert_eq!(attachments.get(&hash)?.as_deref(), Some(&data[..]));
+        assert_eq!(attachments.list(), vec![(hash, data.len())]);
+        assert!(attachments.is_attached(&doc, &hash));
+        assert!(!attachments.is_attached(&doc2, &hash));
+
+        // a peer answering with a known attachment must not remove it.
+        let other = blake3::hash(b"other");
+        assert!(attachments.insert_verified(&doc2, &other, &data).is_err());
+        assert_eq!(attachments.get(&hash)?.as_deref(), Some(&data[..]));
+        assert!(!attachments.is_attached(&doc2, &hash));
+        assert!(attachments
+            .insert_verified(&doc2, &other, &data[1..])
+            .is_err());
+        assert_eq!(attachments.list().len(), 1);
+        attachments.insert_verified(&doc2, &other, b"other")?;
+        assert!(attachments.contains(&other)?);
+
+        attachments.insert(&doc2, &data)?;
+        assert_eq!(attachments.docs(&hash), vec![doc, doc2]);
+        attachments.remove(&hash)?;
+        assert!(attachments.get(&hash)?.is_none());
+        assert!(attachments.docs(&hash).is_empty());
+        Ok(())
+    }
+}
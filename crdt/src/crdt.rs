@@ -1,15 +1,23 @@
-use crate::acl::{Acl, Permission};
-use crate::dotset::DotSet;
+use crate::acl::{invalidate_policy, Acl, Grant, Permission, Token};
+use crate::dotset::{Dot, DotSet};
 use crate::id::{DocId, PeerId};
 use crate::lens::LensesRef;
-use crate::path::{Path, PathBuf};
+use crate::path::{split_sig, Path, PathBuf, Segment};
 use crate::radixdb::BlobSet;
-use crate::subscriber::Subscriber;
-use anyhow::Result;
+use crate::schema::{is_deletion, revoked_token, ArchivedSchema, METADATA, REVOKED_TOKENS};
+use crate::subscriber::{Batch, Subscriber};
+use anyhow::{anyhow, Result};
 use bytecheck::CheckBytes;
+use futures::{Stream, StreamExt};
+use parking_lot::Mutex;
 use rkyv::{Archive, Archived, Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
 use std::iter::FromIterator;
-use vec_collections::radix_tree::{AbstractRadixTree, AbstractRadixTreeMut, IterKey, RadixTree};
+use std::sync::Arc;
+use vec_collections::radix_tree::{
+    AbstractRadixTree, AbstractRadixTreeMut, ArcRadixTree, IterKey, RadixTree,
+};
 
 #[derive(Clone, Default, Eq, PartialEq, Archive, Deserialize, Serialize)]
 #[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
@@ -76,12 +84,9 @@ impl FromIterator<PathBuf> for DotStore {
 
 impl std::fmt::Debug for DotStore {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut m = f.debug_map();
-        for p in self.iter() {
-            let path = p.as_path();
-            m.entry(&path.dot(), &path);
-        }
-        m.finish()
+        f.debug_set()
+            .entries(self.iter().map(|p| format!("{}", p)))
+            .finish()
     }
 }
 
@@ -93,12 +98,9 @@ impl ArchivedDotStore {
 
 impl std::fmt::Debug for ArchivedDotStore {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut m = f.debug_map();
-        for p in self.iter() {
-            let path = p.as_path();
-            m.entry(&path.dot(), &path);
-        }
-        m.finish()
+        f.debug_set()
+            .entries(self.iter().map(|p| format!("{}", p)))
+            .finish()
     }
 }
 
@@ -131,6 +133,35 @@ impl CausalContext {
     pub fn expired(&self) -> &DotSet {
         &self.expired
     }
+
+    /// Adds the dots of `other`. Dots expired in either context are removed from the store.
+    /// Only the dots of `other` are visited, so adding a small context is cheap.
+    pub fn union(&mut self, other: &Self) {
+        for dot in other.expired.iter() {
+            self.store.remove(dot);
+            self.expired.insert(*dot);
+        }
+        for dot in other.store.iter() {
+            if !self.expired.contains(dot) {
+                self.store.insert(*dot);
+            }
+        }
+    }
+
+    /// Returns the number of dots of `self` that are missing in `other`.
+    pub fn missing(&self, other: &Self) -> usize {
+        let store = self
+            .store
+            .iter()
+            .filter(|dot| !other.store.contains(dot) && !other.expired.contains(dot))
+            .count();
+        let expired = self
+            .expired
+            .iter()
+            .filter(|dot| !other.expired.contains(dot))
+            .count();
+        store + expired
+    }
 }
 
 impl ArchivedCausalContext {
@@ -189,6 +220,32 @@ impl Causal {
         self.store.is_empty() && self.expired.is_empty()
     }
 
+    /// Returns the transaction identifier. Tags are not included so that tagging a
+    /// transaction doesn't change it's identifier.
+    pub fn id(&self) -> Dot {
+        let mut hasher = blake3::Hasher::new();
+        for store in [&self.store, &self.expired] {
+            for buf in store.iter().filter(|buf| tag(buf.as_path()).is_none()) {
+                hasher.update(&(buf.as_ref().len() as u64).to_be_bytes());
+                hasher.update(buf.as_ref());
+            }
+            hasher.update(&[0xff; 8]);
+        }
+        Dot::new(hasher.finalize().into())
+    }
+
+    /// Returns the tags of this transaction.
+    pub fn tags(&self) -> impl Iterator<Item = String> + '_ {
+        self.store
+            .iter()
+            .filter_map(|buf| Some(tag(buf.as_path())?.1))
+    }
+
+    /// Returns the documents added to a workspace by this transaction.
+    pub fn members(&self) -> impl Iterator<Item = (DocId, String)> + '_ {
+        self.store.iter().filter_map(|buf| member(buf.as_path()))
+    }
+
     /// Computes the [`CausalContext`] of this transaction.
     pub fn ctx(&self) -> CausalContext {
         let mut ctx = CausalContext::new();
@@ -233,6 +290,67 @@ impl Causal {
         Self { expired, store }
     }
 
+    /// Removes the paths signed by one of `peers`, including the removals they signed. Used to
+    /// drop the changes of blocked peers relayed by others.
+    pub fn remove_signed_by(&mut self, peers: &BTreeSet<PeerId>) {
+        if peers.is_empty() {
+            return;
+        }
+        let signed = |buf: &PathBuf| {
+            let mut path = buf.as_path();
+            split_sig(&mut path)
+                .map(|(peer, _)| peers.contains(&peer))
+                .unwrap_or_default()
+        };
+        self.store = self.store.iter().filter(|buf| !signed(buf)).collect();
+        self.expired = self.expired.iter().filter(|buf| !signed(buf)).collect();
+    }
+
+    /// Splits the transaction by the top-level field of it's paths. Paths that aren't below a
+    /// top-level field, like policies on the document root, are returned separately.
+    pub fn split_fields(&self) -> (Causal, BTreeMap<String, Causal>) {
+        fn field(buf: &PathBuf) -> Option<String> {
+            buf.as_path().child()?.first()?.prim_string()
+        }
+        let mut root = Causal::default();
+        let mut fields: BTreeMap<String, Causal> = BTreeMap::new();
+        for buf in self.store.iter() {
+            match field(&buf) {
+                Some(field) => fields.entry(field).or_default().store.insert(buf),
+                None => root.store.insert(buf),
+            }
+        }
+        for buf in self.expired.iter() {
+            match field(&buf) {
+                Some(field) => fields.entry(field).or_default().expired.insert(buf),
+                None => root.expired.insert(buf),
+            }
+        }
+        (root, fields)
+    }
+
+    /// Removes the paths excluded from replication by the document [`ArchivedSchema`] and the
+    /// paths that aren't part of it, like the fields written through a plugin schema.
+    pub(crate) fn remove_local(&mut self, schema: &ArchivedSchema) {
+        let is_local = |buf: &PathBuf, sigs: usize| {
+            let mut path = buf.as_path();
+            for _ in 0..sigs {
+                if split_sig(&mut path).is_none() {
+                    return true;
+                }
+            }
+            path.split_first()
+                .map(|(_, path)| schema.is_local(path) || !schema.contains(path))
+                .unwrap_or(true)
+        };
+        self.store = self.store.iter().filter(|buf| !is_local(buf, 1)).collect();
+        self.expired = self
+            .expired
+            .iter()
+            .filter(|buf| !is_local(buf, 2))
+            .collect();
+    }
+
     /// Transforms a transaction so that it can be applied to a target document.
     pub fn transform(&mut self, from: LensesRef, to: LensesRef) {
         let mut store = DotStore::new();
@@ -254,11 +372,73 @@ impl Causal {
     }
 }
 
+/// Parses a transaction tag path `doc dot tag peer sig` into the transaction identifier
+/// and the tag.
+pub(crate) fn tag(path: Path) -> Option<(Dot, String)> {
+    let (_, path) = path.parent()?.parent()?.split_first()?;
+    let (tx, path) = path.split_first()?;
+    let (tag, path) = path.split_first()?;
+    if !path.is_empty() {
+        return None;
+    }
+    Some((tx.dot()?, tag.prim_string()?))
+}
+
+/// Parses a workspace member path `doc doc schema peer sig` into the member and it's schema.
+pub(crate) fn member(path: Path) -> Option<(DocId, String)> {
+    let (_, path) = path.parent()?.parent()?.split_first()?;
+    let (doc, path) = path.split_first()?;
+    let (schema, path) = path.split_first()?;
+    if !path.is_empty() {
+        return None;
+    }
+    Some((doc.doc()?, schema.prim_string()?))
+}
+
+/// Returns a short name for the kind of value a path represents.
+fn kind(path: Path) -> &'static str {
+    let mut body = path;
+    while split_sig(&mut body).is_some() {}
+    let segments = body.into_iter().collect::<Vec<_>>();
+    match segments.as_slice() {
+        [Segment::Doc(_), Segment::Dot(_), Segment::Str(_)] => "tag",
+        [Segment::Doc(_), Segment::Doc(_), Segment::Str(_)] => "member",
+        [.., Segment::Policy(_)] => "policy",
+        [.., Segment::Nonce(_), Segment::Bool(_)]
+        | [.., Segment::Nonce(_), Segment::U64(_)]
+        | [.., Segment::Nonce(_), Segment::I64(_)]
+        | [.., Segment::Nonce(_), Segment::F64(_)]
+        | [.., Segment::Nonce(_), Segment::Bytes(_)]
+        | [.., Segment::Nonce(_), Segment::Str(_)] => "reg",
+        [.., Segment::Nonce(_)] => "flag",
+        _ => "other",
+    }
+}
+
+/// Summarizes a set of paths as counts per kind.
+struct Summary(BTreeMap<&'static str, usize>);
+
+impl Summary {
+    fn new(paths: impl Iterator<Item = PathBuf>) -> Self {
+        let mut counts = BTreeMap::new();
+        for path in paths {
+            *counts.entry(kind(path.as_path())).or_default() += 1;
+        }
+        Self(counts)
+    }
+}
+
+impl std::fmt::Debug for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.0.iter()).finish()
+    }
+}
+
 impl std::fmt::Debug for Causal {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Causal")
-            .field("store", &self.store)
-            .field("expired", &self.expired)
+            .field("store", &Summary::new(self.store.iter()))
+            .field("expired", &Summary::new(self.expired.iter()))
             .finish()
     }
 }
@@ -266,17 +446,74 @@ impl std::fmt::Debug for Causal {
 impl std::fmt::Debug for ArchivedCausal {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("Causal")
-            .field("store", &self.store)
-            .field("expired", &self.expired)
+            .field("store", &Summary::new(self.store.iter()))
+            .field("expired", &Summary::new(self.expired.iter()))
             .finish()
     }
 }
 
+/// Remote changes dropped by [`Backend::join`](crate::Backend::join) because the sending peer
+/// lacked a permission.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Denied {
+    /// Document the changes were dropped from.
+    pub doc: DocId,
+    /// Keys and fields leading to the dropped values.
+    pub prefix: PathBuf,
+    /// Peer lacking the permission.
+    pub peer: PeerId,
+    /// Missing permission.
+    pub permission: Permission,
+}
+
+impl Denied {
+    fn new(peer: &PeerId, permission: Permission, path: Path) -> Option<Self> {
+        let doc = path.first()?.doc()?;
+        let mut prefix = PathBuf::new();
+        for segment in path {
+            match segment {
+                Segment::Doc(_)
+                | Segment::Bool(_)
+                | Segment::U64(_)
+                | Segment::I64(_)
+                | Segment::F64(_)
+                | Segment::Str(_)
+                | Segment::Bytes(_)
+                | Segment::Position(_) => prefix.push_segment(segment),
+                _ => break,
+            }
+        }
+        Some(Self {
+            doc,
+            prefix,
+            peer: *peer,
+            permission,
+        })
+    }
+}
+
+/// Local quotas and the number of paths each author has below a quota prefix. The counts are
+/// computed on first use and then updated as paths are inserted into and removed from the store.
+#[derive(Default)]
+struct Quotas {
+    max: BTreeMap<PathBuf, u64>,
+    usage: BTreeMap<(PathBuf, PeerId), u64>,
+}
+
+/// The [`BlobSet`]s are individually locked. Writers additionally take `lock` so that the
+/// checks performed by `join` can't interleave with concurrent writes.
 #[derive(Clone)]
 pub struct Crdt {
     store: BlobSet,
     expired: BlobSet,
     acl: Acl,
+    quotas: Arc<Mutex<Quotas>>,
+    lock: Arc<Mutex<()>>,
+    /// Policy persisted by the acl engine, invalidated before policy changes are written.
+    policy: BlobSet,
+    /// Policy paths inserted into (`true`) or removed from (`false`) the store since the last
+    /// call to [`Crdt::take_policy_changes`].
+    policy_changes: Arc<Mutex<Vec<(bool, PathBuf)>>>,
 }
 
 impl std::fmt::Debug for Crdt {
@@ -315,25 +552,248 @@ impl<'a> std::fmt::Debug for ExpiredDebug<'a> {
     }
 }
 
+/// First byte of an unjoin page cursor pointing into the policy.
+const PAGE_POLICY: u8 = 0;
+/// First byte of an unjoin page cursor pointing into the store.
+const PAGE_STORE: u8 = 1;
+
+/// Returns true if the path is a signed policy statement.
+pub(crate) fn is_policy(path: Path) -> bool {
+    path.parent()
+        .and_then(|path| path.parent())
+        .and_then(|path| path.last())
+        .and_then(|segment| segment.policy())
+        .is_some()
+}
+
+/// Returns the permission needed to write `path`. Deleting a document everywhere requires
+/// [`Permission::Own`].
+fn required_permission(path: Path) -> Permission {
+    if is_deletion(path) {
+        Permission::Own
+    } else {
+        Permission::Write
+    }
+}
+
 impl Crdt {
-    pub fn new(store: BlobSet, expired: BlobSet, acl: Acl) -> Self {
-        Self {
+    /// Creates a new [`Crdt`]. The `store` and `expired` sets are written independently, so
+    /// a crash can persist a tombstone without the removal of the tombstoned path. Such paths
+    /// are removed from the store so that they don't resurrect.
+    pub fn new(store: BlobSet, expired: BlobSet, policy: BlobSet, acl: Acl) -> Result<Self> {
+        let me = Self {
             store,
             expired,
             acl,
+            quotas: Default::default(),
+            lock: Default::default(),
+            policy,
+            policy_changes: Default::default(),
+        };
+        me.reconcile()?;
+        Ok(me)
+    }
+
+    /// Inserts a path into the store, recording it if it is a policy path.
+    fn store_insert(&self, path: Path) {
+        if !self.store.contains(path) {
+            if is_policy(path) {
+                self.policy_changes.lock().push((true, path.to_owned()));
+            }
+            if let Some(id) = revoked_token(path) {
+                let mut body = path;
+                if let Some((peer, _)) = split_sig(&mut body) {
+                    self.acl.revoke_token(&id, peer);
+                }
+            }
+            self.update_usage(path, true);
+        }
+        self.store.insert(path);
+    }
+
+    /// Removes a path from the store, recording it if it is a policy path.
+    fn store_remove(&self, path: Path) {
+        if self.store.contains(path) {
+            if is_policy(path) {
+                self.policy_changes.lock().push((false, path.to_owned()));
+            }
+            self.update_usage(path, false);
+        }
+        self.store.remove(path);
+    }
+
+    /// Updates the cached quota usage of the author of `path`.
+    fn update_usage(&self, path: Path, inserted: bool) {
+        let mut quotas = self.quotas.lock();
+        let Quotas { max, usage } = &mut *quotas;
+        if usage.is_empty() {
+            return;
         }
+        let mut body = path;
+        let author = if let Some((author, _)) = split_sig(&mut body) {
+            author
+        } else {
+            return;
+        };
+        for prefix in max.keys() {
+            if !path.starts_with(prefix.as_path()) {
+                continue;
+            }
+            if let Some(used) = usage.get_mut(&(prefix.clone(), author)) {
+                if inserted {
+                    *used += 1;
+                } else {
+                    *used = used.saturating_sub(1);
+                }
+            }
+        }
+    }
+
+    /// Writes the store, invalidating the persisted policy first if policy paths changed.
+    fn flush_store(&self) -> Result<()> {
+        if !self.policy_changes.lock().is_empty() {
+            invalidate_policy(&self.policy)?;
+        }
+        self.store.flush()?;
+        Ok(())
+    }
+
+    /// Returns the policy paths inserted into (`true`) or removed from (`false`) the store
+    /// since the last call, so that the acl can be updated without diffing the whole store.
+    pub fn take_policy_changes(&self) -> Vec<(bool, PathBuf)> {
+        std::mem::take(&mut *self.policy_changes.lock())
+    }
+
+    /// Removes tombstoned paths from the store. Returns the number of removed paths.
+    fn reconcile(&self) -> Result<usize> {
+        let mut n = 0;
+        for k in self.expired.keys() {
+            let path = Path::new(&k);
+            let store_path = path.parent().unwrap().parent().unwrap();
+            if self.store.contains(store_path) {
+                self.store_remove(store_path);
+                n += 1;
+            }
+        }
+        if n > 0 {
+            tracing::warn!("reconcile: removed {} tombstoned paths from the store", n);
+            self.flush_store()?;
+        }
+        Ok(n)
+    }
+
+    /// Limits the number of paths below `prefix` each author without control permission on
+    /// `prefix` can insert through [`Crdt::join_local`]. Quotas are local to this peer and
+    /// are not replicated, so they aren't applied to remote changes.
+    pub fn set_quota(&self, prefix: Path, max: u64) {
+        self.quotas.lock().max.insert(prefix.to_owned(), max);
+    }
+
+    /// Removes the quota of `prefix`.
+    pub fn remove_quota(&self, prefix: Path) {
+        let mut quotas = self.quotas.lock();
+        quotas.max.remove(prefix.as_ref());
+        quotas
+            .usage
+            .retain(|(quota, _), _| quota.as_path() != prefix);
+    }
+
+    /// Checks that inserting `path` doesn't exceed the quota of it's author.
+    fn within_quota(&self, path: Path) -> Result<bool> {
+        let mut body = path;
+        let author = if let Some((author, _)) = split_sig(&mut body) {
+            author
+        } else {
+            return Ok(true);
+        };
+        let mut quotas = self.quotas.lock();
+        let Quotas { max, usage } = &mut *quotas;
+        for (prefix, max) in max.iter() {
+            if !path.starts_with(prefix.as_path())
+                || self.can(&author, Permission::Control, prefix.as_path())?
+            {
+                continue;
+            }
+            let used = match usage.entry((prefix.clone(), author)) {
+                Entry::Occupied(entry) => *entry.get(),
+                Entry::Vacant(entry) => *entry.insert(
+                    self.scan_path(prefix.as_path())
+                        .filter(|k| {
+                            split_sig(&mut Path::new(k)).map(|(peer, _)| peer) == Some(author)
+                        })
+                        .count() as u64,
+                ),
+            };
+            if used >= *max {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = IterKey<u8>> {
         self.store.keys()
     }
 
+    /// Returns true if the store contains `path`.
+    pub fn contains(&self, path: Path) -> bool {
+        self.store.contains(path)
+    }
+
     pub fn scan_path(&self, path: Path) -> impl Iterator<Item = IterKey<u8>> {
         // ensures that it has a static lifetime.
         #[allow(clippy::unnecessary_to_owned)]
         self.store.scan_prefix(path.as_ref().to_vec())
     }
 
+    /// Returns the policy statements of `doc` known to the acl engine.
+    pub fn policy_paths(&self, doc: &DocId) -> impl Iterator<Item = IterKey<u8>> {
+        let mut path = PathBuf::new();
+        path.doc(doc);
+        self.policy.scan_prefix(path)
+    }
+
+    /// Returns the first path below `prefix` that sorts after `after` and isn't below `after`.
+    pub fn first_after(&self, prefix: Path, after: Option<Path>) -> Option<PathBuf> {
+        let key = self
+            .store
+            .first_after(prefix, after.as_ref().map(|path| path.as_ref()))?;
+        Some(Path::new(&key).to_owned())
+    }
+
+    /// Returns up to `limit` paths with `prefix` that sort after `token` and the token of the
+    /// next page.
+    pub fn scan_page(
+        &self,
+        prefix: Path,
+        token: Option<&[u8]>,
+        limit: usize,
+    ) -> (Vec<PathBuf>, Option<Vec<u8>>) {
+        // seeks to the token instead of skipping the paths of the previous pages.
+        let mut paths = self
+            .store
+            .scan_after(prefix, token)
+            .take(limit.saturating_add(1))
+            .map(|k| Path::new(&k).to_owned())
+            .collect::<Vec<_>>();
+        if paths.len() > limit {
+            paths.truncate(limit);
+            let token = paths.last().map(|path| path.as_ref().to_vec());
+            (paths, token)
+        } else {
+            (paths, None)
+        }
+    }
+
+    /// Returns the version of the store. It changes whenever a path is inserted or removed.
+    pub fn version(&self) -> u64 {
+        self.store.version()
+    }
+
+    pub fn snapshot(&self, path: Path) -> ArcRadixTree<u8, ()> {
+        self.store.snapshot(path)
+    }
+
     pub fn watch_path(&self, path: Path) -> Subscriber {
         Subscriber::new(
             self.store.watch_prefix(path),
@@ -341,10 +801,111 @@ impl Crdt {
         )
     }
 
+    pub fn watch_path_changes(&self, path: Path) -> Subscriber {
+        Subscriber::new(
+            self.store.watch_prefix_changes(path),
+            self.acl
+                .subscribe_changes(&path.first().unwrap().doc().unwrap()),
+        )
+    }
+
+    pub fn watch_acl(&self, doc: &DocId) -> impl Stream<Item = Batch> {
+        self.acl
+            .subscribe_changes(doc)
+            .filter(|diff| futures::future::ready(diff.iter().next().is_some()))
+            .map(Batch::acl)
+    }
+
+    /// Returns a stream of the documents whose acl changed.
+    pub fn watch_acls(&self) -> impl Stream<Item = DocId> {
+        self.acl.subscribe_all().flat_map(|diff| {
+            let docs = diff
+                .iter()
+                .filter_map(|(key, _)| Path::new(&key).first()?.doc())
+                .collect::<BTreeSet<_>>();
+            futures::stream::iter(docs)
+        })
+    }
+
+    /// Adds a capability token unless it was revoked.
+    pub fn add_token(&self, token: Token) -> Result<()> {
+        if self.is_revoked(&token)? {
+            return Err(anyhow!("token was revoked"));
+        }
+        self.acl.add_token(token)
+    }
+
+    /// Returns if `token` was revoked by a peer that can revoke it, see [`Acl::can_revoke`].
+    pub fn is_revoked(&self, token: &Token) -> Result<bool> {
+        let mut prefix = PathBuf::new();
+        prefix.doc(&token.doc()?);
+        prefix.prim_str(METADATA);
+        prefix.prim_str(REVOKED_TOKENS);
+        prefix.prim_bytes(&token.id());
+        for k in self.store.scan_prefix(&prefix) {
+            if let Some((peer, _)) = split_sig(&mut Path::new(&k)) {
+                if self.acl.can_revoke(peer, token)? {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns if `peer` can revoke `token`.
+    pub fn can_revoke(&self, peer: &PeerId, token: &Token) -> Result<bool> {
+        self.acl.can_revoke(*peer, token)
+    }
+
     pub fn can(&self, peer: &PeerId, perm: Permission, path: Path) -> Result<bool> {
         self.acl.can(*peer, perm, path)
     }
 
+    pub fn can_read(&self, peer: &PeerId, path: Path) -> Result<bool> {
+        self.acl.can_read(*peer, path)
+    }
+
+    pub fn grants(&self, peer: &PeerId, path: Path) -> Result<Vec<Grant>> {
+        self.acl.grants(*peer, path)
+    }
+
+    /// Returns all store and expired paths of a document.
+    pub fn state(&self, doc: &DocId) -> Causal {
+        let mut path = PathBuf::new();
+        path.doc(doc);
+        Causal {
+            store: self
+                .store
+                .scan_prefix(&path)
+                .map(|k| Path::new(&k).to_owned())
+                .collect(),
+            expired: self
+                .expired
+                .scan_prefix(&path)
+                .map(|k| Path::new(&k).to_owned())
+                .collect(),
+        }
+    }
+
+    /// Returns the paths of `doc` in the store and the expired paths whose dots are in `ctx`.
+    pub fn causal_of(&self, doc: &DocId, ctx: &CausalContext) -> Causal {
+        let mut causal = self.state(doc);
+        causal.store = causal
+            .store
+            .iter()
+            .filter(|path| ctx.store.contains(&path.as_path().dot()))
+            .collect();
+        causal.expired = causal
+            .expired
+            .iter()
+            .filter(|path| {
+                let dot = path.as_path().parent().unwrap().parent().unwrap().dot();
+                ctx.expired.contains(&dot)
+            })
+            .collect();
+        causal
+    }
+
     pub fn ctx(&self, doc: &DocId) -> Result<CausalContext> {
         let mut ctx = CausalContext::new();
         let mut path = PathBuf::new();
@@ -361,23 +922,15 @@ impl Crdt {
     }
 
     pub fn join_policy(&self, causal: &Causal) -> Result<()> {
+        let _lock = self.lock.lock();
         for buf in causal.store.iter() {
             let path = buf.as_path();
-            if path
-                .parent()
-                .unwrap()
-                .parent()
-                .unwrap()
-                .last()
-                .unwrap()
-                .policy()
-                .is_some()
-            {
+            if is_policy(path) {
                 tracing::info!("join_policy: {}", path);
-                self.store.insert(path);
+                self.store_insert(path);
             }
         }
-        self.store.flush()?;
+        self.flush_store()?;
         Ok(())
     }
 
@@ -385,33 +938,77 @@ impl Crdt {
     /// the peer that created the transaction. The reason for this is that the logic
     /// would be a little bit more complicated to ensure convergence in the presence of
     /// revocations.
-    pub fn join(&self, peer: &PeerId, causal: &Causal) -> Result<()> {
+    ///
+    /// Returns the prefixes of paths that were dropped because the peer lacked permission.
+    pub fn join(&self, peer: &PeerId, causal: &Causal) -> Result<Vec<Denied>> {
+        self.join_with(peer, causal, false)
+    }
+
+    /// Applies a transaction made on this peer. Unlike [`Crdt::join`] paths exceeding the
+    /// quota of their author are dropped.
+    pub fn join_local(&self, peer: &PeerId, causal: &Causal) -> Result<Vec<Denied>> {
+        self.join_with(peer, causal, true)
+    }
+
+    fn join_with(&self, peer: &PeerId, causal: &Causal, quota: bool) -> Result<Vec<Denied>> {
+        let _lock = self.lock.lock();
+        let mut denied = vec![];
+        let mut deny = |perm: Permission, path: Path| {
+            if let Some(d) = Denied::new(peer, perm, path) {
+                if !denied.contains(&d) {
+                    denied.push(d);
+                }
+            }
+        };
         for buf in causal.store.iter() {
             let path = buf.as_path();
             let is_expired = self.expired.scan_prefix(path.as_ref()).next().is_some();
             if !is_expired && !causal.expired.contains_prefix(path) {
-                if !self.can(peer, Permission::Write, path)? {
+                let perm = required_permission(path);
+                if !self.can_join(peer, perm, path, path)? {
                     tracing::info!("join: peer is unauthorized to insert {}", path);
+                    deny(perm, path);
+                    continue;
+                }
+                if quota && !self.store.contains(path) && !self.within_quota(path)? {
+                    tracing::info!("join: author exceeded quota inserting {}", path);
                     continue;
                 }
-                self.store.insert(&path);
+                self.store_insert(path);
             }
         }
         for buf in causal.expired.iter() {
             let path = buf.as_path();
             let store_path = path.parent().unwrap().parent().unwrap();
-            if !self.can(peer, Permission::Write, store_path)? {
+            let perm = required_permission(store_path);
+            if !self.can_join(peer, perm, store_path, path)? {
                 tracing::info!("join: peer is unauthorized to remove {}", store_path);
+                deny(perm, store_path);
                 continue;
             }
             if self.store.contains(store_path) {
-                self.store.remove(store_path);
+                self.store_remove(store_path);
             }
             self.expired.insert(&path);
         }
         self.expired.flush()?;
-        self.store.flush()?;
-        Ok(())
+        self.flush_store()?;
+        Ok(denied)
+    }
+
+    /// Returns `true` if `peer` may send the change of `path` signed in `signed`. Changes
+    /// requiring [`Permission::Own`] are authorized by the peer that signed them, so that they
+    /// propagate when relayed by peers that only have [`Permission::Write`].
+    fn can_join(&self, peer: &PeerId, perm: Permission, path: Path, signed: Path) -> Result<bool> {
+        if perm != Permission::Own {
+            return self.can(peer, perm, path);
+        }
+        let mut body = signed;
+        let signer = match split_sig(&mut body) {
+            Some((signer, _)) => signer,
+            None => return Ok(false),
+        };
+        Ok(self.can(&signer, perm, path)? && self.can(peer, Permission::Write, path)?)
     }
 
     pub fn unjoin(
@@ -437,7 +1034,7 @@ impl Crdt {
             if !store_dots.contains(&dot) {
                 continue;
             }
-            if !self.can(peer_id, Permission::Read, path)? {
+            if !self.can_read(peer_id, path)? {
                 tracing::info!("unjoin: peer is unauthorized to read");
                 continue;
             }
@@ -452,7 +1049,7 @@ impl Crdt {
             if !expired_dots.contains(&dot) {
                 continue;
             }
-            if !self.can(peer_id, Permission::Read, path)? {
+            if !self.can_read(peer_id, path)? {
                 tracing::info!("unjoin: peer is unauthorized to read {}", path);
                 continue;
             }
@@ -463,29 +1060,108 @@ impl Crdt {
         Ok(Causal { expired, store })
     }
 
+    /// Returns a page of the changes required to bring a peer up to speed of at most
+    /// `max_bytes` of paths following the cursor `after`, and the cursor of the next page if
+    /// more paths follow. The policy is sent in the first pages, so that the paths of later
+    /// pages can be authorized. The store is scanned from the cursor, so a page costs the
+    /// paths it contains rather than the whole document.
+    pub fn unjoin_page(
+        &self,
+        peer_id: &PeerId,
+        doc: &DocId,
+        other: &Archived<CausalContext>,
+        after: Option<&[u8]>,
+        max_bytes: usize,
+    ) -> Result<(Causal, Option<Vec<u8>>)> {
+        let mut path = PathBuf::new();
+        path.doc(doc);
+
+        let ctx = self.ctx(doc)?;
+        let expired_dots = ctx.expired.difference(&other.expired);
+        let store_dots = ctx
+            .store
+            .difference(&other.store)
+            .difference(&other.expired);
+
+        let mut page = Causal::default();
+        let mut size = 0;
+        let (mut policy, after) = match after.and_then(|after| after.split_first()) {
+            Some((&PAGE_POLICY, after)) => (Some(self.policy.scan_after(&path, Some(after))), None),
+            Some((_, after)) => (None, Some(after)),
+            None => (Some(self.policy.scan_after(&path, None)), None),
+        };
+        if let Some(policy) = policy.as_mut() {
+            for key in policy.by_ref() {
+                let path = Path::new(&key);
+                if !store_dots.contains(&path.dot()) || !self.store.contains(path) {
+                    continue;
+                }
+                if !self.can_read(peer_id, path)? {
+                    continue;
+                }
+                size += key.len();
+                page.store.insert(path.to_owned());
+                if size >= max_bytes {
+                    return Ok((page, Some([&[PAGE_POLICY][..], &key].concat())));
+                }
+            }
+        }
+        let mut store = self.store.scan_after(&path, after).peekable();
+        let mut expired = self.expired.scan_after(&path, after).peekable();
+        loop {
+            let (key, is_expired) = match (store.peek(), expired.peek()) {
+                (Some(a), Some(b)) if b < a => (expired.next().unwrap(), true),
+                (Some(_), _) => (store.next().unwrap(), false),
+                (None, Some(_)) => (expired.next().unwrap(), true),
+                (None, None) => return Ok((page, None)),
+            };
+            let path = Path::new(&key);
+            let sent = if is_expired {
+                expired_dots.contains(&path.parent().unwrap().parent().unwrap().dot())
+            } else {
+                // policy paths known to the engine were sent in the first pages.
+                store_dots.contains(&path.dot()) && !(is_policy(path) && self.policy.contains(path))
+            };
+            if !sent || !self.can_read(peer_id, path)? {
+                continue;
+            }
+            size += key.len();
+            if is_expired {
+                page.expired.insert(path.to_owned());
+            } else {
+                page.store.insert(path.to_owned());
+            }
+            if size >= max_bytes && (store.peek().is_some() || expired.peek().is_some()) {
+                return Ok((page, Some([&[PAGE_STORE][..], &key].concat())));
+            }
+        }
+    }
+
     pub fn remove(&self, doc: &DocId) -> Result<()> {
+        let _lock = self.lock.lock();
         let mut path = PathBuf::new();
         path.doc(doc);
         for k in self.store.scan_prefix(&path) {
-            self.store.remove(k);
+            self.store_remove(Path::new(&k));
         }
         for k in self.expired.scan_prefix(&path) {
             self.expired.remove(k);
         }
         self.expired.flush()?;
-        self.store.flush()?;
+        self.flush_store()?;
         Ok(())
     }
 
     pub fn transform(&self, doc: &DocId, from: LensesRef, to: LensesRef) -> Result<()> {
+        let _lock = self.lock.lock();
         let mut path = PathBuf::new();
         path.doc(doc);
         for k in self.scan_path(path.as_path()) {
             let path = Path::new(&k);
             if let Some(path) = from.transform_path(path, to) {
-                self.store.insert(path);
+                self.store_insert(path.as_path());
             }
-            self.store.remove(k);
+            self.store_remove(Path::new(&k));
         }
         for k in self.scan_path(path.as_path()) {
             let path = Path::new(&k);
@@ -495,7 +1171,7 @@ impl Crdt {
             self.expired.remove(k);
         }
         self.expired.flush()?;
-        self.store.flush()?;
+        self.flush_store()?;
         Ok(())
     }
 }
@@ -504,12 +1180,177 @@ impl Crdt {
 mod tests {
     use super::*;
     use crate::doc::Backend;
+    use crate::radixdb::{BlobMap, Flusher, MemStorage};
     use crate::util::Ref;
-    use crate::{props::*, Keypair};
+    use crate::{props::*, Change, Cursor, Keypair, Step};
     use proptest::prelude::*;
     use std::collections::BTreeSet;
     use std::pin::Pin;
 
+    fn causal_to_crdt(doc: &DocId, causal: &Causal) -> Crdt {
+        let storage = Flusher::new(Arc::new(MemStorage::default()));
+        let store = BlobSet::load(storage.clone(), "store").unwrap();
+        let expired = BlobSet::load(storage.clone(), "expired").unwrap();
+        let policy = BlobSet::load(storage.clone(), "policy").unwrap();
+        let acl = Acl::new(BlobMap::load(storage, "acl").unwrap());
+        let crdt = Crdt::new(store, expired, policy, acl).unwrap();
+        crdt.join(&(*doc).into(), causal).unwrap();
+        crdt
+    }
+
+    fn crdt_to_causal(doc: &DocId, crdt: &Crdt) -> Causal {
+        let other = CausalContext::new();
+        let other = Ref::archive(&other);
+        crdt.unjoin(&(*doc).into(), doc, other.as_ref()).unwrap()
+    }
+
+    #[test]
+    fn test_reconcile() -> Result<()> {
+        let storage = Flusher::new(Arc::new(MemStorage::default()));
+        let store = BlobSet::load(storage.clone(), "store")?;
+        let expired = BlobSet::load(storage.clone(), "expired")?;
+        let policy = BlobSet::load(storage.clone(), "policy")?;
+        let acl = Acl::new(BlobMap::load(storage, "acl")?);
+        let la = Keypair::generate();
+        let mut path = PathBuf::new();
+        path.doc(&DocId::new([0; 32]));
+        path.prim_str("a");
+        path.peer(&la.peer_id());
+        path.sig(la.sign(path.as_ref()));
+        let mut tomb = path.clone();
+        tomb.peer(&la.peer_id());
+        tomb.sig(la.sign(tomb.as_ref()));
+        let mut other = PathBuf::new();
+        other.doc(&DocId::new([0; 32]));
+        other.prim_str("b");
+        other.peer(&la.peer_id());
+        other.sig(la.sign(other.as_ref()));
+
+        // the tombstone was written but the crash happened before the store was.
+        store.insert(&path);
+        store.insert(&other);
+        expired.insert(&tomb);
+
+        let crdt = Crdt::new(store, expired, policy, acl)?;
+        assert_eq!(crdt.reconcile()?, 0);
+        let paths = crdt
+            .iter()
+            .map(|k| Path::new(&k).to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec![other]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_remove_signed_by() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Table<u64> .{}: MVReg<String> } }")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.apply(&doc.cursor().key_u64(0)?.assign_str("a")?)?;
+        let mut causal = doc.cursor().key_u64(0)?.assign_str("b")?;
+        assert!(!causal.store.is_empty() && !causal.expired.is_empty());
+        let original = causal.clone();
+        causal.remove_signed_by(&std::iter::once(Keypair::generate().peer_id()).collect());
+        assert_eq!(causal, original);
+        causal.remove_signed_by(&std::iter::once(peer).collect());
+        assert!(causal.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ctx_union() {
+        let (a, b, c) = (Dot::new([1; 32]), Dot::new([2; 32]), Dot::new([3; 32]));
+        let mut ctx = CausalContext::new();
+        ctx.store.insert(a);
+        ctx.store.insert(b);
+        let mut other = CausalContext::new();
+        other.store.insert(c);
+        other.expired.insert(a);
+        ctx.union(&other);
+        assert_eq!(ctx.store().iter().copied().collect::<Vec<_>>(), vec![b, c]);
+        assert_eq!(ctx.expired().iter().copied().collect::<Vec<_>>(), vec![a]);
+        assert_eq!(ctx.missing(&other), 1);
+        assert_eq!(other.missing(&ctx), 0);
+        assert_eq!(CausalContext::new().missing(&ctx), 0);
+    }
+
+    #[async_std::test]
+    async fn test_unjoin_page() -> Result<()> {
+        let packages = "todoapp { 0.1.0 { .: Table<u64> .{}: MVReg<String> } }";
+        let mut sdk = Backend::test(packages)?;
+        let mut sdk2 = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        for i in 0..10 {
+            doc.apply(&doc.cursor().key_u64(i)?.assign_str("a")?)?;
+            doc.apply(&doc.cursor().key_u64(i)?.assign_str("b")?)?;
+        }
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let hash = doc.schema()?.as_ref().hash();
+        let full = sdk.unjoin(&peer2, doc.id(), Ref::archive(&doc2.ctx()?).as_ref())?;
+        let policies = full
+            .store
+            .iter()
+            .filter(|buf| is_policy(buf.as_path()))
+            .count();
+        assert!(policies > 1);
+
+        // pages are requested with the context of the receiver after applying the previous
+        // page.
+        let mut pages = vec![];
+        let mut after = None;
+        loop {
+            let ctx = Ref::archive(&doc2.ctx()?);
+            let (page, next) =
+                sdk.unjoin_page(&peer2, doc.id(), ctx.as_ref(), after.as_deref(), 200)?;
+            let size = page
+                .store
+                .iter()
+                .chain(page.expired.iter())
+                .map(|buf| buf.as_ref().len())
+                .collect::<Vec<_>>();
+            assert!(size.iter().sum::<usize>() < 200 + size.iter().max().unwrap_or(&0));
+            pages.push(page.clone());
+            sdk2.join(&peer, doc.id(), &hash, page)?;
+            after = match next {
+                Some(next) => Some(next),
+                None => break,
+            };
+        }
+        assert!(pages.len() > 2);
+        let mut joined = Causal::default();
+        let mut data = false;
+        for page in &pages {
+            for buf in page.store.iter().chain(page.expired.iter()) {
+                // the policy is sent before the data it authorizes.
+                if is_policy(buf.as_path()) {
+                    assert!(!data);
+                } else {
+                    data = true;
+                }
+            }
+            joined.join(page);
+        }
+        assert_eq!(joined, full);
+        let ctx = Ref::archive(&doc2.ctx()?);
+        assert!(sdk.unjoin(&peer2, doc.id(), ctx.as_ref())?.is_empty());
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let (page, next) = sdk.unjoin_page(&peer2, doc.id(), ctx.as_ref(), None, usize::MAX)?;
+        assert!(page.is_empty() && next.is_none());
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_ewflag() -> Result<()> {
         let packages = r#"
@@ -539,6 +1380,240 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_counter() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Struct
+                    .count: PNCounter
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert_eq!(doc.cursor().field("count")?.counter_value()?, 0);
+        assert!(doc.cursor().field("count")?.assign_i64(1).is_err());
+
+        doc.apply(&doc.cursor().field("count")?.increment(5)?)?;
+        doc.apply(&doc.cursor().field("count")?.increment(1)?)?;
+        // the peer's path holding it's totals replaces the previous one.
+        let mut count = PathBuf::new();
+        count.prim_key("count");
+        assert_eq!(doc.scan(count.as_path(), None, 10).paths.len(), 1);
+        assert_eq!(doc.cursor().field("count")?.counter_value()?, 6);
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Write)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "test")?;
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let delta = sdk.unjoin(&peer2, doc2.id(), ctx.as_ref())?;
+        let hash = sdk.registry().lookup("test").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+
+        // concurrent increments of different peers are both counted.
+        let a = doc.cursor().field("count")?.increment(-2)?;
+        let b = doc2.cursor().field("count")?.increment(-3)?;
+        doc.apply(&a)?;
+        sdk.join(&peer2, doc.id(), &hash, b)?;
+        assert_eq!(doc.scan(count.as_path(), None, 10).paths.len(), 2);
+        assert_eq!(doc.cursor().field("count")?.counter_value()?, 1);
+
+        doc.apply(&doc.cursor().field("count")?.remove()?)?;
+        assert_eq!(doc.cursor().field("count")?.counter_value()?, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_changes() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                    .tags: Table<String>
+                    .tags.{}: MVReg<u64>
+                    .list: Array
+                    .list.[]: MVReg<String>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mut sub = doc.cursor().subscribe_changes();
+        let title = || Step::Field("title".into());
+        let tags = || Step::Field("tags".into());
+        let list = || Step::Field("list".into());
+
+        doc.apply(&doc.cursor().field("title")?.assign_str("a")?)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![Change::Assigned(vec![title()], Segment::Str("a".into()))]
+        );
+
+        // a removal that leaves a concurrent value reports the remaining value.
+        let op_b = doc.cursor().field("title")?.assign_str("b")?;
+        let op_c = doc.cursor().field("title")?.assign_str("c")?;
+        doc.apply(&op_b)?;
+        sub.next().await.unwrap();
+        let op_remove = doc.cursor().field("title")?.remove()?;
+        doc.apply(&op_c)?;
+        sub.next().await.unwrap();
+        doc.apply(&op_remove)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![Change::Assigned(vec![title()], Segment::Str("c".into()))]
+        );
+
+        doc.apply(&doc.cursor().field("tags")?.key_str("x")?.assign_u64(1)?)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![
+                Change::KeyInserted(vec![tags()], Segment::Str("x".into())),
+                Change::Assigned(
+                    vec![tags(), Step::Key(Segment::Str("x".into()))],
+                    Segment::U64(1)
+                ),
+            ]
+        );
+
+        doc.apply(&doc.cursor().field("tags")?.key_str("x")?.remove()?)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![Change::KeyRemoved(vec![tags()], Segment::Str("x".into()))]
+        );
+
+        for (ix, s) in ["first", "second"].iter().enumerate() {
+            doc.apply(&doc.cursor().field("list")?.index(ix)?.assign_str(s)?)?;
+        }
+        let mut inserted = vec![];
+        while inserted.len() < 2 {
+            let batch = sub.next().await.unwrap();
+            for change in batch.changes(&doc.cursor()) {
+                if let Change::ElementInserted(_, uid, ix) = change {
+                    inserted.push((uid, ix));
+                }
+            }
+        }
+        assert_eq!(inserted[0].1, 0);
+        assert_eq!(inserted[1].1, 1);
+
+        doc.apply(&doc.cursor().field("list")?.index(1)?.r#move(0)?)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![Change::ElementMoved(vec![list()], inserted[1].0, 0)]
+        );
+
+        doc.apply(&doc.cursor().field("list")?.index(0)?.delete()?)?;
+        let batch = sub.next().await.unwrap();
+        assert_eq!(
+            batch.changes(&doc.cursor()),
+            vec![Change::ElementRemoved(vec![list()], inserted[1].0)]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_text() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Struct
+                    .text: Text
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let text = || -> Result<Cursor> {
+            let mut cursor = doc.cursor();
+            cursor.field("text")?;
+            Ok(cursor)
+        };
+        assert_eq!(text()?.text()?, "");
+
+        doc.apply(&text()?.insert_str(0, "hello")?)?;
+        doc.apply(&text()?.insert_str(5, " world")?)?;
+        doc.apply(&text()?.insert_str(0, ">")?)?;
+        assert_eq!(text()?.text()?, ">hello world");
+        assert!(text()?.insert_str(13, "!").is_err());
+
+        doc.apply(&text()?.delete_range(0, 1)?)?;
+        doc.apply(&text()?.delete_range(5, 6)?)?;
+        assert_eq!(text()?.text()?, "hello");
+        assert!(text()?.delete_range(3, 3).is_err());
+
+        // concurrent inserts at the same index are not interleaved.
+        let a = text()?.insert_str(5, "abc")?;
+        let b = text()?.insert_str(5, "xyz")?;
+        doc.apply(&a)?;
+        doc.apply(&b)?;
+        let s = text()?.text()?;
+        assert!(s == "helloabcxyz" || s == "helloxyzabc");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_split_fields() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                    .done: EWFlag
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mut causal = doc.cursor().field("title")?.assign_str("title")?;
+        causal.join(&doc.cursor().field("done")?.enable()?);
+        causal.join(&doc.cursor().say_can(None, Permission::Read)?);
+
+        let (root, fields) = causal.split_fields();
+        assert_eq!(root.store().iter().count(), 1);
+        assert_eq!(
+            fields
+                .keys()
+                .map(|field| field.as_str())
+                .collect::<Vec<_>>(),
+            vec!["done", "title"]
+        );
+        let mut joined = root;
+        for field in fields.values() {
+            joined.join(field);
+        }
+        assert_eq!(joined, causal);
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_ewflag_unjoin() -> Result<()> {
         let packages = r#"
@@ -627,6 +1702,103 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_orset() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Set<String>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        doc.apply(&doc.cursor().add_str("a")?)?;
+        doc.apply(&doc.cursor().add_str("b")?)?;
+        doc.apply(&doc.cursor().add_str("a")?)?;
+        let elements = doc.cursor().elements_str()?.collect::<Vec<_>>();
+        assert_eq!(elements, vec!["a".to_string(), "b".to_string()]);
+        assert!(doc.cursor().contains_str("a")?);
+        assert!(!doc.cursor().contains_str("ab")?);
+
+        // concurrent add wins over remove
+        let add = doc.cursor().add_str("a")?;
+        doc.apply(&doc.cursor().remove_str("a")?)?;
+        assert!(!doc.cursor().contains_str("a")?);
+        doc.apply(&add)?;
+        assert!(doc.cursor().contains_str("a")?);
+
+        // elements sharing a prefix are looked up separately
+        doc.apply(&doc.cursor().add_str("ab")?)?;
+        doc.apply(&doc.cursor().remove_str("a")?)?;
+        assert!(!doc.cursor().contains_str("a")?);
+        assert!(doc.cursor().contains_str("ab")?);
+        doc.apply(&doc.cursor().remove_str("ab")?)?;
+        let elements = doc.cursor().elements_str()?.collect::<Vec<_>>();
+        assert_eq!(elements, vec!["b".to_string()]);
+        assert!(doc.cursor().add_u64(42).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_dynamic() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Dynamic
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        doc.apply(&doc.cursor().field("title")?.assign_str("hello")?)?;
+        doc.apply(&doc.cursor().field("title")?.key_u64(0)?.assign_u64(42)?)?;
+        doc.apply(&doc.cursor().field("done")?.enable()?)?;
+        doc.apply(&doc.cursor().field("tags")?.add_str("a")?)?;
+        doc.apply(&doc.cursor().field("tags")?.add_u64(1)?)?;
+
+        let mut cursor = doc.cursor();
+        cursor.field("title")?;
+        assert_eq!(cursor.strs()?.collect::<Result<Vec<_>>>()?, vec!["hello"]);
+        assert_eq!(cursor.u64s()?.count(), 0);
+        assert!(!cursor.enabled()?);
+        assert_eq!(
+            cursor.at_key_u64(0)?.u64s()?.collect::<Result<Vec<_>>>()?,
+            vec![42]
+        );
+        doc.apply(&cursor.assign_bool(true)?)?;
+        assert_eq!(cursor.strs()?.count(), 0);
+        assert_eq!(cursor.bools()?.collect::<Result<Vec<_>>>()?, vec![true]);
+        assert_eq!(cursor.at_key_u64(0)?.u64s()?.count(), 1);
+
+        let mut cursor = doc.cursor();
+        cursor.field("done")?;
+        assert!(cursor.enabled()?);
+        doc.apply(&cursor.disable()?)?;
+        assert!(!cursor.enabled()?);
+
+        let mut cursor = doc.cursor();
+        cursor.field("tags")?;
+        assert_eq!(cursor.elements_str()?.collect::<Vec<_>>(), vec!["a"]);
+        assert_eq!(cursor.elements_u64()?.collect::<Vec<_>>(), vec![1]);
+        doc.apply(&cursor.remove_str("a")?)?;
+        assert!(!cursor.contains_str("a")?);
+        assert!(cursor.contains_u64(1)?);
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_orarray_smoke() -> Result<()> {
         let packages = r#"
@@ -674,6 +1846,110 @@ mod tests {
         Ok(())
     }
 
+    #[async_std::test]
+    async fn test_orarray_iter() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Array
+                    .[]: MVReg<u64>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let cursor = doc.cursor();
+        for i in 0..5 {
+            assert_eq!(cursor.len()?, i as u32);
+            doc.apply(&cursor.clone().index(i)?.assign_u64(i as u64)?)?;
+        }
+        assert_eq!(cursor.len()?, 5);
+        let values = cursor
+            .iter_array()?
+            .map(|c| c.u64s()?.next().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, vec![0, 1, 2, 3, 4]);
+
+        doc.apply(&cursor.clone().index(0)?.r#move(5)?)?;
+        let values = cursor
+            .iter_array()?
+            .map(|c| c.u64s()?.next().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, vec![1, 2, 3, 4, 0]);
+
+        // appended elements keep their order when applied as a single transaction.
+        let mut causal = Causal::default();
+        for (i, cursor) in cursor.append(3)?.into_iter().enumerate() {
+            causal.join(&cursor.assign_u64(5 + i as u64)?);
+        }
+        doc.apply(&causal)?;
+        let values = cursor
+            .iter_array()?
+            .map(|c| c.u64s()?.next().unwrap())
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(values, vec![1, 2, 3, 4, 0, 5, 6, 7]);
+
+        // concurrent inserts at the same index get distinct positions.
+        let a = cursor.clone().index(8)?.assign_u64(8)?;
+        let b = cursor.clone().index(8)?.assign_u64(9)?;
+        doc.apply(&a)?;
+        doc.apply(&b)?;
+        assert_eq!(cursor.len()?, 10);
+        doc.apply(&cursor.clone().index(10)?.assign_u64(10)?)?;
+        assert_eq!(cursor.len()?, 11);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_orarray_rebalance() -> Result<()> {
+        let packages = r#"
+            test {
+                0.1.0 {
+                    .: Array
+                    .[]: MVReg<u64>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "test", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let cursor = doc.cursor();
+        doc.apply(&cursor.clone().index(0)?.assign_u64(0)?)?;
+        doc.apply(&cursor.clone().index(1)?.assign_u64(100)?)?;
+        // always append and move right after the first element
+        for i in 1..100 {
+            doc.apply(&cursor.clone().index(i + 1)?.assign_u64(100 - i as u64)?)?;
+            doc.apply(&cursor.clone().index(i + 1)?.r#move(1)?)?;
+        }
+        let values = |cursor: &Cursor| {
+            cursor
+                .iter_array()?
+                .map(|c| c.u64s()?.next().unwrap())
+                .collect::<Result<Vec<_>>>()
+        };
+        assert_eq!(values(&cursor)?, (0..=100).collect::<Vec<_>>());
+        assert!(cursor.max_position_len()? > 10);
+
+        doc.apply(&cursor.rebalance()?)?;
+        assert_eq!(values(&cursor)?, (0..=100).collect::<Vec<_>>());
+        assert_eq!(cursor.max_position_len()?, 2);
+        assert!(cursor.rebalance()?.store.is_empty());
+
+        doc.apply(&cursor.clone().index(50)?.assign_u64(1000)?)?;
+        doc.apply(&cursor.clone().index(50)?.assign_u64(1001)?)?;
+        assert_eq!(values(&cursor)?[50], 1001);
+        Ok(())
+    }
+
     #[async_std::test]
     async fn test_orarray_nested_crdt() -> Result<()> {
         let packages = r#"
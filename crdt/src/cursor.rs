@@ -1,31 +1,92 @@
+use std::cmp::Ordering;
 use std::collections::{BTreeMap, BTreeSet};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 
-use crate::acl::{Actor, Can, Permission, Policy};
+use crate::acl::{Actor, Can, EffectivePermissions, Grant, NotGranted, Permission, Policy, Token};
 use crate::crdt::{Causal, Crdt, DotStore};
 use crate::crypto::Keypair;
 use crate::cursor::array_util::ArrayMetaEntry;
 use crate::dotset::Dot;
 use crate::fraction::Fraction;
 use crate::id::{DocId, PeerId};
-use crate::path::{Path, PathBuf};
-use crate::schema::{ArchivedSchema, PrimitiveKind, Schema};
+use crate::keystore::Keystore;
+use crate::path::{is_reserved, Path, PathBuf, Segment};
+use crate::schema::{
+    metadata_schema, ArchivedSchema, Merge, PrimitiveKind, Schema, MAX_BYTES, METADATA,
+};
 use crate::subscriber::Subscriber;
 use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
 use rkyv::Archived;
 use smallvec::SmallVec;
+use vec_collections::radix_tree::IterKey;
+
+/// Totals of the increments and decrements of each peer of a counter.
+type CounterTotals = BTreeMap<PeerId, (u64, u64)>;
 
 /// A cursor into a document used to construct transactions.
 #[derive(Clone, Debug)]
 pub struct Cursor<'a> {
     key: Keypair,
     peer_id: PeerId,
+    /// The [`Schema`] of the document.
+    root: &'a Archived<Schema>,
     /// The [`Schema`] this [`Cursor`] is pointing to.
     schema: &'a Archived<Schema>,
     crdt: &'a Crdt,
-    /// The path this [`Cursor`] is pointing to.
+    /// The path this [`Cursor`] is pointing to. Shared copy-on-write between clones.
+    path: Arc<PathBuf>,
+    /// Helpers to work with nested ORArrays. Shared copy-on-write between clones.
+    array: Arc<SmallVec<[ArrayWrapper; 1]>>,
+    /// Distinct elements of the last array scanned. Shared between clones.
+    array_cache: Arc<Mutex<Option<ArrayCache>>>,
+    /// Key of the [`Schema::Encrypted`] registers below this [`Cursor`].
+    encryption: Option<Arc<Keystore>>,
+}
+
+/// Distinct `(position, uid)` pairs of an array at a version of the store.
+#[derive(Debug)]
+struct ArrayCache {
+    version: u64,
     path: PathBuf,
-    /// Helpers to work with nested ORArrays.
-    array: SmallVec<[ArrayWrapper; 1]>,
+    items: Arc<Vec<(Fraction, u64)>>,
+}
+
+/// f64 ordered by [`f64::total_cmp`].
+#[derive(Clone, Copy, Debug)]
+struct TotalF64(f64);
+
+impl PartialEq for TotalF64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for TotalF64 {}
+
+impl PartialOrd for TotalF64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TotalF64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Formats a table key like [`Cursor::keys`]. Returns `None` for reserved fields.
+fn key_string(key: Segment) -> Option<String> {
+    match key {
+        Segment::Bool(b) => Some(b.to_string()),
+        Segment::U64(n) => Some(n.to_string()),
+        Segment::I64(n) => Some(n.to_string()),
+        // the document metadata isn't a key of a table at the root.
+        Segment::Str(s) if is_reserved(&s) => None,
+        key => key.prim_key(),
+    }
 }
 
 #[allow(clippy::len_without_is_empty)]
@@ -37,23 +98,182 @@ impl<'a> Cursor<'a> {
         Self {
             key,
             peer_id: key.peer_id(),
+            root: schema,
             schema,
-            path,
+            path: Arc::new(path),
             crdt,
             array: Default::default(),
+            array_cache: Default::default(),
+            encryption: None,
         }
     }
 
-    /// Subscribe to a path.
+    /// Sets the key of the [`Schema::Encrypted`] registers below this [`Cursor`]. The key is
+    /// inherited by the cursors derived from it. Peers without the key replicate the
+    /// ciphertext.
+    pub fn set_encryption_key(&mut self, key: [u8; 32]) -> &mut Self {
+        self.encryption = Some(Arc::new(Keystore::from_key(&key)));
+        self
+    }
+
+    /// Returns if the cursor points to an encrypted register.
+    pub fn is_encrypted(&self) -> bool {
+        matches!(self.schema, ArchivedSchema::Encrypted(_))
+    }
+
+    /// Appends the value of a register, encrypting it with the path of the register as
+    /// associated data if the register is encrypted.
+    fn push_value(&self, path: &mut PathBuf, value: Segment) -> Result<()> {
+        if !self.is_encrypted() {
+            path.push_segment(value);
+            return Ok(());
+        }
+        let keystore = self
+            .encryption
+            .as_ref()
+            .ok_or_else(|| anyhow!("no encryption key"))?;
+        let mut plaintext = PathBuf::new();
+        plaintext.push_segment(value);
+        path.prim_bytes(&keystore.encrypt(plaintext.as_ref(), self.path.as_path().as_ref()));
+        Ok(())
+    }
+
+    /// Decrypts the value of an encrypted register. Returns `None` if the value wasn't
+    /// encrypted with the key of this [`Cursor`] for the path of the register.
+    fn decrypt_value(&self, keystore: &Keystore, value: Segment) -> Option<Segment> {
+        let plaintext = keystore
+            .decrypt(&value.prim_bytes()?, self.path.as_path().as_ref())
+            .ok()?;
+        let path = Path::checked(&plaintext).ok()?;
+        match path.split_first()? {
+            (value, rest) if rest.is_empty() => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Creates a new [`Cursor`] pointing to the metadata of a document.
+    pub fn metadata(key: Keypair, id: DocId, crdt: &'a Crdt) -> Self {
+        let mut cursor = Self::new(key, id, metadata_schema(), crdt);
+        cursor.path_mut().prim_str(METADATA);
+        cursor
+    }
+
+    fn path_mut(&mut self) -> &mut PathBuf {
+        Arc::make_mut(&mut self.path)
+    }
+
+    /// Subscribe to a path. Each batch holds the events since the subscription started.
     pub fn subscribe(&self) -> Subscriber {
         self.crdt.watch_path(self.path.as_path())
     }
 
+    /// Subscribe to a path. Each batch holds the events since the previous batch, use
+    /// [`Batch::changes`](crate::Batch::changes) to decode them relative to this [`Cursor`].
+    pub fn subscribe_changes(&self) -> Subscriber {
+        self.crdt.watch_path_changes(self.path.as_path())
+    }
+
+    /// Returns the path this [`Cursor`] is pointing to.
+    pub(crate) fn path(&self) -> Path<'_> {
+        self.path.as_path()
+    }
+
+    /// Returns the paths in the store starting with `path`.
+    pub(crate) fn scan_path(&self, path: Path) -> impl Iterator<Item = IterKey<u8>> {
+        self.crdt.scan_path(path)
+    }
+
     /// Checks permissions.
     pub fn can(&self, peer: &PeerId, perm: Permission) -> Result<bool> {
         self.crdt.can(peer, perm, self.path.as_path())
     }
 
+    /// Checks if `peer` can read, either because of the policy or a capability token
+    /// presented to this replica.
+    pub fn can_read(&self, peer: &PeerId) -> Result<bool> {
+        self.crdt.can_read(peer, self.path.as_path())
+    }
+
+    /// Explains the permissions of `peer` on the path of this [`Cursor`], listing the rules,
+    /// policy statements and tokens they are derived from, and the statements naming `peer`
+    /// that don't grant it a permission.
+    pub fn effective_permissions(&self, peer: &PeerId) -> Result<EffectivePermissions> {
+        let mut grants = self.crdt.grants(peer, self.path.as_path())?;
+        let ids = grants
+            .iter()
+            .filter_map(|grant| match grant {
+                Grant::Rule { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        let mut statements = BTreeMap::new();
+        let mut revoked = BTreeMap::new();
+        // the statements are read from the policy index instead of scanning the document.
+        let doc = self
+            .path
+            .as_path()
+            .first()
+            .and_then(|doc| doc.doc())
+            .context("missing doc")?;
+        for k in self.crdt.policy_paths(&doc) {
+            let path = Path::new(&k);
+            let statement = path.split_last().and_then(|(rest, _)| {
+                let (rest, peer) = rest.split_last()?;
+                let (rest, policy) = rest.split_last()?;
+                Some((rest.to_owned(), peer.peer()?, policy.policy()?))
+            });
+            if let Some((target, issuer, policy)) = statement {
+                if let Policy::Revokes(id) = &policy {
+                    revoked.insert(*id, path.dot());
+                }
+                statements.insert(path.dot(), (target, issuer, policy));
+            }
+        }
+        let mut not_granted = vec![];
+        for (id, (target, issuer, policy)) in &statements {
+            if ids.contains(id) || !target.as_path().is_ancestor(self.path.as_path()) {
+                continue;
+            }
+            let (actor, cond) = match policy {
+                Policy::Can(actor, _) => (actor, None),
+                Policy::CanIf(actor, _, cond) => (actor, Some(cond)),
+                Policy::Revokes(_) => continue,
+            };
+            if *actor != Actor::Peer(*peer) && *actor != Actor::Anonymous {
+                continue;
+            }
+            let id = *id;
+            if let Some(by) = revoked.get(&id) {
+                not_granted.push(NotGranted::Revoked { id, by: *by });
+            } else if !self
+                .crdt
+                .can(issuer, Permission::Control, target.as_path())?
+            {
+                not_granted.push(NotGranted::IssuerNotAuthorized {
+                    id,
+                    issuer: *issuer,
+                });
+            } else if let Some(cond) = cond {
+                not_granted.push(NotGranted::ConditionNotMet {
+                    id,
+                    cond: cond.clone(),
+                });
+            }
+        }
+        for grant in &mut grants {
+            if let Grant::Rule { id, statement, .. } = grant {
+                *statement = statements
+                    .remove(id)
+                    .map(|(_, issuer, policy)| (issuer, policy));
+            }
+        }
+        Ok(EffectivePermissions {
+            perm: grants.iter().map(Grant::perm).max(),
+            grants,
+            not_granted,
+        })
+    }
+
     /// Return the current schema.
     pub fn schema(&self) -> &'a Archived<Schema> {
         self.schema
@@ -61,78 +281,253 @@ impl<'a> Cursor<'a> {
 
     /// Returns if a flag is enabled.
     pub fn enabled(&self) -> Result<bool> {
-        if let ArchivedSchema::Flag = &self.schema {
-            Ok(self
-                .crdt
-                .scan_path(self.path.as_path())
-                .find_map(|k| Path::new(&k).parent()?.parent()?.last()?.nonce())
-                .is_some())
+        if !matches!(self.schema, ArchivedSchema::Flag | ArchivedSchema::Dynamic) {
+            return Err(anyhow!("not a flag"));
+        }
+        Ok(self
+            .values()
+            .any(|(_, value)| matches!(value.as_slice(), [Segment::Nonce(_)])))
+    }
+
+    /// Returns the values of a register. A last-writer-wins register only returns the value
+    /// written last, ordered by the logical clock in the upper half of the nonce and then by
+    /// the peer id of the writer.
+    fn reg(&self, kind: PrimitiveKind) -> Result<impl Iterator<Item = Segment>> {
+        if !matches!(self.schema, ArchivedSchema::Reg(k) | ArchivedSchema::MergeReg(k, _) | ArchivedSchema::CheckedReg(k, _) | ArchivedSchema::LwwReg(k) | ArchivedSchema::Encrypted(k) if *k == kind)
+            && *self.schema != ArchivedSchema::Dynamic
+        {
+            return Err(anyhow!("not a Reg<{:?}>", kind));
+        }
+        let keystore = if self.is_encrypted() {
+            Some(
+                self.encryption
+                    .as_ref()
+                    .ok_or_else(|| anyhow!("no encryption key"))?,
+            )
         } else {
-            Err(anyhow!("not a flag"))
+            None
+        };
+        let mut values = self
+            .values()
+            .filter_map(|(path, value)| match value.as_slice() {
+                [Segment::Nonce(nonce), prim] => {
+                    let prim = match keystore {
+                        Some(keystore) => self.decrypt_value(keystore, prim.clone())?,
+                        None => prim.clone(),
+                    };
+                    if !kind.validate(prim.clone()) {
+                        return None;
+                    }
+                    let peer = path.as_path().parent()?.last()?.peer()?;
+                    Some(((nonce >> 32, peer, *nonce), prim))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if self.is_lww() {
+            values = values
+                .into_iter()
+                .max_by(|a, b| a.0.cmp(&b.0))
+                .into_iter()
+                .collect();
         }
+        Ok(values.into_iter().map(|(_, prim)| prim))
+    }
+
+    /// Returns if the cursor points to a last-writer-wins register.
+    pub fn is_lww(&self) -> bool {
+        matches!(self.schema, ArchivedSchema::LwwReg(_))
     }
 
     /// Returns an iterator of bools.
     pub fn bools(&self) -> Result<impl Iterator<Item = Result<bool>>> {
-        if let ArchivedSchema::Reg(PrimitiveKind::Bool) = &self.schema {
-            Ok(self.crdt.scan_path(self.path.as_path()).filter_map(|path| {
-                Some(Ok(Path::new(&path)
-                    .parent()?
-                    .parent()?
-                    .last()?
-                    .prim_bool()?))
-            }))
-        } else {
-            Err(anyhow!("not a Reg<bool>"))
-        }
+        Ok(self
+            .reg(PrimitiveKind::Bool)?
+            .filter_map(|prim| Some(Ok(prim.prim_bool()?))))
     }
 
     /// Returns an iterator of u64s.
     pub fn u64s(&self) -> Result<impl Iterator<Item = Result<u64>>> {
-        if let ArchivedSchema::Reg(PrimitiveKind::U64) = &self.schema {
-            Ok(self.crdt.scan_path(self.path.as_path()).filter_map(|path| {
-                Some(Ok(Path::new(&path)
-                    .parent()?
-                    .parent()?
-                    .last()?
-                    .prim_u64()?))
-            }))
-        } else {
-            Err(anyhow!("not a Reg<u64>"))
-        }
+        Ok(self
+            .reg(PrimitiveKind::U64)?
+            .filter_map(|prim| Some(Ok(prim.prim_u64()?))))
     }
 
     /// Returns an iterator of i64s.
     pub fn i64s(&self) -> Result<impl Iterator<Item = Result<i64>>> {
-        if let ArchivedSchema::Reg(PrimitiveKind::I64) = &self.schema {
-            Ok(self.crdt.scan_path(self.path.as_path()).filter_map(|path| {
-                Some(Ok(Path::new(&path)
-                    .parent()?
-                    .parent()?
-                    .last()?
-                    .prim_i64()?))
-            }))
-        } else {
-            Err(anyhow!("not a Reg<i64>"))
-        }
+        Ok(self
+            .reg(PrimitiveKind::I64)?
+            .filter_map(|prim| Some(Ok(prim.prim_i64()?))))
+    }
+
+    /// Returns an iterator of f64s.
+    pub fn f64s(&self) -> Result<impl Iterator<Item = Result<f64>>> {
+        Ok(self
+            .reg(PrimitiveKind::F64)?
+            .filter_map(|prim| Some(Ok(prim.prim_f64()?))))
+    }
+
+    /// Returns an iterator of byte vectors.
+    pub fn bytes(&self) -> Result<impl Iterator<Item = Result<Vec<u8>>>> {
+        Ok(self
+            .reg(PrimitiveKind::Bytes)?
+            .filter_map(|prim| Some(Ok(prim.prim_bytes()?))))
     }
 
     /// Returns an iterator of strs.
     pub fn strs(&self) -> Result<impl Iterator<Item = Result<String>>> {
-        if let ArchivedSchema::Reg(PrimitiveKind::Str) = &self.schema {
-            Ok(self.crdt.scan_path(self.path.as_path()).filter_map(|path| {
-                Some(Ok(Path::new(&path)
-                    .parent()?
-                    .parent()?
-                    .last()?
-                    .prim_str()?
-                    .to_owned()))
-            }))
+        Ok(self
+            .reg(PrimitiveKind::Str)?
+            .filter_map(|prim| Some(Ok(prim.prim_string()?))))
+    }
+
+    /// Returns the [`Merge`] strategy if the cursor points to a register that declares one.
+    pub fn merge(&self) -> Option<Merge> {
+        if let ArchivedSchema::MergeReg(_, merge) = self.schema {
+            Some(*merge)
         } else {
-            Err(anyhow!("not a Reg<String>"))
+            None
         }
     }
 
+    fn merged<T: Ord>(
+        &self,
+        kind: PrimitiveKind,
+        value: impl Fn(Segment) -> Option<Result<T>>,
+        sum: Option<fn(T, T) -> T>,
+    ) -> Result<Option<T>> {
+        if self.is_lww() {
+            return self.reg(kind)?.find_map(&value).transpose();
+        }
+        let merge = self
+            .merge()
+            .ok_or_else(|| anyhow!("register has no merge strategy"))?;
+        let values = || -> Result<_> {
+            let values = self.reg(kind)?.filter_map(&value);
+            Ok(values.collect::<Result<Vec<_>>>()?.into_iter())
+        };
+        Ok(match (merge, sum) {
+            (Merge::Max, _) => values()?.max(),
+            (Merge::Min, _) => values()?.min(),
+            (Merge::Sum, Some(sum)) => values()?.reduce(sum),
+            (Merge::Sum, None) => return Err(anyhow!("cannot sum a non numeric register")),
+            (Merge::Custom, _) => return Err(anyhow!("register is resolved by the application")),
+        })
+    }
+
+    /// Returns the bool resolved by the [`Merge`] strategy of the register.
+    pub fn merged_bool(&self) -> Result<Option<bool>> {
+        self.merged(
+            PrimitiveKind::Bool,
+            |prim| Some(Ok(prim.prim_bool()?)),
+            None,
+        )
+    }
+
+    /// Returns the u64 resolved by the [`Merge`] strategy of the register.
+    pub fn merged_u64(&self) -> Result<Option<u64>> {
+        self.merged(
+            PrimitiveKind::U64,
+            |prim| Some(Ok(prim.prim_u64()?)),
+            Some(u64::saturating_add),
+        )
+    }
+
+    /// Returns the i64 resolved by the [`Merge`] strategy of the register.
+    pub fn merged_i64(&self) -> Result<Option<i64>> {
+        self.merged(
+            PrimitiveKind::I64,
+            |prim| Some(Ok(prim.prim_i64()?)),
+            Some(i64::saturating_add),
+        )
+    }
+
+    /// Returns the f64 resolved by the [`Merge`] strategy of the register. Values are ordered
+    /// by their total order.
+    pub fn merged_f64(&self) -> Result<Option<f64>> {
+        let value = self.merged(
+            PrimitiveKind::F64,
+            |prim| Some(Ok(TotalF64(prim.prim_f64()?))),
+            Some(|a: TotalF64, b: TotalF64| TotalF64(a.0 + b.0)),
+        )?;
+        Ok(value.map(|value| value.0))
+    }
+
+    /// Returns the bytes resolved by the [`Merge`] strategy of the register.
+    pub fn merged_bytes(&self) -> Result<Option<Vec<u8>>> {
+        self.merged(
+            PrimitiveKind::Bytes,
+            |prim| Some(Ok(prim.prim_bytes()?)),
+            None,
+        )
+    }
+
+    /// Returns the str resolved by the [`Merge`] strategy of the register.
+    pub fn merged_str(&self) -> Result<Option<String>> {
+        self.merged(
+            PrimitiveKind::Str,
+            |prim| Some(Ok(prim.prim_string()?)),
+            None,
+        )
+    }
+
+    /// Returns the value of a counter, which is the sum of all increments.
+    pub fn counter_value(&self) -> Result<i64> {
+        let (_, totals) = self.counter_paths()?;
+        let sum = totals
+            .into_iter()
+            .map(|(_, (inc, dec))| inc as i128 - dec as i128)
+            .sum::<i128>();
+        Ok(sum.clamp(i64::MIN as i128, i64::MAX as i128) as i64)
+    }
+
+    /// Returns the paths of a counter and the totals of the increments and decrements of each
+    /// peer. The totals only grow, so the largest of concurrent totals of a peer is kept.
+    fn counter_paths(&self) -> Result<(Vec<(PeerId, PathBuf)>, CounterTotals)> {
+        if *self.schema != ArchivedSchema::Counter {
+            return Err(anyhow!("not a Counter"));
+        }
+        let mut paths = vec![];
+        let mut totals = CounterTotals::new();
+        for (path, value) in self.values() {
+            if let [Segment::Nonce(_), Segment::U64(inc), Segment::U64(dec)] = value.as_slice() {
+                let peer = match path.as_path().parent().and_then(|path| path.last()) {
+                    Some(peer) => match peer.peer() {
+                        Some(peer) => peer,
+                        None => continue,
+                    },
+                    None => continue,
+                };
+                let total = totals.entry(peer).or_default();
+                total.0 = total.0.max(*inc);
+                total.1 = total.1.max(*dec);
+                paths.push((peer, path));
+            }
+        }
+        Ok((paths, totals))
+    }
+
+    /// Returns the characters of a text and their positions in order.
+    fn chars(&self) -> Result<Vec<(Fraction, String)>> {
+        if *self.schema != ArchivedSchema::Text {
+            return Err(anyhow!("not a Text"));
+        }
+        Ok(self
+            .values()
+            .filter_map(|(_, value)| match value.as_slice() {
+                [Segment::Position(pos), Segment::Str(c)] => Some((pos.clone(), c.clone())),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect())
+    }
+
+    /// Returns the string of a text.
+    pub fn text(&self) -> Result<String> {
+        Ok(self.chars()?.into_iter().map(|(_, c)| c).collect())
+    }
+
     /// If the cursor points to a Struct or a Table, returns an iterator of all existing keys.
     pub fn keys(&self) -> Result<Vec<String>> {
         match self.schema {
@@ -152,6 +547,8 @@ impl<'a> Cursor<'a> {
                         Ok(crate::Segment::Bool(b)) => Some(Ok(b.to_string())),
                         Ok(crate::Segment::U64(n)) => Some(Ok(n.to_string())),
                         Ok(crate::Segment::I64(n)) => Some(Ok(n.to_string())),
+                        // the document metadata isn't a key of a table at the root.
+                        Ok(crate::Segment::Str(s)) if is_reserved(&s) => None,
                         Ok(crate::Segment::Str(s)) => Some(Ok(s)),
                         Ok(_) => None,
                         Err(e) => Some(Err(e)),
@@ -165,46 +562,54 @@ impl<'a> Cursor<'a> {
 
     /// Returns a cursor to a value in a table.
     pub fn key_bool(&mut self, key: bool) -> Result<&mut Self> {
-        if let ArchivedSchema::Table(PrimitiveKind::Bool, schema) = &self.schema {
-            self.path.prim_bool(key);
-            self.schema = schema;
-            Ok(self)
-        } else {
-            Err(anyhow!("not a Table<bool, _>"))
+        match &self.schema {
+            ArchivedSchema::Table(PrimitiveKind::Bool, schema) => {
+                self.schema = schema.unwrap_local()
+            }
+            ArchivedSchema::Dynamic => {}
+            _ => return Err(anyhow!("not a Table<bool, _>")),
         }
+        self.path_mut().prim_bool(key);
+        Ok(self)
     }
 
     /// Returns a cursor to a value in a table.
     pub fn key_u64(&mut self, key: u64) -> Result<&mut Self> {
-        if let ArchivedSchema::Table(PrimitiveKind::U64, schema) = &self.schema {
-            self.path.prim_u64(key);
-            self.schema = schema;
-            Ok(self)
-        } else {
-            Err(anyhow!("not a Table<u64, _>"))
+        match &self.schema {
+            ArchivedSchema::Table(PrimitiveKind::U64, schema) => {
+                self.schema = schema.unwrap_local()
+            }
+            ArchivedSchema::Dynamic => {}
+            _ => return Err(anyhow!("not a Table<u64, _>")),
         }
+        self.path_mut().prim_u64(key);
+        Ok(self)
     }
 
     /// Returns a cursor to a value in a table.
     pub fn key_i64(&mut self, key: i64) -> Result<&mut Self> {
-        if let ArchivedSchema::Table(PrimitiveKind::I64, schema) = &self.schema {
-            self.path.prim_i64(key);
-            self.schema = schema;
-            Ok(self)
-        } else {
-            Err(anyhow!("not a Table<i64, _>"))
+        match &self.schema {
+            ArchivedSchema::Table(PrimitiveKind::I64, schema) => {
+                self.schema = schema.unwrap_local()
+            }
+            ArchivedSchema::Dynamic => {}
+            _ => return Err(anyhow!("not a Table<i64, _>")),
         }
+        self.path_mut().prim_i64(key);
+        Ok(self)
     }
 
     /// Returns a cursor to a value in a table.
     pub fn key_str(&mut self, key: &str) -> Result<&mut Self> {
-        if let ArchivedSchema::Table(PrimitiveKind::Str, schema) = &self.schema {
-            self.path.prim_str(key);
-            self.schema = schema;
-            Ok(self)
-        } else {
-            Err(anyhow!("not a Table<String, _>"))
+        match &self.schema {
+            ArchivedSchema::Table(PrimitiveKind::Str, schema) => {
+                self.schema = schema.unwrap_local()
+            }
+            ArchivedSchema::Dynamic => {}
+            _ => return Err(anyhow!("not a Table<String, _>")),
         }
+        self.path_mut().prim_key(key);
+        Ok(self)
     }
 
     /// Returns an iterator of table keys.
@@ -256,55 +661,303 @@ impl<'a> Cursor<'a> {
     pub fn keys_str(&self) -> Result<impl Iterator<Item = String> + '_> {
         if let ArchivedSchema::Table(PrimitiveKind::Str, _) = &self.schema {
             Ok(self.crdt.scan_path(self.path.as_path()).filter_map(|key| {
-                Path::new(&key)
+                let key = Path::new(&key)
                     .strip_prefix(self.path.as_path())
                     .ok()?
-                    .first()?
-                    .prim_string()
+                    .first()?;
+                if key.prim_str().map(is_reserved).unwrap_or_default() {
+                    return None;
+                }
+                key.prim_key()
             }))
         } else {
             Err(anyhow!("not a Table<String, _>"))
         }
     }
 
+    /// Returns up to `limit` keys of a table starting at `start`. Keys are returned in storage
+    /// order, so passing the last key of a page as [`Bound::Excluded`] returns the next page.
+    /// Unlike [`Cursor::keys`] only the returned keys are visited.
+    pub fn keys_range(&self, start: Bound<&str>, limit: usize) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut after = match start {
+            Bound::Unbounded => None,
+            Bound::Included(key) | Bound::Excluded(key) => {
+                let path = self.key_path(key)?;
+                let included = matches!(start, Bound::Included(_));
+                if included && limit > 0 && self.crdt.scan_path(path.as_path()).next().is_some() {
+                    keys.push(key.to_string());
+                }
+                Some(path)
+            }
+        };
+        while keys.len() < limit {
+            match self.next_key(after.as_ref())? {
+                Some((key, path)) => {
+                    keys.extend(key_string(key));
+                    after = Some(path);
+                }
+                None => break,
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Returns the number of keys of a table without collecting them.
+    pub fn len_keys(&self) -> Result<usize> {
+        let mut len = 0;
+        let mut after = None;
+        while let Some((key, path)) = self.next_key(after.as_ref())? {
+            if key_string(key).is_some() {
+                len += 1;
+            }
+            after = Some(path);
+        }
+        Ok(len)
+    }
+
+    /// Returns the keys of a `Table<u64, _>` within `range` in ascending order. Keys are
+    /// encoded big-endian, so only the keys within the range are visited.
+    pub fn range_u64(
+        &self,
+        range: impl RangeBounds<u64>,
+    ) -> Result<impl Iterator<Item = u64> + '_> {
+        if !matches!(self.schema, ArchivedSchema::Table(PrimitiveKind::U64, _)) {
+            return Err(anyhow!("not a Table<u64, _>"));
+        }
+        let start = match range.start_bound() {
+            Bound::Included(start) => Some(*start),
+            Bound::Excluded(start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Some(*end),
+            Bound::Excluded(end) => end.checked_sub(1),
+            Bound::Unbounded => Some(u64::MAX),
+        };
+        Ok(self.range_bits(start.zip(end)))
+    }
+
+    /// Returns the keys of a `Table<i64, _>` within `range` in ascending order. Only the keys
+    /// within the range are visited.
+    pub fn range_i64(
+        &self,
+        range: impl RangeBounds<i64>,
+    ) -> Result<impl Iterator<Item = i64> + '_> {
+        if !matches!(self.schema, ArchivedSchema::Table(PrimitiveKind::I64, _)) {
+            return Err(anyhow!("not a Table<i64, _>"));
+        }
+        let start = match range.start_bound() {
+            Bound::Included(start) => Some(*start),
+            Bound::Excluded(start) => start.checked_add(1),
+            Bound::Unbounded => Some(i64::MIN),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(end) => Some(*end),
+            Bound::Excluded(end) => end.checked_sub(1),
+            Bound::Unbounded => Some(i64::MAX),
+        };
+        // negative keys sort after positive keys in two's complement, so the range is split.
+        let (negative, positive) = match start.zip(end) {
+            Some((start, end)) => (
+                Some((start, end.min(-1))).filter(|(start, end)| start <= end),
+                Some((start.max(0), end)).filter(|(start, end)| start <= end),
+            ),
+            None => (None, None),
+        };
+        let bits = |range: Option<(i64, i64)>| range.map(|(start, end)| (start as u64, end as u64));
+        Ok(self
+            .range_bits(bits(negative))
+            .chain(self.range_bits(bits(positive)))
+            .map(|key| key as i64))
+    }
+
+    /// Returns the keys of a table keyed by integers whose big-endian bytes are within the
+    /// inclusive `range`.
+    fn range_bits(&self, range: Option<(u64, u64)>) -> impl Iterator<Item = u64> + '_ {
+        let (start, end) = range.filter(|(start, end)| start <= end).unwrap_or((1, 0));
+        let mut done = start > end;
+        let mut after = start.checked_sub(1).map(|key| {
+            let mut path = self.path.as_ref().clone();
+            match &self.schema {
+                ArchivedSchema::Table(PrimitiveKind::I64, _) => path.prim_i64(key as i64),
+                _ => path.prim_u64(key),
+            }
+            path
+        });
+        let signed = matches!(self.schema, ArchivedSchema::Table(PrimitiveKind::I64, _));
+        std::iter::from_fn(move || loop {
+            if done {
+                return None;
+            }
+            let (key, path) = self.next_key(after.as_ref()).ok()??;
+            after = Some(path);
+            // other segments like the document metadata sort between the keys.
+            let key = match key {
+                Segment::U64(key) if !signed => key,
+                Segment::I64(key) if signed => key as u64,
+                _ => continue,
+            };
+            if key >= end {
+                done = true;
+            }
+            return Some(key).filter(|key| *key <= end);
+        })
+    }
+
+    /// Returns the path of `key` in a table.
+    fn key_path(&self, key: &str) -> Result<PathBuf> {
+        let mut path = self.path.as_ref().clone();
+        match &self.schema {
+            ArchivedSchema::Table(PrimitiveKind::Bool, _) => path.prim_bool(key.parse()?),
+            ArchivedSchema::Table(PrimitiveKind::U64, _) => path.prim_u64(key.parse()?),
+            ArchivedSchema::Table(PrimitiveKind::I64, _) => path.prim_i64(key.parse()?),
+            ArchivedSchema::Table(PrimitiveKind::Str, _) => path.prim_key(key),
+            _ => return Err(anyhow!("not a Table<_, _>")),
+        }
+        Ok(path)
+    }
+
+    /// Returns the key following the key at `after` and the path of the key.
+    fn next_key(&self, after: Option<&PathBuf>) -> Result<Option<(Segment, PathBuf)>> {
+        if !matches!(self.schema, ArchivedSchema::Table(_, _)) {
+            return Err(anyhow!("not a Table<_, _>"));
+        }
+        let next = if let Some(next) = self
+            .crdt
+            .first_after(self.path.as_path(), after.map(|path| path.as_path()))
+        {
+            next
+        } else {
+            return Ok(None);
+        };
+        let segment = next
+            .as_path()
+            .strip_prefix(self.path.as_path())?
+            .first()
+            .context("Empty")?;
+        let mut path = self.path.as_ref().clone();
+        path.push_segment(segment.clone());
+        Ok(Some((segment, path)))
+    }
+
     /// Returns a cursor to a value in an array.
     pub fn index(&mut self, ix: usize) -> Result<&mut Self> {
         if let ArchivedSchema::Array(schema) = &self.schema {
-            self.schema = schema;
-            let (array, path) = ArrayWrapper::new(self, ix)?;
-            self.array.push(array);
-            self.path = path;
+            self.schema = schema.unwrap_local();
+            let items = self.array_items(self.path.as_path())?;
+            let (array, path) = ArrayWrapper::new(self, &items, ix)?;
+            Arc::make_mut(&mut self.array).push(array);
+            self.path = Arc::new(path);
             Ok(self)
         } else {
             anyhow::bail!("not an Array<_>");
         }
     }
 
+    /// Returns a cursor to each element of the array. The array is scanned once.
+    pub fn iter_array(&self) -> Result<impl Iterator<Item = Self> + '_> {
+        if let ArchivedSchema::Array(schema) = &self.schema {
+            let items = self.array_items(self.path.as_path())?;
+            Ok((0..items.len()).map(move |ix| {
+                let mut cursor = self.clone();
+                cursor.schema = schema.unwrap_local();
+                let (pos, uid) = items[ix].clone();
+                let (array, path) = ArrayWrapper::at(&cursor, pos, uid);
+                Arc::make_mut(&mut cursor.array).push(array);
+                cursor.path = Arc::new(path);
+                cursor
+            }))
+        } else {
+            anyhow::bail!("not an Array<_>");
+        }
+    }
+
+    /// Returns cursors to `n` new elements after the end of the array. Unlike calling
+    /// [`Cursor::at_index`] with the length of the array for each element, the elements keep
+    /// their order when the transactions writing them are joined before being applied.
+    pub fn append(&self, n: usize) -> Result<Vec<Self>> {
+        if let ArchivedSchema::Array(schema) = &self.schema {
+            let items = self.array_items(self.path.as_path())?;
+            let last = items.last().map(|(pos, _)| pos);
+            Ok(Fraction::run(last, None, n, nonce())?
+                .into_iter()
+                .map(|pos| {
+                    let mut cursor = self.clone();
+                    cursor.schema = schema.unwrap_local();
+                    let (array, path) = ArrayWrapper::at(&cursor, pos, nonce());
+                    Arc::make_mut(&mut cursor.array).push(array);
+                    cursor.path = Arc::new(path);
+                    cursor
+                })
+                .collect())
+        } else {
+            anyhow::bail!("not an Array<_>");
+        }
+    }
+
     /// Returns the length of the array.
     pub fn len(&self) -> Result<u32> {
         if let ArchivedSchema::Array(_) = &self.schema {
-            let mut path = self.path.clone();
-            path.prim_str(array_util::ARRAY_VALUES);
+            Ok(self.array_items(self.path.as_path())?.len() as u32)
+        } else {
+            anyhow::bail!("not an Array<_>");
+        }
+    }
 
-            let res = self.pos_iter(Some(path)).collect::<BTreeSet<_>>().len();
-            Ok(res as u32)
+    /// Returns the distinct elements of the array at `path` ordered by position. The result
+    /// is cached until the store changes.
+    pub(crate) fn array_items(&self, path: Path) -> Result<Arc<Vec<(Fraction, u64)>>> {
+        let version = self.crdt.version();
+        let mut cache = self.array_cache.lock();
+        if let Some(cache) = cache.as_ref() {
+            if cache.version == version && cache.path.as_path() == path {
+                return Ok(cache.items.clone());
+            }
+        }
+        let items = Arc::new(
+            ArrayWrapper::distinct_arr_items(self, path.to_owned()).collect::<Result<Vec<_>>>()?,
+        );
+        *cache = Some(ArrayCache {
+            version,
+            path: path.to_owned(),
+            items: items.clone(),
+        });
+        Ok(items)
+    }
+
+    /// Returns the length in bytes of the longest position in the array. Positions grow when
+    /// elements are repeatedly inserted at the same spot, see [`Cursor::rebalance`].
+    pub fn max_position_len(&self) -> Result<usize> {
+        if let ArchivedSchema::Array(_) = &self.schema {
+            let items = self.array_items(self.path.as_path())?;
+            Ok(items
+                .iter()
+                .map(|(pos, _)| pos.as_bytes().len())
+                .max()
+                .unwrap_or_default())
         } else {
             anyhow::bail!("not an Array<_>");
         }
     }
 
-    fn pos_iter(&self, path: Option<PathBuf>) -> impl Iterator<Item = Fraction> + '_ {
-        let path = path.unwrap_or_else(|| self.path.clone());
-        self.crdt.scan_path(path.as_path()).filter_map(move |e| {
-            let p = Path::new(&e);
-            p.strip_prefix(path.as_path())
-                .ok()
-                .and_then(|e| e.first())
-                .and_then(|x| match x {
-                    crate::Segment::Position(x) => Some(x),
-                    _ => None,
-                })
-        })
+    /// Returns a transaction that moves the elements of the array to evenly spaced short
+    /// positions without changing their order. Elements inserted or moved concurrently keep
+    /// their old position, so this is best run in the background while the array is idle.
+    pub fn rebalance(&self) -> Result<Causal> {
+        if let ArchivedSchema::Array(_) = &self.schema {
+            let items = self.array_items(self.path.as_path())?;
+            let mut causal = Causal::default();
+            for (ix, pos) in Fraction::spread(items.len()).into_iter().enumerate() {
+                if items[ix].0 != pos {
+                    let (array, _) = ArrayWrapper::at(self, items[ix].0.clone(), items[ix].1);
+                    causal.join(&array.move_to(self, pos)?);
+                }
+            }
+            Ok(causal)
+        } else {
+            anyhow::bail!("not an Array<_>");
+        }
     }
 
     /// Returns if the array is empty.
@@ -314,17 +967,160 @@ impl<'a> Cursor<'a> {
 
     /// Returns a cursor to a field in a struct.
     pub fn field(&mut self, key: &str) -> Result<&mut Self> {
-        if let ArchivedSchema::Struct(fields) = &self.schema {
-            if let Some(schema) = fields.get(key) {
-                self.path.prim_str(key);
-                self.schema = schema;
-                Ok(self)
-            } else {
-                Err(anyhow!("field doesn't exist"))
+        match &self.schema {
+            ArchivedSchema::Struct(fields) => {
+                self.schema = fields
+                    .get(key)
+                    .context("field doesn't exist")?
+                    .unwrap_local();
             }
-        } else {
-            Err(anyhow!("not a struct"))
+            ArchivedSchema::Dynamic => {}
+            _ => return Err(anyhow!("not a struct")),
+        }
+        self.path_mut().prim_key(key);
+        Ok(self)
+    }
+
+    /// Returns a JSON-pointer-like string of the path this [`Cursor`] is pointing to, for
+    /// example `/todos/2/title`. Array elements are identified by their current index. The
+    /// cursor can be restored with [`Cursor::pointer`] or [`Doc::cursor_at`](crate::Doc::cursor_at).
+    pub fn path_string(&self) -> Result<String> {
+        let mut segments = self.path.as_path().into_iter();
+        let mut base = PathBuf::new();
+        base.push_segment(segments.next().context("empty path")?);
+        let mut schema = self.root.unwrap_local();
+        let mut pointer = String::new();
+        while let Some(mut segment) = segments.next() {
+            let step = match schema {
+                ArchivedSchema::Struct(fields) => {
+                    let name = segment.clone().prim_key().context("invalid field")?;
+                    schema = fields.get(name.as_str()).context("invalid field")?;
+                    name
+                }
+                ArchivedSchema::Table(kind, value) => {
+                    schema = value;
+                    match kind {
+                        PrimitiveKind::Bool => segment.clone().prim_bool().map(|b| b.to_string()),
+                        PrimitiveKind::U64 => segment.clone().prim_u64().map(|n| n.to_string()),
+                        PrimitiveKind::I64 => segment.clone().prim_i64().map(|n| n.to_string()),
+                        PrimitiveKind::F64 => segment.clone().prim_f64().map(|n| n.to_string()),
+                        PrimitiveKind::Bytes => segment.clone().prim_bytes().map(base64::encode),
+                        PrimitiveKind::Str => segment.clone().prim_key(),
+                    }
+                    .context("invalid key")?
+                }
+                ArchivedSchema::Array(value) => {
+                    let (pos, uid) = match (segment.prim_str(), segments.next(), segments.next()) {
+                        (Some(values), Some(pos), Some(Segment::U64(uid)))
+                            if values == array_util::ARRAY_VALUES =>
+                        {
+                            (pos, uid)
+                        }
+                        _ => return Err(anyhow!("invalid array element")),
+                    };
+                    let items = self.array_items(base.as_path())?;
+                    let ix = items
+                        .iter()
+                        .position(|(_, id)| *id == uid)
+                        .context("array element was removed")?;
+                    schema = value;
+                    base.push_segment(segment);
+                    base.push_segment(pos);
+                    segment = Segment::U64(uid);
+                    ix.to_string()
+                }
+                _ => return Err(anyhow!("can't describe path into {:?}", schema)),
+            };
+            base.push_segment(segment);
+            schema = schema.unwrap_local();
+            pointer.push('/');
+            pointer.push_str(&step.replace('~', "~0").replace('/', "~1"));
         }
+        Ok(pointer)
+    }
+
+    /// Moves the cursor along a path returned from [`Cursor::path_string`].
+    pub fn pointer(&mut self, pointer: &str) -> Result<&mut Self> {
+        if pointer.is_empty() {
+            return Ok(self);
+        }
+        let steps = pointer
+            .strip_prefix('/')
+            .context("pointer must start with a `/`")?;
+        for step in steps.split('/') {
+            let step = step.replace("~1", "/").replace("~0", "~");
+            let err = || anyhow!("invalid step {} of {}", step, pointer);
+            match self.schema {
+                ArchivedSchema::Struct(_) => self.field(&step)?,
+                ArchivedSchema::Table(PrimitiveKind::Bool, _) => {
+                    self.key_bool(step.parse().map_err(|_| err())?)?
+                }
+                ArchivedSchema::Table(PrimitiveKind::U64, _) => {
+                    self.key_u64(step.parse().map_err(|_| err())?)?
+                }
+                ArchivedSchema::Table(PrimitiveKind::I64, _) => {
+                    self.key_i64(step.parse().map_err(|_| err())?)?
+                }
+                ArchivedSchema::Table(PrimitiveKind::Str, _) => self.key_str(&step)?,
+                ArchivedSchema::Array(_) => {
+                    let ix: usize = step.parse().map_err(|_| err())?;
+                    if ix >= self.len()? as usize {
+                        return Err(anyhow!("index {} out of bounds", ix));
+                    }
+                    self.index(ix)?
+                }
+                _ => return Err(err()),
+            };
+        }
+        Ok(self)
+    }
+
+    /// Returns a new cursor to a value in a table. Unlike [`Cursor::key_bool`] this leaves
+    /// `self` untouched.
+    pub fn at_key_bool(&self, key: bool) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.key_bool(key)?;
+        Ok(cursor)
+    }
+
+    /// Returns a new cursor to a value in a table. Unlike [`Cursor::key_u64`] this leaves
+    /// `self` untouched.
+    pub fn at_key_u64(&self, key: u64) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.key_u64(key)?;
+        Ok(cursor)
+    }
+
+    /// Returns a new cursor to a value in a table. Unlike [`Cursor::key_i64`] this leaves
+    /// `self` untouched.
+    pub fn at_key_i64(&self, key: i64) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.key_i64(key)?;
+        Ok(cursor)
+    }
+
+    /// Returns a new cursor to a value in a table. Unlike [`Cursor::key_str`] this leaves
+    /// `self` untouched.
+    pub fn at_key_str(&self, key: &str) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.key_str(key)?;
+        Ok(cursor)
+    }
+
+    /// Returns a new cursor to a value in an array. Unlike [`Cursor::index`] this leaves
+    /// `self` untouched.
+    pub fn at_index(&self, ix: usize) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.index(ix)?;
+        Ok(cursor)
+    }
+
+    /// Returns a new cursor to a field in a struct. Unlike [`Cursor::field`] this leaves
+    /// `self` untouched.
+    pub fn at_field(&self, key: &str) -> Result<Self> {
+        let mut cursor = self.clone();
+        cursor.field(key)?;
+        Ok(cursor)
     }
 
     fn nonce(&self, path: &mut PathBuf) {
@@ -360,15 +1156,45 @@ impl<'a> Cursor<'a> {
         Ok(expired)
     }
 
+    /// Returns the paths below the cursor together with the value segments between the cursor
+    /// and the trailing `peer sig`.
+    fn values(&self) -> impl Iterator<Item = (PathBuf, Vec<Segment>)> {
+        let prefix = self.path.clone();
+        self.crdt
+            .scan_path(self.path.as_path())
+            .filter_map(move |k| {
+                let path = Path::new(&k);
+                let value = path
+                    .parent()?
+                    .parent()?
+                    .strip_prefix(prefix.as_path())
+                    .ok()?
+                    .into_iter()
+                    .collect();
+                Some((path.to_owned(), value))
+            })
+    }
+
+    fn tombstone_values(&self, f: impl Fn(&[Segment]) -> bool) -> DotStore {
+        let mut expired = DotStore::new();
+        for (mut path, value) in self.values() {
+            if f(&value) {
+                self.sign(&mut path);
+                expired.insert(path);
+            }
+        }
+        expired
+    }
+
     /// Enables a flag.
     pub fn enable(&self) -> Result<Causal> {
-        if *self.schema != ArchivedSchema::Flag {
+        if !matches!(self.schema, ArchivedSchema::Flag | ArchivedSchema::Dynamic) {
             return Err(anyhow!("not a flag"));
         }
         if !self.can(&self.peer_id, Permission::Write)? {
             return Err(anyhow!("unauthorized"));
         }
-        let mut path = self.path.to_owned();
+        let mut path = self.path.as_path().to_owned();
         self.nonce(&mut path);
         self.sign(&mut path);
         let mut store = DotStore::new();
@@ -382,7 +1208,7 @@ impl<'a> Cursor<'a> {
 
     /// Disables a flag.
     pub fn disable(&self) -> Result<Causal> {
-        if *self.schema != ArchivedSchema::Flag {
+        if !matches!(self.schema, ArchivedSchema::Flag | ArchivedSchema::Dynamic) {
             return Err(anyhow!("not a flag"));
         }
         if !self.can(&self.peer_id, Permission::Write)? {
@@ -391,7 +1217,7 @@ impl<'a> Cursor<'a> {
 
         let c = Causal {
             store: DotStore::new(),
-            expired: self.tombstone()?,
+            expired: self.tombstone_values(|value| matches!(value, [Segment::Nonce(_)])),
         };
         self.augment_array(c)
     }
@@ -400,19 +1226,35 @@ impl<'a> Cursor<'a> {
         if !self.can(&self.peer_id, Permission::Write)? {
             return Err(anyhow!("unauthorized"));
         }
-        if *self.schema != ArchivedSchema::Reg(kind) {
+        if !matches!(self.schema, ArchivedSchema::Reg(k) | ArchivedSchema::MergeReg(k, _) | ArchivedSchema::CheckedReg(k, _) | ArchivedSchema::LwwReg(k) | ArchivedSchema::Encrypted(k) if *k == kind)
+            && *self.schema != ArchivedSchema::Dynamic
+        {
             return Err(anyhow!("not a Reg<{:?}>", kind));
         }
-        let mut path = self.path.to_owned();
-        self.nonce(&mut path);
-        Ok((path, self.tombstone()?))
+        let mut path = self.path.as_path().to_owned();
+        if self.is_lww() {
+            path.nonce(lww_nonce(self.crdt, self.path.as_path()));
+        } else {
+            self.nonce(&mut path);
+        }
+        let expired = self.tombstone_values(|value| matches!(value, [Segment::Nonce(_), _]));
+        Ok((path, expired))
+    }
+
+    fn check(&self, value: Segment) -> Result<()> {
+        if let ArchivedSchema::CheckedReg(_, invariant) = self.schema {
+            if !invariant.validate(value) {
+                return Err(anyhow!("value violates check {}", invariant));
+            }
+        }
+        Ok(())
     }
 
     /// Assigns a value to a register.
     pub fn assign_bool(&self, value: bool) -> Result<Causal> {
         let (mut path, expired) = self.assign(PrimitiveKind::Bool)?;
         let mut store = DotStore::new();
-        path.prim_bool(value);
+        self.push_value(&mut path, Segment::Bool(value))?;
         self.sign(&mut path);
         store.insert(path);
 
@@ -423,8 +1265,9 @@ impl<'a> Cursor<'a> {
     /// Assigns a value to a register.
     pub fn assign_u64(&self, value: u64) -> Result<Causal> {
         let (mut path, expired) = self.assign(PrimitiveKind::U64)?;
+        self.check(Segment::U64(value))?;
         let mut store = DotStore::new();
-        path.prim_u64(value);
+        self.push_value(&mut path, Segment::U64(value))?;
         self.sign(&mut path);
         store.insert(path);
 
@@ -435,8 +1278,38 @@ impl<'a> Cursor<'a> {
     /// Assigns a value to a register.
     pub fn assign_i64(&self, value: i64) -> Result<Causal> {
         let (mut path, expired) = self.assign(PrimitiveKind::I64)?;
+        self.check(Segment::I64(value))?;
         let mut store = DotStore::new();
-        path.prim_i64(value);
+        self.push_value(&mut path, Segment::I64(value))?;
+        self.sign(&mut path);
+        store.insert(path);
+
+        let c = Causal { store, expired };
+        self.augment_array(c)
+    }
+
+    /// Assigns a value to a register.
+    pub fn assign_f64(&self, value: f64) -> Result<Causal> {
+        let (mut path, expired) = self.assign(PrimitiveKind::F64)?;
+        self.check(Segment::F64(value))?;
+        let mut store = DotStore::new();
+        self.push_value(&mut path, Segment::F64(value))?;
+        self.sign(&mut path);
+        store.insert(path);
+
+        let c = Causal { store, expired };
+        self.augment_array(c)
+    }
+
+    /// Assigns a value of at most [`MAX_BYTES`] bytes to a register.
+    pub fn assign_bytes(&self, value: &[u8]) -> Result<Causal> {
+        if value.len() > MAX_BYTES {
+            return Err(anyhow!("bytes exceed {} bytes", MAX_BYTES));
+        }
+        let (mut path, expired) = self.assign(PrimitiveKind::Bytes)?;
+        self.check(Segment::Bytes(value.to_vec()))?;
+        let mut store = DotStore::new();
+        self.push_value(&mut path, Segment::Bytes(value.to_vec()))?;
         self.sign(&mut path);
         store.insert(path);
 
@@ -447,8 +1320,9 @@ impl<'a> Cursor<'a> {
     /// Assigns a value to a register.
     pub fn assign_str(&self, value: &str) -> Result<Causal> {
         let (mut path, expired) = self.assign(PrimitiveKind::Str)?;
+        self.check(Segment::Str(value.into()))?;
         let mut store = DotStore::new();
-        path.prim_str(value);
+        self.push_value(&mut path, Segment::Str(value.into()))?;
         self.sign(&mut path);
         store.insert(path);
 
@@ -456,6 +1330,93 @@ impl<'a> Cursor<'a> {
         self.augment_array(c)
     }
 
+    /// Increments a counter by `delta`, which may be negative. Each peer keeps a single path
+    /// holding the totals of it's increments and decrements, which replaces the previous one.
+    /// [`Cursor::remove`] resets the counter, the totals of increments concurrent to the reset
+    /// are kept.
+    pub fn increment(&self, delta: i64) -> Result<Causal> {
+        let (paths, totals) = self.counter_paths()?;
+        if !self.can(&self.peer_id, Permission::Write)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        let (mut inc, mut dec) = totals.get(&self.peer_id).copied().unwrap_or_default();
+        if delta >= 0 {
+            inc = inc.saturating_add(delta.unsigned_abs());
+        } else {
+            dec = dec.saturating_add(delta.unsigned_abs());
+        }
+        let mut path = self.path.as_path().to_owned();
+        self.nonce(&mut path);
+        path.prim_u64(inc);
+        path.prim_u64(dec);
+        self.sign(&mut path);
+        let mut store = DotStore::new();
+        store.insert(path);
+        let mut expired = DotStore::new();
+        for (peer, mut path) in paths {
+            if peer == self.peer_id {
+                self.sign(&mut path);
+                expired.insert(path);
+            }
+        }
+        let c = Causal { store, expired };
+        self.augment_array(c)
+    }
+
+    /// Inserts `s` into a text before the character at index `ix`. Strings inserted
+    /// concurrently at the same index are not interleaved.
+    pub fn insert_str(&self, ix: usize, s: &str) -> Result<Causal> {
+        let chars = self.chars()?;
+        if ix > chars.len() {
+            return Err(anyhow!("index {} out of bounds", ix));
+        }
+        if !self.can(&self.peer_id, Permission::Write)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        let left = ix.checked_sub(1).map(|i| &chars[i].0);
+        let right = chars.get(ix).map(|(pos, _)| pos);
+        let n = s.chars().count();
+        let mut store = DotStore::new();
+        for (pos, c) in Fraction::run(left, right, n, nonce())?
+            .into_iter()
+            .zip(s.chars())
+        {
+            let mut path = self.path.as_path().to_owned();
+            path.position(&pos);
+            path.prim_str(c.encode_utf8(&mut [0; 4]));
+            self.sign(&mut path);
+            store.insert(path);
+        }
+        let c = Causal {
+            store,
+            expired: Default::default(),
+        };
+        self.augment_array(c)
+    }
+
+    /// Deletes `len` characters of a text starting at index `ix`.
+    pub fn delete_range(&self, ix: usize, len: usize) -> Result<Causal> {
+        let chars = self.chars()?;
+        let end = ix.saturating_add(len);
+        if end > chars.len() {
+            return Err(anyhow!("range {}..{} out of bounds", ix, end));
+        }
+        if !self.can(&self.peer_id, Permission::Write)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        let deleted = &chars[ix..end];
+        let c = Causal {
+            store: DotStore::new(),
+            expired: self.tombstone_values(|value| match value {
+                [Segment::Position(pos), Segment::Str(c)] => deleted
+                    .binary_search_by(|(p, s)| (p, s).cmp(&(pos, c)))
+                    .is_ok(),
+                _ => false,
+            }),
+        };
+        self.augment_array(c)
+    }
+
     /// Removes a value from a map.
     pub fn remove(&self) -> Result<Causal> {
         if !self.can(&self.peer_id, Permission::Write)? {
@@ -468,6 +1429,245 @@ impl<'a> Cursor<'a> {
         self.augment_array(c)
     }
 
+    fn element(&self, kind: PrimitiveKind) -> Result<PathBuf> {
+        if *self.schema != ArchivedSchema::Set(kind) && *self.schema != ArchivedSchema::Dynamic {
+            return Err(anyhow!("not a Set<{:?}>", kind));
+        }
+        Ok(self.path.as_path().to_owned())
+    }
+
+    fn add(&self, mut path: PathBuf) -> Result<Causal> {
+        if !self.can(&self.peer_id, Permission::Write)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        self.nonce(&mut path);
+        self.sign(&mut path);
+        let mut store = DotStore::new();
+        store.insert(path);
+        let c = Causal {
+            store,
+            expired: DotStore::new(),
+        };
+        self.augment_array(c)
+    }
+
+    /// Returns the paths of a set element, scanning only the paths below the element.
+    fn element_paths(&self, elem: Segment) -> impl Iterator<Item = PathBuf> {
+        let mut prefix = self.path.as_path().to_owned();
+        prefix.push_segment(elem);
+        self.crdt.scan_path(prefix.as_path()).filter_map(move |k| {
+            let path = Path::new(&k);
+            let nonce = path.parent()?.parent()?;
+            // the encoding of a longer element can start with the encoding of `elem`.
+            if nonce.last()?.nonce().is_none() || nonce.parent()? != prefix.as_path() {
+                return None;
+            }
+            Some(path.to_owned())
+        })
+    }
+
+    fn remove_element(&self, elem: Segment) -> Result<Causal> {
+        if !self.can(&self.peer_id, Permission::Write)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        let mut expired = DotStore::new();
+        for mut path in self.element_paths(elem) {
+            self.sign(&mut path);
+            expired.insert(path);
+        }
+        let c = Causal {
+            store: DotStore::new(),
+            expired,
+        };
+        self.augment_array(c)
+    }
+
+    fn contains_element(&self, elem: Segment) -> bool {
+        self.element_paths(elem).next().is_some()
+    }
+
+    fn elements(&self, kind: PrimitiveKind) -> Result<impl Iterator<Item = Segment>> {
+        self.element(kind)?;
+        Ok(self
+            .values()
+            .filter_map(move |(_, value)| match value.as_slice() {
+                [prim, Segment::Nonce(_)] if kind.validate(prim.clone()) => Some(prim.clone()),
+                _ => None,
+            }))
+    }
+
+    /// Adds a value to a set.
+    pub fn add_bool(&self, value: bool) -> Result<Causal> {
+        let mut path = self.element(PrimitiveKind::Bool)?;
+        path.prim_bool(value);
+        self.add(path)
+    }
+
+    /// Adds a value to a set.
+    pub fn add_u64(&self, value: u64) -> Result<Causal> {
+        let mut path = self.element(PrimitiveKind::U64)?;
+        path.prim_u64(value);
+        self.add(path)
+    }
+
+    /// Adds a value to a set.
+    pub fn add_i64(&self, value: i64) -> Result<Causal> {
+        let mut path = self.element(PrimitiveKind::I64)?;
+        path.prim_i64(value);
+        self.add(path)
+    }
+
+    /// Adds a value to a set.
+    pub fn add_f64(&self, value: f64) -> Result<Causal> {
+        let mut path = self.element(PrimitiveKind::F64)?;
+        path.prim_f64(value);
+        self.add(path)
+    }
+
+    /// Adds a value of at most [`MAX_BYTES`] bytes to a set.
+    pub fn add_bytes(&self, value: &[u8]) -> Result<Causal> {
+        if value.len() > MAX_BYTES {
+            return Err(anyhow!("bytes exceed {} bytes", MAX_BYTES));
+        }
+        let mut path = self.element(PrimitiveKind::Bytes)?;
+        path.prim_bytes(value);
+        self.add(path)
+    }
+
+    /// Adds a value to a set.
+    pub fn add_str(&self, value: &str) -> Result<Causal> {
+        let mut path = self.element(PrimitiveKind::Str)?;
+        path.prim_str(value);
+        self.add(path)
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_bool(&self, value: bool) -> Result<Causal> {
+        self.element(PrimitiveKind::Bool)?;
+        self.remove_element(Segment::Bool(value))
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_u64(&self, value: u64) -> Result<Causal> {
+        self.element(PrimitiveKind::U64)?;
+        self.remove_element(Segment::U64(value))
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_i64(&self, value: i64) -> Result<Causal> {
+        self.element(PrimitiveKind::I64)?;
+        self.remove_element(Segment::I64(value))
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_f64(&self, value: f64) -> Result<Causal> {
+        self.element(PrimitiveKind::F64)?;
+        self.remove_element(Segment::F64(value))
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_bytes(&self, value: &[u8]) -> Result<Causal> {
+        self.element(PrimitiveKind::Bytes)?;
+        self.remove_element(Segment::Bytes(value.to_vec()))
+    }
+
+    /// Removes a value from a set.
+    pub fn remove_str(&self, value: &str) -> Result<Causal> {
+        self.element(PrimitiveKind::Str)?;
+        self.remove_element(Segment::Str(value.to_owned()))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_bool(&self, value: bool) -> Result<bool> {
+        self.element(PrimitiveKind::Bool)?;
+        Ok(self.contains_element(Segment::Bool(value)))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_u64(&self, value: u64) -> Result<bool> {
+        self.element(PrimitiveKind::U64)?;
+        Ok(self.contains_element(Segment::U64(value)))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_i64(&self, value: i64) -> Result<bool> {
+        self.element(PrimitiveKind::I64)?;
+        Ok(self.contains_element(Segment::I64(value)))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_f64(&self, value: f64) -> Result<bool> {
+        self.element(PrimitiveKind::F64)?;
+        Ok(self.contains_element(Segment::F64(value)))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_bytes(&self, value: &[u8]) -> Result<bool> {
+        self.element(PrimitiveKind::Bytes)?;
+        Ok(self.contains_element(Segment::Bytes(value.to_vec())))
+    }
+
+    /// Returns if a set contains a value.
+    pub fn contains_str(&self, value: &str) -> Result<bool> {
+        self.element(PrimitiveKind::Str)?;
+        Ok(self.contains_element(Segment::Str(value.to_owned())))
+    }
+
+    /// Returns an iterator of set elements.
+    pub fn elements_bool(&self) -> Result<impl Iterator<Item = bool>> {
+        Ok(self
+            .elements(PrimitiveKind::Bool)?
+            .filter_map(|prim| prim.prim_bool())
+            .collect::<BTreeSet<_>>()
+            .into_iter())
+    }
+
+    /// Returns an iterator of set elements.
+    pub fn elements_u64(&self) -> Result<impl Iterator<Item = u64>> {
+        Ok(self
+            .elements(PrimitiveKind::U64)?
+            .filter_map(|prim| prim.prim_u64())
+            .collect::<BTreeSet<_>>()
+            .into_iter())
+    }
+
+    /// Returns an iterator of set elements.
+    pub fn elements_i64(&self) -> Result<impl Iterator<Item = i64>> {
+        Ok(self
+            .elements(PrimitiveKind::I64)?
+            .filter_map(|prim| prim.prim_i64())
+            .collect::<BTreeSet<_>>()
+            .into_iter())
+    }
+
+    /// Returns an iterator of set elements in their total order.
+    pub fn elements_f64(&self) -> Result<impl Iterator<Item = f64>> {
+        Ok(self
+            .elements(PrimitiveKind::F64)?
+            .filter_map(|prim| Some(TotalF64(prim.prim_f64()?)))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|f| f.0))
+    }
+
+    /// Returns an iterator of set elements.
+    pub fn elements_bytes(&self) -> Result<impl Iterator<Item = Vec<u8>>> {
+        Ok(self
+            .elements(PrimitiveKind::Bytes)?
+            .filter_map(|prim| prim.prim_bytes())
+            .collect::<BTreeSet<_>>()
+            .into_iter())
+    }
+
+    /// Returns an iterator of set elements.
+    pub fn elements_str(&self) -> Result<impl Iterator<Item = String>> {
+        Ok(self
+            .elements(PrimitiveKind::Str)?
+            .filter_map(|prim| prim.prim_string())
+            .collect::<BTreeSet<_>>()
+            .into_iter())
+    }
+
     fn say(&self, policy: &Policy) -> Result<Causal> {
         if !match &policy {
             Policy::Can(_, perm) | Policy::CanIf(_, perm, _) => {
@@ -481,7 +1681,7 @@ impl<'a> Cursor<'a> {
         } {
             return Err(anyhow!("unauthorized"));
         }
-        let mut path = self.path.clone();
+        let mut path = self.path.as_path().to_owned();
         path.policy(policy);
         self.sign(&mut path);
         let mut store = DotStore::new();
@@ -494,14 +1694,111 @@ impl<'a> Cursor<'a> {
         self.augment_array(c)
     }
 
-    /// Gives permission to a peer.
+    /// Tags a transaction with a semantic operation like `completeTodo`. The tag is stored in
+    /// a signed path referencing the [`Causal::id`] of the transaction.
+    pub fn tag(&self, causal: &Causal, tag: &str) -> Result<Causal> {
+        let mut path = self.root();
+        if !self
+            .crdt
+            .can(&self.peer_id, Permission::Write, path.as_path())?
+        {
+            return Err(anyhow!("unauthorized"));
+        }
+        path.dot(&causal.id());
+        path.prim_str(tag);
+        self.sign(&mut path);
+        let mut causal = causal.clone();
+        causal.store.insert(path);
+        Ok(causal)
+    }
+
+    fn root(&self) -> PathBuf {
+        let doc = self
+            .path
+            .as_path()
+            .first()
+            .and_then(|doc| doc.doc())
+            .unwrap();
+        let mut path = PathBuf::new();
+        path.doc(&doc);
+        path
+    }
+
+    /// Adds a document to the workspace. Peers syncing the workspace automatically subscribe
+    /// to it's members.
+    pub fn add_member(&self, doc: &DocId, schema: &str) -> Result<Causal> {
+        let mut path = self.root();
+        if !self
+            .crdt
+            .can(&self.peer_id, Permission::Write, path.as_path())?
+        {
+            return Err(anyhow!("unauthorized"));
+        }
+        path.doc(doc);
+        path.prim_str(schema);
+        self.sign(&mut path);
+        let mut store = DotStore::new();
+        store.insert(path);
+        Ok(Causal {
+            store,
+            expired: DotStore::new(),
+        })
+    }
+
+    /// Removes a document from the workspace.
+    pub fn remove_member(&self, doc: &DocId) -> Result<Causal> {
+        let mut path = self.root();
+        if !self
+            .crdt
+            .can(&self.peer_id, Permission::Write, path.as_path())?
+        {
+            return Err(anyhow!("unauthorized"));
+        }
+        path.doc(doc);
+        let mut expired = DotStore::new();
+        for k in self.crdt.scan_path(path.as_path()) {
+            let mut path = Path::new(&k).to_owned();
+            self.sign(&mut path);
+            expired.insert(path);
+        }
+        Ok(Causal {
+            store: DotStore::new(),
+            expired,
+        })
+    }
+
+    /// Gives every actor with a permission on the workspace the same permission on the
+    /// document.
+    pub fn inherit(&self, workspace: &DocId) -> Result<Causal> {
+        let mut root = PathBuf::new();
+        root.doc(workspace);
+        let mut causal = Causal::default();
+        for perm in [
+            Permission::Read,
+            Permission::Write,
+            Permission::Control,
+            Permission::Own,
+        ] {
+            let cond = Can::new(Actor::Unbound, perm, root.clone());
+            causal.join(&self.say_can_if(Actor::Unbound, perm, cond)?);
+        }
+        Ok(causal)
+    }
+
+    /// Gives permission to a peer on the current path and every path below it. Siblings of the
+    /// current path are not affected, so calling this on a field shares only that field.
     pub fn say_can(&self, actor: Option<PeerId>, perm: Permission) -> Result<Causal> {
         self.say(&Policy::Can(actor.into(), perm))
     }
 
+    /// Gives permission to all current and future members of a group.
+    pub fn say_can_group(&self, group: &DocId, perm: Permission) -> Result<Causal> {
+        self.say(&Policy::Can(Actor::Group(*group), perm))
+    }
+
     /// Constructs a new condition.
     pub fn cond(&self, actor: Actor, perm: Permission) -> Can {
-        Can::new(actor, perm, self.path.clone())
+        Can::new(actor, perm, self.path.as_path().to_owned())
     }
 
     /// Gives conditional permission to a peer.
@@ -509,6 +1806,48 @@ impl<'a> Cursor<'a> {
         self.say(&Policy::CanIf(actor, perm, cond))
     }
 
+    /// Gives every peer that can read the current path permission on `field` only.
+    pub fn say_readers_can(&self, field: &str, perm: Permission) -> Result<Causal> {
+        let cond = self.cond(Actor::Unbound, Permission::Read);
+        let mut cursor = self.clone();
+        cursor.field(field)?;
+        cursor.say_can_if(Actor::Unbound, perm, cond)
+    }
+
+    /// Mints a capability token giving a peer permission on the current path until `expiry`
+    /// (seconds since the unix epoch). The token can be verified without the issuer being
+    /// online.
+    pub fn mint_token(&self, peer: Option<PeerId>, perm: Permission, expiry: u64) -> Result<Token> {
+        let required = if perm.controllable() {
+            Permission::Control
+        } else {
+            Permission::Own
+        };
+        if !self.can(&self.peer_id, required)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        let can = Can::new(peer.into(), perm, self.path.as_path().to_owned());
+        Ok(Token::new(&self.key, can, expiry))
+    }
+
+    /// Returns the policy claims made at the path of this [`Cursor`] with the [`Dot`]
+    /// identifying the claim and the issuing peer. The [`Dot`] can be passed to
+    /// [`Cursor::revoke`].
+    pub fn policies(&self) -> impl Iterator<Item = (Dot, PeerId, Policy)> + '_ {
+        self.crdt
+            .scan_path(self.path.as_path())
+            .filter_map(move |k| {
+                let path = Path::new(&k);
+                let (rest, _) = path.split_last()?;
+                let (rest, peer) = rest.split_last()?;
+                let (rest, policy) = rest.split_last()?;
+                if rest != self.path.as_path() {
+                    return None;
+                }
+                Some((path.dot(), peer.peer()?, policy.policy()?))
+            })
+    }
+
     /// Revokes a policy.
     pub fn revoke(&self, claim: Dot) -> Result<Causal> {
         self.say(&Policy::Revokes(claim))
@@ -516,32 +1855,55 @@ impl<'a> Cursor<'a> {
 
     /// Moves the entry inside an array.
     pub fn r#move(&mut self, to: usize) -> Result<Causal> {
-        let array = self.array.pop().context("Not inside an ORArray")?;
+        let array = Arc::make_mut(&mut self.array)
+            .pop()
+            .context("Not inside an ORArray")?;
         array.r#move(self, to)
     }
 
     /// Deletes the entry from an array.
     pub fn delete(&mut self) -> Result<Causal> {
-        let array = self.array.pop().context("Not inside an ORArray")?;
+        let array = Arc::make_mut(&mut self.array)
+            .pop()
+            .context("Not inside an ORArray")?;
         array.delete(self)
     }
 
     /// Augments a causal with array metadata if in an array, otherwise just returns the causal
     /// unchanged.
     fn augment_array(&self, mut inner: Causal) -> Result<Causal> {
-        for a in &self.array {
+        for a in self.array.iter() {
             inner = a.augment_causal(self, inner)?;
         }
         Ok(inner)
     }
 }
 
-fn nonce() -> u64 {
+pub(crate) fn nonce() -> u64 {
     let mut nonce = [0; 8];
     getrandom::getrandom(&mut nonce).unwrap();
     u64::from_le_bytes(nonce)
 }
 
+/// Returns a nonce for a new value of the last-writer-wins register at `reg`. The upper half
+/// is a logical clock ahead of the clocks of all values of the register.
+pub(crate) fn lww_nonce(crdt: &Crdt, reg: Path) -> u64 {
+    let clock = crdt
+        .scan_path(reg)
+        .filter_map(|k| {
+            let path = Path::new(&k);
+            let value = path.parent()?.parent()?.strip_prefix(reg).ok()?;
+            match value.into_iter().collect::<Vec<_>>().as_slice() {
+                [Segment::Nonce(nonce), _] => Some(nonce >> 32),
+                _ => None,
+            }
+        })
+        .max()
+        .map(|clock| (clock + 1).min(u32::MAX as u64))
+        .unwrap_or_default();
+    clock << 32 | nonce() & u32::MAX as u64
+}
+
 #[derive(Clone, Debug)]
 // The ORArray needs to store additional metadata additional to the actual value paths in order to
 // support insert, move, update, and delete semantics.
@@ -613,51 +1975,39 @@ impl ArrayWrapper {
         cursor: &Cursor,
         array_root: PathBuf,
     ) -> impl Iterator<Item = Result<(Fraction, u64)>> {
-        let mut scan_state = BTreeMap::default();
+        let mut scan_state = BTreeSet::default();
+        // elements inserted concurrently can share a position, they are ordered by uid.
         Self::arr_items(cursor, array_root).filter_map(move |val| match val {
-            Ok(data) => match scan_state.insert(data.pos.clone(), data.uid) {
-                Some(existing) if existing != data.uid => unreachable!(),
-                Some(_) => None,
-                None => Some(Ok((data.pos, data.uid))),
-            },
+            Ok(data) => scan_state
+                .insert((data.pos.clone(), data.uid))
+                .then(|| Ok((data.pos, data.uid))),
             Err(e) => Some(Err(e)),
         })
     }
 
-    fn new(cursor: &Cursor, mut ix: usize) -> Result<(Self, PathBuf)> {
-        let array_path = cursor.path.clone();
-
-        let len = Self::distinct_arr_items(cursor, cursor.path.clone()).count();
-        let mut iter = Self::distinct_arr_items(cursor, cursor.path.clone());
+    /// Returns a position to insert an element at index `ix` given the distinct elements of
+    /// the array. The position is salted so that concurrent inserts at the same index don't
+    /// end up at the same position.
+    fn insert_pos(items: &[(Fraction, u64)], ix: usize) -> Result<Fraction> {
+        let ix = ix.min(items.len());
+        let left = ix.checked_sub(1).map(|i| &items[i].0);
+        let right = items.get(ix).map(|(pos, _)| pos);
+        Ok(Fraction::between(left, right)?.extend(nonce()))
+    }
 
-        ix = ix.min(len);
-        let (pos, uid) = if let Some(entry) = iter.nth(ix) {
-            entry?
+    fn new(cursor: &Cursor, items: &[(Fraction, u64)], ix: usize) -> Result<(Self, PathBuf)> {
+        let (pos, uid) = if let Some(entry) = items.get(ix) {
+            entry.clone()
         } else {
-            let p_c = cursor.path.clone();
             // No entry, find position to insert
-            let (left, right) = match ix.checked_sub(1) {
-                Some(s) => {
-                    let mut iter = Self::distinct_arr_items(cursor, p_c)
-                        .skip(s)
-                        .map(|v| v.map(|(p, _)| p));
-                    (iter.next(), iter.next())
-                }
-                None => {
-                    let mut iter = Self::distinct_arr_items(cursor, p_c).map(|v| v.map(|(p, _)| p));
-
-                    (None, iter.next())
-                }
-            };
-
-            let left = left.transpose()?.unwrap_or_else(Fraction::zero);
-            let pos = if let Some(r) = right.transpose()? {
-                left.mid(&r)
-            } else {
-                left.succ()
-            };
-            (pos, nonce())
+            (Self::insert_pos(items, ix)?, nonce())
         };
+        Ok(Self::at(cursor, pos, uid))
+    }
+
+    /// Returns the element with `uid` at `pos` of the array at `cursor`.
+    fn at(cursor: &Cursor, pos: Fraction, uid: u64) -> (Self, PathBuf) {
+        let array_path = cursor.path.as_path().to_owned();
 
         let value_path = {
             let mut p = array_path.clone();
@@ -674,7 +2024,7 @@ impl ArrayWrapper {
             p
         };
 
-        Ok((
+        (
             Self {
                 array_path,
                 pos,
@@ -683,39 +2033,20 @@ impl ArrayWrapper {
                 meta_path,
             },
             value_path,
-        ))
+        )
     }
 
-    pub fn r#move(self, cursor: &Cursor, mut to: usize) -> Result<Causal> {
-        // On a Move, the replica deletes all children of all existing roots, and adds a single
-        // child tree to all roots with the new position.
-
+    pub fn r#move(self, cursor: &Cursor, to: usize) -> Result<Causal> {
         let new_pos = {
-            let len = Self::distinct_arr_items(cursor, self.array_path.clone()).count();
-
-            to = to.min(len);
-            let p_c = self.array_path.clone();
-            let (left, right) = match to.checked_sub(1) {
-                Some(s) => {
-                    let mut iter = Self::distinct_arr_items(cursor, p_c)
-                        .skip(s)
-                        .map(|v| v.map(|(p, _)| p));
-                    (iter.next(), iter.next())
-                }
-                None => {
-                    let mut iter = Self::distinct_arr_items(cursor, p_c).map(|v| v.map(|(p, _)| p));
-
-                    (None, iter.next())
-                }
-            };
-
-            let left = left.transpose()?.unwrap_or_else(Fraction::zero);
-            if let Some(right) = right.transpose()? {
-                left.mid(&right)
-            } else {
-                left.succ()
-            }
+            let items = cursor.array_items(self.array_path.as_path())?;
+            Self::insert_pos(&items, to)?
         };
+        self.move_to(cursor, new_pos)
+    }
+
+    fn move_to(self, cursor: &Cursor, new_pos: Fraction) -> Result<Causal> {
+        // On a Move, the replica deletes all children of all existing roots, and adds a single
+        // child tree to all roots with the new position.
 
         let existing_meta = cursor
             .crdt
@@ -739,27 +2070,28 @@ impl ArrayWrapper {
 
             store.insert(path);
         }
-        // remove old pos
-        let old = cursor
-            .crdt
-            .scan_path(self.value_path.as_path())
-            .next()
-            .context("Concurrent access")?;
-        let mut p = Path::new(&old).to_owned();
-        let mut v = self.get_value(p.as_path())?;
-        cursor.sign(&mut p);
-        expired.insert(p);
-
-        v.pos = new_pos;
-        let mut new_value_path = v.to_path({
-            let mut p = self.array_path;
+        // remove old pos of all values
+        let values_path = {
+            let mut p = self.array_path.clone();
             p.prim_str(array_util::ARRAY_VALUES);
             p
-        });
+        };
+        let mut moved = false;
+        for old in cursor.crdt.scan_path(self.value_path.as_path()) {
+            let mut p = Path::new(&old).to_owned();
+            let mut v = self.get_value(p.as_path())?;
+            cursor.sign(&mut p);
+            expired.insert(p);
+
+            v.pos = new_pos.clone();
+            let mut new_value_path = v.to_path(values_path.clone());
 
-        // overwrite existing peer and sig fields
-        cursor.sign(&mut new_value_path);
-        store.insert(new_value_path);
+            // overwrite existing peer and sig fields
+            cursor.sign(&mut new_value_path);
+            store.insert(new_value_path);
+            moved = true;
+        }
+        anyhow::ensure!(moved, "Concurrent access");
 
         Ok(Causal { store, expired })
     }
@@ -843,8 +2175,8 @@ mod array_util {
     use crate::Segment;
     use anyhow::Context;
 
-    pub(crate) const ARRAY_VALUES: &str = "VALUES";
-    pub(crate) const ARRAY_META: &str = "META";
+    pub(crate) const ARRAY_VALUES: &str = crate::path::RESERVED[0];
+    pub(crate) const ARRAY_META: &str = crate::path::RESERVED[1];
 
     // <path_to_array>.VALUES.<pos>.<uid>.<value>
     #[derive(Debug)]
@@ -878,9 +2210,6 @@ mod array_util {
                 .prim_u64()
                 .context("Unexpected layout")?;
 
-            // nonce
-            path.next();
-
             let mut value = path.collect::<Vec<_>>();
             anyhow::ensure!(
                 matches!(value.pop(), Some(Segment::Sig(_))),
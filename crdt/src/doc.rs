@@ -1,23 +1,40 @@
-use crate::acl::{Acl, Engine, Permission};
-use crate::crdt::{Causal, CausalContext, Crdt};
+use crate::acl::{Acl, Engine, InviteToken, Permission, Token};
+use crate::attachment::Attachments;
+use crate::crdt::{is_policy, member, tag, Causal, CausalContext, Crdt, Denied, DotStore};
 use crate::crypto::Keypair;
-use crate::cursor::Cursor;
+use crate::cursor::{lww_nonce, nonce, Cursor};
+use crate::dotset::Dot;
 use crate::id::{DocId, PeerId};
+use crate::keystore::Keystore;
 use crate::lens::LensesRef;
-use crate::path::Path;
-use crate::radixdb::{BlobMap, BlobSet, Storage};
-use crate::registry::{Expanded, Hash, Registry};
+use crate::metrics::Metrics;
+#[cfg(feature = "metrics")]
+use crate::metrics::MetricsSnapshot;
+use crate::path::{split_sig, Path, PathBuf, Segment};
+use crate::radixdb::{BlobMap, BlobSet, Diff, Durability, Flusher, Storage};
+use crate::registry::{Expanded, Hash, Package, Registry};
+use crate::replay::{ReplayEntry, ReplayKind, ReplayLog};
+use crate::schema::{
+    is_deletion, is_metadata, verify_signatures, DELETED_AT, METADATA, REVOKED_TOKENS,
+};
+use crate::subscriber::Batch;
 use crate::util::Ref;
 use crate::MemStorage;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context as _, Result};
+use bytecheck::CheckBytes;
 use futures::channel::{mpsc, oneshot};
 use futures::prelude::*;
+use parking_lot::Mutex;
 use rkyv::{Archive, Archived, Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryInto;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::Duration;
+use vec_collections::radix_tree::{AbstractRadixTree, ArcRadixTree};
 
 /// Information about the schema of a document.
 #[derive(Debug, Archive, Deserialize, Serialize)]
@@ -41,6 +58,48 @@ impl SchemaInfo {
     }
 }
 
+/// Lifecycle state of a document.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DocState {
+    /// The document accepts local changes.
+    Active,
+    /// The document is kept but doesn't accept local changes until it is unarchived.
+    Archived,
+    /// The document was removed. Remaining handles don't accept local changes.
+    Removed,
+}
+
+impl DocState {
+    fn from_byte(b: u8) -> Result<Self> {
+        Ok(match b {
+            0 => Self::Active,
+            1 => Self::Archived,
+            2 => Self::Removed,
+            _ => return Err(anyhow!("invalid doc state {}", b)),
+        })
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Active => 0,
+            Self::Archived => 1,
+            Self::Removed => 2,
+        }
+    }
+}
+
+/// A document was migrated or extended to a newer schema. Open [`Doc`] handles keep the
+/// lenses they were opened with until they are [refreshed](Doc::refresh).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SchemaUpgraded {
+    /// The upgraded document.
+    pub doc: DocId,
+    /// Previous schema version.
+    pub from: u32,
+    /// New schema version.
+    pub to: u32,
+}
+
 impl ArchivedSchemaInfo {
     pub fn name(&self) -> &str {
         &self.name
@@ -55,12 +114,51 @@ impl ArchivedSchemaInfo {
     }
 }
 
+/// A change of an entry of a document in the docs map.
+#[derive(Clone)]
+struct DocsChange {
+    key: [u8; 33],
+    prev: Option<Arc<[u8]>>,
+    curr: Option<Arc<[u8]>>,
+}
+
+impl DocsChange {
+    fn id(&self) -> DocId {
+        DocId::new(self.key[..32].try_into().unwrap())
+    }
+}
+
 #[derive(Clone)]
-struct Docs(BlobMap);
+struct Docs(
+    BlobMap,
+    Arc<Mutex<Option<Keystore>>>,
+    Arc<Mutex<Vec<mpsc::UnboundedSender<DocsChange>>>>,
+);
 
 impl Docs {
     pub fn new(tree: BlobMap) -> Self {
-        Self(tree)
+        Self(tree, Default::default(), Default::default())
+    }
+
+    /// Writes an entry of a document with `f` and sends the change to the subscribers, so
+    /// that they don't need to compare the whole map.
+    fn update(&self, key: [u8; 33], f: impl FnOnce(&BlobMap) -> Result<()>) -> Result<()> {
+        let prev = self.0.get(key)?;
+        f(&self.0)?;
+        let curr = self.0.get(key)?;
+        if prev != curr {
+            let change = DocsChange { key, prev, curr };
+            self.2
+                .lock()
+                .retain(|tx| tx.unbounded_send(change.clone()).is_ok());
+        }
+        Ok(())
+    }
+
+    fn changes(&self) -> mpsc::UnboundedReceiver<DocsChange> {
+        let (tx, rx) = mpsc::unbounded();
+        self.2.lock().push(tx);
+        rx
     }
 
     pub fn docs(&self) -> impl Iterator<Item = Result<DocId>> + '_ {
@@ -86,13 +184,68 @@ impl Docs {
     pub fn remove(&self, id: &DocId) -> Result<()> {
         let mut key = [0; 33];
         key[..32].copy_from_slice(id.as_ref());
-        key[32] = 0;
+        for kind in [0, 1, 4] {
+            key[32] = kind;
+            self.update(key, |map| map.remove(key))?;
+        }
+        key[32] = 8;
         self.0.remove(key)?;
-        key[32] = 1;
+        key[32] = 9;
         self.0.remove(key)?;
+        self.set_state(id, DocState::Removed)?;
+        for (entry, _) in self.journal_doc(id).collect::<Result<Vec<_>>>()? {
+            self.journal_remove(id, &entry)?;
+        }
         Ok(())
     }
 
+    fn journal_key(id: &DocId, entry: &Dot) -> [u8; 65] {
+        let mut key = [0; 65];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 5;
+        key[33..].copy_from_slice(entry.as_ref());
+        key
+    }
+
+    pub fn journal_append(&self, id: &DocId, entry: &Dot, ctx: &CausalContext) -> Result<()> {
+        self.0.insert_archived(Self::journal_key(id, entry), ctx)
+    }
+
+    pub fn journal_remove(&self, id: &DocId, entry: &Dot) -> Result<()> {
+        self.0.remove(Self::journal_key(id, entry))
+    }
+
+    pub fn journal(&self) -> impl Iterator<Item = Result<(DocId, Dot, CausalContext)>> + '_ {
+        self.0.iter().filter_map(|(k, v)| {
+            if k.len() == 65 && k[32] == 5 {
+                let id = DocId::new(k[..32].try_into().unwrap());
+                let entry = Dot::new(k[33..].try_into().unwrap());
+                Some(
+                    Ref::<CausalContext>::new(v.clone())
+                        .to_owned()
+                        .map(|ctx| (id, entry, ctx)),
+                )
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn journal_doc(
+        &self,
+        id: &DocId,
+    ) -> impl Iterator<Item = Result<(Dot, CausalContext)>> + '_ {
+        let mut prefix = [0; 33];
+        prefix[..32].copy_from_slice(id.as_ref());
+        prefix[32] = 5;
+        self.0.scan_prefix(prefix).map(|(k, v)| {
+            let entry = Dot::new(k[33..].try_into().unwrap());
+            Ref::<CausalContext>::new(v.clone())
+                .to_owned()
+                .map(|ctx| (entry, ctx))
+        })
+    }
+
     pub fn docs_by_schema(&self, schema: String) -> impl Iterator<Item = Result<DocId>> + '_ {
         let docs = self.clone();
         self.docs()
@@ -130,7 +283,22 @@ impl Docs {
         let mut key = [0; 33];
         key[..32].copy_from_slice(id.as_ref());
         key[32] = 0;
-        self.0.insert_archived(key, schema)?;
+        self.update(key, |map| map.insert_archived(key, schema))
+    }
+
+    /// Returns true if the table keys named `METADATA` of the document were migrated.
+    pub fn metadata_migrated(&self, id: &DocId) -> Result<bool> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 9;
+        Ok(self.0.get(key)?.is_some())
+    }
+
+    pub fn set_metadata_migrated(&self, id: &DocId) -> Result<()> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 9;
+        self.0.insert(key, [1])?;
         Ok(())
     }
 
@@ -149,32 +317,189 @@ impl Docs {
         let mut key = [0; 33];
         key[..32].copy_from_slice(id.as_ref());
         key[32] = 1;
-        self.0.insert(key, peer.as_ref())?;
+        self.update(key, |map| map.insert(key, peer.as_ref()))
+    }
+
+    pub fn state(&self, id: &DocId) -> Result<DocState> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 6;
+        if let Some(v) = self.0.get(key)? {
+            return DocState::from_byte(v[0]);
+        }
+        if self.contains(id)? {
+            Ok(DocState::Active)
+        } else {
+            Err(anyhow!("doc {} doesn't exist", id))
+        }
+    }
+
+    pub fn set_state(&self, id: &DocId, state: DocState) -> Result<()> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 6;
+        self.update(key, |map| {
+            if state == DocState::Active {
+                map.remove(key)
+            } else {
+                map.insert(key, [state.to_byte()])
+            }
+        })
+    }
+
+    /// Removes the [`DocState::Removed`] markers.
+    pub fn clear_removed(&self) -> Result<()> {
+        let removed = self
+            .0
+            .iter()
+            .filter(|(k, v)| k.len() == 33 && k[32] == 6 && v[0] == DocState::Removed.to_byte())
+            .map(|(k, _)| k.to_vec())
+            .collect::<Vec<_>>();
+        for key in removed {
+            self.0.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub fn expiry(&self, id: &DocId) -> Result<Option<u64>> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 4;
+        Ok(self
+            .0
+            .get(key)?
+            .map(|v| u64::from_be_bytes(v.as_ref().try_into().unwrap())))
+    }
+
+    pub fn set_expiry(&self, id: &DocId, expiry: u64) -> Result<()> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 4;
+        self.update(key, |map| map.insert(key, expiry.to_be_bytes()))
+    }
+
+    pub fn expiries(&self) -> impl Iterator<Item = (DocId, u64)> + '_ {
+        self.0.iter().filter_map(|(k, v)| {
+            if k[32] == 4 {
+                let id = DocId::new(k[..32].try_into().unwrap());
+                Some((id, u64::from_be_bytes(v.as_ref().try_into().unwrap())))
+            } else {
+                None
+            }
+        })
+    }
+
+    pub fn deletion(&self, id: &DocId) -> Result<Option<u64>> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 8;
+        Ok(self
+            .0
+            .get(key)?
+            .map(|v| u64::from_be_bytes(v.as_ref().try_into().unwrap())))
+    }
+
+    pub fn set_deletion(&self, id: &DocId, deletion: Option<u64>) -> Result<()> {
+        let mut key = [0; 33];
+        key[..32].copy_from_slice(id.as_ref());
+        key[32] = 8;
+        if let Some(deletion) = deletion {
+            self.0.insert(key, deletion.to_be_bytes())?;
+        } else {
+            self.0.remove(key)?;
+        }
+        Ok(())
+    }
+
+    pub fn deletions(&self) -> impl Iterator<Item = (DocId, u64)> + '_ {
+        self.0.iter().filter_map(|(k, v)| {
+            if k[32] == 8 {
+                let id = DocId::new(k[..32].try_into().unwrap());
+                Some((id, u64::from_be_bytes(v.as_ref().try_into().unwrap())))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn keystore_meta(&self) -> Result<Option<Arc<[u8]>>> {
+        let mut key = [0; 33];
+        key[32] = 7;
+        self.0.get(key)
+    }
+
+    /// Returns the keystore if the keypairs are encrypted.
+    fn keystore(&self) -> Result<Option<Keystore>> {
+        if self.keystore_meta()?.is_none() {
+            return Ok(None);
+        }
+        match self.1.lock().clone() {
+            Some(keystore) => Ok(Some(keystore)),
+            None => Err(anyhow!("keystore is locked")),
+        }
+    }
+
+    pub fn is_locked(&self) -> Result<bool> {
+        Ok(self.keystore_meta()?.is_some() && self.1.lock().is_none())
+    }
+
+    pub fn encrypt_keystore(&self, password: &str) -> Result<()> {
+        if self.keystore_meta()?.is_some() {
+            return Err(anyhow!("keystore is already encrypted"));
+        }
+        let (keystore, meta) = Keystore::generate(password)?;
+        let mut entries = vec![];
+        for peer in self.keys() {
+            let keypair = self.keypair(&peer?)?;
+            let key = keypair_key(&keypair.peer_id());
+            let value = keystore.encrypt(keypair.as_ref(), &key);
+            entries.push((key, value));
+        }
+        let mut key = [0; 33];
+        key[32] = 7;
+        entries.push((key, meta));
+        // the keypairs and the metadata are written at once, and the file is rewritten so that
+        // it doesn't contain the plaintext keypairs anymore.
+        self.0.insert_many(entries)?;
+        self.0.vacuum()?;
+        *self.1.lock() = Some(keystore);
+        Ok(())
+    }
+
+    pub fn unlock(&self, password: &str) -> Result<()> {
+        let meta = self.keystore_meta()?.context("keystore is not encrypted")?;
+        *self.1.lock() = Some(Keystore::open(password, &meta)?);
         Ok(())
     }
 
+    pub fn lock(&self) {
+        self.1.lock().take();
+    }
+
     pub fn add_keypair(&self, keypair: Keypair) -> Result<PeerId> {
         let peer = keypair.peer_id();
-        let mut key = [0; 33];
-        key[..32].copy_from_slice(peer.as_ref());
-        key[32] = 2;
-        self.0.insert(key, keypair.as_ref())?;
+        let key = keypair_key(&peer);
+        match self.keystore()? {
+            Some(keystore) => self
+                .0
+                .insert(key, keystore.encrypt(keypair.as_ref(), &key))?,
+            None => self.0.insert(key, keypair.as_ref())?,
+        }
         Ok(peer)
     }
 
     pub fn keypair(&self, peer: &PeerId) -> Result<Keypair> {
-        let mut key = [0; 33];
-        key[..32].copy_from_slice(peer.as_ref());
-        key[32] = 2;
-        let keypair = self.0.get(key)?.unwrap();
-        Ok(Keypair::new(keypair.as_ref().try_into().unwrap()))
+        let key = keypair_key(peer);
+        let keypair = self.0.get(key)?.context("no keypair for peer")?;
+        let secret = match self.keystore()? {
+            Some(keystore) => keystore.decrypt(&keypair, &key)?,
+            None => keypair.to_vec(),
+        };
+        Ok(Keypair::new(secret.as_slice().try_into()?))
     }
 
     pub fn remove_keypair(&self, peer: &PeerId) -> Result<()> {
-        let mut key = [0; 33];
-        key[..32].copy_from_slice(peer.as_ref());
-        key[32] = 2;
-        self.0.remove(key)?;
+        self.0.remove(keypair_key(peer))?;
         Ok(())
     }
 
@@ -202,6 +527,63 @@ impl Docs {
     pub fn subscribe(&self) -> impl Stream<Item = ()> {
         self.0.watch_prefix(&[]).map(|_| ())
     }
+
+    /// Yields when an entry starting with `prefix` whose key matches `filter` changes.
+    fn watch(&self, prefix: &[u8], filter: fn(&[u8]) -> bool) -> impl Stream<Item = ()> {
+        let prefix = prefix.to_vec();
+        self.changes().filter_map(move |change| {
+            let changed = change.key.starts_with(&prefix) && filter(&change.key);
+            future::ready(if changed { Some(()) } else { None })
+        })
+    }
+
+    pub fn subscribe_docs(&self) -> impl Stream<Item = ()> {
+        self.watch(&[], |k| k[32] == 1)
+    }
+
+    pub fn subscribe_schemas(&self) -> impl Stream<Item = ()> {
+        self.watch(&[], |k| k[32] == 0)
+    }
+
+    pub fn subscribe_doc(&self, id: &DocId) -> impl Stream<Item = ()> {
+        self.watch(id.as_ref(), |k| matches!(k[32], 0 | 1 | 4 | 6))
+    }
+
+    pub fn subscribe_lifecycle(&self) -> impl Stream<Item = (DocId, DocState)> {
+        self.changes().filter_map(|change| {
+            let state = match (change.key[32], &change.prev, &change.curr) {
+                // a doc is active when it's peer id is set until a state is set.
+                (1, None, Some(_)) => Some(DocState::Active),
+                (6, _, Some(v)) => DocState::from_byte(v[0]).ok(),
+                // the states of removed docs are only cleared on startup.
+                (6, Some(v), None) if v[0] != DocState::Removed.to_byte() => Some(DocState::Active),
+                _ => None,
+            };
+            future::ready(state.map(|state| (change.id(), state)))
+        })
+    }
+
+    pub fn subscribe_upgrades(&self) -> impl Stream<Item = SchemaUpgraded> {
+        self.changes().filter_map(|change| {
+            let upgrade = match (change.key[32], change.prev.clone(), change.curr.clone()) {
+                (0, Some(prev), Some(curr)) => {
+                    let prev = Ref::<SchemaInfo>::new(prev);
+                    let curr = Ref::<SchemaInfo>::new(curr);
+                    if prev.as_ref().hash() == curr.as_ref().hash() {
+                        None
+                    } else {
+                        Some(SchemaUpgraded {
+                            doc: change.id(),
+                            from: prev.as_ref().version(),
+                            to: curr.as_ref().version(),
+                        })
+                    }
+                }
+                _ => None,
+            };
+            future::ready(upgrade)
+        })
+    }
 }
 
 struct DebugDoc<'a>(&'a Docs, DocId);
@@ -250,44 +632,263 @@ impl std::fmt::Debug for Docs {
     }
 }
 
+/// Maximum size of the buffered changes of a single peer.
+const MAX_PENDING_PEER: usize = 4 * 1024 * 1024;
+/// Maximum size of all buffered changes.
+const MAX_PENDING: usize = 32 * 1024 * 1024;
+
+/// Error returned when a remote change is rejected because the sending peer is faulty or
+/// malicious.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidChange {
+    /// The change contains paths of other documents.
+    ForeignPaths,
+    /// A path isn't signed by the peer it names.
+    InvalidSignature,
+    /// A path doesn't match the schema the change was made with.
+    InvalidSchema,
+}
+
+impl std::fmt::Display for InvalidChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ForeignPaths => "change contains paths of other docs",
+            Self::InvalidSignature => "change failed signature verification",
+            Self::InvalidSchema => "crdt failed schema validation",
+        })
+    }
+}
+
+impl std::error::Error for InvalidChange {}
+
+/// Statistics about remote changes waiting for the lenses of their schema.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PendingStats {
+    /// Number of buffered changes.
+    pub entries: usize,
+    /// Size of the buffered changes in bytes.
+    pub bytes: usize,
+    /// Number of changes rejected because the buffer was full since startup.
+    pub rejected: u64,
+    /// Number of buffered changes evicted since startup.
+    pub evicted: u64,
+}
+
+/// Remote changes waiting for the lenses of their schema.
+#[derive(Clone)]
+struct Pending {
+    tree: BlobMap,
+    rejected: Arc<AtomicU64>,
+    evicted: Arc<AtomicU64>,
+}
+
+impl Pending {
+    pub fn new(tree: BlobMap) -> Self {
+        Self {
+            tree,
+            rejected: Default::default(),
+            evicted: Default::default(),
+        }
+    }
+
+    pub fn insert(&self, schema: &Hash, doc: &DocId, peer: &PeerId, causal: &Causal) -> Result<()> {
+        let causal = Ref::archive(causal);
+        let (mut total, mut by_peer) = (causal.as_bytes().len(), causal.as_bytes().len());
+        for (k, v) in self.tree.iter() {
+            total += v.len();
+            if &k[64..96] == peer.as_ref() {
+                by_peer += v.len();
+            }
+        }
+        if total > MAX_PENDING || by_peer > MAX_PENDING_PEER {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return Err(anyhow!(
+                "pending buffer full, dropping change from {}",
+                peer
+            ));
+        }
+        let mut key = [0; 128];
+        key[..32].copy_from_slice(schema.as_bytes());
+        key[32..64].copy_from_slice(doc.as_ref());
+        key[64..96].copy_from_slice(peer.as_ref());
+        key[96..].copy_from_slice(blake3::hash(causal.as_bytes()).as_bytes());
+        self.tree.insert(key, causal.as_bytes())
+    }
+
+    pub fn remove(&self, key: &[u8]) -> Result<()> {
+        self.tree.remove(key)
+    }
+
+    /// Removes all changes waiting for `schema` and returns their number.
+    pub fn evict(&self, schema: &Hash) -> Result<usize> {
+        let keys = self
+            .tree
+            .scan_prefix(schema.as_bytes())
+            .map(|(k, _)| k.to_vec())
+            .collect::<Vec<_>>();
+        for key in &keys {
+            self.tree.remove(key)?;
+        }
+        self.evicted.fetch_add(keys.len() as u64, Ordering::Relaxed);
+        Ok(keys.len())
+    }
+
+    pub fn stats(&self) -> PendingStats {
+        let mut stats = PendingStats {
+            rejected: self.rejected.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for (_, v) in self.tree.iter() {
+            stats.entries += 1;
+            stats.bytes += v.len();
+        }
+        stats
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, Hash, DocId, PeerId, Ref<Causal>)> + '_ {
+        self.tree.iter().map(|(k, v)| {
+            let schema: [u8; 32] = k[..32].try_into().unwrap();
+            let doc = DocId::new(k[32..64].try_into().unwrap());
+            let peer = PeerId::new(k[64..96].try_into().unwrap());
+            (k.to_vec(), schema.into(), doc, peer, Ref::new(v.clone()))
+        })
+    }
+}
+
+/// Returns the key of the keypair of `peer` in [`Docs`].
+fn keypair_key(peer: &PeerId) -> [u8; 33] {
+    let mut key = [0; 33];
+    key[..32].copy_from_slice(peer.as_ref());
+    key[32] = 2;
+    key
+}
+
+/// Full state of a document exported with [`Backend::export_snapshot`].
+#[derive(Archive, Deserialize, Serialize)]
+#[archive_attr(derive(CheckBytes))]
+#[repr(C)]
+struct DocSnapshot {
+    doc: DocId,
+    schema: String,
+    hash: [u8; 32],
+    causal: Causal,
+}
+
+/// Records an entry in the replay log if it is enabled.
+fn record_replay(
+    replay: &Mutex<Option<ReplayLog>>,
+    entry: impl FnOnce() -> Result<ReplayEntry>,
+) -> Result<()> {
+    if let Some(log) = replay.lock().as_mut().filter(|log| log.enabled) {
+        log.record(&entry()?)?;
+    }
+    Ok(())
+}
+
+/// Updates the time at which a document is deleted if `causal` deletes it everywhere or
+/// cancels the deletion.
+fn update_deletion(crdt: &Crdt, docs: &Docs, doc: &DocId, causal: &Causal) -> Result<()> {
+    if !causal
+        .store
+        .iter()
+        .chain(causal.expired.iter())
+        .any(|buf| is_deletion(buf.as_path()))
+    {
+        return Ok(());
+    }
+    // reading doesn't need a signing key.
+    let deleted_at = Cursor::metadata(Keypair::new([0; 32]), *doc, crdt)
+        .field(DELETED_AT)?
+        .u64s()?
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .min();
+    docs.set_deletion(doc, deleted_at)
+}
+
 /// The crdt [`Backend`] is the main entry point to interact with this crate.
 pub struct Backend {
     registry: Registry,
+    /// Packages added at runtime by the hash of their lenses.
+    packages: BlobMap,
     crdt: Crdt,
     docs: Docs,
+    pending: Pending,
     engine: Engine,
+    flusher: Flusher,
+    /// Documents migrated when the backend was created.
+    migrated: Arc<[SchemaUpgraded]>,
+    history: Arc<Mutex<BTreeMap<DocId, History>>>,
+    attachments: Attachments,
+    replay: Arc<Mutex<Option<ReplayLog>>>,
     tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
     rx: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
 }
 
 impl Backend {
     /// Creates a new [`Backend`] from a radixdb storage.
+    ///
+    /// Fails with a [`RegistryError`](crate::RegistryError) if the package rewrites the lenses of
+    /// a previous version or of a stored document.
     pub fn new(storage: Arc<dyn Storage>, package: &[u8]) -> Result<Self> {
         let registry = Registry::new(package)?;
-        let docs = Docs::new(BlobMap::load(storage.clone(), "docs")?);
-        let acl = Acl::new(BlobMap::load(storage.clone(), "acl")?);
+        let flusher = Flusher::new(storage);
+        let packages = BlobMap::load(flusher.clone(), "packages")?;
+        Self::load_packages(&registry, &packages)?;
+        let docs = Docs::new(BlobMap::load(flusher.clone(), "docs")?);
+        let pending = Pending::new(BlobMap::load(flusher.clone(), "pending")?);
+        let acl = Acl::new(BlobMap::load(flusher.clone(), "acl")?);
+        let policy = BlobSet::load(flusher.clone(), "policy")?;
         let crdt = Crdt::new(
-            BlobSet::load(storage.clone(), "store")?,
-            BlobSet::load(storage, "expired")?,
+            BlobSet::load(flusher.clone(), "store")?,
+            BlobSet::load(flusher.clone(), "expired")?,
+            policy.clone(),
             acl.clone(),
-        );
-        let engine = Engine::new(acl)?;
+        )?;
+        let engine = Engine::new(acl, policy)?;
+        let attachments = Attachments::load(flusher.clone())?;
         let (tx, rx) = mpsc::unbounded();
         let mut me = Self {
             registry,
+            packages,
             crdt,
             docs,
+            pending,
             engine,
+            flusher,
+            migrated: Arc::new([]),
+            history: Default::default(),
+            attachments,
+            replay: Default::default(),
             tx,
             rx,
         };
-        me.update_acl()?;
+        // handles of removed docs don't survive a restart, so their state isn't needed anymore.
+        me.docs.clear_removed()?;
+        if me.engine.is_complete() {
+            // the derived acl is persisted, only policy changes made since need to be fed to
+            // the engine.
+            me.update_acl()?;
+        } else {
+            // policy changes were written to the store without the engine persisting them.
+            me.crdt.take_policy_changes();
+            let store = me.crdt.snapshot(Path::new(&[]));
+            me.engine
+                .rebuild(store.iter().map(|(key, _)| key.to_vec()))?;
+        }
 
         // migrate docs
+        let mut migrated = vec![];
         for res in me.docs.docs() {
             let id = res?;
             let info = me.docs.schema(&id)?;
             let (version, hash) = me.registry.lookup(&info.as_ref().name).unwrap();
+            me.registry.verify_doc(
+                &info.as_ref().name,
+                info.as_ref().version,
+                &info.as_ref().hash.into(),
+            )?;
             if version > info.as_ref().version {
                 tracing::info!(
                     "migrating document {} from {} to {}",
@@ -300,13 +901,111 @@ impl Backend {
                 let curr_lenses = LensesRef::new(&lenses.lenses().lenses()[..end]);
                 me.crdt
                     .transform(&id, curr_lenses, lenses.lenses().to_ref())?;
+                migrated.push(SchemaUpgraded {
+                    doc: id,
+                    from: info.as_ref().version,
+                    to: version,
+                });
                 let info = SchemaInfo::new(info.as_ref().name.to_string(), version, hash);
                 me.docs.set_schema(&id, &info)?;
             }
         }
+        me.migrated = migrated.into();
+
+        for res in me.docs.docs() {
+            let id = res?;
+            if me.docs.metadata_migrated(&id)? {
+                continue;
+            }
+            let mut path = PathBuf::new();
+            path.doc(&id);
+            let paths = me
+                .crdt
+                .scan_path(path.as_path())
+                .map(|key| Path::new(&key).to_owned())
+                .collect::<Vec<_>>();
+            if me.migrate_metadata_keys(&id, paths)? {
+                me.docs.set_metadata_migrated(&id)?;
+            }
+        }
         Ok(me)
     }
 
+    /// Moves the table keys named `METADATA`, written before the name was reserved for the
+    /// document metadata, to their escaped key so that they aren't hidden by the metadata.
+    /// The moved paths are signed by the local peer, paths it can't write are left as is.
+    /// Paths at the document root that are valid metadata can't be told apart and are kept.
+    ///
+    /// Returns false if the keypair of the local peer is locked.
+    fn migrate_metadata_keys(
+        &self,
+        doc: &DocId,
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<bool> {
+        let mut key = None;
+        let mut causal = Causal::default();
+        for buf in paths {
+            let mut path = buf.as_path();
+            if split_sig(&mut path).is_none() {
+                continue;
+            }
+            let rel = match path.child() {
+                Some(rel) => rel,
+                None => continue,
+            };
+            let mut migrated = PathBuf::new();
+            migrated.doc(doc);
+            let mut escaped = false;
+            for (i, seg) in rel.into_iter().enumerate() {
+                match seg {
+                    Segment::Str(s) if s == METADATA && (i > 0 || !is_metadata(rel)) => {
+                        migrated.prim_key(&s);
+                        escaped = true;
+                    }
+                    seg => migrated.push_segment(seg),
+                }
+            }
+            if !escaped {
+                continue;
+            }
+            let schema = self.docs.schema(doc)?;
+            let lenses = self
+                .registry
+                .get(&schema.as_ref().hash.into())
+                .ok_or_else(|| anyhow!("missing lenses for {}", doc))?;
+            if !lenses
+                .schema()
+                .contains(migrated.as_path().child().unwrap())
+            {
+                continue;
+            }
+            let (peer, key) = match key {
+                Some(key) => key,
+                None => {
+                    let peer = self.docs.peer_id(doc)?;
+                    match self.docs.keypair(&peer) {
+                        Ok(keypair) => *key.insert((peer, keypair)),
+                        Err(_) => return Ok(false),
+                    }
+                }
+            };
+            tracing::info!("migrating {} to {}", path, migrated.as_path());
+            let sig = key.sign(migrated.as_ref());
+            migrated.peer(&peer);
+            migrated.sig(sig);
+            causal.store.insert(migrated);
+            let mut tomb = buf.clone();
+            let sig = key.sign(tomb.as_ref());
+            tomb.peer(&peer);
+            tomb.sig(sig);
+            causal.expired.insert(tomb);
+        }
+        if let Some((peer, _)) = key {
+            self.crdt.join(&peer, &causal)?;
+        }
+        Ok(true)
+    }
+
     /// Creates a new in memory [`Backend`].
     pub fn memory(package: &[u8]) -> Result<Self> {
         Self::new(Arc::new(MemStorage::default()), package)
@@ -338,12 +1037,52 @@ impl Backend {
         &self.registry
     }
 
+    /// Registers a package at runtime like [`Registry::add_package`] and persists it, so that
+    /// documents created with it can be loaded after a restart.
+    pub fn add_package(&self, package: &[u8]) -> Result<Hash> {
+        let hash = self.registry.add_package(package)?;
+        self.packages.insert(hash.as_bytes(), package)?;
+        Ok(hash)
+    }
+
+    fn load_packages(registry: &Registry, packages: &BlobMap) -> Result<()> {
+        let mut added = vec![];
+        for (_, bytes) in packages.iter() {
+            let package = Ref::<Package>::checked(bytes)?;
+            let package = package.as_ref();
+            added.push((package.name().to_string(), package.version(), bytes.clone()));
+        }
+        // each version is added after the version it appends to.
+        added.sort();
+        for (name, version, bytes) in added {
+            // the app may ship the package itself after an update.
+            if registry.lookup_version(&name, version).is_some() {
+                continue;
+            }
+            if let Err(err) = registry.add_package(&bytes) {
+                tracing::warn!("skipping package {} version {}: {}", name, version, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Feeds the policies inserted or removed since the last update to the acl engine. The
+    /// engine only runs when the policy changed.
     fn update_acl(&mut self) -> Result<()> {
-        for key in self.crdt.iter() {
-            let path = Path::new(&key[..]);
-            self.engine.add_policy(path);
+        let mut changed = false;
+        for (insert, path) in self.crdt.take_policy_changes() {
+            if insert {
+                changed |= self.engine.add_policy(path.as_path());
+            } else {
+                changed |= self.engine.remove_policy(path.as_path())?;
+            }
         }
-        self.engine.update_acl()
+        if changed {
+            self.engine.update_acl()?;
+            self.flusher.metrics().record_acl_update();
+        }
+        self.engine.set_complete()?;
+        Ok(())
     }
 
     /// Checks if the store contains a document.
@@ -356,92 +1095,433 @@ impl Backend {
         self.engine.active_peer(peer)
     }
 
-    /// Applies a remote change received from a peer.
+    /// Applies a remote change received from a peer. Returns the parts of the change that were
+    /// dropped because the peer lacked permission.
     pub fn join(
         &mut self,
         peer_id: &PeerId,
         doc: &DocId,
         causal_schema: &Hash,
         mut causal: Causal,
-    ) -> Result<()> {
+    ) -> Result<Vec<Denied>> {
         if causal.is_empty() {
-            return Ok(());
+            return Ok(vec![]);
+        }
+        let foreign = causal
+            .store
+            .iter()
+            .chain(causal.expired.iter())
+            .any(|buf| buf.as_path().first().and_then(|seg| seg.doc()) != Some(*doc));
+        if foreign {
+            return Err(InvalidChange::ForeignPaths.into());
         }
         let doc_schema = self.docs.schema(doc)?;
+        record_replay(&self.replay, || {
+            Ok(ReplayEntry {
+                kind: ReplayKind::Remote,
+                peer: *peer_id,
+                doc: *doc,
+                doc_schema: doc_schema.as_ref().name.to_string(),
+                schema: *causal_schema.as_bytes(),
+                causal: causal.clone(),
+            })
+        })?;
         let doc_lenses = self.registry.get(&doc_schema.as_ref().hash.into()).unwrap();
         let lenses = self
             .registry
             .get(causal_schema)
             .ok_or_else(|| anyhow!("missing lenses with hash {}", causal_schema))?;
         if !lenses.schema().validate(&causal) {
-            return Err(anyhow!("crdt failed schema validation"));
+            if !verify_signatures(doc, &causal) {
+                return Err(InvalidChange::InvalidSignature.into());
+            }
+            return Err(InvalidChange::InvalidSchema.into());
         }
         causal.transform(lenses.lenses().to_ref(), doc_lenses.lenses().to_ref());
+        causal.remove_local(doc_lenses.schema());
         self.crdt.join_policy(&causal)?;
         self.update_acl()?;
-        self.crdt.join(peer_id, &causal)?;
-        Ok(())
+        let denied = self.crdt.join(peer_id, &causal)?;
+        update_deletion(&self.crdt, &self.docs, doc, &causal)?;
+        // older peers still write unescaped `METADATA` keys.
+        self.migrate_metadata_keys(doc, causal.store.iter())?;
+        self.flusher.metrics().record_join();
+        Ok(denied)
     }
 
-    /// Returns the changes required to bring a peer up to speed.
-    pub fn unjoin(
-        &self,
-        peer_id: &PeerId,
-        doc: &DocId,
-        ctx: &Archived<CausalContext>,
-    ) -> Result<Causal> {
-        self.crdt.unjoin(peer_id, doc, ctx)
+    /// Serializes the state of a document including it's policy, for backups or seeding a new
+    /// device with [`Backend::import_snapshot`].
+    pub fn export_snapshot(&self, doc: &DocId) -> Result<Vec<u8>> {
+        let schema = self.docs.schema(doc)?;
+        let snapshot = DocSnapshot {
+            doc: *doc,
+            schema: schema.as_ref().name.to_string(),
+            hash: schema.as_ref().hash,
+            causal: self.crdt.state(doc),
+        };
+        Ok(Ref::archive(&snapshot).as_bytes().to_vec())
     }
 
-    /// Returns a clonable [`Frontend`].
-    pub fn frontend(&self) -> Frontend {
-        Frontend::new(
-            self.crdt.clone(),
-            self.docs.clone(),
-            self.registry.clone(),
-            self.tx.clone(),
-        )
+    /// Restores a document from a snapshot. Unknown documents are added with the default
+    /// [`Keypair`], known documents are merged with the snapshot. The snapshot is validated
+    /// like a remote change, so the lenses of the snapshot need to be registered. Every path
+    /// is joined as the peer that signed it, so paths whose signer lacks permission are
+    /// dropped.
+    pub fn import_snapshot(&mut self, bytes: &[u8]) -> Result<DocId> {
+        let snapshot = Ref::<DocSnapshot>::checked(bytes)?.to_owned()?;
+        let doc = snapshot.doc;
+        self.join_state(doc, snapshot.schema, snapshot.hash.into(), snapshot.causal)?;
+        Ok(doc)
     }
-}
 
-impl Future for Backend {
-    type Output = Result<()>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        if let Poll::Ready(Some(tx)) = Pin::new(&mut self.rx).poll_next(cx) {
-            let res = self.update_acl();
+    /// Joins the state of a document like [`Backend::import_snapshot`].
+    pub(crate) fn join_state(
+        &mut self,
+        doc: DocId,
+        schema: String,
+        hash: Hash,
+        causal: Causal,
+    ) -> Result<()> {
+        let lenses = self
+            .registry
+            .get(&hash)
+            .ok_or_else(|| anyhow!("missing lenses with hash {}", hash))?;
+        if !verify_signatures(&doc, &causal) {
+            return Err(InvalidChange::InvalidSignature.into());
+        }
+        let mut policy = Causal::default();
+        let mut signers = BTreeMap::<PeerId, Causal>::new();
+        for buf in causal.store.iter() {
+            let mut path = buf.as_path();
+            let (peer, _) = split_sig(&mut path).context("unsigned path")?;
+            if is_policy(buf.as_path()) {
+                policy.store.insert(buf.clone());
+            }
+            signers.entry(peer).or_default().store.insert(buf);
+        }
+        for buf in causal.expired.iter() {
+            let mut path = buf.as_path();
+            let (peer, _) = split_sig(&mut path).context("unsigned path")?;
+            signers.entry(peer).or_default().expired.insert(buf);
+        }
+        if !self.docs.contains(&doc)? {
+            let version = lenses.lenses().lenses().len() as u32;
+            let info = SchemaInfo::new(schema, version, hash);
+            self.docs.set_schema(&doc, &info)?;
+            self.docs
+                .set_peer_id(&doc, &self.docs.default_keypair()?.peer_id())?;
+            self.docs.set_state(&doc, DocState::Active)?;
+        }
+        // the policy is known before any path is checked against it.
+        self.crdt.join_policy(&policy)?;
+        self.update_acl()?;
+        for (peer, causal) in signers {
+            self.join(&peer, &doc, &hash, causal)?;
+        }
+        Ok(())
+    }
+
+    /// Buffers a remote change until the lenses with hash `causal_schema` are registered.
+    /// Buffered changes are persisted so they survive restarts.
+    ///
+    /// Only signed changes to known documents are buffered, and the size of the buffer is
+    /// bounded per peer and in total.
+    pub fn buffer(
+        &self,
+        peer_id: &PeerId,
+        doc: &DocId,
+        causal_schema: &Hash,
+        causal: &Causal,
+    ) -> Result<()> {
+        if !self.docs.contains(doc)? {
+            return Err(anyhow!("not buffering change to unknown doc {}", doc));
+        }
+        if !verify_signatures(doc, causal) {
+            return Err(InvalidChange::InvalidSignature.into());
+        }
+        self.pending.insert(causal_schema, doc, peer_id, causal)
+    }
+
+    /// Drops the buffered changes waiting for the lenses with hash `schema`, for example
+    /// because no peer provided them. Returns the number of dropped changes.
+    pub fn evict_pending(&self, schema: &Hash) -> Result<usize> {
+        self.pending.evict(schema)
+    }
+
+    /// Returns statistics about the buffered changes.
+    pub fn pending_stats(&self) -> PendingStats {
+        self.pending.stats()
+    }
+
+    /// Returns the lenses missing to apply buffered changes and the peers to request them from.
+    pub fn pending(&self) -> Vec<(Hash, PeerId)> {
+        let mut pending = BTreeSet::new();
+        for (_, schema, _, peer, _) in self.pending.iter() {
+            if !self.registry.contains(&schema) {
+                pending.insert((*schema.as_bytes(), peer));
+            }
+        }
+        pending
+            .into_iter()
+            .map(|(schema, peer)| (schema.into(), peer))
+            .collect()
+    }
+
+    /// Applies the buffered changes whose lenses are registered. Returns the parts of the
+    /// changes that were dropped because the peer lacked permission.
+    pub fn join_pending(&mut self) -> Result<Vec<Denied>> {
+        let ready = self
+            .pending
+            .iter()
+            .filter(|(_, schema, _, _, _)| self.registry.contains(schema))
+            .collect::<Vec<_>>();
+        let mut denied = vec![];
+        for (key, schema, doc, peer, causal) in ready {
+            self.pending.remove(&key)?;
+            let res = causal
+                .to_owned()
+                .and_then(|causal| self.join(&peer, &doc, &schema, causal));
+            match res {
+                Ok(d) => denied.extend(d),
+                Err(err) => tracing::error!("{}", err),
+            }
+        }
+        Ok(denied)
+    }
+
+    /// Returns the changes required to bring a peer up to speed.
+    pub fn unjoin(
+        &self,
+        peer_id: &PeerId,
+        doc: &DocId,
+        ctx: &Archived<CausalContext>,
+    ) -> Result<Causal> {
+        let mut causal = self.crdt.unjoin(peer_id, doc, ctx)?;
+        self.remove_local(doc, &mut causal)?;
+        self.flusher.metrics().record_unjoin();
+        Ok(causal)
+    }
+
+    /// Returns a page of the changes required to bring a peer up to speed, see
+    /// [`Crdt::unjoin_page`].
+    pub fn unjoin_page(
+        &self,
+        peer_id: &PeerId,
+        doc: &DocId,
+        ctx: &Archived<CausalContext>,
+        after: Option<&[u8]>,
+        max_bytes: usize,
+    ) -> Result<(Causal, Option<Vec<u8>>)> {
+        let (mut causal, next) = self.crdt.unjoin_page(peer_id, doc, ctx, after, max_bytes)?;
+        self.remove_local(doc, &mut causal)?;
+        if after.is_none() {
+            self.flusher.metrics().record_unjoin();
+        }
+        Ok((causal, next))
+    }
+
+    /// Removes the paths of a document which are excluded from replication by a `local`
+    /// schema annotation.
+    pub fn remove_local(&self, doc: &DocId, causal: &mut Causal) -> Result<()> {
+        let schema = self.docs.schema(doc)?;
+        let lenses = self
+            .registry
+            .get(&schema.as_ref().hash.into())
+            .ok_or_else(|| anyhow!("missing lenses for {}", doc))?;
+        causal.remove_local(lenses.schema());
+        Ok(())
+    }
+
+    /// Sets the [`Durability`] mode.
+    pub fn set_durability(&self, durability: Durability) {
+        self.flusher.set_durability(durability);
+    }
+
+    /// Writes all pending changes to storage.
+    pub fn sync(&self) -> impl Future<Output = Result<()>> {
+        self.flusher.sync()
+    }
+
+    /// Enables or disables the replay log, see [`Frontend::set_replay_log`].
+    pub fn set_replay_log(&mut self, enabled: bool) -> Result<()> {
+        self.frontend().set_replay_log(enabled)
+    }
+
+    /// Returns the changes recorded by the replay log in the order they were joined.
+    pub fn replay_log(&self) -> Result<Vec<ReplayEntry>> {
+        self.frontend().replay_log()
+    }
+
+    /// Removes the changes recorded by the replay log.
+    pub fn clear_replay_log(&mut self) -> Result<()> {
+        self.frontend().clear_replay_log()
+    }
+
+    /// Returns the [`Metrics`] of the backend, for recording operations of the networking
+    /// layer.
+    pub fn metrics(&self) -> &Metrics {
+        self.flusher.metrics()
+    }
+
+    /// Returns the current [`Metrics`] including the length of the storage logs.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> MetricsSnapshot {
+        self.flusher.metrics().snapshot(self.flusher.size())
+    }
+
+    /// Returns a clonable [`Frontend`].
+    pub fn frontend(&self) -> Frontend {
+        Frontend::new(
+            self.crdt.clone(),
+            self.docs.clone(),
+            self.registry.clone(),
+            self.flusher.clone(),
+            self.migrated.clone(),
+            self.history.clone(),
+            self.attachments.clone(),
+            self.replay.clone(),
+            self.tx.clone(),
+        )
+    }
+}
+
+impl Future for Backend {
+    type Output = Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // batch all pending applies into a single acl update.
+        let mut txs = vec![];
+        while let Poll::Ready(Some(tx)) = Pin::new(&mut self.rx).poll_next(cx) {
+            txs.push(tx);
+        }
+        if txs.is_empty() {
+            return Poll::Pending;
+        }
+        let res = self.update_acl();
+        for tx in txs {
             tx.send(()).ok();
-            Poll::Ready(res)
-        } else {
-            Poll::Pending
         }
+        Poll::Ready(res)
     }
 }
 
 /// Clonable [`Frontend`].
+///
+/// [`Frontend`], [`Doc`] and [`Cursor`] are `Send + Sync` and can be shared between threads.
+/// Stores are guarded by a mutex each and transactions are joined one at a time.
 #[derive(Clone)]
 pub struct Frontend {
     crdt: Crdt,
     docs: Docs,
     registry: Registry,
+    flusher: Flusher,
+    migrated: Arc<[SchemaUpgraded]>,
+    history: Arc<Mutex<BTreeMap<DocId, History>>>,
+    attachments: Attachments,
+    replay: Arc<Mutex<Option<ReplayLog>>>,
     tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
 }
 
 impl Frontend {
+    #[allow(clippy::too_many_arguments)]
     fn new(
         crdt: Crdt,
         docs: Docs,
         registry: Registry,
+        flusher: Flusher,
+        migrated: Arc<[SchemaUpgraded]>,
+        history: Arc<Mutex<BTreeMap<DocId, History>>>,
+        attachments: Attachments,
+        replay: Arc<Mutex<Option<ReplayLog>>>,
         tx: mpsc::UnboundedSender<oneshot::Sender<()>>,
     ) -> Self {
         Self {
             crdt,
             docs,
             registry,
+            flusher,
+            migrated,
+            history,
+            attachments,
+            replay,
             tx,
         }
     }
 
+    /// Returns a reference to the lens registry.
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Returns the [`Durability`] mode.
+    pub fn durability(&self) -> Durability {
+        self.flusher.durability()
+    }
+
+    /// Returns the local [`Attachments`] store.
+    pub fn attachments(&self) -> &Attachments {
+        &self.attachments
+    }
+
+    /// Sets the [`Durability`] mode.
+    pub fn set_durability(&self, durability: Durability) {
+        self.flusher.set_durability(durability);
+    }
+
+    /// Writes all pending changes to storage.
+    pub fn sync(&self) -> impl Future<Output = Result<()>> {
+        self.flusher.sync()
+    }
+
+    /// Enables or disables recording the changes joined into a persistent replay log, which
+    /// can be rebuilt with a [`Replay`](crate::Replay) when debugging convergence issues.
+    /// Enabling the log records the current state of every document first, so that local
+    /// and remote changes recorded afterwards replay on top of it. Disabling the log keeps
+    /// the recorded changes.
+    pub fn set_replay_log(&self, enabled: bool) -> Result<()> {
+        self.with_replay_log(|log| {
+            if enabled && !log.enabled {
+                for doc in self.docs.docs() {
+                    let doc = doc?;
+                    let causal = self.crdt.state(&doc);
+                    if causal.is_empty() {
+                        continue;
+                    }
+                    let schema = self.docs.schema(&doc)?;
+                    log.record(&ReplayEntry {
+                        kind: ReplayKind::State,
+                        peer: self.docs.peer_id(&doc)?,
+                        doc,
+                        doc_schema: schema.as_ref().name.to_string(),
+                        schema: schema.as_ref().hash,
+                        causal,
+                    })?;
+                }
+            }
+            log.enabled = enabled;
+            Ok(())
+        })
+    }
+
+    /// Returns the changes recorded by the replay log in the order they were joined.
+    pub fn replay_log(&self) -> Result<Vec<ReplayEntry>> {
+        self.with_replay_log(|log| log.entries())
+    }
+
+    /// Removes the changes recorded by the replay log and reclaims their space.
+    pub fn clear_replay_log(&self) -> Result<()> {
+        self.with_replay_log(|log| log.clear())
+    }
+
+    /// Loads the replay log on first use.
+    fn with_replay_log<T>(&self, f: impl FnOnce(&mut ReplayLog) -> Result<T>) -> Result<T> {
+        let mut replay = self.replay.lock();
+        if replay.is_none() {
+            *replay = Some(ReplayLog::load(self.flusher.clone())?);
+        }
+        f(replay.as_mut().unwrap())
+    }
+
     /// Adds a [`Keypair`].
     pub fn add_keypair(&self, key: Keypair) -> Result<PeerId> {
         self.docs.add_keypair(key)
@@ -468,6 +1548,29 @@ impl Frontend {
         self.docs.remove_keypair(peer)
     }
 
+    /// Encrypts the stored [`Keypair`]s with a key derived from `password`. Keypairs added
+    /// afterwards are encrypted too. The keystore stays unlocked until [`Frontend::lock`] is
+    /// called or the database is reopened.
+    pub fn encrypt_keystore(&self, password: &str) -> Result<()> {
+        self.docs.encrypt_keystore(password)
+    }
+
+    /// Unlocks an encrypted keystore. Fails if the password is wrong.
+    pub fn unlock(&self, password: &str) -> Result<()> {
+        self.docs.unlock(password)
+    }
+
+    /// Forgets the key of an encrypted keystore.
+    pub fn lock(&self) {
+        self.docs.lock()
+    }
+
+    /// Returns true if the keystore is encrypted and locked. Reading or adding a [`Keypair`]
+    /// fails while the keystore is locked.
+    pub fn is_locked(&self) -> Result<bool> {
+        self.docs.is_locked()
+    }
+
     /// Returns an iterator of [`DocId`].
     pub fn docs(&self) -> impl Iterator<Item = Result<DocId>> + '_ {
         self.docs.docs()
@@ -478,12 +1581,37 @@ impl Frontend {
         self.docs.docs_by_schema(schema)
     }
 
+    /// Returns an iterator of all [`DocId`]s with their schema name and version.
+    pub fn all_docs(&self) -> impl Iterator<Item = Result<(DocId, String, u32)>> + '_ {
+        self.docs.docs().map(move |res| {
+            let id = res?;
+            let info = self.docs.schema(&id)?;
+            let info = info.as_ref();
+            Ok((id, info.name().to_string(), info.version()))
+        })
+    }
+
     /// Creates a new document using [`Keypair`] with initial schema and owner.
     pub fn create_doc(
         &self,
         owner: PeerId,
         schema: &str,
         la: Keypair,
+    ) -> Result<impl Future<Output = Doc>> {
+        self.create_doc_with(owner, schema, la, |_| Ok(Causal::default()))
+    }
+
+    /// Creates a new document using [`Keypair`] with initial schema, owner and content.
+    ///
+    /// The transaction returned by `init` is signed by the document [`Keypair`] and joined
+    /// together with the ownership policy, so the document never exists without its initial
+    /// content and acls.
+    pub fn create_doc_with(
+        &self,
+        owner: PeerId,
+        schema: &str,
+        la: Keypair,
+        init: impl FnOnce(&mut Cursor) -> Result<Causal>,
     ) -> Result<impl Future<Output = Doc>> {
         let id = DocId::new(la.peer_id().into());
         let (version, hash) = self
@@ -492,11 +1620,31 @@ impl Frontend {
             .ok_or_else(|| anyhow!("missing schema {}", schema))?;
         let info = SchemaInfo::new(schema.into(), version, hash);
         let schema = self.registry.get(&hash).unwrap();
-        self.docs.set_peer_id(&id, &id.into())?;
-        self.docs.set_schema(&id, &info)?;
         let doc = Doc::new(id, self.clone(), la, schema);
-        let delta = doc.cursor().say_can(Some(owner), Permission::Own)?;
-        let fut = self.apply(&id, &delta)?;
+        let mut delta = doc.cursor().say_can(Some(owner), Permission::Own)?;
+        delta.join(&init(&mut doc.cursor())?);
+        // peers that don't know the metadata reject changes containing it, so it is joined
+        // separately from the ownership policy.
+        let metadata = doc
+            .metadata()
+            .field("created_at")?
+            .assign_u64(crate::util::now())?;
+        self.crdt.join(&id.into(), &delta)?;
+        let fut = self.join(&id.into(), &metadata)?;
+        for causal in [delta, metadata] {
+            record_replay(&self.replay, || {
+                Ok(ReplayEntry {
+                    kind: ReplayKind::Local,
+                    peer: id.into(),
+                    doc: id,
+                    doc_schema: info.name.clone(),
+                    schema: info.hash,
+                    causal,
+                })
+            })?;
+        }
+        self.docs.set_metadata_migrated(&id)?;
+        self.docs.set_schema(&id, &info)?;
         self.docs.set_peer_id(&id, &owner)?;
         let doc = self.doc(id)?;
         Ok(async move {
@@ -515,6 +1663,7 @@ impl Frontend {
         let info = SchemaInfo::new(schema.into(), version, hash);
         self.docs.set_schema(&id, &info)?;
         self.docs.set_peer_id(&id, peer)?;
+        self.docs.set_state(&id, DocState::Active)?;
         self.doc(id)
     }
 
@@ -522,9 +1671,95 @@ impl Frontend {
     pub fn remove_doc(&self, id: &DocId) -> Result<()> {
         self.crdt.remove(id)?;
         self.docs.remove(id)?;
+        self.history.lock().remove(id);
+        Ok(())
+    }
+
+    /// Returns the [`DocState`] of a document. Removed documents keep their state so that
+    /// remaining handles can tell they were removed.
+    pub fn doc_state(&self, id: &DocId) -> Result<DocState> {
+        self.docs.state(id)
+    }
+
+    /// Archives a document. Archived documents are kept but reject local changes.
+    pub fn archive_doc(&self, id: &DocId) -> Result<()> {
+        match self.doc_state(id)? {
+            DocState::Removed => Err(anyhow!("doc {} was removed", id)),
+            _ => self.docs.set_state(id, DocState::Archived),
+        }
+    }
+
+    /// Makes an archived document accept local changes again.
+    pub fn unarchive_doc(&self, id: &DocId) -> Result<()> {
+        match self.doc_state(id)? {
+            DocState::Removed => Err(anyhow!("doc {} was removed", id)),
+            _ => self.docs.set_state(id, DocState::Active),
+        }
+    }
+
+    /// Records a local change in the outbound journal before it is applied, so that it can be
+    /// broadcast again if the app is killed before a peer received it. Only the dots of the
+    /// change are recorded, the paths are read from the store. Returns the journal entry.
+    pub fn journal_append(&self, id: &DocId, causal: &Causal) -> Result<Dot> {
+        let entry = causal.id();
+        self.docs.journal_append(id, &entry, &causal.ctx())?;
+        Ok(entry)
+    }
+
+    /// Removes an entry from the outbound journal once a peer received the change.
+    pub fn journal_remove(&self, id: &DocId, entry: &Dot) -> Result<()> {
+        self.docs.journal_remove(id, entry)
+    }
+
+    /// Returns the local changes that were applied but not received by a peer yet.
+    pub fn journal(&self) -> impl Iterator<Item = Result<(DocId, Dot, Causal)>> + '_ {
+        self.docs.journal().map(move |res| {
+            let (id, entry, ctx) = res?;
+            Ok((id, entry, self.crdt.causal_of(&id, &ctx)))
+        })
+    }
+
+    /// Clears the outbound journal. Cleared changes are only sent to peers when they request
+    /// an unjoin.
+    pub fn clear_journal(&self) -> Result<()> {
+        for (id, entry, _) in self.docs.journal().collect::<Result<Vec<_>>>()? {
+            self.journal_remove(&id, &entry)?;
+        }
         Ok(())
     }
 
+    /// Returns the remaining time to live of an ephemeral document or of a document that is
+    /// deleted everywhere.
+    pub fn ttl(&self, id: &DocId) -> Result<Option<Duration>> {
+        let now = crate::util::now();
+        let expiry = match (self.docs.expiry(id)?, self.docs.deletion(id)?) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        Ok(expiry.map(|expiry| Duration::from_secs(expiry.saturating_sub(now))))
+    }
+
+    /// Marks a document as ephemeral. After `ttl` the document should be removed.
+    pub fn set_ttl(&self, id: &DocId, ttl: Duration) -> Result<()> {
+        self.docs
+            .set_expiry(id, crate::util::now().saturating_add(ttl.as_secs()))
+    }
+
+    /// Returns the ephemeral documents and the documents deleted everywhere and their
+    /// remaining time to live.
+    pub fn ttls(&self) -> Vec<(DocId, Duration)> {
+        let now = crate::util::now();
+        let mut expiries = BTreeMap::new();
+        for (id, expiry) in self.docs.expiries().chain(self.docs.deletions()) {
+            let min = expiries.entry(id).or_insert(expiry);
+            *min = expiry.min(*min);
+        }
+        expiries
+            .into_iter()
+            .map(|(id, expiry)| (id, Duration::from_secs(expiry.saturating_sub(now))))
+            .collect()
+    }
+
     /// Returns the local [`PeerId`] associated with a document.
     pub fn peer_id(&self, id: &DocId) -> Result<PeerId> {
         self.docs.peer_id(id)
@@ -542,6 +1777,11 @@ impl Frontend {
             .ok_or_else(|| anyhow!("missing schema for {}", id))
     }
 
+    /// Adds a capability token presented by a peer.
+    pub fn add_token(&self, token: Token) -> Result<()> {
+        self.crdt.add_token(token)
+    }
+
     /// Computes the [`CausalContext`] to sync with a remote peer.
     pub fn ctx(&self, id: &DocId) -> Result<CausalContext> {
         self.crdt.ctx(id)
@@ -561,10 +1801,108 @@ impl Frontend {
         Ok(Doc::new(id, self.clone(), key, schema))
     }
 
-    /// Applies a local change to a document.
+    /// Opens a document through the registered schema `schema` instead of it's own.
+    ///
+    /// The lenses of one schema need to be a prefix of the lenses of the other and the lenses
+    /// in between may only add to the schema. The schema of the document isn't changed, so
+    /// the fields added by `schema` are only stored locally and aren't replicated.
+    pub fn doc_with_schema(&self, id: DocId, schema: &str) -> Result<Doc> {
+        let info = self.schema(&id)?;
+        let doc_hash = Hash::from(info.as_ref().hash);
+        let (_, hash) = self
+            .registry
+            .lookup(schema)
+            .ok_or_else(|| anyhow!("missing schema {}", schema))?;
+        let (ancestor, descendant) = if self.registry.is_ancestor(&doc_hash, &hash) {
+            (doc_hash, hash)
+        } else if self.registry.is_ancestor(&hash, &doc_hash) {
+            (hash, doc_hash)
+        } else {
+            return Err(anyhow!(
+                "schema {} doesn't share the ancestry of doc {}",
+                schema,
+                id
+            ));
+        };
+        let start = self.lenses(&ancestor)?.lenses().lenses().len();
+        let lenses = self.lenses(&descendant)?;
+        if !lenses.lenses().lenses()[start..]
+            .iter()
+            .all(|lens| lens.is_additive())
+        {
+            return Err(anyhow!("schema {} isn't a union with doc {}", schema, id));
+        }
+        let key = self.keypair(&self.peer_id(&id)?)?;
+        Ok(Doc::new(id, self.clone(), key, self.lenses(&hash)?))
+    }
+
+    /// Applies a local change to a document. The change can be reverted with [`Doc::undo`].
     pub fn apply(&self, doc: &DocId, causal: &Causal) -> Result<impl Future<Output = ()>> {
+        let fut = self.apply_untracked(doc, causal)?;
+        // policy statements can't be reverted so they don't take a slot in the history.
+        let policy_only = causal.store().iter().all(|path| is_policy(path.as_path()))
+            && causal.expired().iter().all(|path| {
+                path.as_path()
+                    .parent()
+                    .and_then(|path| path.parent())
+                    .map(is_policy)
+                    .unwrap_or(true)
+            });
+        if policy_only {
+            return Ok(fut);
+        }
+        let mut history = self.history.lock();
+        let history = history.entry(*doc).or_default();
+        if history.undo.len() >= MAX_UNDO {
+            history.undo.remove(0);
+        }
+        history.undo.push(causal.clone());
+        history.redo.clear();
+        Ok(fut)
+    }
+
+    /// Applies a local change to a document without recording it in the undo history.
+    fn apply_untracked(&self, doc: &DocId, causal: &Causal) -> Result<impl Future<Output = ()>> {
+        match self.doc_state(doc)? {
+            DocState::Active => {}
+            DocState::Archived => return Err(anyhow!("doc {} is archived", doc)),
+            DocState::Removed => return Err(anyhow!("doc {} was removed", doc)),
+        }
         let peer = self.peer_id(doc)?;
-        self.crdt.join(&peer, causal)?;
+        let fut = self.join(&peer, causal)?;
+        update_deletion(&self.crdt, &self.docs, doc, causal)?;
+        record_replay(&self.replay, || {
+            let schema = self.docs.schema(doc)?;
+            Ok(ReplayEntry {
+                kind: ReplayKind::Local,
+                peer,
+                doc: *doc,
+                doc_schema: schema.as_ref().name.to_string(),
+                schema: schema.as_ref().hash,
+                causal: causal.clone(),
+            })
+        })?;
+        Ok(fut)
+    }
+
+    /// Applies several local changes to a document at once. The changes are joined into a
+    /// single transaction, so the store is flushed and the acl updated once.
+    pub fn apply_batch(&self, doc: &DocId, causals: &[Causal]) -> Result<impl Future<Output = ()>> {
+        let mut batch = Causal::default();
+        for causal in causals {
+            batch.join(causal);
+        }
+        self.apply(doc, &batch)
+    }
+
+    fn join(&self, peer: &PeerId, causal: &Causal) -> Result<impl Future<Output = ()>> {
+        self.crdt.join_local(peer, causal)?;
+        self.barrier()
+    }
+
+    /// Returns a future that resolves once the effects of all prior local changes, including
+    /// policy changes, are observable through every [`Doc`] handle.
+    pub fn barrier(&self) -> Result<impl Future<Output = ()>> {
         let (tx, rx) = oneshot::channel();
         self.tx.clone().unbounded_send(tx)?;
         Ok(async move {
@@ -576,6 +1914,38 @@ impl Frontend {
     pub fn subscribe(&self) -> impl Stream<Item = ()> {
         self.docs.subscribe()
     }
+
+    /// Subscribes to documents being added or removed. Unlike [`Frontend::subscribe`] this
+    /// doesn't yield for keypair or metadata changes.
+    pub fn subscribe_docs(&self) -> impl Stream<Item = ()> {
+        self.docs.subscribe_docs()
+    }
+
+    /// Subscribes to acl changes of any document.
+    pub fn subscribe_acls(&self) -> impl Stream<Item = DocId> {
+        self.crdt.watch_acls()
+    }
+
+    /// Subscribes to schema changes of any document, for example after a migration.
+    pub fn subscribe_schemas(&self) -> impl Stream<Item = ()> {
+        self.docs.subscribe_schemas()
+    }
+
+    /// Subscribes to metadata changes of a single document.
+    pub fn subscribe_doc(&self, id: &DocId) -> impl Stream<Item = ()> {
+        self.docs.subscribe_doc(id)
+    }
+
+    /// Subscribes to documents being added, archived, unarchived or removed.
+    pub fn subscribe_lifecycle(&self) -> impl Stream<Item = (DocId, DocState)> {
+        self.docs.subscribe_lifecycle()
+    }
+
+    /// Subscribes to documents being upgraded to a newer schema. Starts with the documents
+    /// migrated when the [`Backend`] was created.
+    pub fn subscribe_upgrades(&self) -> impl Stream<Item = SchemaUpgraded> {
+        stream::iter(self.migrated.to_vec()).chain(self.docs.subscribe_upgrades())
+    }
 }
 
 impl std::fmt::Debug for Frontend {
@@ -587,6 +1957,101 @@ impl std::fmt::Debug for Frontend {
     }
 }
 
+/// A page of raw paths returned from [`Doc::scan`].
+#[derive(Clone, Debug, Default)]
+pub struct ScanPage {
+    /// Paths in this page.
+    pub paths: Vec<PathBuf>,
+    /// Continuation token of the next page if there are more paths.
+    pub token: Option<Vec<u8>>,
+}
+
+/// The paths of a document at a point in time.
+#[derive(Clone)]
+pub struct Snapshot(ArcRadixTree<u8, ()>);
+
+impl Snapshot {
+    /// Returns the paths of the snapshot.
+    pub fn paths(&self) -> impl Iterator<Item = PathBuf> + '_ {
+        self.0.iter().map(|(k, _)| Path::new(&k).to_owned())
+    }
+
+    /// Returns the changes from `self` to a later snapshot `other`.
+    pub fn diff(&self, other: &Snapshot) -> SnapshotDiff {
+        SnapshotDiff(Diff::new(self.0.clone(), other.0.clone()))
+    }
+}
+
+impl std::fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_set()
+            .entries(self.paths().map(|p| format!("{}", p)))
+            .finish()
+    }
+}
+
+/// The changes between two [`Snapshot`]s.
+pub struct SnapshotDiff(Diff<u8, ()>);
+
+impl SnapshotDiff {
+    /// Returns the paths that were added.
+    pub fn added(&self) -> impl Iterator<Item = PathBuf> {
+        self.0
+            .added()
+            .into_iter()
+            .map(|(k, _)| Path::new(&k).to_owned())
+    }
+
+    /// Returns the paths that were removed.
+    pub fn removed(&self) -> impl Iterator<Item = PathBuf> {
+        self.0
+            .removed()
+            .into_iter()
+            .map(|(k, _)| Path::new(&k).to_owned())
+    }
+
+    /// Returns `true` if nothing changed.
+    pub fn is_empty(&self) -> bool {
+        self.added().next().is_none() && self.removed().next().is_none()
+    }
+}
+
+/// Maximum number of local changes per document that can be undone.
+const MAX_UNDO: usize = 128;
+
+/// Local changes of a document that can be undone and redone.
+#[derive(Default)]
+struct History {
+    undo: Vec<Causal>,
+    redo: Vec<Causal>,
+    /// Store paths that were added again under a new identity.
+    renamed: BTreeMap<PathBuf, PathBuf>,
+}
+
+/// Returns `path` with a new identity by replacing it's last nonce or extending it's last
+/// position with `nonce`. Returns `None` if the path has neither.
+fn renew(path: Path, nonce: u64) -> Option<PathBuf> {
+    let mut segments = vec![];
+    let mut rest = path;
+    while let Some((segment, tail)) = rest.split_first() {
+        segments.push(segment);
+        rest = tail;
+    }
+    let segment = segments
+        .iter_mut()
+        .rev()
+        .find(|segment| matches!(segment, Segment::Nonce(_) | Segment::Position(_)))?;
+    *segment = match segment {
+        Segment::Position(pos) => Segment::Position(pos.extend(nonce)),
+        _ => Segment::Nonce(nonce),
+    };
+    let mut path = PathBuf::new();
+    for segment in segments {
+        path.push_segment(segment);
+    }
+    Some(path)
+}
+
 /// A clonable document handle.
 #[derive(Debug, Clone)]
 pub struct Doc {
@@ -616,38 +2081,386 @@ impl Doc {
         self.frontend.schema(&self.id)
     }
 
+    /// Returns the [`DocState`] of the doc.
+    pub fn state(&self) -> Result<DocState> {
+        self.frontend.doc_state(&self.id)
+    }
+
+    /// Rebinds the handle to the current schema of the doc after a [`SchemaUpgraded`].
+    pub fn refresh(&mut self) -> Result<()> {
+        let info = self.frontend.schema(&self.id)?;
+        self.schema = self.frontend.lenses(&info.as_ref().hash())?;
+        Ok(())
+    }
+
     /// Computes the [`CausalContext`] to sync with a remote peer.
     pub fn ctx(&self) -> Result<CausalContext> {
         self.frontend.ctx(&self.id)
     }
 
+    /// Returns the tags of a transaction identified by [`Causal::id`].
+    pub fn tags(&self, tx: &Dot) -> impl Iterator<Item = String> {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        path.dot(tx);
+        self.frontend
+            .crdt
+            .scan_path(path.as_path())
+            .filter_map(|k| Some(tag(Path::new(&k))?.1))
+    }
+
+    /// Returns the members of the workspace and their schema.
+    pub fn members(&self) -> impl Iterator<Item = (DocId, String)> {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        self.frontend
+            .crdt
+            .scan_path(path.as_path())
+            .filter_map(|k| member(Path::new(&k)))
+    }
+
     /// Returns a cursor for the document.
     pub fn cursor(&self) -> Cursor<'_> {
         Cursor::new(self.key, self.id, self.schema.schema(), &self.frontend.crdt)
     }
 
-    /// Applies a local change to the document.
-    pub fn apply(&self, causal: &Causal) -> Result<()> {
-        let fut = self.frontend.apply(&self.id, causal)?;
-        drop(fut);
-        Ok(())
+    /// Returns a cursor to the metadata of the document, a struct with a `title`, `icon` and
+    /// `created_at` register that is available independent of the schema.
+    pub fn metadata(&self) -> Cursor<'_> {
+        Cursor::metadata(self.key, self.id, &self.frontend.crdt)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::Permission;
+    /// Deletes the document on every peer once `grace` elapsed. Peers remove their local state
+    /// and stop syncing the document, like an expired ephemeral document. Until then the
+    /// deletion can be undone with [`Doc::cancel_delete`]. Requires [`Permission::Own`].
+    pub fn delete_everywhere(&self, grace: Duration) -> Result<Causal> {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        if !self
+            .frontend
+            .crdt
+            .can(&self.key.peer_id(), Permission::Own, path.as_path())?
+        {
+            return Err(anyhow!("only owners can delete a document everywhere"));
+        }
+        self.metadata()
+            .field(DELETED_AT)?
+            .assign_u64(crate::util::now().saturating_add(grace.as_secs()))
+    }
 
-    #[async_std::test]
-    async fn test_api() -> Result<()> {
-        let packages = r#"
-            todoapp {
-                0.1.0 {
-                    .: Struct
-                    .todos: Table<u64>
-                    .todos.{}: Struct
-                    .todos.{}.title: MVReg<String>
+    /// Cancels a pending [`Doc::delete_everywhere`].
+    pub fn cancel_delete(&self) -> Result<Causal> {
+        self.metadata().field(DELETED_AT)?.remove()
+    }
+
+    /// Returns the time in seconds since the unix epoch at which the document is deleted on
+    /// every peer.
+    pub fn deleted_at(&self) -> Result<Option<u64>> {
+        self.frontend.docs.deletion(&self.id)
+    }
+
+    /// Returns a cursor at a path returned from [`Cursor::path_string`], for example to restore
+    /// the location a user was editing.
+    pub fn cursor_at(&self, pointer: &str) -> Result<Cursor<'_>> {
+        let mut cursor = self.cursor();
+        cursor.pointer(pointer)?;
+        Ok(cursor)
+    }
+
+    /// Creates an [`InviteToken`] that grants anyone redeeming it `perm` on the document until
+    /// `expiry` (seconds since the unix epoch). Requires the same permissions as
+    /// [`Cursor::mint_token`]. The invite can be revoked with [`Doc::revoke_token`].
+    pub fn create_invite_token(&self, perm: Permission, expiry: u64) -> Result<InviteToken> {
+        if expiry == u64::MAX {
+            return Err(anyhow!("invite tokens need to expire"));
+        }
+        let token = self.cursor().mint_token(None, perm, expiry)?;
+        let schema = self.schema()?;
+        Ok(InviteToken::new(
+            &self.key,
+            token,
+            schema.as_ref().name().to_string(),
+            schema.as_ref().hash().into(),
+        ))
+    }
+
+    /// Returns a policy statement granting `peer` the permission of a capability token it
+    /// presented, so that the grant replicates like any other statement. Returns `None` if
+    /// the token isn't for the root of this document or doesn't apply to `peer`, if `peer`
+    /// already has the permission or if the issuer or the local peer can't delegate it.
+    pub fn redeem_token(&self, token: &Token, peer: &PeerId) -> Result<Option<Causal>> {
+        token.verify()?;
+        let cursor = self.cursor();
+        if token.path() != cursor.path()
+            || self.frontend.crdt.is_revoked(token)?
+            || !token.grants(*peer, token.perm(), token.path(), crate::util::now())
+            || cursor.can(peer, token.perm())?
+        {
+            return Ok(None);
+        }
+        let required = if token.perm().controllable() {
+            Permission::Control
+        } else {
+            Permission::Own
+        };
+        if !cursor.can(&token.issuer(), required)? || !cursor.can(&self.key.peer_id(), required)? {
+            return Ok(None);
+        }
+        Ok(Some(cursor.say_can(Some(*peer), token.perm())?))
+    }
+
+    /// Revokes a capability token, for example the one of an [`InviteToken`]. Replicas stop
+    /// accepting and redeeming the token once they received the revocation. Requires being the
+    /// issuer of the token or being allowed to delegate it's permission.
+    pub fn revoke_token(&self, token: &Token) -> Result<Causal> {
+        if token.doc()? != self.id {
+            return Err(anyhow!("token is for another document"));
+        }
+        if !self.frontend.crdt.can_revoke(&self.key.peer_id(), token)? {
+            return Err(anyhow!("unauthorized"));
+        }
+        self.metadata()
+            .field(REVOKED_TOKENS)?
+            .add_bytes(&token.id())
+    }
+
+    /// Applies a local change to the document.
+    pub fn apply(&self, causal: &Causal) -> Result<()> {
+        let fut = self.frontend.apply(&self.id, causal)?;
+        drop(fut);
+        Ok(())
+    }
+
+    /// Applies several local changes to the document at once. See [`Frontend::apply_batch`].
+    pub fn apply_batch(&self, causals: &[Causal]) -> Result<()> {
+        let fut = self.frontend.apply_batch(&self.id, causals)?;
+        drop(fut);
+        Ok(())
+    }
+
+    /// Reverts the last local change that wasn't undone yet by applying a compensating change.
+    /// Paths added by the change are removed and paths it removed are added again, under a
+    /// new nonce or position as tombstones are permanent. Returns the compensating change so
+    /// that it can be sent to other peers, or `None` if there is nothing to undo.
+    pub fn undo(&self) -> Result<Option<Causal>> {
+        self.revert(true)
+    }
+
+    /// Reverts the last [`Doc::undo`] unless a local change was applied since.
+    pub fn redo(&self) -> Result<Option<Causal>> {
+        self.revert(false)
+    }
+
+    fn revert(&self, undo: bool) -> Result<Option<Causal>> {
+        let mut history = self.frontend.history.lock();
+        let History {
+            undo: undos,
+            redo: redos,
+            renamed,
+        } = history.entry(self.id).or_default();
+        let (from, to) = if undo { (undos, redos) } else { (redos, undos) };
+        let mut renames = vec![];
+        let causal = loop {
+            let causal = if let Some(causal) = from.last() {
+                self.invert(causal, renamed, &mut renames)
+            } else {
+                return Ok(None);
+            };
+            // nothing left to revert, e.g. all paths were already removed by other peers.
+            if !causal.is_empty() {
+                break causal;
+            }
+            from.pop();
+        };
+        drop(self.frontend.apply_untracked(&self.id, &causal)?);
+        renamed.extend(renames);
+        from.pop();
+        to.push(causal.clone());
+        Ok(Some(causal))
+    }
+
+    /// Computes the change reverting `causal`. Paths that are added again under a new
+    /// identity are pushed to `renames`.
+    fn invert(
+        &self,
+        causal: &Causal,
+        renamed: &BTreeMap<PathBuf, PathBuf>,
+        renames: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> Causal {
+        let mut inverse = Causal::default();
+        for mut path in causal.store().iter() {
+            while let Some(next) = renamed.get(&path) {
+                path = next.clone();
+            }
+            if !is_policy(path.as_path()) && self.frontend.crdt.contains(path.as_path()) {
+                self.sign(&mut path);
+                inverse.expired.insert(path);
+            }
+        }
+        for path in causal.expired().iter() {
+            let store_path = match path.as_path().parent().and_then(|path| path.parent()) {
+                Some(path) if !is_policy(path) => path,
+                _ => continue,
+            };
+            let value = match store_path.parent().and_then(|path| path.parent()) {
+                Some(value) => value,
+                None => continue,
+            };
+            // values of last-writer-wins registers need a clock ahead of the current values.
+            let reg = value.parent().and_then(|path| path.parent());
+            let nonce = match (reg, value.child()) {
+                (Some(reg), Some(rel)) if self.schema.schema().is_lww(rel) => {
+                    lww_nonce(&self.frontend.crdt, reg)
+                }
+                _ => nonce(),
+            };
+            if let Some(mut path) = renew(value, nonce) {
+                self.sign(&mut path);
+                renames.push((store_path.to_owned(), path.clone()));
+                inverse.store.insert(path);
+            }
+        }
+        inverse
+    }
+
+    fn sign(&self, path: &mut PathBuf) {
+        let sig = self.key.sign(path.as_ref());
+        path.peer(&self.key.peer_id());
+        path.sig(sig);
+    }
+
+    /// Applies a local change to the document and records it in the outbound journal. The
+    /// returned entry should be removed once the change was sent.
+    pub fn apply_journaled(&self, causal: &Causal) -> Result<Dot> {
+        let entry = self.frontend.journal_append(&self.id, causal)?;
+        if let Err(err) = self.apply(causal) {
+            self.frontend.journal_remove(&self.id, &entry)?;
+            return Err(err);
+        }
+        Ok(entry)
+    }
+
+    /// Records a change that was already applied in the outbound journal. See
+    /// [`Doc::apply_journaled`].
+    pub fn journal(&self, causal: &Causal) -> Result<Dot> {
+        self.frontend.journal_append(&self.id, causal)
+    }
+
+    /// Returns up to `limit` raw paths starting with `prefix` relative to the document root.
+    /// Pass the token of the previous [`ScanPage`] to continue scanning.
+    pub fn scan(&self, prefix: Path, token: Option<&[u8]>, limit: usize) -> ScanPage {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        path.extend(prefix);
+        let (paths, token) = self.frontend.crdt.scan_page(path.as_path(), token, limit);
+        ScanPage { paths, token }
+    }
+
+    /// Returns a snapshot of the document. Snapshots can be diffed to compute the changes
+    /// between two points in time.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        Snapshot(self.frontend.crdt.snapshot(path.as_path()))
+    }
+
+    /// Limits the number of paths below `prefix` relative to the document root each author
+    /// without control permission can insert with local writes. Use with an anonymous write
+    /// permission to build public inboxes.
+    pub fn set_quota(&self, prefix: Path, max: u64) {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        path.extend(prefix);
+        self.frontend.crdt.set_quota(path.as_path(), max);
+    }
+
+    /// Removes the quota of `prefix` relative to the document root.
+    pub fn remove_quota(&self, prefix: Path) {
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        path.extend(prefix);
+        self.frontend.crdt.remove_quota(path.as_path());
+    }
+
+    /// Subscribes to acl changes of the document. Each [`Batch`] contains an
+    /// [`Event::Granted`](crate::Event::Granted) or [`Event::Revoked`](crate::Event::Revoked) for
+    /// every rule of the engine that changed, so
+    /// that UIs can for example disable editing when write permission is revoked.
+    pub fn subscribe_acl(&self) -> impl Stream<Item = Batch> {
+        self.frontend.crdt.watch_acl(&self.id)
+    }
+
+    /// Returns a transaction tombstoning every path authored by a compromised `peer` that is
+    /// not contained in `after`, a [`CausalContext`] from before the compromise. Paths removed
+    /// by the compromised key are not restored. Requires [`Permission::Own`] on the document.
+    pub fn rollback_author(&self, peer: &PeerId, after: &CausalContext) -> Result<Causal> {
+        let mut root = PathBuf::new();
+        root.doc(&self.id);
+        if !self
+            .frontend
+            .crdt
+            .can(&self.key.peer_id(), Permission::Own, root.as_path())?
+        {
+            return Err(anyhow!("unauthorized"));
+        }
+        let mut expired = DotStore::new();
+        for k in self.frontend.crdt.scan_path(root.as_path()) {
+            let path = Path::new(&k);
+            let mut body = path;
+            match split_sig(&mut body) {
+                Some((author, _)) if author == *peer => {}
+                _ => continue,
+            }
+            if after.store().contains(&path.dot()) {
+                continue;
+            }
+            let mut tombstone = path.to_owned();
+            tombstone.peer(&self.key.peer_id());
+            tombstone.sig(self.key.sign(path.as_ref()));
+            expired.insert(tombstone);
+        }
+        Ok(Causal {
+            store: DotStore::new(),
+            expired,
+        })
+    }
+
+    /// Returns a future that resolves once [`PeerId`] has [`Permission`] on the document.
+    pub fn wait_until_can(
+        &self,
+        peer: PeerId,
+        perm: Permission,
+    ) -> impl Future<Output = Result<()>> {
+        let mut acl = self.subscribe_acl();
+        let crdt = self.frontend.crdt.clone();
+        let mut path = PathBuf::new();
+        path.doc(&self.id);
+        async move {
+            while !crdt.can(&peer, perm, path.as_path())? {
+                acl.next()
+                    .await
+                    .ok_or_else(|| anyhow!("acl subscription closed"))?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Event, Merge, Permission, RegistryError, MAX_BYTES};
+    use std::ops::Bound;
+
+    #[async_std::test]
+    async fn test_api() -> Result<()> {
+        let packages = r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .todos: Table<u64>
+                    .todos.{}: Struct
+                    .todos.{}.title: MVReg<String>
                     .todos.{}.complete: EWFlag
                 }
             }
@@ -704,4 +2517,2088 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_pending() -> Result<()> {
+        let packages = r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                }
+                0.2.0 {
+                    .complete: EWFlag
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let op = doc.cursor().field("title")?.assign_str("title")?;
+        doc.apply(&op)?;
+        let hash = sdk.registry().lookup("todoapp").unwrap().1;
+        let ctx = Ref::archive(&CausalContext::new());
+
+        let old = tlfsc::compile_lenses("todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }")?;
+        let old = Ref::archive(&old);
+        let storage = Arc::new(MemStorage::default());
+        let sdk2 = Backend::new(storage.clone(), old.as_bytes())?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let op = doc.cursor().say_can(Some(peer2), Permission::Read)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+        let delta = sdk.unjoin(&peer2, doc.id(), ctx.as_ref())?;
+        sdk2.buffer(&peer, doc.id(), &hash, &delta)?;
+        // the buffered change needs to be on disk before the backend is restarted.
+        sdk2.sync().await?;
+        drop(sdk2);
+
+        let mut sdk2 = Backend::new(storage, old.as_bytes())?;
+        assert_eq!(sdk2.pending(), vec![(hash, peer)]);
+        let lenses = sdk.registry().get(&hash).unwrap();
+        sdk2.registry().register(lenses.as_ref().as_ref())?;
+        sdk2.join_pending()?;
+        assert!(sdk2.pending().is_empty());
+        let doc2 = sdk2.frontend().doc(*doc.id())?;
+        let value = doc2.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(value, "title");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_pending_bounds() -> Result<()> {
+        let packages = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let delta = doc.cursor().field("title")?.assign_str("title")?;
+        let hash = Hash::from([1; 32]);
+
+        let sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        assert!(sdk2.buffer(&peer, doc.id(), &hash, &delta).is_err());
+        sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let other = DocId::new([2; 32]);
+        sdk2.frontend().add_doc(other, &peer2, "todoapp")?;
+        assert!(sdk2.buffer(&peer, &other, &hash, &delta).is_err());
+
+        sdk2.buffer(&peer, doc.id(), &hash, &delta)?;
+        assert_eq!(sdk2.pending(), vec![(hash, peer)]);
+        assert_eq!(sdk2.pending_stats().entries, 1);
+        assert_eq!(sdk2.evict_pending(&hash)?, 1);
+        assert!(sdk2.pending().is_empty());
+        let stats = sdk2.pending_stats();
+        assert_eq!((stats.entries, stats.bytes, stats.evicted), (0, 0, 1));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_verify_chain() -> Result<()> {
+        let v1 = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let v2 =
+            "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } 0.2.0 { .complete: EWFlag } }";
+        let rewritten =
+            "todoapp { 0.1.0 { .: Struct .name: MVReg<String> } 0.2.0 { .complete: EWFlag } }";
+
+        let mut chain = tlfsc::compile_lenses(v1)?;
+        chain.extend(tlfsc::compile_lenses(v2)?);
+        assert!(Registry::verify_chain(Ref::archive(&chain).as_bytes()).is_ok());
+
+        let mut chain = tlfsc::compile_lenses(v2)?;
+        chain.extend(tlfsc::compile_lenses(rewritten)?);
+        let err = Backend::memory(Ref::archive(&chain).as_bytes())
+            .err()
+            .unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RegistryError>(),
+            Some(RegistryError::VersionNotIncreasing { .. })
+        ));
+
+        let mut chain = tlfsc::compile_lenses(v1)?;
+        chain.extend(tlfsc::compile_lenses(rewritten)?);
+        assert_eq!(
+            Registry::verify_chain(Ref::archive(&chain).as_bytes()),
+            Err(RegistryError::HistoryRewritten {
+                name: "todoapp".into(),
+                version: 5,
+                index: 1,
+            })
+        );
+
+        let storage = Arc::new(MemStorage::default());
+        let v2 = Ref::archive(&tlfsc::compile_lenses(v2)?);
+        let mut sdk = Backend::new(storage.clone(), v2.as_bytes())?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        fut.await;
+        drop(sdk);
+
+        let v1 = Ref::archive(&tlfsc::compile_lenses(v1)?);
+        let err = Backend::new(storage.clone(), v1.as_bytes()).err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<RegistryError>(),
+            Some(RegistryError::DocumentRewritten { .. })
+        ));
+        Backend::new(storage, v2.as_bytes())?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_package() -> Result<()> {
+        let v1 = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let v2 =
+            "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } 0.2.0 { .complete: EWFlag } }";
+        let v3 = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } 0.2.0 { .complete: EWFlag } 0.3.0 { .done: EWFlag } }";
+        let other = "other { 0.1.0 { .: Struct .name: MVReg<String> } }";
+        let storage = Arc::new(MemStorage::default());
+        let package = Ref::archive(&tlfsc::compile_lenses(v1)?);
+        let mut sdk = Backend::new(storage.clone(), package.as_bytes())?;
+        let (_, hash1) = sdk.registry().lookup("todoapp").unwrap();
+
+        let v2 = tlfsc::compile_lenses(v2)?.pop().unwrap();
+        let other = tlfsc::compile_lenses(other)?.pop().unwrap();
+        assert!(sdk.add_package(Ref::archive(&v2).as_bytes()).is_ok());
+        assert!(sdk.add_package(Ref::archive(&v2).as_bytes()).is_err());
+        let hash2 = sdk.add_package(Ref::archive(&other).as_bytes())?;
+
+        let packages = sdk.registry().packages();
+        assert_eq!(packages.len(), 3);
+        assert_eq!(packages[0].0, "other");
+        assert_eq!(packages[0].2, hash2);
+        let (v1, v2) = (packages[1].1, packages[2].1);
+        assert!(v1 < v2);
+        assert_eq!(
+            sdk.registry().lookup("todoapp").unwrap(),
+            (v2, packages[2].2)
+        );
+        assert_eq!(
+            sdk.registry().lookup_version("todoapp", v1),
+            Some((v1, hash1))
+        );
+        assert!(sdk.registry().lookup_version("todoapp", v2 + 1).is_none());
+
+        // only one of concurrent calls adding the same version succeeds.
+        let v3 = Ref::archive(&tlfsc::compile_lenses(v3)?.pop().unwrap());
+        let added = (0..4)
+            .map(|_| {
+                let registry = sdk.registry().clone();
+                let v3 = v3.as_bytes().to_vec();
+                std::thread::spawn(move || registry.add_package(&v3).is_ok())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|added| *added)
+            .count();
+        assert_eq!(added, 1);
+
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "other", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.apply(&doc.cursor().field("name")?.assign_str("name")?)?;
+        assert_eq!(doc.cursor().field("name")?.strs()?.next().unwrap()?, "name");
+        sdk.sync().await?;
+        drop(sdk);
+
+        // packages added at runtime are loaded after a restart.
+        let sdk = Backend::new(storage, package.as_bytes())?;
+        assert_eq!(sdk.registry().lookup("other"), Some((packages[0].1, hash2)));
+        let doc = sdk.frontend().doc(*doc.id())?;
+        assert_eq!(doc.cursor().field("name")?.strs()?.next().unwrap()?, "name");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_local_fields() -> Result<()> {
+        let packages = r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                    .draft: local MVReg<String>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mut causal = doc.cursor().field("title")?.assign_str("title")?;
+        causal.join(&doc.cursor().field("draft")?.assign_str("draft")?);
+        doc.apply(&causal)?;
+        let value = doc.cursor().field("draft")?.strs()?.next().unwrap()?;
+        assert_eq!(value, "draft");
+
+        let mut shared = causal.clone();
+        sdk.remove_local(doc.id(), &mut shared)?;
+        assert_eq!(shared.store().iter().count(), 1);
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let op = doc.cursor().say_can(Some(peer2), Permission::Write)?;
+        doc.apply(&op)?;
+        Pin::new(&mut sdk).await?;
+
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let delta = sdk.unjoin(&peer2, doc2.id(), ctx.as_ref())?;
+        let hash = sdk2.frontend().registry.lookup("todoapp").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        let value = doc2.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(value, "title");
+        assert!(doc2.cursor().field("draft")?.strs()?.next().is_none());
+
+        sdk2.join(&peer, doc.id(), &hash, causal)?;
+        assert!(doc2.cursor().field("draft")?.strs()?.next().is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_merged_registers() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            scores {
+                0.1.0 {
+                    .: Struct
+                    .high: MVReg<u64> merge(max)
+                    .total: MVReg<i64> merge(sum)
+                    .name: MVReg<String> merge(min)
+                    .notes: MVReg<String> merge(custom)
+                    .title: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "scores", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert_eq!(doc.cursor().field("high")?.merged_u64()?, None);
+
+        let mut causal = doc.cursor().field("high")?.assign_u64(3)?;
+        causal.join(&doc.cursor().field("high")?.assign_u64(7)?);
+        causal.join(&doc.cursor().field("total")?.assign_i64(-2)?);
+        causal.join(&doc.cursor().field("total")?.assign_i64(5)?);
+        causal.join(&doc.cursor().field("name")?.assign_str("bob")?);
+        causal.join(&doc.cursor().field("name")?.assign_str("alice")?);
+        causal.join(&doc.cursor().field("notes")?.assign_str("a")?);
+        causal.join(&doc.cursor().field("notes")?.assign_str("b")?);
+        doc.apply(&causal)?;
+
+        assert_eq!(doc.cursor().field("high")?.u64s()?.count(), 2);
+        assert_eq!(doc.cursor().field("high")?.merge(), Some(Merge::Max));
+        assert_eq!(doc.cursor().field("high")?.merged_u64()?, Some(7));
+        assert_eq!(doc.cursor().field("total")?.merged_i64()?, Some(3));
+        assert_eq!(
+            doc.cursor().field("name")?.merged_str()?,
+            Some("alice".into())
+        );
+        assert!(doc.cursor().field("notes")?.merged_str().is_err());
+        assert!(doc.cursor().field("title")?.merged_str().is_err());
+
+        doc.apply(&doc.cursor().field("notes")?.assign_str("ab")?)?;
+        let notes = doc
+            .cursor()
+            .field("notes")?
+            .strs()?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(notes, vec!["ab".to_string()]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_f64() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            geo {
+                0.1.0 {
+                    .: Struct
+                    .lat: MVReg<f64>
+                    .total: MVReg<f64> merge(sum)
+                    .samples: Set<f64>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "geo", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        doc.apply(&doc.cursor().field("lat")?.assign_f64(-12.5)?)?;
+        let lat = doc.cursor().field("lat")?.f64s()?.next().unwrap()?;
+        assert_eq!(lat, -12.5);
+        assert!(doc.cursor().field("lat")?.assign_u64(1).is_err());
+
+        let mut causal = doc.cursor().field("total")?.assign_f64(0.25)?;
+        causal.join(&doc.cursor().field("total")?.assign_f64(1.5)?);
+        doc.apply(&causal)?;
+        assert_eq!(doc.cursor().field("total")?.merged_f64()?, Some(1.75));
+
+        for f in [2.0, -0.0, f64::NEG_INFINITY, 0.0] {
+            doc.apply(&doc.cursor().field("samples")?.add_f64(f)?)?;
+        }
+        doc.apply(&doc.cursor().field("samples")?.remove_f64(2.0)?)?;
+        assert!(doc.cursor().field("samples")?.contains_f64(-0.0)?);
+        let samples = doc
+            .cursor()
+            .field("samples")?
+            .elements_f64()?
+            .map(f64::to_bits)
+            .collect::<Vec<_>>();
+        let expected = [f64::NEG_INFINITY, -0.0, 0.0].map(f64::to_bits);
+        assert_eq!(samples, expected);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_bytes() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            photos {
+                0.1.0 {
+                    .: Struct
+                    .thumbnail: MVReg<Bytes>
+                    .image: MVReg<Bytes>
+                    .tags: Set<Bytes>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "photos", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let thumbnail = vec![0, 255, 1, 0];
+        doc.apply(&doc.cursor().field("thumbnail")?.assign_bytes(&thumbnail)?)?;
+        let value = doc.cursor().field("thumbnail")?.bytes()?.next().unwrap()?;
+        assert_eq!(value, thumbnail);
+        let too_large = vec![0; MAX_BYTES + 1];
+        assert!(doc
+            .cursor()
+            .field("thumbnail")?
+            .assign_bytes(&too_large)
+            .is_err());
+
+        // large files are stored as attachments referenced by hash.
+        let image = vec![42; MAX_BYTES * 2];
+        let hash = sdk.frontend().attachments().insert(doc.id(), &image)?;
+        doc.apply(&doc.cursor().field("image")?.assign_bytes(hash.as_bytes())?)?;
+        let value = doc.cursor().field("image")?.bytes()?.next().unwrap()?;
+        let hash: [u8; 32] = value[..].try_into()?;
+        let stored = sdk.frontend().attachments().get(&hash.into())?;
+        assert_eq!(stored.as_deref(), Some(&image[..]));
+
+        doc.apply(&doc.cursor().field("tags")?.add_bytes(b"b")?)?;
+        doc.apply(&doc.cursor().field("tags")?.add_bytes(b"a")?)?;
+        assert!(doc.cursor().field("tags")?.contains_bytes(b"a")?);
+        let tags = doc
+            .cursor()
+            .field("tags")?
+            .elements_bytes()?
+            .collect::<Vec<_>>();
+        assert_eq!(tags, vec![b"a".to_vec(), b"b".to_vec()]);
+        Ok(())
+    }
+
+    /// Rust generated by `tlfsc --emit rust` from the schema in [`test_generated_rust`].
+    #[allow(dead_code)]
+    mod generated {
+        /// Stands in for the sdk, whose `Doc::apply` takes the transaction by value.
+        pub mod tlfs {
+            pub use crate::{Causal, Cursor};
+
+            pub struct Doc(pub crate::Doc);
+
+            impl Doc {
+                pub fn cursor(&self) -> Cursor<'_> {
+                    self.0.cursor()
+                }
+
+                pub fn apply(&self, causal: Causal) -> anyhow::Result<()> {
+                    self.0.apply(&causal)
+                }
+            }
+        }
+
+        include!("../../tlfsc/fixtures/todoapp.rs");
+    }
+
+    #[async_std::test]
+    async fn test_generated_rust() -> Result<()> {
+        use generated::{tlfs, Todoapp, TodoappTodos};
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                    .todos: Table<u64>
+                    .todos.{}: Struct
+                    .todos.{}.title: MVReg<String>
+                    .todos.{}.complete: EWFlag
+                    .todos.{}.tags: Set<String>
+                    .todos.{}.priority: MVReg<u64> merge(max)
+                    .todos.{}.estimate: MVReg<f64>
+                    .todos.{}.thumbnail: MVReg<Bytes>
+                    .todos.{}.votes: PNCounter
+                    .todos.{}.notes: Array
+                    .todos.{}.notes.[]: Text
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = tlfs::Doc(fut.await);
+        assert_eq!(Todoapp::load(&doc)?, Todoapp::default());
+
+        let mut value = Todoapp {
+            title: Some("groceries".into()),
+            ..Default::default()
+        };
+        value.todos.insert(
+            0,
+            TodoappTodos {
+                title: Some("milk".into()),
+                complete: true,
+                tags: ["dairy".to_string()].into_iter().collect(),
+                priority: Some(2),
+                estimate: Some(0.5),
+                thumbnail: Some(vec![0, 255]),
+                votes: 3,
+                notes: vec!["oat".into(), "1l".into(), "cold".into()],
+            },
+        );
+        value.todos.insert(
+            1,
+            TodoappTodos {
+                title: Some("bread".into()),
+                ..Default::default()
+            },
+        );
+        Todoapp::apply_changes(&doc, &value)?;
+        assert_eq!(Todoapp::load(&doc)?, value);
+
+        // writing an unchanged value doesn't apply a transaction.
+        let ctx = doc.0.ctx()?;
+        Todoapp::apply_changes(&doc, &value)?;
+        assert!(doc.0.ctx()? == ctx);
+
+        let todo = value.todos.get_mut(&0).unwrap();
+        todo.complete = false;
+        todo.tags.clear();
+        todo.thumbnail = None;
+        todo.votes = -1;
+        todo.notes.remove(1);
+        todo.notes.push("skimmed".into());
+        todo.notes.push("organic".into());
+        value.todos.remove(&1);
+        Todoapp::apply_changes(&doc, &value)?;
+        assert_eq!(Todoapp::load(&doc)?, value);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_lww_register() -> Result<()> {
+        let schema = r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .opened: MVReg<u64>
+                }
+                0.1.1 {
+                    .opened: LwwReg<u64>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(schema)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let hash = sdk.registry().lookup("todoapp").unwrap().1;
+
+        let mut sdk2 = Backend::test(schema)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Write)?)?;
+        Pin::new(&mut sdk).await?;
+        let sync = |from: &mut Backend, to: &mut Backend| -> Result<()> {
+            let from_peer = from.frontend().default_keypair()?.peer_id();
+            let to_peer = to.frontend().default_keypair()?.peer_id();
+            let ctx = Ref::archive(&to.frontend().ctx(doc.id())?);
+            let delta = from.unjoin(&to_peer, doc.id(), ctx.as_ref())?;
+            to.join(&from_peer, doc.id(), &hash, delta)?;
+            Ok(())
+        };
+
+        doc.apply(&doc.cursor().field("opened")?.assign_u64(1)?)?;
+        sync(&mut sdk, &mut sdk2)?;
+        assert!(doc.cursor().field("opened")?.is_lww());
+        assert_eq!(doc2.cursor().field("opened")?.merged_u64()?, Some(1));
+
+        doc.apply(&doc.cursor().field("opened")?.assign_u64(5)?)?;
+        doc2.apply(&doc2.cursor().field("opened")?.assign_u64(3)?)?;
+        sync(&mut sdk, &mut sdk2)?;
+        sync(&mut sdk2, &mut sdk)?;
+        let winner = if peer > peer2 { 5 } else { 3 };
+        for doc in [&doc, &doc2] {
+            let values = doc.cursor().field("opened")?.u64s()?;
+            assert_eq!(values.collect::<Result<Vec<_>>>()?, vec![winner]);
+            assert_eq!(doc.cursor().field("opened")?.merged_u64()?, Some(winner));
+        }
+
+        doc2.apply(&doc2.cursor().field("opened")?.assign_u64(0)?)?;
+        sync(&mut sdk2, &mut sdk)?;
+        assert_eq!(doc.cursor().field("opened")?.merged_u64()?, Some(0));
+
+        // undo restores the previous value with a clock ahead of the current one.
+        let undo = doc.undo()?.unwrap();
+        assert!(!undo.is_empty());
+        assert_eq!(doc.cursor().field("opened")?.merged_u64()?, Some(1));
+        sync(&mut sdk, &mut sdk2)?;
+        assert_eq!(doc2.cursor().field("opened")?.merged_u64()?, Some(1));
+
+        doc2.apply(&doc2.cursor().field("opened")?.assign_u64(7)?)?;
+        sync(&mut sdk2, &mut sdk)?;
+        assert_eq!(doc.cursor().field("opened")?.merged_u64()?, Some(7));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invariants() -> Result<()> {
+        let packages = r#"
+            profile {
+                0.1.0 {
+                    .: Struct
+                    .stars: MVReg<u64> check(range(1, 5))
+                    .offset: MVReg<i64> check(range(-2, 2))
+                    .name: MVReg<String> check(maxlen(4))
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "profile", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        assert!(doc.cursor().field("stars")?.assign_u64(0).is_err());
+        assert!(doc.cursor().field("stars")?.assign_u64(6).is_err());
+        assert!(doc.cursor().field("offset")?.assign_i64(-3).is_err());
+        assert!(doc.cursor().field("name")?.assign_str("alice").is_err());
+        let mut causal = doc.cursor().field("stars")?.assign_u64(5)?;
+        causal.join(&doc.cursor().field("offset")?.assign_i64(-2)?);
+        causal.join(&doc.cursor().field("name")?.assign_str("bob")?);
+        doc.apply(&causal)?;
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "profile")?;
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let delta = sdk.unjoin(&peer2, doc2.id(), ctx.as_ref())?;
+        let hash = sdk2.frontend().registry.lookup("profile").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        assert_eq!(doc2.cursor().field("stars")?.u64s()?.next().unwrap()?, 5);
+        assert_eq!(doc2.cursor().field("name")?.strs()?.next().unwrap()?, "bob");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_encrypted_subtree() -> Result<()> {
+        let packages = r#"
+            notes {
+                0.1.0 {
+                    .: Struct
+                    .title: MVReg<String>
+                    .secret: Struct
+                    .secret.body: Encrypted<String>
+                    .secret.pin: Encrypted<u64>
+                }
+            }
+        "#;
+        let key = [7; 32];
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "notes", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let mut body = doc.cursor();
+        body.field("secret")?.field("body")?;
+        assert!(body.is_encrypted());
+        assert!(body.assign_str("hi").is_err());
+        let mut secret = doc.cursor();
+        secret.field("secret")?.set_encryption_key(key);
+        assert!(secret.at_field("pin")?.assign_str("1234").is_err());
+        let mut causal = doc.cursor().field("title")?.assign_str("hello")?;
+        causal.join(&secret.at_field("body")?.assign_str("hi")?);
+        causal.join(&secret.at_field("pin")?.assign_u64(1234)?);
+        doc.apply(&causal)?;
+        assert_eq!(secret.at_field("body")?.strs()?.next().unwrap()?, "hi");
+        assert_eq!(secret.at_field("pin")?.u64s()?.next().unwrap()?, 1234);
+        assert!(secret.at_field("pin")?.merged_u64().is_err());
+        assert!(body.strs().is_err());
+
+        // values encrypted with another key are skipped
+        let mut other = doc.cursor();
+        other.field("secret")?.set_encryption_key([8; 32]);
+        assert_eq!(other.at_field("body")?.strs()?.count(), 0);
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "notes")?;
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let delta = sdk.unjoin(&peer2, doc2.id(), ctx.as_ref())?;
+        let hash = sdk2.frontend().registry.lookup("notes").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        assert_eq!(
+            doc2.cursor().field("title")?.strs()?.next().unwrap()?,
+            "hello"
+        );
+        assert!(doc2
+            .cursor()
+            .field("secret")?
+            .field("body")?
+            .strs()
+            .is_err());
+        let mut secret2 = doc2.cursor();
+        secret2.field("secret")?.set_encryption_key(key);
+        assert_eq!(secret2.at_field("body")?.strs()?.next().unwrap()?, "hi");
+        assert_eq!(secret2.at_field("pin")?.u64s()?.next().unwrap()?, 1234);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_barrier() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Struct } }")?;
+        let a = sdk.frontend().generate_keypair()?;
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(a, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        doc.apply(&doc.cursor().say_can(Some(b), Permission::Read)?)?;
+        let barrier = sdk.frontend().barrier()?;
+        assert!(!doc.cursor().can(&b, Permission::Read)?);
+        Pin::new(&mut sdk).await?;
+        barrier.await;
+        let doc2 = sdk.frontend().doc(*doc.id())?;
+        assert!(doc2.cursor().can(&b, Permission::Read)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_scoped_subscriptions() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Struct } }")?;
+        let mut docs = sdk.frontend().subscribe_docs();
+        let mut schemas = sdk.frontend().subscribe_schemas();
+        let peer = sdk.frontend().generate_keypair()?;
+        assert!(docs.next().now_or_never().is_none());
+
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert!(docs.next().now_or_never().is_some());
+        assert!(schemas.next().now_or_never().is_some());
+
+        let mut meta = sdk.frontend().subscribe_doc(doc.id());
+        sdk.frontend().generate_keypair()?;
+        assert!(meta.next().now_or_never().is_none());
+        sdk.frontend().remove_doc(doc.id())?;
+        assert!(meta.next().now_or_never().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_subscribe_acls() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Struct } }")?;
+        let mut acls = sdk.frontend().subscribe_acls();
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert_eq!(acls.next().await, Some(*doc.id()));
+        assert!(acls.next().now_or_never().is_none());
+
+        let peer2 = sdk.frontend().generate_keypair()?;
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        assert_eq!(acls.next().await, Some(*doc.id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_subscribe_acl() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Struct } }")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mut acl = doc.subscribe_acl();
+
+        let peer2 = sdk.frontend().generate_keypair()?;
+        let grant = doc.cursor().say_can(Some(peer2), Permission::Write)?;
+        doc.apply(&grant)?;
+        Pin::new(&mut sdk).await?;
+        let batch = acl.next().await.unwrap();
+        let events = batch.into_iter().collect::<Vec<_>>();
+        assert!(events.iter().any(|ev| matches!(
+            ev,
+            Event::Granted(_, Some(peer), Permission::Write) if *peer == peer2
+        )));
+
+        let claim = grant.store.iter().next().unwrap().as_path().dot();
+        doc.apply(&doc.cursor().revoke(claim)?)?;
+        Pin::new(&mut sdk).await?;
+        let batch = acl.next().await.unwrap();
+        let events = batch.into_iter().collect::<Vec<_>>();
+        assert!(events.iter().any(|ev| matches!(
+            ev,
+            Event::Revoked(_, Some(peer)) if *peer == peer2
+        )));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_apply_batch() -> Result<()> {
+        let mut sdk = Backend::test(
+            "todoapp { 0.1.0 { .: Struct .todos: Table<u64> .todos.{}: MVReg<String> } }",
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let peer2 = sdk.frontend().generate_keypair()?;
+        let todos = doc.cursor().at_field("todos")?;
+        let mut batch = vec![doc.cursor().say_can(Some(peer2), Permission::Write)?];
+        for (key, title) in ["a", "b", "c"].iter().enumerate() {
+            batch.push(todos.at_key_u64(key as u64)?.assign_str(title)?);
+        }
+        doc.apply_batch(&batch)?;
+        Pin::new(&mut sdk).await?;
+        assert_eq!(todos.keys_u64()?.count(), 3);
+        assert!(doc.cursor().can(&peer2, Permission::Write)?);
+
+        let claim = batch[0].store.iter().next().unwrap().as_path().dot();
+        doc.apply_batch(&[doc.cursor().revoke(claim)?, todos.at_key_u64(0)?.remove()?])?;
+        Pin::new(&mut sdk).await?;
+        assert_eq!(todos.keys_u64()?.count(), 2);
+        assert!(!doc.cursor().can(&peer2, Permission::Write)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_undo() -> Result<()> {
+        let mut sdk =
+            Backend::test("todoapp { 0.1.0 { .: Struct .title: MVReg<String> .body: Text } }")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let title = doc.cursor().at_field("title")?;
+        let body = doc.cursor().at_field("body")?;
+        let titles = || -> Result<Vec<String>> { title.strs()?.collect() };
+
+        doc.apply(&title.assign_str("a")?)?;
+        doc.apply(&title.assign_str("b")?)?;
+        let peer2 = sdk.frontend().generate_keypair()?;
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.undo()?.is_some());
+        assert_eq!(titles()?, vec!["a".to_string()]);
+        assert!(doc.cursor().can(&peer2, Permission::Read)?);
+        assert!(doc.undo()?.is_some());
+        assert!(titles()?.is_empty());
+        assert!(doc.undo()?.is_none());
+        assert!(doc.redo()?.is_some());
+        assert!(doc.redo()?.is_some());
+        assert_eq!(titles()?, vec!["b".to_string()]);
+        assert!(doc.redo()?.is_none());
+
+        doc.apply(&body.insert_str(0, "hello")?)?;
+        doc.apply(&body.delete_range(1, 3)?)?;
+        assert_eq!(body.text()?, "ho");
+        let undo = doc.undo()?.unwrap();
+        assert_eq!(undo.store().iter().count(), 3);
+        assert_eq!(body.text()?, "hello");
+        doc.apply(&body.insert_str(5, "!")?)?;
+        assert!(doc.redo()?.is_none());
+        assert!(doc.undo()?.is_some());
+        assert_eq!(body.text()?, "hello");
+
+        sdk.frontend().remove_doc(doc.id())?;
+        assert!(!sdk.frontend().history.lock().contains_key(doc.id()));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_journal() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }")?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let frontend = sdk.frontend();
+        assert!(frontend.journal().next().is_none());
+
+        let op = doc.cursor().field("title")?.assign_str("title")?;
+        let entry = doc.apply_journaled(&op)?;
+        let journal = frontend.journal().collect::<Result<Vec<_>>>()?;
+        assert_eq!(journal, vec![(*doc.id(), entry, op.clone())]);
+        let op2 = doc.cursor().field("title")?.assign_str("title2")?;
+        let entry2 = doc.apply_journaled(&op2)?;
+        let journal = frontend.journal().collect::<Result<Vec<_>>>()?;
+        assert_eq!(journal.len(), 2);
+        // the paths of a journaled change are read from the store
+        assert!(journal.contains(&(*doc.id(), entry, Causal::default())));
+        assert!(journal.contains(&(*doc.id(), entry2, op2.clone())));
+
+        // a peer acknowledged the change
+        frontend.journal_remove(doc.id(), &entry)?;
+        assert_eq!(frontend.journal().count(), 1);
+        frontend.remove_doc(doc.id())?;
+        assert!(frontend.journal().next().is_none());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_rollback_author() -> Result<()> {
+        let mut sdk = Backend::test("todoapp { 0.1.0 { .: Table<u64> .{}: MVReg<String> } }")?;
+        let peer = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mallory = sdk.frontend().generate_keypair()?;
+        doc.apply(&doc.cursor().say_can(Some(mallory), Permission::Write)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk.frontend().doc_as(*doc.id(), &mallory)?;
+
+        doc.apply(&doc.cursor().key_u64(0)?.assign_str("a")?)?;
+        doc2.apply(&doc2.cursor().key_u64(1)?.assign_str("b")?)?;
+        let ctx = doc.ctx()?;
+        doc2.apply(&doc2.cursor().key_u64(2)?.assign_str("c")?)?;
+        doc2.apply(&doc2.cursor().key_u64(3)?.assign_str("d")?)?;
+
+        assert!(doc2.rollback_author(&mallory, &ctx).is_err());
+        let rollback = doc.rollback_author(&mallory, &ctx)?;
+        assert_eq!(rollback.expired().iter().count(), 2);
+        doc.apply(&rollback)?;
+        let keys = doc.cursor().keys_u64()?.collect::<Vec<_>>();
+        assert_eq!(keys, vec![0, 1]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_doc_with_schema() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }
+            todoplugin { 0.1.0 { .: Struct .title: MVReg<String> .done: EWFlag } }
+            renamer { 0.1.0 { .: Struct .title: MVReg<String> } 0.1.1 { .title.rename(name) } }
+            other { 0.1.0 { .: Table<u64> } }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let frontend = sdk.frontend();
+        assert!(frontend.doc_with_schema(*doc.id(), "other").is_err());
+        assert!(frontend.doc_with_schema(*doc.id(), "renamer").is_err());
+
+        let plugin = frontend.doc_with_schema(*doc.id(), "todoplugin")?;
+        assert_eq!(doc.schema()?.as_ref().name(), "todoapp");
+        plugin.apply(&plugin.cursor().field("title")?.assign_str("title")?)?;
+        let done = plugin.cursor().field("done")?.enable()?;
+        plugin.apply(&done)?;
+        assert!(plugin.cursor().field("done")?.enabled()?);
+        let title = doc.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "title");
+        doc.apply(&doc.cursor().field("title")?.assign_str("title2")?)?;
+
+        // the fields of the plugin aren't replicated.
+        let ctx = Ref::archive(&CausalContext::default());
+        let delta = sdk.unjoin(&peer, doc.id(), ctx.as_ref())?;
+        assert!(!delta.store().is_empty());
+        assert!(done
+            .store()
+            .iter()
+            .all(|path| !delta.store().contains(path.as_path())));
+
+        // the doc can still be opened through the schema it was created with.
+        let app = frontend.doc_with_schema(*doc.id(), "todoapp")?;
+        assert_eq!(doc.schema()?.as_ref().name(), "todoapp");
+        let title = app.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "title2");
+        assert!(app.cursor().field("done").is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_export_snapshot() -> Result<()> {
+        let packages = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.apply(&doc.cursor().field("title")?.assign_str("backup")?)?;
+        doc.apply(&doc.cursor().field("title")?.assign_str("restore")?)?;
+        Pin::new(&mut sdk).await?;
+        let snapshot = sdk.export_snapshot(doc.id())?;
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        assert!(sdk2.import_snapshot(&snapshot[1..]).is_err());
+
+        // paths are joined as their signer, forged signatures are rejected.
+        let mut forged = Ref::<DocSnapshot>::checked(&snapshot)?.to_owned()?;
+        let title = forged
+            .causal
+            .store
+            .iter()
+            .find(|buf| !is_policy(buf.as_path()))
+            .unwrap();
+        let mut path = title.as_path();
+        split_sig(&mut path).unwrap();
+        let mallory = Keypair::generate();
+        let sig = mallory.sign(path.as_ref());
+        let mut path = path.to_owned();
+        path.peer(&mallory.peer_id());
+        let mut invalid = path.clone();
+        path.sig(sig);
+        invalid.sig(Keypair::generate().sign(invalid.as_ref()));
+        forged.causal.store.insert(path);
+        let mut invalid_snapshot = Ref::<DocSnapshot>::checked(&snapshot)?.to_owned()?;
+        invalid_snapshot.causal.store.insert(invalid);
+        let invalid_snapshot = Ref::archive(&invalid_snapshot);
+        assert!(sdk2.import_snapshot(invalid_snapshot.as_bytes()).is_err());
+        assert!(!sdk2.contains(doc.id())?);
+        let forged = Ref::archive(&forged);
+        assert_eq!(sdk2.import_snapshot(forged.as_bytes())?, *doc.id());
+        assert_eq!(
+            sdk2.frontend().ctx(doc.id())?,
+            sdk.frontend().ctx(doc.id())?
+        );
+
+        assert_eq!(sdk2.import_snapshot(&snapshot)?, *doc.id());
+        let doc2 = sdk2.frontend().doc(*doc.id())?;
+        assert_eq!(sdk2.frontend().peer_id(doc.id())?, peer2);
+        let title = doc2
+            .cursor()
+            .field("title")?
+            .strs()?
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(title, vec!["restore".to_string()]);
+        assert!(doc2.cursor().can(&peer, Permission::Own)?);
+        assert_eq!(
+            sdk2.frontend().ctx(doc.id())?,
+            sdk.frontend().ctx(doc.id())?
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_encrypted_keystore() -> Result<()> {
+        let storage = Arc::new(MemStorage::default());
+        let package = Ref::archive(&tlfsc::compile_lenses("todoapp {}")?);
+        let sdk = Backend::new(storage.clone(), package.as_bytes())?;
+        let keypair = sdk.frontend().default_keypair()?;
+        let peer = keypair.peer_id();
+        assert!(!sdk.frontend().is_locked()?);
+        sdk.frontend().encrypt_keystore("password")?;
+        assert!(sdk.frontend().encrypt_keystore("password").is_err());
+        // the plaintext keypair is removed from storage.
+        let mut raw = vec![];
+        storage.load("docs", Box::new(|data| raw.extend_from_slice(data)))?;
+        assert!(!raw.windows(32).any(|w| w == keypair.as_ref()));
+        let peer2 = sdk.frontend().generate_keypair()?;
+
+        sdk.frontend().lock();
+        assert!(sdk.frontend().is_locked()?);
+        assert!(sdk.frontend().keypair(&peer).is_err());
+        assert!(sdk.frontend().generate_keypair().is_err());
+        assert!(sdk.frontend().unlock("wrong").is_err());
+        sdk.frontend().unlock("password")?;
+        assert_eq!(sdk.frontend().keypair(&peer)?.peer_id(), peer);
+        sdk.sync().await?;
+        drop(sdk);
+
+        let sdk = Backend::new(storage, package.as_bytes())?;
+        assert!(sdk.frontend().is_locked()?);
+        assert!(sdk.frontend().default_keypair().is_err());
+        sdk.frontend().unlock("password")?;
+        assert_eq!(sdk.frontend().default_keypair()?.peer_id(), peer);
+        assert_eq!(sdk.frontend().keypair(&peer2)?.peer_id(), peer2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_schema_upgrades() -> Result<()> {
+        let v1 = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let v2 =
+            "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } 0.2.0 { .complete: EWFlag } }";
+        let storage = Arc::new(MemStorage::default());
+        let v1 = Ref::archive(&tlfsc::compile_lenses(v1)?);
+        let mut sdk = Backend::new(storage.clone(), v1.as_bytes())?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let from = doc.schema()?.as_ref().version();
+        assert!(sdk
+            .frontend()
+            .subscribe_upgrades()
+            .next()
+            .now_or_never()
+            .is_none());
+        sdk.sync().await?;
+        drop(sdk);
+
+        let v2 = Ref::archive(&tlfsc::compile_lenses(v2)?);
+        let sdk = Backend::new(storage, v2.as_bytes())?;
+        let mut doc = sdk.frontend().doc(*doc.id())?;
+        let to = doc.schema()?.as_ref().version();
+        assert!(to > from);
+        let mut upgrades = sdk.frontend().subscribe_upgrades();
+        let upgrade = upgrades.next().await.unwrap();
+        assert_eq!(
+            upgrade,
+            SchemaUpgraded {
+                doc: *doc.id(),
+                from,
+                to
+            }
+        );
+        assert!(upgrades.next().now_or_never().is_none());
+        doc.refresh()?;
+        doc.apply(&doc.cursor().field("complete")?.enable()?)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_refresh() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }
+            todoplugin { 0.1.0 { .: Struct .title: MVReg<String> .done: EWFlag } }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let mut doc = fut.await;
+        let frontend = sdk.frontend();
+        let mut upgrades = frontend.subscribe_upgrades();
+        // opening a doc through a plugin doesn't upgrade it.
+        frontend.doc_with_schema(*doc.id(), "todoplugin")?;
+        assert!(upgrades.next().now_or_never().is_none());
+        doc.refresh()?;
+        assert_eq!(doc.schema()?.as_ref().name(), "todoapp");
+        assert!(doc.cursor().field("done").is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_join_denied() -> Result<()> {
+        let packages = r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> .done: EWFlag } }
+        "#;
+        // both peers claim ownership of the same doc, so each one drops the changes of the other.
+        let la = Keypair::generate();
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk.frontend().create_doc(peer, "todoapp", la)?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let fut = sdk2.frontend().create_doc(peer2, "todoapp", la)?;
+        Pin::new(&mut sdk2).await?;
+        let doc2 = fut.await;
+        let mut causal = doc2.cursor().field("title")?.assign_str("title")?;
+        causal.join(&doc2.cursor().field("title")?.assign_str("title2")?);
+        causal.join(&doc2.cursor().field("done")?.enable()?);
+        let hash = sdk.frontend().registry.lookup("todoapp").unwrap().1;
+        let denied = sdk.join(&peer2, doc.id(), &hash, causal)?;
+        assert!(doc.cursor().field("title")?.strs()?.next().is_none());
+
+        let mut prefixes = denied
+            .iter()
+            .map(|d| {
+                assert_eq!(d.doc, *doc.id());
+                assert_eq!(d.peer, peer2);
+                assert_eq!(d.permission, Permission::Write);
+                d.prefix.as_path().last().unwrap().prim_string().unwrap()
+            })
+            .collect::<Vec<_>>();
+        prefixes.sort();
+        assert_eq!(prefixes, vec!["done", "title"]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_join_foreign_doc() -> Result<()> {
+        let packages = r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let other = fut.await;
+
+        let causal = other.cursor().field("title")?.assign_str("title")?;
+        let hash = sdk.frontend().registry.lookup("todoapp").unwrap().1;
+        let err = sdk
+            .join(&peer, doc.id(), &hash, causal.clone())
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidChange>(),
+            Some(&InvalidChange::ForeignPaths)
+        );
+        sdk.join(&peer, other.id(), &hash, causal)?;
+        assert!(other.cursor().field("title")?.strs()?.next().is_some());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_join_invalid_schema() -> Result<()> {
+        let packages = r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }
+            counter { 0.1.0 { .: Struct .count: MVReg<u64> } }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let causal = doc.cursor().field("title")?.assign_str("title")?;
+        let hash = sdk.frontend().registry.lookup("counter").unwrap().1;
+        let err = sdk.join(&peer, doc.id(), &hash, causal).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<InvalidChange>(),
+            Some(&InvalidChange::InvalidSchema)
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_doc_state() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }
+        "#,
+        )?;
+        let frontend = sdk.frontend();
+        let mut events = frontend.subscribe_lifecycle();
+        let peer = frontend.default_keypair()?.peer_id();
+        let fut = frontend.create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert_eq!(doc.state()?, DocState::Active);
+        assert_eq!(events.next().await, Some((*doc.id(), DocState::Active)));
+
+        frontend.archive_doc(doc.id())?;
+        assert_eq!(doc.state()?, DocState::Archived);
+        assert_eq!(events.next().await, Some((*doc.id(), DocState::Archived)));
+        let title = doc.cursor().field("title")?.assign_str("title")?;
+        assert!(doc.apply(&title).is_err());
+        frontend.unarchive_doc(doc.id())?;
+        assert_eq!(events.next().await, Some((*doc.id(), DocState::Active)));
+        doc.apply(&title)?;
+
+        frontend.remove_doc(doc.id())?;
+        assert_eq!(doc.state()?, DocState::Removed);
+        assert_eq!(events.next().await, Some((*doc.id(), DocState::Removed)));
+        let title = doc.cursor().field("title")?.assign_str("title2")?;
+        assert!(doc.apply(&title).is_err());
+        assert!(doc.cursor().field("title")?.strs()?.next().is_none());
+        assert!(frontend.archive_doc(doc.id()).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_removed_state_cleared_on_restart() -> Result<()> {
+        let storage = Arc::new(MemStorage::default());
+        let package = Ref::archive(&tlfsc::compile_lenses(
+            "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }",
+        )?);
+        let mut sdk = Backend::new(storage.clone(), package.as_bytes())?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        sdk.frontend().remove_doc(doc.id())?;
+        assert_eq!(sdk.frontend().doc_state(doc.id())?, DocState::Removed);
+        sdk.sync().await?;
+        drop(sdk);
+
+        let sdk = Backend::new(storage, package.as_bytes())?;
+        assert!(sdk.frontend().doc_state(doc.id()).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_all_docs() -> Result<()> {
+        let mut sdk = Backend::test("notes { 0.1.0 { .: Struct } } flags { 0.1.0 { .: EWFlag } }")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "notes", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let a = fut.await;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "flags", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let b = fut.await;
+        let mut docs = sdk.frontend().all_docs().collect::<Result<Vec<_>>>()?;
+        docs.sort();
+        let mut expected = vec![
+            (*a.id(), "notes".to_string(), 1),
+            (*b.id(), "flags".to_string(), 1),
+        ];
+        expected.sort();
+        assert_eq!(docs, expected);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_cursor_at() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .todos: Table<u64>
+                    .todos.{}: Struct
+                    .todos.{}.title: MVReg<String>
+                    .todos.{}.complete: EWFlag
+                    .tags: Array
+                    .tags.[]: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let todos = doc.cursor().at_field("todos")?;
+        let todo = todos.at_key_u64(0)?;
+        doc.apply(&todo.at_field("title")?.assign_str("title")?)?;
+        doc.apply(&todo.at_field("complete")?.enable()?)?;
+
+        assert!(todo.at_field("complete")?.enabled()?);
+        let title = todo.at_field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "title");
+        let keys = todos.keys_u64()?.collect::<BTreeSet<_>>();
+        assert_eq!(keys.into_iter().collect::<Vec<_>>(), vec![0]);
+        assert!(todo.at_field("todos").is_err());
+
+        // moving a clone leaves the shared path untouched
+        let mut clone = todos.clone();
+        clone.key_u64(1)?;
+        assert_eq!(todos.path(), doc.cursor().at_field("todos")?.path());
+        assert_eq!(clone.path(), todos.at_key_u64(1)?.path());
+
+        let tags = doc.cursor().at_field("tags")?;
+        doc.apply(&tags.at_index(0)?.assign_str("a")?)?;
+        doc.apply(&tags.at_index(1)?.assign_str("b")?)?;
+        assert_eq!(tags.len()?, 2);
+        let mut first = tags.at_index(0)?;
+        let mut clone = first.clone();
+        // the array stack of the clone is copied when the clone leaves the array
+        clone.delete()?;
+        doc.apply(&first.delete()?)?;
+        assert_eq!(tags.len()?, 1);
+        let tag = tags.at_index(0)?.strs()?.next().unwrap()?;
+        assert_eq!(tag, "b");
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_path_string() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .lists: Table<String>
+                    .lists.{}: Array
+                    .lists.{}.[]: Struct
+                    .lists.{}.[].title: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let list = doc.cursor().at_field("lists")?.at_key_str("a/b~c")?;
+        for title in ["first", "second"] {
+            let ix = list.len()? as usize;
+            doc.apply(&list.at_index(ix)?.field("title")?.assign_str(title)?)?;
+        }
+        let title = list.at_index(1)?.at_field("title")?;
+        let pointer = title.path_string()?;
+        assert_eq!(pointer, "/lists/a~1b~0c/1/title");
+        let restored = doc.cursor_at(&pointer)?;
+        assert_eq!(restored.strs()?.next().unwrap()?, "second");
+        assert_eq!(doc.cursor().path_string()?, "");
+        assert_eq!(doc.cursor_at("")?.path_string()?, "");
+
+        doc.apply(&list.at_index(0)?.delete()?)?;
+        assert_eq!(title.path_string()?, "/lists/a~1b~0c/0/title");
+        assert!(doc.cursor_at(&pointer).is_err());
+        assert!(doc.cursor_at("lists").is_err());
+        assert!(doc.cursor_at("/missing").is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_create_doc_with() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                    .todos: Table<u64>
+                    .todos.{}: Struct
+                    .todos.{}.title: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let peer2 = sdk.frontend().generate_keypair()?;
+        let fut =
+            sdk.frontend()
+                .create_doc_with(peer, "todoapp", Keypair::generate(), |cursor| {
+                    let mut causal = cursor.say_can(Some(peer2), Permission::Read)?;
+                    cursor.field("todos")?.key_u64(0)?.field("title")?;
+                    causal.join(&cursor.assign_str("title")?);
+                    Ok(causal)
+                })?;
+        let id = sdk.frontend().docs().next().unwrap()?;
+        let title = sdk
+            .frontend()
+            .doc(id)?
+            .cursor()
+            .at_field("todos")?
+            .at_key_u64(0)?
+            .at_field("title")?
+            .strs()?
+            .next()
+            .unwrap()?;
+        assert_eq!(title, "title");
+
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert!(doc.cursor().can(&peer, Permission::Own)?);
+        assert!(doc.cursor().can(&peer2, Permission::Read)?);
+        assert!(!doc.cursor().can(&peer2, Permission::Write)?);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_wait_until_can() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Struct
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let peer2 = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        doc.wait_until_can(peer, Permission::Own).await?;
+
+        let mut wait = Box::pin(doc.wait_until_can(peer2, Permission::Write));
+        assert!(futures::poll!(&mut wait).is_pending());
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Write)?)?;
+        assert!(futures::poll!(&mut wait).is_pending());
+        Pin::new(&mut sdk).await?;
+        wait.await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Frontend>();
+        assert_send_sync::<Doc>();
+        assert_send_sync::<Cursor<'static>>();
+        assert_send_sync::<Causal>();
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_apply() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<u64>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let threads = (0..4u64)
+            .map(|i| {
+                let doc = doc.clone();
+                std::thread::spawn(move || -> Result<()> {
+                    for j in 0..25 {
+                        let key = i * 25 + j;
+                        doc.apply(&doc.cursor().key_u64(key)?.assign_u64(key)?)?;
+                        if key % 2 == 1 {
+                            doc.apply(&doc.cursor().key_u64(key)?.remove()?)?;
+                        }
+                    }
+                    Ok(())
+                })
+            })
+            .collect::<Vec<_>>();
+        let cursor = doc.cursor();
+        while threads.iter().any(|t| !t.is_finished()) {
+            cursor.keys_u64()?.count();
+        }
+        for thread in threads {
+            thread.join().unwrap()?;
+        }
+        let keys = doc.cursor().keys_u64()?.collect::<BTreeSet<_>>();
+        assert_eq!(keys, (0..100).step_by(2).collect::<BTreeSet<_>>());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_metadata() -> Result<()> {
+        let packages = r#"
+            notes {
+                0.1.0 {
+                    .: Table<String>
+                    .{}: MVReg<String>
+                }
+            }
+        "#;
+        let mut sdk = Backend::test(packages)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "notes", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let created_at = doc
+            .metadata()
+            .field("created_at")?
+            .u64s()?
+            .next()
+            .unwrap()?;
+        assert!(created_at > 0);
+        doc.apply(&doc.metadata().field("title")?.assign_str("Groceries")?)?;
+        doc.apply(&doc.cursor().key_str("METADATA")?.assign_str("milk")?)?;
+        assert_eq!(
+            doc.cursor().keys_str()?.collect::<Vec<_>>(),
+            vec!["METADATA"]
+        );
+
+        let mut sdk2 = Backend::test(packages)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "notes")?;
+        let ctx = Ref::archive(&doc2.ctx()?);
+        let delta = sdk.unjoin(&peer2, doc2.id(), ctx.as_ref())?;
+        let hash = sdk2.frontend().registry.lookup("notes").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        let title = doc2.metadata().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "Groceries");
+
+        // keys named `METADATA` written by older peers are moved to the escaped key.
+        let key = sdk.frontend().default_keypair()?;
+        let mut path = PathBuf::new();
+        path.doc(doc.id());
+        path.prim_str("METADATA");
+        path.nonce(nonce());
+        path.prim_str("eggs");
+        let sig = key.sign(path.as_ref());
+        path.peer(&peer);
+        path.sig(sig);
+        let mut old = Causal::default();
+        old.store.insert(path);
+        let hash = sdk.frontend().registry.lookup("notes").unwrap().1;
+        sdk.join(&peer, doc.id(), &hash, old)?;
+        let mut values = doc
+            .cursor()
+            .key_str("METADATA")?
+            .strs()?
+            .collect::<Result<Vec<_>>>()?;
+        values.sort();
+        assert_eq!(values, vec!["eggs".to_string(), "milk".to_string()]);
+        assert!(doc.cursor().keys_str()?.all(|key| key == "METADATA"));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_keys_range() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            notes {
+                0.1.0 {
+                    .: Table<String>
+                    .{}: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "notes", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        for key in ["a", "b", "METADATA", "c", "d"] {
+            doc.apply(&doc.cursor().key_str(key)?.assign_str(key)?)?;
+            doc.apply(&doc.cursor().key_str(key)?.assign_str("again")?)?;
+        }
+        let cursor = doc.cursor();
+        assert_eq!(cursor.len_keys()?, 5);
+
+        let mut keys = vec![];
+        let mut page = cursor.keys_range(Bound::Unbounded, 2)?;
+        while let Some(last) = page.last().cloned() {
+            assert!(page.len() <= 2);
+            keys.extend(page);
+            page = cursor.keys_range(Bound::Excluded(&last), 2)?;
+        }
+        let expected = cursor.keys_str()?.collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        let page = cursor.keys_range(Bound::Included(&expected[1]), 2)?;
+        assert_eq!(page, expected[1..3]);
+        assert!(cursor.keys_range(Bound::Unbounded, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_range_keys() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            buckets {
+                0.1.0 {
+                    .: Struct
+                    .ids: Table<u64>
+                    .ids.{}: MVReg<u64>
+                    .offsets: Table<i64>
+                    .offsets.{}: MVReg<i64>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "buckets", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        for key in [0, 3, 256, 1000, u64::MAX] {
+            doc.apply(&doc.cursor().field("ids")?.key_u64(key)?.assign_u64(key)?)?;
+        }
+        for key in [i64::MIN, -256, -1, 0, 7, 300] {
+            doc.apply(
+                &doc.cursor()
+                    .field("offsets")?
+                    .key_i64(key)?
+                    .assign_i64(key)?,
+            )?;
+        }
+
+        let ids = doc.cursor().field("ids")?.clone();
+        let range = |r: std::ops::RangeInclusive<u64>| -> Result<Vec<u64>> {
+            Ok(ids.range_u64(r)?.collect())
+        };
+        assert_eq!(range(0..=u64::MAX)?, vec![0, 3, 256, 1000, u64::MAX]);
+        assert_eq!(range(1..=1000)?, vec![3, 256, 1000]);
+        assert_eq!(range(4..=255)?, Vec::<u64>::new());
+        assert_eq!(ids.range_u64(3..1000)?.collect::<Vec<_>>(), vec![3, 256]);
+        assert_eq!(ids.range_u64(1001..)?.collect::<Vec<_>>(), vec![u64::MAX]);
+
+        let offsets = doc.cursor().field("offsets")?.clone();
+        assert_eq!(
+            offsets.range_i64(..)?.collect::<Vec<_>>(),
+            vec![i64::MIN, -256, -1, 0, 7, 300]
+        );
+        assert_eq!(
+            offsets.range_i64(-256..7)?.collect::<Vec<_>>(),
+            vec![-256, -1, 0]
+        );
+        assert_eq!(
+            offsets.range_i64(1..=300)?.collect::<Vec<_>>(),
+            vec![7, 300]
+        );
+        assert!(offsets.range_u64(..).is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_range_keys_root() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            buckets {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<u64>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "buckets", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        // the metadata of the document sorts between these keys.
+        let metadata = u64::from_be_bytes(*b"METADATA");
+        let keys = vec![0, metadata - 1, metadata, metadata + 1, u64::MAX];
+        for key in &keys {
+            doc.apply(&doc.cursor().key_u64(*key)?.assign_u64(*key)?)?;
+        }
+        let cursor = doc.cursor();
+        assert_eq!(cursor.range_u64(..)?.collect::<Vec<_>>(), keys);
+        assert_eq!(cursor.range_u64(metadata..)?.collect::<Vec<_>>(), keys[2..]);
+        assert_eq!(
+            cursor.range_u64(1..metadata)?.collect::<Vec<_>>(),
+            vec![metadata - 1]
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_scan() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<u64>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        for i in 0..10 {
+            doc.apply(&doc.cursor().key_u64(i)?.assign_u64(i)?)?;
+        }
+        let mut prefix = PathBuf::new();
+        prefix.prim_u64(3);
+        let page = doc.scan(prefix.as_path(), None, 10);
+        assert_eq!(page.paths.len(), 1);
+        assert!(page.token.is_none());
+
+        let mut paths = vec![];
+        let mut token = None;
+        loop {
+            let page = doc.scan(PathBuf::new().as_path(), token.as_deref(), 3);
+            assert!(page.paths.len() <= 3);
+            paths.extend(page.paths);
+            token = page.token;
+            if token.is_none() {
+                break;
+            }
+        }
+        // 10 values, the owner policy and the creation time
+        assert_eq!(paths.len(), 12);
+        assert!(paths.windows(2).all(|w| w[0] < w[1]));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_tags() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let causal = doc.cursor().key_u64(0)?.assign_str("todo")?;
+        let tagged = doc.cursor().tag(&causal, "addTodo")?;
+        let tagged = doc.cursor().tag(&tagged, "v1.0.0")?;
+        assert_eq!(tagged.id(), causal.id());
+        assert_eq!(tagged.tags().collect::<Vec<_>>(), vec!["addTodo", "v1.0.0"]);
+        assert!(doc.cursor().schema().validate(&tagged));
+        doc.apply(&tagged)?;
+        assert_eq!(
+            doc.tags(&causal.id()).collect::<Vec<_>>(),
+            vec!["addTodo", "v1.0.0"]
+        );
+        assert_eq!(doc.tags(&Causal::default().id()).count(), 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_snapshot() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let s0 = doc.snapshot();
+        doc.apply(&doc.cursor().key_u64(0)?.assign_str("a")?)?;
+        let s1 = doc.snapshot();
+        doc.apply(&doc.cursor().key_u64(0)?.assign_str("b")?)?;
+        let s2 = doc.snapshot();
+
+        assert!(s0.diff(&s0).is_empty());
+        let diff = s0.diff(&s1);
+        let added = diff.added().collect::<Vec<_>>();
+        assert_eq!(added.len(), 1);
+        assert_eq!(
+            added[0]
+                .as_path()
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .last()
+                .unwrap()
+                .prim_str(),
+            Some("a")
+        );
+        assert_eq!(diff.removed().count(), 0);
+
+        let diff = s1.diff(&s2);
+        assert_eq!(diff.added().count(), 1);
+        assert_eq!(diff.removed().collect::<Vec<_>>(), added);
+        assert_eq!(s2.paths().count(), s0.paths().count() + 1);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_expiry() -> Result<()> {
+        let mut sdk = Backend::test("todoapp {}")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        assert_eq!(sdk.frontend().ttl(doc.id())?, None);
+        sdk.frontend().set_ttl(doc.id(), Duration::from_secs(60))?;
+        let ttl = sdk.frontend().ttl(doc.id())?.unwrap();
+        assert!(ttl <= Duration::from_secs(60) && ttl >= Duration::from_secs(59));
+        sdk.frontend().set_ttl(doc.id(), Duration::MAX)?;
+        assert!(sdk.frontend().ttl(doc.id())?.unwrap() > Duration::from_secs(60));
+        sdk.frontend().set_ttl(doc.id(), Duration::ZERO)?;
+        assert_eq!(sdk.frontend().ttls(), vec![(*doc.id(), Duration::ZERO)]);
+        sdk.frontend().remove_doc(doc.id())?;
+        assert!(sdk.frontend().ttls().is_empty());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_delete_everywhere() -> Result<()> {
+        let mut sdk = Backend::test("todoapp {}")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let mut sdk2 = Backend::test("todoapp {}")?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let mut sdk3 = Backend::test("todoapp {}")?;
+        let peer3 = sdk3.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Write)?)?;
+        doc.apply(&doc.cursor().say_can(Some(peer3), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let hash = sdk2.frontend().registry.lookup("todoapp").unwrap().1;
+        let sync = |sdk: &mut Backend, from: &Backend, from_peer: &PeerId| -> Result<()> {
+            let ctx = Ref::archive(&sdk.frontend().ctx(doc.id())?);
+            let delta = from.unjoin(&peer2, doc.id(), ctx.as_ref())?;
+            sdk.join(from_peer, doc.id(), &hash, delta)?;
+            Ok(())
+        };
+        sync(&mut sdk2, &sdk, &peer)?;
+
+        // writers can't delete a document everywhere.
+        assert!(doc2.delete_everywhere(Duration::ZERO).is_err());
+        let forged = doc2.metadata().field("deleted_at")?.assign_u64(0)?;
+        let denied = sdk.join(&peer2, doc.id(), &hash, forged)?;
+        assert_eq!(denied[0].permission, Permission::Own);
+        assert_eq!(doc.deleted_at()?, None);
+
+        doc.apply(&doc.delete_everywhere(Duration::from_secs(60))?)?;
+        let deleted_at = doc.deleted_at()?.unwrap();
+        assert!(deleted_at >= crate::util::now() + 59);
+        sync(&mut sdk2, &sdk, &peer)?;
+        assert_eq!(doc2.deleted_at()?, Some(deleted_at));
+        assert_eq!(sdk2.frontend().ttls().len(), 1);
+
+        doc.apply(&doc.cancel_delete()?)?;
+        assert_eq!(doc.deleted_at()?, None);
+        sync(&mut sdk2, &sdk, &peer)?;
+        assert_eq!(doc2.deleted_at()?, None);
+        assert!(sdk2.frontend().ttls().is_empty());
+
+        doc.apply(&doc.delete_everywhere(Duration::ZERO)?)?;
+        sync(&mut sdk2, &sdk, &peer)?;
+        assert_eq!(sdk2.frontend().ttls(), vec![(*doc.id(), Duration::ZERO)]);
+
+        // a deletion signed by an owner is accepted when relayed by a writer.
+        sdk3.frontend().add_doc(*doc.id(), &peer3, "todoapp")?;
+        let ctx = Ref::archive(&sdk3.frontend().ctx(doc.id())?);
+        let delta = sdk2.unjoin(&peer3, doc.id(), ctx.as_ref())?;
+        assert!(sdk3.join(&peer2, doc.id(), &hash, delta)?.is_empty());
+        assert_eq!(sdk3.frontend().ttls(), vec![(*doc.id(), Duration::ZERO)]);
+
+        assert!(doc.delete_everywhere(Duration::from_secs(u64::MAX)).is_ok());
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics")]
+    #[async_std::test]
+    async fn test_metrics() -> Result<()> {
+        let mut sdk = Backend::test("todoapp {}")?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let metrics = sdk.metrics_snapshot();
+        assert_eq!(metrics.acl_updates, 1);
+        assert!(metrics.log_bytes > 0);
+        assert!(metrics.flushes > 0);
+
+        let mut sdk2 = Backend::test("todoapp {}")?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        let ctx = Ref::archive(&sdk2.frontend().ctx(doc.id())?);
+        let delta = sdk.unjoin(&peer2, doc.id(), ctx.as_ref())?;
+        sdk.metrics().record_broadcast();
+        let hash = sdk.frontend().registry.lookup("todoapp").unwrap().1;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        let metrics = sdk.metrics_snapshot();
+        assert_eq!(metrics.acl_updates, 2);
+        assert_eq!(metrics.unjoins, 1);
+        assert_eq!(metrics.broadcasts, 1);
+        assert_eq!(sdk2.metrics_snapshot().joins, 1);
+        Ok(())
+    }
+
+    /// Measures applying a value to a document with many policy statements. Run with
+    /// `cargo test -p tlfs-crdt bench_apply_with_policies -- --ignored --nocapture`.
+    #[async_std::test]
+    #[ignore]
+    async fn bench_apply_with_policies() -> Result<()> {
+        const POLICIES: usize = 100;
+        const APPLIES: u32 = 50;
+        let mut sdk = Backend::test(
+            r#"bench {
+                0.1.0 {
+                    .: Struct
+                    .count: MVReg<u64>
+                }
+            }"#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "bench", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        for _ in 0..POLICIES {
+            let peer = Keypair::generate().peer_id();
+            doc.apply(&doc.cursor().say_can(Some(peer), Permission::Read)?)?;
+        }
+        Pin::new(&mut sdk).await?;
+
+        let start = std::time::Instant::now();
+        for i in 0..APPLIES {
+            doc.apply(&doc.cursor().field("count")?.assign_u64(i as u64)?)?;
+            Pin::new(&mut sdk).await?;
+        }
+        println!(
+            "apply with {} policies: {:?} per apply",
+            POLICIES,
+            start.elapsed() / APPLIES
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_workspace() -> Result<()> {
+        let mut sdk = Backend::test("todoapp {}")?;
+        let a = sdk.frontend().default_keypair()?.peer_id();
+        let b = sdk.frontend().generate_keypair()?;
+        let fut = sdk
+            .frontend()
+            .create_doc(a, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let ws = fut.await;
+        let fut = sdk
+            .frontend()
+            .create_doc_with(a, "todoapp", Keypair::generate(), |cursor| {
+                cursor.inherit(ws.id())
+            })?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+
+        let causal = ws.cursor().add_member(doc.id(), "todoapp")?;
+        assert!(ws.cursor().schema().validate(&causal));
+        assert_eq!(
+            causal.members().collect::<Vec<_>>(),
+            vec![(*doc.id(), "todoapp".to_string())]
+        );
+        ws.apply(&causal)?;
+        assert_eq!(
+            ws.members().collect::<Vec<_>>(),
+            vec![(*doc.id(), "todoapp".to_string())]
+        );
+
+        assert!(!doc.cursor().can(&b, Permission::Write)?);
+        ws.apply(&ws.cursor().say_can(Some(b), Permission::Write)?)?;
+        Pin::new(&mut sdk).await?;
+        assert!(doc.cursor().can(&b, Permission::Write)?);
+        assert!(!doc.cursor().can(&b, Permission::Own)?);
+
+        let causal = ws.cursor().remove_member(doc.id())?;
+        assert!(ws.cursor().schema().validate(&causal));
+        ws.apply(&causal)?;
+        assert_eq!(ws.members().count(), 0);
+        Ok(())
+    }
 }
@@ -88,6 +88,11 @@ impl DotSet {
         self.0.insert(dot);
     }
 
+    /// Removes a [`Dot`] from the [`DotSet`].
+    pub fn remove(&mut self, dot: &Dot) {
+        self.0.remove(dot);
+    }
+
     /// Inserts all [`Dot`]s from a [`DotSet`].
     pub fn union(&mut self, other: &Self) {
         for dot in other.iter() {
@@ -1,3 +1,4 @@
+use anyhow::Result;
 use core::{fmt, ops::Index};
 use smallvec::{smallvec, SmallVec};
 
@@ -10,6 +11,8 @@ pub struct Fraction(SmallVec<[u8; 8]>);
 const DIGIT_BITS: u32 = 7;
 const DIGIT_MASK: usize = (1 << DIGIT_BITS) - 1;
 const DIGIT_MASK_U8: u8 = (1 << DIGIT_BITS) - 1;
+/// Number of digits derived from a salt, covering 63 of its bits.
+const SALT_DIGITS: u32 = 9;
 
 impl AsRef<[u8]> for Fraction {
     fn as_ref(&self) -> &[u8] {
@@ -112,6 +115,97 @@ impl Fraction {
         }
         Self::from_digits(digits)
     }
+    /// Computes a short number strictly between `lo` and `hi`, where `None` stands for the
+    /// bounds of the interval [0..1).
+    ///
+    /// Unlike [`Fraction::mid`] the result only has as many digits as needed to separate the
+    /// bounds. Between two existing numbers the gap is halved, while at the end of the
+    /// interval the digit next to the bound is picked so repeated appends and prepends only
+    /// add a digit once the digit range is exhausted.
+    ///
+    /// Fails if there is no number between the bounds, which happens when concurrent inserts
+    /// picked the same position.
+    pub fn between(lo: Option<&Self>, hi: Option<&Self>) -> Result<Self> {
+        if let Some(hi) = hi {
+            anyhow::ensure!(
+                lo.map(|lo| lo < hi).unwrap_or(*hi > Self::zero()),
+                "no fraction between {:?} and {}",
+                lo,
+                hi
+            );
+        }
+        let mut digits = SmallVec::new();
+        let mut hi_tight = hi.is_some();
+        for i in 0.. {
+            let l = lo.map(|lo| lo.digit(i)).unwrap_or_default();
+            let h = match hi {
+                Some(hi) if hi_tight => hi.digit(i),
+                _ => DIGIT_MASK + 1,
+            };
+            if h - l > 1 {
+                let d = match (lo, hi) {
+                    (Some(_), None) => l + 1,
+                    (None, Some(_)) => h - 1,
+                    _ => (l + h) / 2,
+                };
+                digits.push(d as u8);
+                break;
+            }
+            digits.push(l as u8);
+            hi_tight &= l == h;
+        }
+        Ok(Self::from_digits(digits))
+    }
+
+    /// Returns `n` evenly spaced numbers in increasing order using the fewest digits that still
+    /// leave a gap between neighbours.
+    pub fn spread(n: usize) -> Vec<Self> {
+        let base = 1u128 << DIGIT_BITS;
+        let mut len = 1;
+        let mut range = base;
+        while range < 2 * (n as u128 + 1) {
+            len += 1;
+            range *= base;
+        }
+        (1..=n as u128)
+            .map(|i| {
+                let mut x = i * range / (n as u128 + 1);
+                let mut digits = smallvec![0u8; len];
+                for digit in digits.iter_mut().rev() {
+                    *digit = (x % base) as u8;
+                    x /= base;
+                }
+                Self::from_digits(digits)
+            })
+            .collect()
+    }
+
+    /// Returns `n` increasing numbers strictly between `lo` and `hi` for a run of consecutive
+    /// elements.
+    ///
+    /// The run is placed behind a prefix derived from `salt`, so runs generated concurrently
+    /// for the same bounds with different salts don't interleave.
+    pub fn run(lo: Option<&Self>, hi: Option<&Self>, n: usize, salt: u64) -> Result<Vec<Self>> {
+        let mut prefix = Self::between(lo, hi)?.digits();
+        push_salt(&mut prefix, salt);
+        Ok(Self::spread(n)
+            .into_iter()
+            .map(|f| {
+                let mut digits = prefix.clone();
+                digits.extend(f.digits());
+                Self::from_digits(digits)
+            })
+            .collect())
+    }
+
+    /// Returns a fraction directly after `self`, which sorts before all greater fractions that
+    /// don't start with the digits of `self`.
+    pub fn extend(&self, salt: u64) -> Self {
+        let mut digits = self.digits();
+        push_salt(&mut digits, salt);
+        Self::from_digits(digits)
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         self.0.as_ref()
     }
@@ -132,6 +226,14 @@ impl Fraction {
     }
 }
 
+/// Appends digits derived from `salt`. Digits are never zero so that the result doesn't end
+/// in a zero digit.
+fn push_salt(digits: &mut SmallVec<[u8; 8]>, salt: u64) {
+    for i in 0..SALT_DIGITS {
+        digits.push((1 + (salt >> (i * DIGIT_BITS)) % DIGIT_MASK as u64) as u8);
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -147,6 +249,104 @@ mod tests {
         println!("{:?} < {:?} < {:?}", t, v, u);
     }
 
+    #[test]
+    fn between_growth() {
+        let n = 1000;
+        // appending
+        let mut last = Fraction::between(None, None).unwrap();
+        for _ in 0..n {
+            last = Fraction::between(Some(&last), None).unwrap();
+        }
+        assert!(last.0.len() <= n / 127 + 2);
+        // prepending
+        let mut first = Fraction::between(None, None).unwrap();
+        for _ in 0..n {
+            first = Fraction::between(None, Some(&first)).unwrap();
+        }
+        assert!(first.0.len() <= n / 127 + 2);
+        // inserting at the same index, before and after the previous insert
+        let lo = Fraction::between(None, None).unwrap();
+        let hi = Fraction::between(Some(&lo), None).unwrap();
+        let (mut before, mut after) = (hi.clone(), lo.clone());
+        for _ in 0..n {
+            before = Fraction::between(Some(&lo), Some(&before)).unwrap();
+            after = Fraction::between(Some(&after), Some(&hi)).unwrap();
+        }
+        assert!(before.0.len() <= n / 7 + 2);
+        assert!(after.0.len() <= n / 7 + 2);
+        // alternating between both ends of a shrinking gap
+        let (mut lo, mut hi) = (lo, hi);
+        for i in 0..n {
+            let m = Fraction::between(Some(&lo), Some(&hi)).unwrap();
+            if i % 2 == 0 {
+                lo = m;
+            } else {
+                hi = m;
+            }
+        }
+        assert!(lo.0.len().max(hi.0.len()) <= n / 7 + 2);
+    }
+
+    #[test]
+    fn extend_salt() {
+        let f = Fraction::half();
+        let a = f.extend(1);
+        let b = f.extend(1 | 1 << 40);
+        assert_ne!(a, b);
+        assert!(f < a && f < b);
+        assert!(a < f.succ() && b < f.succ());
+    }
+
+    #[test]
+    fn between_empty() {
+        let f = Fraction::half();
+        assert!(Fraction::between(Some(&f), Some(&f)).is_err());
+        assert!(Fraction::between(Some(&f.succ()), Some(&f)).is_err());
+        assert!(Fraction::between(None, Some(&Fraction::zero())).is_err());
+    }
+
+    #[test]
+    fn spread() {
+        for n in [0, 1, 2, 63, 64, 1000, 100_000] {
+            let fs = Fraction::spread(n);
+            assert_eq!(fs.len(), n);
+            assert!(fs.windows(2).all(|w| w[0] < w[1]));
+            if let (Some(first), Some(last)) = (fs.first(), fs.last()) {
+                assert!(Fraction::zero() < *first);
+                let len = fs.iter().map(|f| f.0.len()).max().unwrap();
+                assert!(128usize.pow(len as u32 - 1) < 2 * (n + 1));
+                // there is room left around every element
+                let _ = Fraction::between(None, Some(first)).unwrap();
+                let _ = Fraction::between(Some(last), None).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn run() {
+        let lo = Fraction::between(None, None).unwrap();
+        let hi = Fraction::between(Some(&lo), None).unwrap();
+        for (lo, hi) in [
+            (None, None),
+            (Some(&lo), None),
+            (None, Some(&hi)),
+            (Some(&lo), Some(&hi)),
+        ] {
+            let a = Fraction::run(lo, hi, 100, 1).unwrap();
+            let b = Fraction::run(lo, hi, 100, 2).unwrap();
+            for run in [&a, &b] {
+                assert!(run.windows(2).all(|w| w[0] < w[1]));
+                assert!(lo.map(|lo| *lo < run[0]).unwrap_or(true));
+                assert!(hi.map(|hi| run[99] < *hi).unwrap_or(true));
+            }
+            // the runs don't interleave
+            assert!(a[99] < b[0]);
+            // salts differing only in their high bits lead to different runs
+            let c = Fraction::run(lo, hi, 100, 1 | 1 << 40).unwrap();
+            assert!(a[99] < c[0] || c[99] < a[0]);
+        }
+    }
+
     fn arb_fraction() -> impl Strategy<Value = Fraction> {
         any::<Vec<u8>>().prop_map(|v| {
             let mut digits: SmallVec<[u8; 8]> = v.into();
@@ -194,6 +394,27 @@ mod tests {
             }
         }
 
+        #[test]
+        fn between(
+            mut a in arb_fraction(),
+            mut b in arb_fraction(),
+        ) {
+            if a > b {
+                std::mem::swap(&mut a, &mut b);
+            }
+            if a != b {
+                let m = Fraction::between(Some(&a), Some(&b)).unwrap();
+                prop_assert!(a < m && m < b);
+                prop_assert!(m.0.len() <= a.0.len().max(b.0.len()) + 1);
+            }
+            let m = Fraction::between(Some(&a), None).unwrap();
+            prop_assert!(a < m);
+            if b > Fraction::zero() {
+                let m = Fraction::between(None, Some(&b)).unwrap();
+                prop_assert!(Fraction::zero() < m && m < b);
+            }
+        }
+
         #[test]
         fn succ(
             a in arb_fraction(),
@@ -0,0 +1,136 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const MAC_LEN: usize = 32;
+const TAG_LEN: usize = 16;
+/// Number of bytes [`Keystore::encrypt`] adds to the plaintext.
+pub(crate) const OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// Key derived from a password, used to encrypt keypairs before they are written to disk.
+///
+/// The key is derived from the password with Argon2id, data is encrypted with
+/// XChaCha20-Poly1305 using a random nonce.
+#[derive(Clone)]
+pub(crate) struct Keystore {
+    enc: [u8; 32],
+    mac: [u8; 32],
+}
+
+impl std::fmt::Debug for Keystore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keystore").finish_non_exhaustive()
+    }
+}
+
+impl Keystore {
+    fn derive(password: &str, salt: &[u8]) -> Result<Self> {
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), salt, &mut key)
+            .map_err(|err| anyhow!("{}", err))?;
+        Ok(Self::from_key(&key))
+    }
+
+    /// Creates a keystore from a raw 32 byte key, for data that isn't protected by a password.
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        Self {
+            enc: blake3::derive_key("tlfs keystore encryption", key),
+            mac: blake3::derive_key("tlfs keystore authentication", key),
+        }
+    }
+
+    fn verifier(&self) -> blake3::Hash {
+        blake3::keyed_hash(&self.mac, b"verifier")
+    }
+
+    /// Creates a new keystore with a random salt. Returns the keystore and the metadata
+    /// needed to open it again.
+    pub fn generate(password: &str) -> Result<(Self, Vec<u8>)> {
+        let mut salt = [0; SALT_LEN];
+        getrandom::getrandom(&mut salt).map_err(|err| anyhow!("{}", err))?;
+        let keystore = Self::derive(password, &salt)?;
+        let mut meta = salt.to_vec();
+        meta.extend_from_slice(keystore.verifier().as_bytes());
+        Ok((keystore, meta))
+    }
+
+    /// Opens a keystore from it's metadata. Fails if the password is wrong.
+    pub fn open(password: &str, meta: &[u8]) -> Result<Self> {
+        if meta.len() != SALT_LEN + MAC_LEN {
+            return Err(anyhow!("invalid keystore metadata"));
+        }
+        let keystore = Self::derive(password, &meta[..SALT_LEN])?;
+        let verifier: [u8; MAC_LEN] = meta[SALT_LEN..].try_into()?;
+        // comparing `blake3::Hash`es is constant time.
+        if keystore.verifier() != blake3::Hash::from(verifier) {
+            return Err(anyhow!("wrong password"));
+        }
+        Ok(keystore)
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.enc))
+    }
+
+    /// Encrypts `plaintext` into `nonce | ciphertext | tag`. `aad` is authenticated but not
+    /// stored, the same `aad` has to be passed to [`Keystore::decrypt`].
+    pub fn encrypt(&self, plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).unwrap();
+        let ciphertext = self
+            .cipher()
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("plaintext too large");
+        let mut out = nonce.to_vec();
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Decrypts data returned from [`Keystore::encrypt`].
+    pub fn decrypt(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if data.len() < OVERHEAD {
+            return Err(anyhow!("invalid ciphertext"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| anyhow!("invalid ciphertext"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keystore() -> Result<()> {
+        let (keystore, meta) = Keystore::generate("password")?;
+        let ciphertext = keystore.encrypt(b"secret", b"aad");
+        assert_eq!(ciphertext.len(), 6 + OVERHEAD);
+        assert!(Keystore::open("wrong", &meta).is_err());
+        let keystore = Keystore::open("password", &meta)?;
+        assert_eq!(keystore.decrypt(&ciphertext, b"aad")?, b"secret");
+        assert!(keystore.decrypt(&ciphertext, b"other").is_err());
+
+        let mut tampered = ciphertext;
+        tampered[NONCE_LEN] ^= 1;
+        assert!(keystore.decrypt(&tampered, b"aad").is_err());
+        Ok(())
+    }
+}
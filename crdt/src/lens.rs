@@ -1,5 +1,5 @@
-use crate::path::{Path, PathBuf, Segment};
-use crate::schema::{PrimitiveKind, Schema};
+use crate::path::{is_reserved, Path, PathBuf, Segment};
+use crate::schema::{Invariant, Merge, PrimitiveKind, Schema, METADATA};
 use anyhow::{anyhow, Result};
 use bytecheck::CheckBytes;
 use rkyv::ser::serializers::AllocSerializer;
@@ -31,6 +31,18 @@ pub enum Kind {
     ///
     /// [Rinberg et al. 2021]: https://dl.acm.org/doi/10.1145/3447865.3457971
     Array,
+    /// ORSet with elements of [`PrimitiveKind`].
+    Set(PrimitiveKind),
+    /// Schema-less kind accepting any well formed path.
+    Dynamic,
+    /// PN-Counter whose value is the sum of all increments.
+    Counter,
+    /// Sequence of characters supporting concurrent inserts and deletes.
+    Text,
+    /// Last-writer-wins register with values of [`PrimitiveKind`].
+    LwwReg(PrimitiveKind),
+    /// MVReg with values of [`PrimitiveKind`] encrypted with a key shared out of band.
+    Encrypted(PrimitiveKind),
 }
 
 /// A [`Lens`] is a bidirectional transform on [`Schema`]s.
@@ -76,6 +88,22 @@ pub enum Lens {
         #[archive_attr(omit_bounds)]
         Box<Lens>,
     ),
+    /// Excludes a crdt from replication.
+    MakeLocal,
+    /// Includes a local crdt in replication.
+    MakeShared,
+    /// Resolves concurrent values of a [`Kind::Reg`] using [`Merge`].
+    AddMerge(Merge),
+    /// Removes the [`Merge`] strategy of a [`Kind::Reg`].
+    RemoveMerge(Merge),
+    /// Restricts the values of a [`Kind::Reg`] to those satisfying the [`Invariant`].
+    AddInvariant(Invariant),
+    /// Removes the [`Invariant`] of a [`Kind::Reg`].
+    RemoveInvariant(Invariant),
+    /// Converts a [`Kind::Reg`] into a [`Kind::LwwReg`].
+    MakeLww,
+    /// Converts a [`Kind::LwwReg`] into a [`Kind::Reg`].
+    MakeMultiValue,
 }
 
 impl Lens {
@@ -93,6 +121,23 @@ impl Lens {
 }
 
 impl ArchivedLens {
+    /// Returns true if the [`ArchivedLens`] only adds to a [`Schema`], so that every path valid
+    /// before applying it remains valid and unchanged afterwards.
+    pub fn is_additive(&self) -> bool {
+        match self {
+            Self::Make(_)
+            | Self::AddProperty(_)
+            | Self::AddMerge(_)
+            | Self::RemoveInvariant(_)
+            | Self::MakeLww
+            | Self::MakeMultiValue => true,
+            Self::LensIn(_, lens) | Self::LensMap(lens) | Self::LensMapValue(lens) => {
+                lens.is_additive()
+            }
+            _ => false,
+        }
+    }
+
     /// Returns a [`LensRef`] to an [`ArchivedLens`].
     pub fn to_ref(&self) -> LensRef<'_> {
         match self {
@@ -106,6 +151,14 @@ impl ArchivedLens {
             Self::LensIn(k, l) => LensRef::LensIn(false, k, l),
             Self::LensMap(l) => LensRef::LensMap(false, l),
             Self::LensMapValue(l) => LensRef::LensMapValue(false, l),
+            Self::MakeLocal => LensRef::MakeLocal,
+            Self::MakeShared => LensRef::MakeShared,
+            Self::AddMerge(m) => LensRef::AddMerge(*m),
+            Self::RemoveMerge(m) => LensRef::RemoveMerge(*m),
+            Self::AddInvariant(i) => LensRef::AddInvariant(*i),
+            Self::RemoveInvariant(i) => LensRef::RemoveInvariant(*i),
+            Self::MakeLww => LensRef::MakeLww,
+            Self::MakeMultiValue => LensRef::MakeMultiValue,
         }
     }
 }
@@ -133,6 +186,22 @@ pub enum LensRef<'a> {
     LensMap(bool, &'a ArchivedLens),
     /// Reference to [`Lens::LensMapValue`].
     LensMapValue(bool, &'a ArchivedLens),
+    /// Reference to [`Lens::MakeLocal`].
+    MakeLocal,
+    /// Reference to [`Lens::MakeShared`].
+    MakeShared,
+    /// Reference to [`Lens::AddMerge`].
+    AddMerge(Merge),
+    /// Reference to [`Lens::RemoveMerge`].
+    RemoveMerge(Merge),
+    /// Reference to [`Lens::AddInvariant`].
+    AddInvariant(Invariant),
+    /// Reference to [`Lens::RemoveInvariant`].
+    RemoveInvariant(Invariant),
+    /// Reference to [`Lens::MakeLww`].
+    MakeLww,
+    /// Reference to [`Lens::MakeMultiValue`].
+    MakeMultiValue,
 }
 
 impl<'a> LensRef<'a> {
@@ -150,6 +219,14 @@ impl<'a> LensRef<'a> {
             Self::LensIn(rev, key, lens) => Self::LensIn(!rev, key, lens),
             Self::LensMap(rev, lens) => Self::LensMap(!rev, lens),
             Self::LensMapValue(rev, lens) => Self::LensMapValue(!rev, lens),
+            Self::MakeLocal => Self::MakeShared,
+            Self::MakeShared => Self::MakeLocal,
+            Self::AddMerge(merge) => Self::RemoveMerge(merge),
+            Self::RemoveMerge(merge) => Self::AddMerge(merge),
+            Self::AddInvariant(invariant) => Self::RemoveInvariant(invariant),
+            Self::RemoveInvariant(invariant) => Self::AddInvariant(invariant),
+            Self::MakeLww => Self::MakeMultiValue,
+            Self::MakeMultiValue => Self::MakeLww,
         }
     }
 
@@ -166,6 +243,60 @@ impl<'a> LensRef<'a> {
     /// Applies the [`Lens`] to a [`Schema`].
     pub fn transform_schema(&self, s: &mut Schema) -> Result<()> {
         match (self, s) {
+            (Self::MakeLocal, s) => {
+                if matches!(s, Schema::Null | Schema::Local(_)) {
+                    return Err(anyhow!("cannot make schema local: {:?}", s));
+                }
+                *s = Schema::Local(Box::new(std::mem::take(s)));
+            }
+            (Self::MakeShared, s) => {
+                if let Schema::Local(inner) = s {
+                    *s = std::mem::take(inner);
+                } else {
+                    return Err(anyhow!("cannot make non local schema shared: {:?}", s));
+                }
+            }
+            (lens, Schema::Local(inner)) if !matches!(lens, Self::Destroy(_)) => {
+                self.transform_schema(inner)?;
+            }
+            (Self::AddMerge(merge), s) => {
+                let kind = if let Schema::Reg(kind) = s {
+                    *kind
+                } else {
+                    return Err(anyhow!("cannot merge non register schema: {:?}", s));
+                };
+                if *merge == Merge::Sum
+                    && !matches!(
+                        kind,
+                        PrimitiveKind::U64 | PrimitiveKind::I64 | PrimitiveKind::F64
+                    )
+                {
+                    return Err(anyhow!("cannot sum a Reg<{}>", kind));
+                }
+                *s = Schema::MergeReg(kind, *merge);
+            }
+            (Self::RemoveMerge(merge), s) => match s {
+                Schema::MergeReg(kind, m) if m == merge => *s = Schema::Reg(*kind),
+                _ => return Err(anyhow!("cannot remove merge {} from {:?}", merge, s)),
+            },
+            (Self::AddInvariant(invariant), s) => match s {
+                Schema::Reg(kind) if *kind == invariant.kind() => {
+                    *s = Schema::CheckedReg(*kind, *invariant)
+                }
+                _ => return Err(anyhow!("cannot check {} on {:?}", invariant, s)),
+            },
+            (Self::RemoveInvariant(invariant), s) => match s {
+                Schema::CheckedReg(kind, i) if i == invariant => *s = Schema::Reg(*kind),
+                _ => return Err(anyhow!("cannot remove check {} from {:?}", invariant, s)),
+            },
+            (Self::MakeLww, s) => match s {
+                Schema::Reg(kind) => *s = Schema::LwwReg(*kind),
+                _ => return Err(anyhow!("cannot make {:?} last-writer-wins", s)),
+            },
+            (Self::MakeMultiValue, s) => match s {
+                Schema::LwwReg(kind) => *s = Schema::Reg(*kind),
+                _ => return Err(anyhow!("cannot make {:?} multi-value", s)),
+            },
             (Self::Make(k), s) => {
                 if *s != Schema::Null {
                     return Err(anyhow!("cannot make schema"));
@@ -174,15 +305,34 @@ impl<'a> LensRef<'a> {
                     ArchivedKind::Null => return Err(anyhow!("cannot make a null schema")),
                     ArchivedKind::Flag => Schema::Flag,
                     ArchivedKind::Reg(kind) => Schema::Reg(*kind),
+                    ArchivedKind::Table(kind @ (PrimitiveKind::F64 | PrimitiveKind::Bytes)) => {
+                        return Err(anyhow!("cannot key a table by {}", kind))
+                    }
                     ArchivedKind::Table(kind) => Schema::Table(*kind, Box::new(Schema::Null)),
                     ArchivedKind::Struct => Schema::Struct(Default::default()),
                     ArchivedKind::Array => Schema::Array(Box::new(Schema::Null)),
+                    ArchivedKind::Set(kind) => Schema::Set(*kind),
+                    ArchivedKind::Dynamic => Schema::Dynamic,
+                    ArchivedKind::Counter => Schema::Counter,
+                    ArchivedKind::Text => Schema::Text,
+                    ArchivedKind::LwwReg(kind) => Schema::LwwReg(*kind),
+                    ArchivedKind::Encrypted(kind) => Schema::Encrypted(*kind),
                 }
             }
             (Self::Destroy(k), s) => {
                 match (k, &s) {
                     (ArchivedKind::Flag, Schema::Flag) => {}
-                    (ArchivedKind::Reg(k1), Schema::Reg(k2)) => {
+                    (ArchivedKind::Dynamic, Schema::Dynamic) => {}
+                    (ArchivedKind::Counter, Schema::Counter) => {}
+                    (ArchivedKind::Text, Schema::Text) => {}
+                    (ArchivedKind::Reg(k1), Schema::Reg(k2))
+                    | (ArchivedKind::LwwReg(k1), Schema::LwwReg(k2))
+                    | (ArchivedKind::Encrypted(k1), Schema::Encrypted(k2)) => {
+                        if k1 != k2 {
+                            return Err(anyhow!("can't destroy different kind"));
+                        }
+                    }
+                    (ArchivedKind::Set(k1), Schema::Set(k2)) => {
                         if k1 != k2 {
                             return Err(anyhow!("can't destroy different kind"));
                         }
@@ -207,6 +357,9 @@ impl<'a> LensRef<'a> {
                 *s = Schema::Null;
             }
             (Self::AddProperty(key), Schema::Struct(m)) => {
+                if is_reserved(key) {
+                    return Err(anyhow!("property {} is reserved", key));
+                }
                 if m.contains_key(key.as_str()) {
                     return Err(anyhow!("property {} already exists in schema", key));
                 }
@@ -221,6 +374,9 @@ impl<'a> LensRef<'a> {
                 m.remove(key.as_str());
             }
             (Self::RenameProperty(from, to), Schema::Struct(m)) => {
+                if is_reserved(to) {
+                    return Err(anyhow!("property {} is reserved", to));
+                }
                 if m.contains_key(to.as_str()) {
                     return Err(anyhow!("trying to rename to existing property: {}", to));
                 }
@@ -343,6 +499,20 @@ impl<'a> LensRef<'a> {
                 p2.extend(path);
                 return p2;
             }
+            Self::MakeLocal
+            | Self::MakeShared
+            | Self::AddMerge(_)
+            | Self::RemoveMerge(_)
+            | Self::RemoveInvariant(_)
+            | Self::MakeLww
+            | Self::MakeMultiValue => {}
+            Self::AddInvariant(invariant) => {
+                if let [Segment::Nonce(_), prim, ..] = path {
+                    if !invariant.validate(prim.clone()) {
+                        return vec![];
+                    }
+                }
+            }
         }
         path.to_vec()
     }
@@ -422,6 +592,10 @@ impl<'a> LensesRef<'a> {
     /// target [`Schema`].
     pub fn transform_path(&self, path: Path, target: LensesRef<'a>) -> Option<PathBuf> {
         let mut segments: Vec<Segment> = path.child().unwrap().into_iter().collect();
+        // the document metadata doesn't depend on the schema.
+        if segments.first().and_then(|seg| seg.prim_str()) == Some(METADATA) {
+            return Some(path.to_owned());
+        }
         for lens in self.transform(target) {
             segments = lens.transform_path(&segments);
             if segments.is_empty() {
@@ -465,4 +639,39 @@ mod tests {
             prop_assert!(validate(&schema, &causal));
         }
     }
+
+    #[test]
+    fn reserved_properties() {
+        for lens in [
+            Lens::AddProperty("VALUES".into()),
+            Lens::RenameProperty("a".into(), "META".into()),
+        ] {
+            let mut schema = Schema::Struct(Default::default());
+            let add = Ref::archive(&Lens::AddProperty("a".into()));
+            add.as_ref().to_ref().transform_schema(&mut schema).unwrap();
+            let lens = Ref::archive(&lens);
+            assert!(lens
+                .as_ref()
+                .to_ref()
+                .transform_schema(&mut schema)
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn invariant_drops_invalid_values() {
+        let lens = LensRef::AddInvariant(Invariant::U64Range(1, 5));
+        let valid = [Segment::Nonce(0), Segment::U64(3)];
+        assert_eq!(lens.transform_path(&valid), valid.to_vec());
+        let invalid = [Segment::Nonce(0), Segment::U64(6)];
+        assert!(lens.transform_path(&invalid).is_empty());
+        let mut schema = Schema::Reg(PrimitiveKind::U64);
+        lens.transform_schema(&mut schema).unwrap();
+        assert_eq!(
+            schema,
+            Schema::CheckedReg(PrimitiveKind::U64, Invariant::U64Range(1, 5))
+        );
+        let lens = LensRef::AddInvariant(Invariant::MaxLen(3));
+        assert!(lens.transform_schema(&mut schema).is_err());
+    }
 }
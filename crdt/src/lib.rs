@@ -7,15 +7,17 @@
 //!
 //! ## Path
 //! The elements stored in this ORSet are called paths. These paths are used to represent other
-//! crdts like the EWFlag, MVReg, ORMap, and ORArray. The path has the following logical format:
+//! crdts like the EWFlag, MVReg, ORMap, ORSet and ORArray. The path has the following logical format:
 //! ```bnf
-//! prim := prim_bool | prim_u64 | prim_i64 | prim_str
+//! prim := prim_bool | prim_u64 | prim_i64 | prim_f64 | prim_str | prim_bytes
 //! key := prim
 //! field := prim_str
 //! ewflag := nonce
 //! mvreg := nonce prim
-//! path := doc (key | field)* (ewflag | mvreg | policy) peer sig
+//! orset := prim nonce
+//! path := doc (key | field)* (ewflag | mvreg | orset | policy) peer sig
 //! tombstone := path peer sig
+//! tag := doc dot prim_str peer sig
 //! ```
 //!
 //! ## Case study: Using ORSet<Path> to construct an MVReg
@@ -81,8 +83,9 @@
 //!
 //! where permission is one of read/write/control/own. control allows delegating read and write
 //! permission while own allows delegating read/write/control/own permissions and actor is either
-//! a public key or anonymous. The `anonymous` actor can be used to for example give read
-//! permissions to everyone.
+//! a public key, a group or anonymous. The `anonymous` actor can be used to for example give read
+//! permissions to everyone. A group is a document whose members are the peers with any
+//! permission on it's root, so a single statement covers all current and future members.
 //!
 //! The set of all policy statements is used to deduce if a peer is authorized to perform a task.
 //! There are five inference rules that can be used to determine if a peer has access:
@@ -123,13 +126,15 @@
 //!
 //! ## Future improvements
 //! - compromise recovery: recover from accidental or malicious modification to restore a previous
-//! state.
+//!   state. `Doc::rollback_author` is a first step, it removes everything a compromised key
+//!   added since a known good `CausalContext` but doesn't restore what it removed.
 //! - using untrusted servers: currently the ORSet converges even when the paths are encrypted.
 //! However for correct operation we need to also prove that encrypted updates don't violate the
 //! invariants and that the author had permission to make the change. In additon homomorphic
 //! transforms which preserve the zero knowledge proofs will be necessary.
 #![warn(missing_docs)]
 mod acl;
+mod attachment;
 mod crdt;
 mod crypto;
 mod cursor;
@@ -137,30 +142,54 @@ mod doc;
 mod dotset;
 mod fraction;
 mod id;
+mod keystore;
 mod lens;
+mod metrics;
 mod path;
-#[cfg(test)]
+#[cfg(any(test, feature = "testing"))]
 mod props;
 mod radixdb;
 mod registry;
+mod replay;
 mod schema;
 mod subscriber;
 mod util;
+mod wire;
+
+/// Proptest strategies and helpers for writing property tests against schemas.
+#[cfg(feature = "testing")]
+pub mod testing {
+    pub use crate::props::{
+        arb_causal, arb_causal_for_schema, arb_key_kind, arb_lens_for_schema, arb_path,
+        arb_peer_id, arb_primitive_for_kind, arb_primitive_kind, arb_prop, arb_schema, join,
+        lens_and_schema, lens_schema_and_causal, schema_and_causal, schema_and_causal2, validate,
+        Primitive,
+    };
+}
 
-pub use crate::acl::{Actor, Can, Permission, Policy};
-pub use crate::crdt::{Causal, CausalContext};
+pub use crate::acl::{
+    Actor, Can, EffectivePermissions, Grant, InviteToken, NotGranted, Permission, Policy, Token,
+};
+pub use crate::attachment::Attachments;
+pub use crate::crdt::{Causal, CausalContext, Denied};
 pub use crate::crypto::Keypair;
 pub use crate::cursor::Cursor;
-pub use crate::doc::{Backend, Doc, Frontend, SchemaInfo};
+pub use crate::doc::{
+    Backend, Doc, DocState, Frontend, InvalidChange, PendingStats, ScanPage, SchemaInfo,
+    SchemaUpgraded, Snapshot, SnapshotDiff,
+};
 pub use crate::dotset::{ArchivedDotSet, Dot, DotSet};
 pub use crate::id::{DocId, PeerId};
 pub use crate::lens::{ArchivedKind, ArchivedLens, ArchivedLenses, Kind, Lens, LensRef, Lenses};
-pub use crate::path::{Path, PathBuf, Segment};
-pub use crate::radixdb::{FileStorage, MemStorage, Storage};
-pub use crate::registry::{Expanded, Hash, Package, Registry};
-pub use crate::schema::{ArchivedSchema, PrimitiveKind, Schema};
-pub use crate::subscriber::{Batch, Event, Iter, Subscriber};
+pub use crate::metrics::{Metrics, MetricsSnapshot};
+pub use crate::path::{is_reserved, Path, PathBuf, Segment, RESERVED};
+pub use crate::radixdb::{Durability, EncryptedStorage, FileStorage, MemStorage, Storage};
+pub use crate::registry::{Expanded, Hash, Package, Registry, RegistryError};
+pub use crate::replay::{Replay, ReplayEntry, ReplayKind};
+pub use crate::schema::{ArchivedSchema, Invariant, Merge, PrimitiveKind, Schema, MAX_BYTES};
+pub use crate::subscriber::{Batch, Change, Event, Iter, Step, Subscriber};
 pub use crate::util::Ref;
+pub use crate::wire::{ArchivedCompressedCausal, CompressedCausal};
 
 #[cfg(target_arch = "wasm32")]
 pub use crate::radixdb::browser::BrowserCacheStorage;
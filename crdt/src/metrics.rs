@@ -0,0 +1,91 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Counters of the operations of a [`Backend`](crate::Backend). Counters are only recorded
+/// with the `metrics` feature enabled.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    joins: AtomicU64,
+    unjoins: AtomicU64,
+    broadcasts: AtomicU64,
+    acl_updates: AtomicU64,
+    flushes: AtomicU64,
+    flush_nanos: AtomicU64,
+}
+
+/// Point in time copy of the [`Metrics`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MetricsSnapshot {
+    /// Number of remote changes joined.
+    pub joins: u64,
+    /// Number of unjoins answered.
+    pub unjoins: u64,
+    /// Number of changes broadcast to peers.
+    pub broadcasts: u64,
+    /// Number of times the acl was recomputed.
+    pub acl_updates: u64,
+    /// Length of the storage logs in bytes. Includes superseded entries, so it only shrinks
+    /// when a log is compacted.
+    pub log_bytes: u64,
+    /// Number of writes to storage.
+    pub flushes: u64,
+    /// Total time spent writing to storage. Not measured on wasm.
+    pub flush_duration: Duration,
+}
+
+impl Metrics {
+    fn add(counter: &AtomicU64, n: u64) {
+        if cfg!(feature = "metrics") {
+            counter.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_join(&self) {
+        Self::add(&self.joins, 1);
+    }
+
+    pub(crate) fn record_unjoin(&self) {
+        Self::add(&self.unjoins, 1);
+    }
+
+    /// Records a change broadcast by the networking layer.
+    pub fn record_broadcast(&self) {
+        Self::add(&self.broadcasts, 1);
+    }
+
+    pub(crate) fn record_acl_update(&self) {
+        Self::add(&self.acl_updates, 1);
+    }
+
+    pub(crate) fn record_flush(&self, duration: Duration) {
+        Self::add(&self.flushes, 1);
+        Self::add(&self.flush_nanos, duration.as_nanos() as u64);
+    }
+
+    /// Returns the current counters, with the log length reported by the caller.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn snapshot(&self, log_bytes: u64) -> MetricsSnapshot {
+        MetricsSnapshot {
+            joins: self.joins.load(Ordering::Relaxed),
+            unjoins: self.unjoins.load(Ordering::Relaxed),
+            broadcasts: self.broadcasts.load(Ordering::Relaxed),
+            acl_updates: self.acl_updates.load(Ordering::Relaxed),
+            log_bytes,
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flush_duration: Duration::from_nanos(self.flush_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Runs `f` and returns how long it took. The duration is only measured with the `metrics`
+/// feature enabled and is zero on wasm, where time isn't available.
+pub(crate) fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    #[cfg(all(feature = "metrics", not(target_family = "wasm")))]
+    {
+        let start = std::time::Instant::now();
+        let res = f();
+        (res, start.elapsed())
+    }
+    #[cfg(not(all(feature = "metrics", not(target_family = "wasm"))))]
+    (f(), Duration::default())
+}
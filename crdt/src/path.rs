@@ -11,6 +11,17 @@ use std::borrow::Borrow;
 use std::convert::TryInto;
 use std::iter::FromIterator;
 
+/// String segments used by the `ORArray` encoding and the document metadata. They can't be
+/// used as field names and are escaped when used as keys.
+pub const RESERVED: [&str; 3] = ["VALUES", "META", "METADATA"];
+
+const ESCAPE: char = '\\';
+
+/// Returns true if `name` collides with one of the [`RESERVED`] string segments.
+pub fn is_reserved(name: &str) -> bool {
+    RESERVED.contains(&name)
+}
+
 #[derive(
     Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Archive, Deserialize, Serialize,
 )]
@@ -28,6 +39,8 @@ pub enum SegmentType {
     Dot,
     Position,
     Sig,
+    F64,
+    Bytes,
 }
 
 impl SegmentType {
@@ -45,14 +58,16 @@ impl SegmentType {
             u if u == Dot as u8 => Some(Dot),
             u if u == Position as u8 => Some(Position),
             u if u == Sig as u8 => Some(Sig),
-            _ => unreachable!("Unexpected SegmentType: {}", u),
+            u if u == F64 as u8 => Some(F64),
+            u if u == Bytes as u8 => Some(Bytes),
+            _ => None,
         }
     }
 
     fn last_element(data: &[u8]) -> Option<(SegmentType, usize, &[u8])> {
         use std::mem::size_of;
         let last = data.last()?;
-        let ty = SegmentType::new(*last).unwrap();
+        let ty = SegmentType::new(*last).expect("Unexpected SegmentType");
         let len = 1 + match ty {
             SegmentType::Doc => size_of::<DocId>(),
             SegmentType::Peer => size_of::<PeerId>(),
@@ -60,9 +75,10 @@ impl SegmentType {
             SegmentType::Bool => size_of::<bool>(),
             SegmentType::U64 => size_of::<u64>(),
             SegmentType::I64 => size_of::<i64>(),
+            SegmentType::F64 => size_of::<f64>(),
             SegmentType::Dot => size_of::<Dot>(),
             SegmentType::Sig => size_of::<Signature>(),
-            SegmentType::Str | SegmentType::Position | SegmentType::Policy => {
+            SegmentType::Str | SegmentType::Position | SegmentType::Policy | SegmentType::Bytes => {
                 if data.len() < 3 {
                     return None;
                 }
@@ -96,13 +112,34 @@ impl SegmentType {
     fn is_variable_length(&self) -> bool {
         matches!(
             self,
-            SegmentType::Position | SegmentType::Str | SegmentType::Policy
+            SegmentType::Position | SegmentType::Str | SegmentType::Policy | SegmentType::Bytes
         )
     }
 }
 
+/// Encodes a f64 so that the byte order of the encoding is the total order of f64s.
+fn f64_to_bytes(f: f64) -> [u8; 8] {
+    let bits = f.to_bits();
+    let bits = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | 1 << 63
+    };
+    bits.to_be_bytes()
+}
+
+fn f64_from_bytes(bytes: [u8; 8]) -> f64 {
+    let bits = u64::from_be_bytes(bytes);
+    let bits = if bits >> 63 == 1 {
+        bits & !(1 << 63)
+    } else {
+        !bits
+    };
+    f64::from_bits(bits)
+}
+
 /// A segment of a path.
-#[derive(Clone, Eq, PartialEq)]
+#[derive(Clone)]
 pub enum Segment {
     /// Document identifier.
     Doc(DocId),
@@ -116,8 +153,12 @@ pub enum Segment {
     U64(u64),
     /// Signed integer primitive.
     I64(i64),
+    /// Floating point primitive. Segments are equal if their bits are equal.
+    F64(f64),
     /// Utf8 string primitive.
     Str(String),
+    /// Binary primitive.
+    Bytes(Vec<u8>),
     /// Policy statement.
     Policy(Policy),
     /// Path identifier.
@@ -137,6 +178,8 @@ impl Segment {
             SegmentType::Bool => Self::Bool(data[0] > 0),
             SegmentType::U64 => Self::U64(u64::from_be_bytes(data.try_into().unwrap())),
             SegmentType::I64 => Self::I64(i64::from_be_bytes(data.try_into().unwrap())),
+            SegmentType::F64 => Self::F64(f64_from_bytes(data.try_into().unwrap())),
+            SegmentType::Bytes => Self::Bytes(data.to_vec()),
             SegmentType::Str => {
                 Self::Str(unsafe { std::str::from_utf8_unchecked(data) }.to_string())
             }
@@ -204,6 +247,15 @@ impl Segment {
         }
     }
 
+    /// Returns the `f64`.
+    pub fn prim_f64(self) -> Option<f64> {
+        if let Segment::F64(f) = self {
+            Some(f)
+        } else {
+            None
+        }
+    }
+
     /// Returns the `&str`.
     pub fn prim_str(&self) -> Option<&str> {
         if let Segment::Str(s) = self {
@@ -222,6 +274,24 @@ impl Segment {
         }
     }
 
+    /// Returns the bytes.
+    pub fn prim_bytes(self) -> Option<Vec<u8>> {
+        if let Segment::Bytes(b) = self {
+            Some(b)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the user supplied `String` appended with [`PathBuf::prim_key`].
+    pub fn prim_key(self) -> Option<String> {
+        let s = self.prim_string()?;
+        Some(match s.strip_prefix(ESCAPE) {
+            Some(s) => s.to_string(),
+            None => s,
+        })
+    }
+
     /// Returns the `Policy`.
     pub fn policy(self) -> Option<Policy> {
         if let Segment::Policy(policy) = self {
@@ -258,6 +328,29 @@ impl Segment {
     }
 }
 
+impl PartialEq for Segment {
+    fn eq(&self, other: &Self) -> bool {
+        // matches on `self` only so that new variants can't be forgotten.
+        match self {
+            Self::Doc(a) => matches!(other, Self::Doc(b) if a == b),
+            Self::Peer(a) => matches!(other, Self::Peer(b) if a == b),
+            Self::Nonce(a) => matches!(other, Self::Nonce(b) if a == b),
+            Self::Bool(a) => matches!(other, Self::Bool(b) if a == b),
+            Self::U64(a) => matches!(other, Self::U64(b) if a == b),
+            Self::I64(a) => matches!(other, Self::I64(b) if a == b),
+            Self::F64(a) => matches!(other, Self::F64(b) if a.to_bits() == b.to_bits()),
+            Self::Str(a) => matches!(other, Self::Str(b) if a == b),
+            Self::Bytes(a) => matches!(other, Self::Bytes(b) if a == b),
+            Self::Policy(a) => matches!(other, Self::Policy(b) if a == b),
+            Self::Dot(a) => matches!(other, Self::Dot(b) if a == b),
+            Self::Position(a) => matches!(other, Self::Position(b) if a == b),
+            Self::Sig(a) => matches!(other, Self::Sig(b) if a == b),
+        }
+    }
+}
+
+impl Eq for Segment {}
+
 impl std::fmt::Debug for Segment {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
@@ -267,7 +360,9 @@ impl std::fmt::Debug for Segment {
             Self::Bool(s) => write!(f, "{}", s),
             Self::U64(s) => write!(f, "{}", s),
             Self::I64(s) => write!(f, "{}", s),
+            Self::F64(s) => write!(f, "{:?}", s),
             Self::Str(s) => write!(f, "{:?}", s),
+            Self::Bytes(s) => write!(f, "Bytes({})", base64::encode(s)),
             Self::Policy(s) => write!(f, "{:?}", s),
             Self::Dot(s) => write!(f, "{:?}", s),
             Self::Position(s) => write!(f, "Position({})", base64::encode(s)),
@@ -316,7 +411,9 @@ impl PathBuf {
             Segment::Bool(d) => self.prim_bool(d),
             Segment::U64(d) => self.prim_u64(d),
             Segment::I64(d) => self.prim_i64(d),
+            Segment::F64(d) => self.prim_f64(d),
             Segment::Str(d) => self.prim_str(&*d),
+            Segment::Bytes(d) => self.prim_bytes(&d),
             Segment::Policy(d) => self.policy(&d),
             Segment::Dot(d) => self.dot(&d),
             Segment::Position(d) => self.position(&d),
@@ -355,11 +452,31 @@ impl PathBuf {
         self.push(SegmentType::I64, i.to_be_bytes().as_ref());
     }
 
+    /// Appends a f64 segment.
+    pub fn prim_f64(&mut self, f: f64) {
+        self.push(SegmentType::F64, f64_to_bytes(f).as_ref());
+    }
+
     /// Appends a utf8 segment.
     pub fn prim_str(&mut self, s: &str) {
         self.push(SegmentType::Str, s.as_bytes());
     }
 
+    /// Appends a bytes segment.
+    pub fn prim_bytes(&mut self, b: &[u8]) {
+        self.push(SegmentType::Bytes, b);
+    }
+
+    /// Appends a user supplied utf8 segment. Strings colliding with a [`RESERVED`] segment
+    /// and strings starting with the escape character are escaped.
+    pub fn prim_key(&mut self, s: &str) {
+        if is_reserved(s) || s.starts_with(ESCAPE) {
+            self.prim_str(&format!("{}{}", ESCAPE, s));
+        } else {
+            self.prim_str(s);
+        }
+    }
+
     /// Appends a policy segment.
     pub fn policy(&mut self, policy: &Policy) {
         self.push(SegmentType::Policy, Ref::archive(policy).as_bytes());
@@ -456,6 +573,33 @@ impl<'a> Path<'a> {
         other.as_ref().starts_with(self.as_ref())
     }
 
+    /// Creates a new path from an untrusted byte slice, failing if any of its segments is
+    /// malformed.
+    pub fn checked(p: &'a [u8]) -> Result<Self> {
+        let mut data = p;
+        while let Some(last) = data.last() {
+            SegmentType::new(*last).ok_or_else(|| anyhow::anyhow!("invalid segment type"))?;
+            let (ty, len, content) = SegmentType::last_element(data)
+                .ok_or_else(|| anyhow::anyhow!("truncated segment"))?;
+            match ty {
+                SegmentType::Str => {
+                    std::str::from_utf8(content)?;
+                }
+                SegmentType::Policy => {
+                    // copied into an aligned buffer like in `Segment::new`.
+                    let content: std::sync::Arc<[u8]> = content.into();
+                    Ref::<Policy>::checked(&content)?;
+                }
+                SegmentType::Sig => {
+                    Signature::from_bytes(content)?;
+                }
+                _ => {}
+            }
+            data = &data[..data.len() - len];
+        }
+        Ok(Self(p))
+    }
+
     /// Returns an owned `PathBuf`.
     pub fn to_owned(&self) -> PathBuf {
         PathBuf(self.0.to_vec())
@@ -579,9 +723,80 @@ impl<'a> std::fmt::Debug for Path<'a> {
     }
 }
 
+/// Strips a trailing `peer sig` pair from a path.
+pub(crate) fn split_sig(path: &mut Path) -> Option<(PeerId, Signature)> {
+    let (rest, sig) = path.split_last()?;
+    let sig = sig.sig()?;
+    let (rest, peer) = rest.split_last()?;
+    let peer = peer.peer()?;
+    *path = rest;
+    Some((peer, sig))
+}
+
+/// Abbreviated base64 encoding of an identifier.
+fn short_id(id: &[u8; 32]) -> String {
+    let mut s = base64::encode_config(id, base64::URL_SAFE);
+    s.truncate(4);
+    s
+}
+
+fn is_prim(segment: &Segment) -> bool {
+    matches!(
+        segment,
+        Segment::Bool(_)
+            | Segment::U64(_)
+            | Segment::I64(_)
+            | Segment::F64(_)
+            | Segment::Str(_)
+            | Segment::Bytes(_)
+    )
+}
+
+/// Formats a path as `doc:ab12…/todos/0/title = "x" by peer:cd34…`.
 impl<'a> std::fmt::Display for Path<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        let mut body = *self;
+        let mut authors = vec![];
+        while let Some((peer, _)) = split_sig(&mut body) {
+            authors.push(peer);
+        }
+        authors.reverse();
+        let segments = body.into_iter().collect::<Vec<_>>();
+        let (keys, value) = match segments.as_slice() {
+            [keys @ .., Segment::Nonce(_), value] if is_prim(value) => (keys, Some(value)),
+            [keys @ .., Segment::Policy(_)] => (keys, segments.last()),
+            keys => (keys, None),
+        };
+        let mut sep = "";
+        for segment in keys {
+            match segment {
+                Segment::Doc(doc) => write!(f, "{}doc:{}…", sep, short_id(doc.as_ref()))?,
+                Segment::Peer(peer) => write!(f, "{}peer:{}…", sep, short_id(peer.as_ref()))?,
+                Segment::Nonce(_) => continue,
+                Segment::Bool(b) => write!(f, "{}{}", sep, b)?,
+                Segment::U64(n) => write!(f, "{}{}", sep, n)?,
+                Segment::I64(n) => write!(f, "{}{}", sep, n)?,
+                Segment::F64(n) => write!(f, "{}{}", sep, n)?,
+                Segment::Str(s) => write!(f, "{}{}", sep, s)?,
+                Segment::Bytes(b) => write!(f, "{}{}", sep, base64::encode(b))?,
+                Segment::Policy(p) => write!(f, "{}{:?}", sep, p)?,
+                Segment::Dot(dot) => write!(f, "{}{}", sep, dot)?,
+                Segment::Position(pos) => write!(f, "{}@{}", sep, pos)?,
+                Segment::Sig(_) => write!(f, "{}sig", sep)?,
+            }
+            sep = "/";
+        }
+        match value {
+            Some(Segment::Policy(policy)) => write!(f, ": {:?}", policy)?,
+            Some(value) => write!(f, " = {:?}", value)?,
+            None if matches!(segments.last(), Some(Segment::Nonce(_))) => write!(f, " = on")?,
+            None => {}
+        }
+        for (i, peer) in authors.iter().enumerate() {
+            let by = if i == 0 { "by" } else { "removed by" };
+            write!(f, " {} peer:{}…", by, short_id(peer.as_ref()))?;
+        }
+        Ok(())
     }
 }
 
@@ -594,6 +809,17 @@ impl<'a> AsRef<[u8]> for Path<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::acl::{Actor, Permission};
+    use crate::props::arb_path;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn segments_roundtrip(path in arb_path()) {
+            let segments = path.as_path().into_iter().collect::<PathBuf>();
+            prop_assert_eq!(segments, path);
+        }
+    }
 
     #[test]
     fn iter() {
@@ -619,6 +845,45 @@ mod tests {
         assert!(path.next().is_none());
     }
 
+    #[test]
+    fn f64_order() {
+        let values = [
+            f64::NEG_INFINITY,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            1e-300,
+            2.5,
+            f64::INFINITY,
+        ];
+        let paths = values
+            .iter()
+            .map(|f| {
+                let mut p = PathBuf::new();
+                p.prim_f64(*f);
+                assert_eq!(p.as_path().first().unwrap(), Segment::F64(*f));
+                p
+            })
+            .collect::<Vec<_>>();
+        assert!(paths.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn prim_key() {
+        for key in ["a", "VALUES", "META", "\\", "\\VALUES", ""] {
+            let mut p = PathBuf::new();
+            p.prim_key(key);
+            let seg = p.as_path().first().unwrap();
+            assert_eq!(
+                seg.prim_str() != Some(key),
+                is_reserved(key) || key.starts_with('\\')
+            );
+            assert!(!is_reserved(seg.prim_str().unwrap()));
+            assert_eq!(seg.prim_key().unwrap(), key);
+        }
+    }
+
     #[test]
     fn strip_prefix() {
         let mut p = PathBuf::new();
@@ -645,4 +910,44 @@ mod tests {
         }
         assert!(iter.next().is_none());
     }
+
+    #[test]
+    fn display() {
+        let sig = Signature::from_bytes(&[0; 64]).unwrap();
+        let mut p = PathBuf::new();
+        p.doc(&DocId::new([0; 32]));
+        p.prim_str("todos");
+        p.prim_u64(0);
+        p.prim_str("title");
+        p.nonce(42);
+        p.prim_str("x");
+        p.peer(&PeerId::new([1; 32]));
+        p.sig(sig);
+        assert_eq!(
+            p.to_string(),
+            r#"doc:AAAA…/todos/0/title = "x" by peer:AQEB…"#
+        );
+        p.peer(&PeerId::new([2; 32]));
+        p.sig(sig);
+        assert_eq!(
+            p.to_string(),
+            r#"doc:AAAA…/todos/0/title = "x" by peer:AQEB… removed by peer:AgIC…"#
+        );
+    }
+
+    #[test]
+    fn checked() {
+        let mut p = PathBuf::new();
+        p.doc(&DocId::new([0; 32]));
+        p.prim_str("todos");
+        p.policy(&Policy::Can(Actor::Anonymous, Permission::Read));
+        p.prim_u64(0);
+        assert!(Path::checked(p.as_path().as_ref()).is_ok());
+        let bytes = p.as_path().as_ref().to_vec();
+        assert!(Path::checked(&bytes[1..]).is_err());
+        assert!(Path::checked(&[0xff, 0, 1, SegmentType::Str as u8]).is_err());
+        assert!(Path::checked(&[0xff]).is_err());
+        assert!(Path::checked(&[0xff, 0xff, SegmentType::Str as u8]).is_err());
+        assert!(Path::checked(&[]).is_ok());
+    }
 }
@@ -1,47 +1,122 @@
-use std::sync::Arc;
-
-use crate::acl::Acl;
-use crate::crdt::{Causal, CausalContext, Crdt, DotStore};
+use crate::crdt::{Causal, DotStore};
 use crate::id::{DocId, PeerId};
 use crate::lens::{Kind, Lens};
 use crate::path::PathBuf;
-use crate::radixdb::{BlobMap, BlobSet, MemStorage};
-use crate::schema::{PrimitiveKind, Schema};
+use crate::schema::{Invariant, Merge, PrimitiveKind, Schema};
 use crate::util::Ref;
 use proptest::collection::SizeRange;
 use proptest::prelude::*;
+use std::cmp::Ordering;
 
-#[derive(Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+/// A primitive value of a [`PrimitiveKind`]. f64s are ordered by their total order.
+#[derive(Clone, Debug)]
 pub enum Primitive {
+    /// A [`PrimitiveKind::Bool`].
     Bool(bool),
+    /// A [`PrimitiveKind::U64`].
     U64(u64),
+    /// A [`PrimitiveKind::I64`].
     I64(i64),
+    /// A [`PrimitiveKind::Str`].
     Str(String),
+    /// A [`PrimitiveKind::F64`].
+    F64(f64),
+    /// A [`PrimitiveKind::Bytes`].
+    Bytes(Vec<u8>),
+}
+
+impl Primitive {
+    fn rank(&self) -> u8 {
+        match self {
+            Self::Bool(_) => 0,
+            Self::U64(_) => 1,
+            Self::I64(_) => 2,
+            Self::Str(_) => 3,
+            Self::F64(_) => 4,
+            Self::Bytes(_) => 5,
+        }
+    }
+}
+
+impl Ord for Primitive {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a.cmp(b),
+            (Self::U64(a), Self::U64(b)) => a.cmp(b),
+            (Self::I64(a), Self::I64(b)) => a.cmp(b),
+            (Self::Str(a), Self::Str(b)) => a.cmp(b),
+            (Self::F64(a), Self::F64(b)) => a.total_cmp(b),
+            (Self::Bytes(a), Self::Bytes(b)) => a.cmp(b),
+            _ => self.rank().cmp(&other.rank()),
+        }
+    }
 }
 
+impl PartialOrd for Primitive {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Primitive {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Primitive {}
+
+fn push_primitive(path: &mut PathBuf, prim: Primitive) {
+    match prim {
+        Primitive::Bool(value) => path.prim_bool(value),
+        Primitive::U64(value) => path.prim_u64(value),
+        Primitive::I64(value) => path.prim_i64(value),
+        Primitive::Str(value) => path.prim_str(&value),
+        Primitive::F64(value) => path.prim_f64(value),
+        Primitive::Bytes(value) => path.prim_bytes(&value),
+    }
+}
+
+/// Generates single letter field names.
 pub fn arb_prop() -> impl Strategy<Value = String> {
     "[a-z]"
 }
 
+/// Generates one of five peer ids, so that generated changes overlap.
 pub fn arb_peer_id() -> impl Strategy<Value = PeerId> {
     (0u8..5).prop_map(|i| PeerId::new([i; 32]))
 }
 
+/// Generates a [`PrimitiveKind`].
 pub fn arb_primitive_kind() -> impl Strategy<Value = PrimitiveKind> {
     prop_oneof![
         Just(PrimitiveKind::Bool),
         Just(PrimitiveKind::U64),
         Just(PrimitiveKind::I64),
         Just(PrimitiveKind::Str),
+        Just(PrimitiveKind::F64),
+        Just(PrimitiveKind::Bytes),
     ]
 }
 
+/// Generates a [`PrimitiveKind`] that can key a table.
+pub fn arb_key_kind() -> impl Strategy<Value = PrimitiveKind> {
+    arb_primitive_kind().prop_filter("table key", |kind| {
+        !matches!(kind, PrimitiveKind::F64 | PrimitiveKind::Bytes)
+    })
+}
+
+/// Generates a [`Primitive`] of `kind`.
 pub fn arb_primitive_for_kind(kind: PrimitiveKind) -> BoxedStrategy<Primitive> {
     match kind {
         PrimitiveKind::Bool => any::<bool>().prop_map(Primitive::Bool).boxed(),
         PrimitiveKind::U64 => any::<u64>().prop_map(Primitive::U64).boxed(),
         PrimitiveKind::I64 => any::<i64>().prop_map(Primitive::I64).boxed(),
         PrimitiveKind::Str => arb_prop().prop_map(Primitive::Str).boxed(),
+        PrimitiveKind::F64 => any::<f64>().prop_map(Primitive::F64).boxed(),
+        PrimitiveKind::Bytes => prop::collection::vec(any::<u8>(), 0..8)
+            .prop_map(Primitive::Bytes)
+            .boxed(),
     }
 }
 
@@ -69,12 +144,26 @@ fn arb_dotfun(kind: PrimitiveKind, elems: impl Into<SizeRange>) -> impl Strategy
             let mut path = PathBuf::new();
             path.peer(&peer);
             path.nonce(nonce);
-            match prim {
-                Primitive::Bool(value) => path.prim_bool(value),
-                Primitive::U64(value) => path.prim_u64(value),
-                Primitive::I64(value) => path.prim_i64(value),
-                Primitive::Str(value) => path.prim_str(&value),
-            }
+            push_primitive(&mut path, prim);
+            store.insert(path);
+        }
+        store
+    })
+}
+
+fn arb_counter(elems: impl Into<SizeRange>) -> impl Strategy<Value = DotStore> {
+    prop::collection::btree_set(
+        (arb_peer_id(), any::<u64>(), any::<u64>(), any::<u64>()),
+        elems,
+    )
+    .prop_map(|set| {
+        let mut store = DotStore::new();
+        for (peer, nonce, inc, dec) in set {
+            let mut path = PathBuf::new();
+            path.peer(&peer);
+            path.nonce(nonce);
+            path.prim_u64(inc);
+            path.prim_u64(dec);
             store.insert(path);
         }
         store
@@ -90,12 +179,7 @@ fn arb_dotmap(
         let mut dotmap = DotStore::new();
         for (prim, store) in set {
             let mut path = PathBuf::new();
-            match prim {
-                Primitive::Bool(value) => path.prim_bool(value),
-                Primitive::U64(value) => path.prim_u64(value),
-                Primitive::I64(value) => path.prim_i64(value),
-                Primitive::Str(value) => path.prim_str(&value),
-            }
+            push_primitive(&mut path, prim);
             dotmap.union(&store.prefix(path.as_path()));
         }
         dotmap
@@ -146,14 +230,21 @@ fn arb_causal_for_dotstore(
     })
 }
 
+/// Generates a [`Schema`].
 pub fn arb_schema() -> impl Strategy<Value = Schema> {
     let leaf = prop_oneof![
         Just(Schema::Flag),
         arb_primitive_kind().prop_map(Schema::Reg),
+        arb_primitive_kind().prop_map(|kind| Schema::MergeReg(kind, Merge::Max)),
+        arb_primitive_kind().prop_map(Schema::LwwReg),
+        arb_primitive_kind().prop_map(Schema::Encrypted),
+        arb_primitive_kind().prop_map(Schema::Set),
+        Just(Schema::Dynamic),
+        Just(Schema::Counter),
     ];
     leaf.prop_recursive(8, 256, 10, |inner| {
         prop_oneof![
-            (arb_primitive_kind(), inner.clone())
+            (arb_key_kind(), inner.clone())
                 .prop_map(|(kind, schema)| Schema::Table(kind, Box::new(schema))),
             prop::collection::btree_map(arb_prop(), inner.clone(), 0..10).prop_map(Schema::Struct),
             inner.prop_map(|s| Schema::Array(Box::new(s)))
@@ -165,7 +256,15 @@ fn arb_dotstore_for_schema(s: Schema) -> BoxedStrategy<DotStore> {
     match s {
         Schema::Null => Just(DotStore::new()).boxed(),
         Schema::Flag => arb_dotset(0..10).boxed(),
-        Schema::Reg(kind) => arb_dotfun(kind, 0..10).boxed(),
+        Schema::Reg(kind)
+        | Schema::MergeReg(kind, _)
+        | Schema::CheckedReg(kind, _)
+        | Schema::LwwReg(kind) => arb_dotfun(kind, 0..10).boxed(),
+        Schema::Encrypted(_) => arb_dotfun(PrimitiveKind::Bytes, 0..10).boxed(),
+        Schema::Set(kind) => arb_dotmap(kind, arb_dotset(1..3), 0..10).boxed(),
+        Schema::Dynamic => arb_dotset(0..10).boxed(),
+        Schema::Counter => arb_counter(0..10).boxed(),
+        Schema::Text => Just(DotStore::new()).boxed(),
         Schema::Table(kind, schema) => {
             arb_dotmap(kind, arb_dotstore_for_schema(*schema), 0..10).boxed()
         }
@@ -188,23 +287,49 @@ fn arb_dotstore_for_schema(s: Schema) -> BoxedStrategy<DotStore> {
                     .boxed()
             }),
         Schema::Array(schema) => arb_dotstore_for_schema(*schema),
+        Schema::Local(schema) => arb_dotstore_for_schema(*schema),
     }
 }
 
+/// Generates a [`Causal`] of the document with id zero, which might not be valid for any
+/// schema.
 pub fn arb_causal() -> impl Strategy<Value = Causal> {
     arb_causal_for_dotstore(arb_dotstore())
 }
 
+/// Generates a [`Causal`] of the document with id zero, which is valid for `schema`.
 pub fn arb_causal_for_schema(schema: Schema) -> impl Strategy<Value = Causal> {
     arb_causal_for_dotstore(arb_dotstore_for_schema(schema))
 }
 
+/// Generates a path of the document with id zero ending in a dot.
+pub fn arb_path() -> impl Strategy<Value = PathBuf> {
+    let prim = arb_primitive_kind().prop_flat_map(arb_primitive_for_kind);
+    (
+        prop::collection::vec(prim, 0..5),
+        arb_peer_id(),
+        any::<u64>(),
+    )
+        .prop_map(|(prims, peer, nonce)| {
+            let mut path = PathBuf::new();
+            path.doc(&DocId::new([0; 32]));
+            for prim in prims {
+                push_primitive(&mut path, prim);
+            }
+            path.peer(&peer);
+            path.nonce(nonce);
+            path
+        })
+}
+
+/// Checks if `value` is valid for `schema`.
 pub fn validate(schema: &Schema, value: &Causal) -> bool {
     let schema = Ref::archive(schema);
     schema.as_ref().validate(value)
 }
 
 prop_compose! {
+    /// Generates a [`Schema`] and a [`Causal`] valid for it.
     pub fn schema_and_causal()
         (schema in arb_schema())
         (schema in Just(schema.clone()), crdt in arb_causal_for_schema(schema)) -> (Schema, Causal)
@@ -214,6 +339,7 @@ prop_compose! {
 }
 
 prop_compose! {
+    /// Generates a [`Schema`] and two [`Causal`]s valid for it.
     pub fn schema_and_causal2()
         (schema in arb_schema())
         (
@@ -226,6 +352,7 @@ prop_compose! {
     }
 }
 
+/// Generates a [`Lens`] that can be applied to `s`.
 pub fn arb_lens_for_schema(s: &Schema) -> BoxedStrategy<Lens> {
     let mut strategy = vec![];
     match s {
@@ -234,14 +361,42 @@ pub fn arb_lens_for_schema(s: &Schema) -> BoxedStrategy<Lens> {
                 prop_oneof![
                     Just(Lens::Make(Kind::Flag)),
                     arb_primitive_kind().prop_map(|kind| Lens::Make(Kind::Reg(kind))),
-                    arb_primitive_kind().prop_map(|kind| Lens::Make(Kind::Table(kind))),
+                    arb_primitive_kind().prop_map(|kind| Lens::Make(Kind::LwwReg(kind))),
+                    arb_primitive_kind().prop_map(|kind| Lens::Make(Kind::Encrypted(kind))),
+                    arb_key_kind().prop_map(|kind| Lens::Make(Kind::Table(kind))),
+                    arb_primitive_kind().prop_map(|kind| Lens::Make(Kind::Set(kind))),
+                    Just(Lens::Make(Kind::Dynamic)),
+                    Just(Lens::Make(Kind::Counter)),
+                    Just(Lens::Make(Kind::Text)),
                     Just(Lens::Make(Kind::Struct)),
                 ]
                 .boxed(),
             );
         }
         Schema::Flag => strategy.push(Just(Lens::Destroy(Kind::Flag)).boxed()),
-        Schema::Reg(kind) => strategy.push(Just(Lens::Destroy(Kind::Reg(*kind))).boxed()),
+        Schema::Reg(kind) => {
+            strategy.push(Just(Lens::Destroy(Kind::Reg(*kind))).boxed());
+            strategy.push(Just(Lens::AddMerge(Merge::Min)).boxed());
+            strategy.push(Just(Lens::MakeLww).boxed());
+            if *kind == PrimitiveKind::Str {
+                strategy.push(Just(Lens::AddInvariant(Invariant::MaxLen(8))).boxed());
+            }
+        }
+        Schema::MergeReg(_, merge) => strategy.push(Just(Lens::RemoveMerge(*merge)).boxed()),
+        Schema::LwwReg(kind) => {
+            strategy.push(Just(Lens::Destroy(Kind::LwwReg(*kind))).boxed());
+            strategy.push(Just(Lens::MakeMultiValue).boxed());
+        }
+        Schema::Encrypted(kind) => {
+            strategy.push(Just(Lens::Destroy(Kind::Encrypted(*kind))).boxed())
+        }
+        Schema::CheckedReg(_, invariant) => {
+            strategy.push(Just(Lens::RemoveInvariant(*invariant)).boxed())
+        }
+        Schema::Set(kind) => strategy.push(Just(Lens::Destroy(Kind::Set(*kind))).boxed()),
+        Schema::Dynamic => strategy.push(Just(Lens::Destroy(Kind::Dynamic)).boxed()),
+        Schema::Counter => strategy.push(Just(Lens::Destroy(Kind::Counter)).boxed()),
+        Schema::Text => strategy.push(Just(Lens::Destroy(Kind::Text)).boxed()),
         Schema::Table(kind, s) => {
             if **s == Schema::Null {
                 strategy.push(Just(Lens::Destroy(Kind::Table(*kind))).boxed());
@@ -297,6 +452,7 @@ pub fn arb_lens_for_schema(s: &Schema) -> BoxedStrategy<Lens> {
                     .boxed(),
             );
         }
+        Schema::Local(_) => strategy.push(Just(Lens::MakeShared).boxed()),
     }
     (0..strategy.len())
         .prop_flat_map(move |i| strategy[i].clone())
@@ -304,6 +460,7 @@ pub fn arb_lens_for_schema(s: &Schema) -> BoxedStrategy<Lens> {
 }
 
 prop_compose! {
+    /// Generates a [`Schema`] and a [`Lens`] that can be applied to it.
     pub fn lens_and_schema()
         (schema in arb_schema())
         (schema in Just(schema.clone()), lens in arb_lens_for_schema(&schema)) -> (Lens, Schema)
@@ -313,6 +470,8 @@ prop_compose! {
 }
 
 prop_compose! {
+    /// Generates a [`Schema`], a [`Lens`] that can be applied to it and a [`Causal`] valid
+    /// for it.
     pub fn lens_schema_and_causal()
         (schema in arb_schema())
         (lens in arb_lens_for_schema(&schema), schema in Just(schema.clone()), crdt in arb_causal_for_schema(schema)) -> (Lens, Schema, Causal)
@@ -321,24 +480,9 @@ prop_compose! {
     }
 }
 
+/// Returns the join of `c` and `o`.
 pub fn join(c: &Causal, o: &Causal) -> Causal {
     let mut c = c.clone();
     c.join(o);
     c
 }
-
-pub fn causal_to_crdt(doc: &DocId, causal: &Causal) -> Crdt {
-    let storage = Arc::new(MemStorage::default());
-    let store = BlobSet::load(storage.clone(), "store").unwrap();
-    let expired = BlobSet::load(storage.clone(), "expired").unwrap();
-    let acl = Acl::new(BlobMap::load(storage, "acl").unwrap());
-    let crdt = Crdt::new(store, expired, acl);
-    crdt.join(&(*doc).into(), causal).unwrap();
-    crdt
-}
-
-pub fn crdt_to_causal(doc: &DocId, crdt: &Crdt) -> Causal {
-    let other = CausalContext::new();
-    let other = Ref::archive(&other);
-    crdt.unjoin(&(*doc).into(), doc, other.as_ref()).unwrap()
-}
@@ -1,16 +1,26 @@
 use std::{
-    collections::{hash_map, BTreeMap},
+    collections::{hash_map, BTreeMap, VecDeque},
     fs, io,
     io::Write,
     path::PathBuf,
-    sync::Arc,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
+    time::Duration,
 };
 
+use crate::metrics::{timed, Metrics};
 use futures::{
-    channel::mpsc::{UnboundedReceiver, UnboundedSender},
+    channel::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
     future,
     stream::BoxStream,
-    StreamExt,
+    Future, StreamExt,
 };
 use parking_lot::Mutex;
 use rkyv::{
@@ -29,6 +39,7 @@ use vec_collections::radix_tree::{
     AbstractRadixTree, AbstractRadixTreeMut, ArcRadixTree, IterKey, TKey, TValue,
 };
 
+use crate::keystore::Keystore;
 use crate::Ref;
 
 /// The difference between a tree at one point in time `v0` and at a later point in time `v1`.
@@ -40,6 +51,10 @@ pub struct Diff<K: TKey, V: TValue> {
 }
 
 impl<K: TKey, V: TValue> Diff<K, V> {
+    pub(crate) fn new(v0: ArcRadixTree<K, V>, v1: ArcRadixTree<K, V>) -> Self {
+        Self { v0, v1 }
+    }
+
     /// the previous state as a tree
     pub fn prev(&self) -> &ArcRadixTree<K, V> {
         &self.v0
@@ -154,8 +169,9 @@ impl SharedDeserializeRegistry for SharedDeserializeMap2 {
 pub trait AbstractRadixDb<K: TKey, V: TValue> {
     fn tree(&self) -> &ArcRadixTree<K, V>;
     fn tree_mut(&mut self) -> &mut ArcRadixTree<K, V>;
-    fn flush(&mut self) -> anyhow::Result<()>;
-    fn vacuum(&mut self) -> anyhow::Result<()>;
+    fn flush(&mut self) -> anyhow::Result<Flush>;
+    fn sync(&mut self) -> anyhow::Result<Flush>;
+    fn vacuum(&mut self) -> anyhow::Result<Flush>;
     fn watch(&mut self) -> futures::channel::mpsc::UnboundedReceiver<ArcRadixTree<K, V>>;
     fn watch_prefix(&mut self, prefix: Vec<K>) -> BoxStream<'static, Diff<K, V>> {
         let tree = self.tree().clone();
@@ -167,6 +183,18 @@ pub trait AbstractRadixDb<K: TKey, V: TValue> {
             })
             .boxed()
     }
+    /// Like [`AbstractRadixDb::watch_prefix`], but each diff is relative to the tree of the
+    /// previous diff instead of the tree when the watch started.
+    fn watch_prefix_changes(&mut self, prefix: Vec<K>) -> BoxStream<'static, Diff<K, V>> {
+        let tree = self.tree().filter_prefix(&prefix);
+        self.watch()
+            .scan(tree, move |prev, curr| {
+                let v1 = curr.filter_prefix(&prefix);
+                let v0 = std::mem::replace(prev, v1.clone());
+                future::ready(Some(Diff { v0, v1 }))
+            })
+            .boxed()
+    }
 }
 
 /// Trait for radixdb storage
@@ -366,6 +394,9 @@ pub mod browser {
 }
 
 /// Very basic file based storage
+///
+/// Every store lives in it's own subdirectory of the base directory, so the directory can be
+/// fsynced after atomically replacing a file.
 #[derive(Default, Clone)]
 pub struct FileStorage {
     base: PathBuf,
@@ -378,40 +409,69 @@ impl FileStorage {
             base: base.as_ref().to_path_buf(),
         }
     }
+
+    /// Returns the directory of a store, moving a store from the old flat layout if
+    /// necessary.
+    fn dir(&self, file: &str) -> io::Result<PathBuf> {
+        let dir = self.base.join(file);
+        let tmp = self.base.join(format!("{}.migrate", file));
+        if dir.is_file() {
+            fs::rename(&dir, &tmp)?;
+        }
+        fs::create_dir_all(&dir)?;
+        // also completes a migration that was interrupted
+        if tmp.is_file() {
+            fs::rename(&tmp, dir.join("data"))?;
+            sync_dir(&self.base)?;
+        }
+        Ok(dir)
+    }
+}
+
+/// Makes renames and newly created files in a directory durable.
+fn sync_dir(dir: &std::path::Path) -> io::Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
 }
 
 impl Storage for FileStorage {
     fn append(&self, file: &str, chunk: &[u8]) -> io::Result<()> {
         if !chunk.is_empty() {
+            let dir = self.dir(file)?;
+            let path = dir.join("data");
+            let created = !path.exists();
             let mut file = fs::OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(self.base.join(file))?;
+                .open(path)?;
             file.write_all(chunk)?;
+            file.sync_data()?;
+            if created {
+                sync_dir(&dir)?;
+            }
         }
         Ok(())
     }
 
     fn set(&self, file: &str, data: &[u8]) -> std::io::Result<()> {
-        let tmp = format!("{}.tmp", file);
+        let dir = self.dir(file)?;
+        let from = dir.join("data.tmp");
         let mut tmp_file = fs::OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(self.base.join(&tmp))?;
+            .open(&from)?;
         tmp_file.write_all(data)?;
-        tmp_file.flush()?;
-        let from = self.base.join(tmp);
-        let to = self.base.join(file);
-        match fs::rename(&from, &to) {
-            Ok(()) => {}
-            Err(e) => return Err(e),
-        }
-        Ok(())
+        tmp_file.sync_all()?;
+        fs::rename(&from, dir.join("data"))?;
+        sync_dir(&dir)
     }
 
     fn load(&self, file: &str, mut f: Box<dyn FnMut(&[u8]) + '_>) -> io::Result<()> {
-        match std::fs::read(self.base.join(file)) {
+        match std::fs::read(self.dir(file)?.join("data")) {
             Ok(data) => f(&data),
             Err(e) if e.kind() == io::ErrorKind::NotFound => f(&[]),
             Err(e) => return Err(e),
@@ -420,21 +480,529 @@ impl Storage for FileStorage {
     }
 }
 
+/// Magic bytes at the start of every file written by [`EncryptedStorage`].
+const ENCRYPTED_MAGIC: &[u8; 8] = b"tlfsenc1";
+
+/// Encrypts the files of another [`Storage`].
+///
+/// Every appended chunk is encrypted on it's own with XChaCha20-Poly1305 and stored as a frame
+/// prefixed with it's length, so appending never rewrites previous chunks. The file name and
+/// the index of the frame are authenticated with every frame, so frames can't be reordered,
+/// dropped or moved to another file. Files start with a magic number and loading a file that
+/// wasn't written by an [`EncryptedStorage`] fails. A truncated last frame, as left by a crash
+/// during an append, is removed when the file is loaded.
+#[derive(Clone)]
+pub struct EncryptedStorage {
+    inner: Arc<dyn Storage>,
+    keystore: Keystore,
+    /// Number of frames of every file that was loaded or written.
+    frames: Arc<Mutex<BTreeMap<String, u64>>>,
+}
+
+impl EncryptedStorage {
+    /// Creates a new encrypted storage wrapping `inner`.
+    pub fn new(inner: Arc<dyn Storage>, key: [u8; 32]) -> Self {
+        Self {
+            inner,
+            keystore: Keystore::from_key(&key),
+            frames: Default::default(),
+        }
+    }
+
+    /// Generates a random key.
+    pub fn generate_key() -> [u8; 32] {
+        let mut key = [0; 32];
+        getrandom::getrandom(&mut key).unwrap();
+        key
+    }
+
+    fn aad(file: &str, index: u64) -> Vec<u8> {
+        let mut aad = index.to_le_bytes().to_vec();
+        aad.extend_from_slice(file.as_bytes());
+        aad
+    }
+
+    fn encrypt(&self, file: &str, index: u64, data: &[u8]) -> Vec<u8> {
+        let frame = self.keystore.encrypt(data, &Self::aad(file, index));
+        let mut out = Vec::with_capacity(ENCRYPTED_MAGIC.len() + 4 + frame.len());
+        if index == 0 {
+            out.extend_from_slice(ENCRYPTED_MAGIC);
+        }
+        out.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        out.extend_from_slice(&frame);
+        out
+    }
+
+    /// Decrypts a file. Returns the plaintext, the number of frames and the length of the
+    /// valid prefix of `data`.
+    fn decrypt(&self, file: &str, data: &[u8]) -> io::Result<(AlignedVec, u64, usize)> {
+        let mut out = AlignedVec::new();
+        if data.len() < ENCRYPTED_MAGIC.len() && ENCRYPTED_MAGIC.starts_with(data) {
+            return Ok((out, 0, 0));
+        }
+        if !data.starts_with(ENCRYPTED_MAGIC) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not encrypted", file),
+            ));
+        }
+        let mut pos = ENCRYPTED_MAGIC.len();
+        let mut index = 0;
+        while data.len() - pos >= 4 {
+            let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            if data.len() - pos - 4 < len {
+                break;
+            }
+            let chunk = self
+                .keystore
+                .decrypt(&data[pos + 4..pos + 4 + len], &Self::aad(file, index))
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            out.extend_from_slice(&chunk);
+            pos += 4 + len;
+            index += 1;
+        }
+        Ok((out, index, if index == 0 { 0 } else { pos }))
+    }
+
+    /// Loads and decrypts a file, removing a truncated last frame.
+    fn read(&self, file: &str) -> io::Result<(AlignedVec, u64)> {
+        let mut res = Ok(Default::default());
+        let mut torn = None;
+        self.inner.load(
+            file,
+            Box::new(|data| {
+                res = self.decrypt(file, data).map(|(out, frames, len)| {
+                    if len < data.len() {
+                        torn = Some((data[..len].to_vec(), data.len() - len));
+                    }
+                    (out, frames)
+                })
+            }),
+        )?;
+        if let Some((valid, len)) = torn {
+            tracing::warn!("removing truncated frame of {} bytes from {}", len, file);
+            self.inner.set(file, &valid)?;
+        }
+        res
+    }
+}
+
+impl Storage for EncryptedStorage {
+    fn append(&self, file: &str, chunk: &[u8]) -> io::Result<()> {
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        let mut frames = self.frames.lock();
+        let index = match frames.get(file) {
+            Some(index) => *index,
+            None => self.read(file)?.1,
+        };
+        // a failed append can leave a partial frame, the next append reads the file again.
+        frames.remove(file);
+        self.inner.append(file, &self.encrypt(file, index, chunk))?;
+        frames.insert(file.to_owned(), index + 1);
+        Ok(())
+    }
+
+    fn set(&self, file: &str, data: &[u8]) -> io::Result<()> {
+        let mut frames = self.frames.lock();
+        frames.remove(file);
+        if data.is_empty() {
+            self.inner.set(file, &[])?;
+            frames.insert(file.to_owned(), 0);
+        } else {
+            self.inner.set(file, &self.encrypt(file, 0, data))?;
+            frames.insert(file.to_owned(), 1);
+        }
+        Ok(())
+    }
+
+    fn load(&self, file: &str, mut f: Box<dyn FnMut(&[u8]) + '_>) -> io::Result<()> {
+        let mut frames = self.frames.lock();
+        let (data, index) = self.read(file)?;
+        frames.insert(file.to_owned(), index);
+        f(&data);
+        Ok(())
+    }
+}
+
+/// Resolves once a flush has been written to storage.
+pub struct Flush(oneshot::Receiver<io::Result<()>>);
+
+impl Flush {
+    fn ready(res: io::Result<()>) -> Self {
+        let (tx, rx) = oneshot::channel();
+        tx.send(res).ok();
+        Self(rx)
+    }
+}
+
+impl Future for Flush {
+    type Output = anyhow::Result<()>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        Poll::Ready(match futures::ready!(Pin::new(&mut self.0).poll(cx)) {
+            Ok(res) => res.map_err(Into::into),
+            Err(_) => Err(anyhow::anyhow!("flush worker stopped")),
+        })
+    }
+}
+
+enum WriteKind {
+    Append,
+    Set,
+    /// Writes nothing, completes once all writes queued before it are written.
+    Barrier,
+}
+
+struct PendingWrite {
+    file: String,
+    kind: WriteKind,
+    data: Vec<u8>,
+    done: Vec<oneshot::Sender<io::Result<()>>>,
+    /// Set when the write fails.
+    failed: Vec<Arc<AtomicBool>>,
+}
+
+impl PendingWrite {
+    /// Merges a later write to the same file into this one.
+    fn coalesce(&mut self, other: PendingWrite) {
+        match other.kind {
+            WriteKind::Append => self.data.extend_from_slice(&other.data),
+            WriteKind::Set => {
+                self.kind = WriteKind::Set;
+                self.data = other.data;
+            }
+            WriteKind::Barrier => {}
+        }
+        self.done.extend(other.done);
+        self.failed.extend(other.failed);
+    }
+
+    fn run(self, storage: &dyn Storage, metrics: &Metrics) {
+        let res = match self.kind {
+            WriteKind::Append | WriteKind::Set => {
+                let (res, duration) = timed(|| match self.kind {
+                    WriteKind::Set => storage.set(&self.file, &self.data),
+                    _ => storage.append(&self.file, &self.data),
+                });
+                metrics.record_flush(duration);
+                res
+            }
+            WriteKind::Barrier => Ok(()),
+        };
+        if let Err(err) = &res {
+            tracing::error!("failed to write {}: {}", self.file, err);
+            for failed in &self.failed {
+                failed.store(true, Ordering::SeqCst);
+            }
+        }
+        for tx in self.done {
+            let res = match &res {
+                Ok(()) => Ok(()),
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            };
+            tx.send(res).ok();
+        }
+    }
+}
+
+/// Controls when changes are written to storage.
+///
+/// Changes are always serialized by the thread making them and written by a background
+/// worker. With `Always` a change is written before the call making it returns. `Interval` and
+/// `Manual` keep changes in memory until the next interval or call to `sync`, so a crash can
+/// lose changes that were not written yet. Files are written append only and vacuumed atomically, but an append
+/// interrupted by a crash can leave a torn file.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Durability {
+    /// Every change is written to storage.
+    #[default]
+    Always,
+    /// Changes are written to storage periodically. On targets without threads this behaves
+    /// like `Always`.
+    Interval(Duration),
+    /// Changes are only written to storage when calling `sync`.
+    Manual,
+}
+
+/// A database that can write its pending changes to storage.
+trait Dirty: Send + Sync {
+    fn sync(&self) -> anyhow::Result<Flush>;
+
+    /// Like `sync` but skips the database if it is locked.
+    fn try_sync(&self) -> Option<anyhow::Result<Flush>>;
+
+    /// Returns the length of the database log in bytes.
+    #[cfg(feature = "metrics")]
+    fn size(&self) -> u64;
+}
+
+impl<K, V> Dirty for Mutex<RadixDb<K, V>>
+where
+    K: TKey,
+    V: TValue,
+    RadixDb<K, V>: AbstractRadixDb<K, V> + Send,
+{
+    fn sync(&self) -> anyhow::Result<Flush> {
+        self.lock().sync()
+    }
+
+    fn try_sync(&self) -> Option<anyhow::Result<Flush>> {
+        Some(self.try_lock()?.sync())
+    }
+
+    #[cfg(feature = "metrics")]
+    fn size(&self) -> u64 {
+        self.lock().pos as u64
+    }
+}
+
+#[derive(Default)]
+struct Shared {
+    durability: Mutex<Durability>,
+    dbs: Mutex<Vec<Weak<dyn Dirty>>>,
+    metrics: Metrics,
+}
+
+impl Shared {
+    fn sync(&self) -> Vec<anyhow::Result<Flush>> {
+        let mut dbs = self.dbs.lock();
+        dbs.retain(|db| db.strong_count() > 0);
+        dbs.iter()
+            .filter_map(|db| db.upgrade())
+            .map(|db| db.sync())
+            .collect()
+    }
+
+    /// Syncs the databases that aren't locked. Used by the worker, which must not wait on a
+    /// database whose owner waits on the worker.
+    #[cfg(not(target_family = "wasm"))]
+    fn try_sync(&self) -> Vec<anyhow::Result<Flush>> {
+        let mut dbs = self.dbs.lock();
+        dbs.retain(|db| db.strong_count() > 0);
+        dbs.iter()
+            .filter_map(|db| db.upgrade())
+            .filter_map(|db| db.try_sync())
+            .collect()
+    }
+}
+
+/// Writes to a [`Storage`] from a background worker.
+///
+/// Writes queued while the worker is busy are coalesced into a single write per file. On
+/// targets without threads writes are performed on the calling thread.
+#[derive(Clone)]
+pub struct Flusher {
+    storage: Arc<dyn Storage>,
+    shared: Arc<Shared>,
+    #[cfg(not(target_family = "wasm"))]
+    tx: Arc<Mutex<std::sync::mpsc::Sender<Option<PendingWrite>>>>,
+}
+
+impl Flusher {
+    /// Creates a new [`Flusher`] and spawns its worker.
+    pub fn new(storage: Arc<dyn Storage>) -> Self {
+        let shared = Arc::new(Shared::default());
+        #[cfg(not(target_family = "wasm"))]
+        {
+            let (tx, rx) = std::sync::mpsc::channel();
+            let worker = storage.clone();
+            let worker_shared = shared.clone();
+            std::thread::Builder::new()
+                .name("tlfs-flush".into())
+                .spawn(move || Self::worker(&*worker, &worker_shared, rx))
+                .expect("failed to spawn flush worker");
+            Self {
+                storage,
+                shared,
+                tx: Arc::new(Mutex::new(tx)),
+            }
+        }
+        #[cfg(target_family = "wasm")]
+        Self { storage, shared }
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn worker(
+        storage: &dyn Storage,
+        shared: &Shared,
+        rx: std::sync::mpsc::Receiver<Option<PendingWrite>>,
+    ) {
+        use std::sync::mpsc::RecvTimeoutError;
+        let mut last_sync = std::time::Instant::now();
+        loop {
+            let durability = *shared.durability.lock();
+            let write = if let Durability::Interval(interval) = durability {
+                match rx.recv_timeout(interval.saturating_sub(last_sync.elapsed())) {
+                    Ok(write) => write,
+                    Err(RecvTimeoutError::Timeout) => {
+                        for res in shared.try_sync() {
+                            if let Err(err) = res {
+                                tracing::error!("{}", err);
+                            }
+                        }
+                        last_sync = std::time::Instant::now();
+                        continue;
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            } else {
+                match rx.recv() {
+                    Ok(write) => write,
+                    Err(_) => break,
+                }
+            };
+            let mut batch: Vec<PendingWrite> = write.into_iter().collect();
+            for write in rx.try_iter().flatten() {
+                if let Some(prev) = batch.iter_mut().find(|prev| prev.file == write.file) {
+                    prev.coalesce(write);
+                } else {
+                    batch.push(write);
+                }
+            }
+            for write in batch {
+                write.run(storage, &shared.metrics);
+            }
+        }
+    }
+
+    /// Returns the [`Durability`] mode.
+    pub fn durability(&self) -> Durability {
+        *self.shared.durability.lock()
+    }
+
+    /// Sets the [`Durability`] mode.
+    pub fn set_durability(&self, durability: Durability) {
+        *self.shared.durability.lock() = durability;
+        // wake up the worker so that it picks up the new interval
+        #[cfg(not(target_family = "wasm"))]
+        self.tx.lock().send(None).ok();
+    }
+
+    /// Returns true if writes should be deferred until the next sync.
+    fn deferred(&self) -> bool {
+        match self.durability() {
+            Durability::Always => false,
+            Durability::Interval(_) => !cfg!(target_family = "wasm"),
+            Durability::Manual => true,
+        }
+    }
+
+    fn register(&self, db: Weak<dyn Dirty>) {
+        self.shared.dbs.lock().push(db);
+    }
+
+    pub(crate) fn metrics(&self) -> &Metrics {
+        &self.shared.metrics
+    }
+
+    /// Returns the total length of the database logs in bytes.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn size(&self) -> u64 {
+        self.shared
+            .dbs
+            .lock()
+            .iter()
+            .filter_map(|db| db.upgrade())
+            .map(|db| db.size())
+            .sum()
+    }
+
+    /// Writes all pending changes. The returned future resolves once they and all writes
+    /// queued before are written to storage.
+    pub fn sync(&self) -> impl Future<Output = anyhow::Result<()>> {
+        let flushes = self.shared.sync();
+        let barrier = self.barrier();
+        async move {
+            for flush in flushes {
+                flush?.await?;
+            }
+            barrier.await
+        }
+    }
+
+    /// Returns a [`Flush`] that resolves once all writes queued so far are written.
+    fn barrier(&self) -> Flush {
+        self.write("", WriteKind::Barrier, &[], None)
+    }
+
+    fn write(
+        &self,
+        file: &str,
+        kind: WriteKind,
+        data: &[u8],
+        failed: Option<Arc<AtomicBool>>,
+    ) -> Flush {
+        let (tx, rx) = oneshot::channel();
+        let write = PendingWrite {
+            file: file.to_owned(),
+            kind,
+            data: data.to_vec(),
+            done: vec![tx],
+            failed: failed.into_iter().collect(),
+        };
+        #[cfg(not(target_family = "wasm"))]
+        if let Err(err) = self.tx.lock().send(Some(write)) {
+            if let Some(write) = err.0 {
+                write.run(&*self.storage, &self.shared.metrics);
+            }
+        }
+        #[cfg(target_family = "wasm")]
+        write.run(&*self.storage, &self.shared.metrics);
+        Flush(rx)
+    }
+
+    /// Queues appending a chunk to a file.
+    pub fn append(&self, file: &str, chunk: &[u8]) -> Flush {
+        if chunk.is_empty() {
+            return Flush::ready(Ok(()));
+        }
+        self.write(file, WriteKind::Append, chunk, None)
+    }
+
+    /// Queues setting the content of a file.
+    pub fn set(&self, file: &str, data: &[u8]) -> Flush {
+        self.write(file, WriteKind::Set, data, None)
+    }
+
+    /// Loads a file after waiting for the queued writes. See [`Storage::load`].
+    pub fn load(&self, file: &str, f: impl FnMut(&[u8])) -> io::Result<()> {
+        futures::executor::block_on(self.barrier())
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        self.storage.load(file, Box::new(f))
+    }
+}
+
+impl From<Arc<dyn Storage>> for Flusher {
+    fn from(storage: Arc<dyn Storage>) -> Self {
+        Self::new(storage)
+    }
+}
+
 #[allow(clippy::type_complexity)]
 pub struct RadixDb<K: TKey, V: TValue> {
-    storage: Arc<dyn Storage>,
+    storage: Flusher,
     name: String,
     serializers: Option<(
         SharedSerializeMap2,
         BTreeMap<usize, Arc<Vec<ArcRadixTree<K, V>>>>,
     )>,
+    /// The serialized roots. The value of the root isn't behind one of the arcs kept alive
+    /// for the serializers, so without keeping it alive a new value could be allocated at
+    /// it's address and be mistaken for the serialized one.
+    roots: Vec<ArcRadixTree<K, V>>,
     pos: usize,
+    dirty: bool,
+    /// Set by the worker when an append failed. `pos` and the serializers assume that every
+    /// queued append was written, so the next sync has to rewrite the whole file.
+    failed: Arc<AtomicBool>,
     tree: ArcRadixTree<K, V>,
     watchers: Vec<UnboundedSender<ArcRadixTree<K, V>>>,
 }
 
 impl<K: TKey, V: TValue> RadixDb<K, V> {
-    pub fn load(storage: Arc<dyn Storage>, name: impl Into<String>) -> anyhow::Result<Self>
+    pub fn load(storage: Flusher, name: impl Into<String>) -> anyhow::Result<Self>
     where
         Archived<K>: Deserialize<K, SharedDeserializeMap2>,
         Archived<V>: Deserialize<V, SharedDeserializeMap2>,
@@ -443,29 +1011,29 @@ impl<K: TKey, V: TValue> RadixDb<K, V> {
         let mut tree: anyhow::Result<ArcRadixTree<K, V>> = Ok(Default::default());
         let mut map = Default::default();
         let mut pos = Default::default();
-        storage.load(
-            &name,
-            Box::new(|data| {
-                if !data.is_empty() {
-                    let mut deserializer = SharedDeserializeMap2::default();
-                    let archived: &Archived<ArcRadixTree<K, V>> =
-                        unsafe { archived_root::<ArcRadixTree<K, V>>(data) };
-                    tree = archived
-                        .deserialize(&mut deserializer)
-                        .map_err(|e| anyhow::anyhow!("Error while deserializing: {}", e));
-                    map = deserializer.to_shared_serializer_map(&data[0] as *const u8);
-                    pos = data.len();
-                }
-            }),
-        )?;
+        storage.load(&name, |data: &[u8]| {
+            if !data.is_empty() {
+                let mut deserializer = SharedDeserializeMap2::default();
+                let archived: &Archived<ArcRadixTree<K, V>> =
+                    unsafe { archived_root::<ArcRadixTree<K, V>>(data) };
+                tree = archived
+                    .deserialize(&mut deserializer)
+                    .map_err(|e| anyhow::anyhow!("Error while deserializing: {}", e));
+                map = deserializer.to_shared_serializer_map(&data[0] as *const u8);
+                pos = data.len();
+            }
+        })?;
         let tree = tree?;
         let mut arcs = Default::default();
         tree.all_arcs(&mut arcs);
         Ok(Self {
+            roots: vec![tree.clone()],
             tree,
             name,
             storage,
             pos,
+            dirty: false,
+            failed: Default::default(),
             serializers: Some((map, arcs)),
             watchers: Default::default(),
         })
@@ -497,7 +1065,7 @@ where
         &mut self.tree
     }
 
-    fn vacuum(&mut self) -> anyhow::Result<()> {
+    fn vacuum(&mut self) -> anyhow::Result<Flush> {
         // write ourselves to a new file
         let mut file = AlignedVec::new();
         let mut serializer = CompositeSerializer::new(
@@ -515,14 +1083,37 @@ where
         let mut arcs = BTreeMap::default();
         self.tree.all_arcs(&mut arcs);
         // store the new file and the new arcs
-        self.storage.set(&self.name, &file)?;
+        self.failed.store(false, Ordering::SeqCst);
+        let flush =
+            self.storage
+                .write(&self.name, WriteKind::Set, &file, Some(self.failed.clone()));
         self.pos = file.len();
         self.serializers = Some((map, arcs));
+        self.roots = vec![self.tree.clone()];
+        self.dirty = false;
         self.notify();
-        Ok(())
+        Ok(flush)
     }
 
-    fn flush(&mut self) -> anyhow::Result<()> {
+    fn flush(&mut self) -> anyhow::Result<Flush> {
+        self.dirty = true;
+        let res = if self.storage.deferred() {
+            Ok(())
+        } else {
+            let flush = self.sync()?;
+            futures::executor::block_on(flush)
+        };
+        self.notify();
+        res.map(|_| Flush::ready(Ok(())))
+    }
+
+    fn sync(&mut self) -> anyhow::Result<Flush> {
+        if self.failed.load(Ordering::SeqCst) {
+            return self.vacuum();
+        }
+        if !self.dirty {
+            return Ok(Flush::ready(Ok(())));
+        }
         let (map, mut arcs) = self.serializers.take().unwrap_or_default();
         let mut t = AlignedVec::new();
         let mut serializer = CompositeSerializer::new(
@@ -537,11 +1128,17 @@ where
         // disables copy on write for these nodes.
         self.tree.all_arcs(&mut arcs);
         let (_, _, map) = serializer.into_components();
-        self.storage.append(&self.name, &t)?;
+        let flush = if t.is_empty() {
+            Flush::ready(Ok(()))
+        } else {
+            self.storage
+                .write(&self.name, WriteKind::Append, &t, Some(self.failed.clone()))
+        };
         self.pos += t.len();
         self.serializers = Some((map, arcs));
-        self.notify();
-        Ok(())
+        self.roots.push(self.tree.clone());
+        self.dirty = false;
+        Ok(flush)
     }
 
     fn watch(&mut self) -> UnboundedReceiver<ArcRadixTree<K, V>> {
@@ -551,9 +1148,109 @@ where
     }
 }
 
-/// A set of blobs, backed by a radix tree
+fn first_after<T: AbstractRadixTree<u8, ()>>(
+    node: &T,
+    mut key: Vec<u8>,
+    after: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    key.extend_from_slice(node.prefix());
+    if let Some(after) = after {
+        let n = key.len().min(after.len());
+        match key[..n].cmp(&after[..n]) {
+            std::cmp::Ordering::Less => return None,
+            std::cmp::Ordering::Equal if key.len() >= after.len() => return None,
+            std::cmp::Ordering::Equal => {
+                // the key is a prefix of `after`, so only children can sort after it.
+                return node
+                    .children()
+                    .iter()
+                    .find_map(|child| first_after(child, key.clone(), Some(after)));
+            }
+            std::cmp::Ordering::Greater => {}
+        }
+    }
+    if node.value().is_some() {
+        return Some(key);
+    }
+    node.children()
+        .iter()
+        .find_map(|child| first_after(child, key.clone(), None))
+}
+
+/// Appends the keys that sort after `after` to `out` in order until it holds `limit` keys.
+/// Subtrees sorting before `after` are skipped without visiting their keys.
+fn keys_after<T: AbstractRadixTree<u8, ()>>(
+    node: &T,
+    key: &mut Vec<u8>,
+    after: Option<&[u8]>,
+    limit: usize,
+    out: &mut Vec<Vec<u8>>,
+) {
+    let len = key.len();
+    key.extend_from_slice(node.prefix());
+    let mut after = after;
+    let mut value = node.value().is_some();
+    if let Some(bound) = after {
+        let n = key.len().min(bound.len());
+        match key[..n].cmp(&bound[..n]) {
+            std::cmp::Ordering::Less => {
+                key.truncate(len);
+                return;
+            }
+            std::cmp::Ordering::Equal if key.len() >= bound.len() => {
+                // the key starts with `after`, only the key itself doesn't sort after it.
+                value &= key.len() > bound.len();
+                after = None;
+            }
+            // the key is a prefix of `after`, so only children can sort after it.
+            std::cmp::Ordering::Equal => value = false,
+            std::cmp::Ordering::Greater => after = None,
+        }
+    }
+    if value && out.len() < limit {
+        out.push(key.clone());
+    }
+    for child in node.children() {
+        if out.len() >= limit {
+            break;
+        }
+        keys_after(child, key, after, limit, out);
+    }
+    key.truncate(len);
+}
+
+/// Number of keys collected at a time by [`ScanAfter`].
+const SCAN_CHUNK: usize = 256;
+
+/// Iterator over the keys of a snapshot that sort after a key, see [`BlobSet::scan_after`].
+pub struct ScanAfter {
+    tree: ArcRadixTree<u8, ()>,
+    after: Option<Vec<u8>>,
+    keys: VecDeque<Vec<u8>>,
+    done: bool,
+}
+
+impl Iterator for ScanAfter {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.keys.is_empty() && !self.done {
+            let mut keys = Vec::with_capacity(SCAN_CHUNK);
+            let after = self.after.as_deref();
+            keys_after(&self.tree, &mut Vec::new(), after, SCAN_CHUNK, &mut keys);
+            self.done = keys.len() < SCAN_CHUNK;
+            if let Some(last) = keys.last() {
+                self.after = Some(last.clone());
+            }
+            self.keys.extend(keys);
+        }
+        self.keys.pop_front()
+    }
+}
+
+/// A set of blobs, backed by a radix tree. The version is incremented on every write.
 #[derive(Clone)]
-pub struct BlobSet(Arc<Mutex<RadixDb<u8, ()>>>);
+pub struct BlobSet(Arc<Mutex<RadixDb<u8, ()>>>, Arc<AtomicU64>);
 
 impl std::fmt::Debug for BlobSet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -566,25 +1263,34 @@ impl std::fmt::Debug for BlobSet {
 }
 
 impl BlobSet {
-    pub fn load(storage: Arc<dyn Storage>, name: &str) -> anyhow::Result<Self> {
-        Ok(Self(Arc::new(Mutex::new(RadixDb::load(storage, name)?))))
+    pub fn load(storage: Flusher, name: &str) -> anyhow::Result<Self> {
+        let db = Arc::new(Mutex::new(RadixDb::load(storage.clone(), name)?));
+        let weak: Weak<dyn Dirty> = Arc::downgrade(&db) as _;
+        storage.register(weak);
+        Ok(Self(db, Default::default()))
     }
 
-    pub fn flush(&self) -> anyhow::Result<()> {
+    pub fn flush(&self) -> anyhow::Result<Flush> {
         self.0.lock().flush()
     }
 
+    pub fn version(&self) -> u64 {
+        self.1.load(Ordering::SeqCst)
+    }
+
     pub fn insert(&self, key: impl AsRef<[u8]>) {
         let t: ArcRadixTree<u8, ()> = ArcRadixTree::single(key.as_ref(), ());
         // right biased union
         let mut db = self.0.lock();
         db.tree_mut().union_with(&t);
+        self.1.fetch_add(1, Ordering::SeqCst);
     }
 
     pub fn remove(&self, key: impl AsRef<[u8]>) {
         let t = ArcRadixTree::single(key.as_ref(), ());
         let mut db = self.0.lock();
         db.tree_mut().difference_with(&t);
+        self.1.fetch_add(1, Ordering::SeqCst);
     }
 
     pub fn contains(&self, key: impl AsRef<[u8]>) -> bool {
@@ -602,12 +1308,44 @@ impl BlobSet {
         tree.into_iter().map(|(k, _)| k)
     }
 
+    /// Returns the smallest key starting with `prefix` that sorts after `after` and doesn't
+    /// start with `after`. Subtrees sorting before `after` are skipped without visiting their
+    /// keys.
+    pub fn first_after(&self, prefix: impl AsRef<[u8]>, after: Option<&[u8]>) -> Option<Vec<u8>> {
+        let tree = self.0.lock().tree().filter_prefix(prefix.as_ref());
+        first_after(&tree, Vec::new(), after)
+    }
+
+    /// Iterates the keys starting with `prefix` that sort after `after` in a snapshot of the
+    /// set. Seeks to `after` instead of visiting the keys before it.
+    pub fn scan_after(&self, prefix: impl AsRef<[u8]>, after: Option<&[u8]>) -> ScanAfter {
+        ScanAfter {
+            tree: self.0.lock().tree().filter_prefix(prefix.as_ref()),
+            after: after.map(|after| after.to_vec()),
+            keys: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Returns the current state of the keys starting with `prefix`. The tree shares it's
+    /// nodes with the set, so taking a snapshot is cheap.
+    pub fn snapshot(&self, prefix: impl AsRef<[u8]>) -> ArcRadixTree<u8, ()> {
+        self.0.lock().tree().filter_prefix(prefix.as_ref())
+    }
+
     pub fn watch_prefix<'a>(
         &'a self,
         prefix: impl AsRef<[u8]>,
     ) -> BoxStream<'static, Diff<u8, ()>> {
         self.0.lock().watch_prefix(prefix.as_ref().into())
     }
+
+    pub fn watch_prefix_changes(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> BoxStream<'static, Diff<u8, ()>> {
+        self.0.lock().watch_prefix_changes(prefix.as_ref().into())
+    }
 }
 
 /// A map with blob keys and values, backed by a radix tree
@@ -625,8 +1363,11 @@ impl std::fmt::Debug for BlobMap {
 }
 
 impl BlobMap {
-    pub fn load(storage: Arc<dyn Storage>, name: &str) -> anyhow::Result<Self> {
-        Ok(Self(Arc::new(Mutex::new(RadixDb::load(storage, name)?))))
+    pub fn load(storage: Flusher, name: &str) -> anyhow::Result<Self> {
+        let db = Arc::new(Mutex::new(RadixDb::load(storage.clone(), name)?));
+        let weak: Weak<dyn Dirty> = Arc::downgrade(&db) as _;
+        storage.register(weak);
+        Ok(Self(db))
     }
 
     pub fn insert(&self, key: impl AsRef<[u8]>, value: impl AsRef<[u8]>) -> anyhow::Result<()> {
@@ -641,6 +1382,30 @@ impl BlobMap {
         Ok(())
     }
 
+    /// Inserts all `entries` with a single write.
+    pub fn insert_many<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        entries: impl IntoIterator<Item = (K, V)>,
+    ) -> anyhow::Result<()> {
+        let mut db = self.0.lock();
+        for (key, value) in entries {
+            let t = ArcRadixTree::single(key.as_ref(), value.as_ref().into());
+            db.tree_mut().outer_combine_with(&t, |a, b| {
+                *a = b.clone();
+                true
+            });
+        }
+        db.flush()?;
+        Ok(())
+    }
+
+    /// Rewrites the file of the map, dropping overwritten and removed entries. Waits until
+    /// the file is written.
+    pub fn vacuum(&self) -> anyhow::Result<()> {
+        let flush = self.0.lock().vacuum()?;
+        futures::executor::block_on(flush)
+    }
+
     pub fn insert_archived<T: Archive + Serialize<AllocSerializer<256>>>(
         &self,
         key: impl AsRef<[u8]>,
@@ -690,4 +1455,288 @@ impl BlobMap {
     ) -> BoxStream<'static, Diff<u8, Arc<[u8]>>> {
         self.0.lock().watch_prefix(prefix.as_ref().into())
     }
+
+    pub fn watch_prefix_changes(
+        &self,
+        prefix: impl AsRef<[u8]>,
+    ) -> BoxStream<'static, Diff<u8, Arc<[u8]>>> {
+        self.0.lock().watch_prefix_changes(prefix.as_ref().into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keystore::OVERHEAD;
+
+    #[async_std::test]
+    async fn test_flusher() -> anyhow::Result<()> {
+        let storage = Flusher::new(Arc::new(MemStorage::default()));
+        let mut flushes = vec![];
+        for i in 0..100u8 {
+            flushes.push(storage.append("file", &[i]));
+        }
+        flushes.push(storage.set("file", &[0, 1]));
+        flushes.push(storage.append("file", &[2]));
+        for flush in flushes {
+            flush.await?;
+        }
+        let mut data = vec![];
+        storage.load("file", |chunk| data.extend_from_slice(chunk))?;
+        assert_eq!(data, vec![0, 1, 2]);
+        Ok(())
+    }
+
+    /// Storage whose appends fail while `fail` is set.
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: MemStorage,
+        fail: AtomicBool,
+    }
+
+    impl Storage for FlakyStorage {
+        fn append(&self, file: &str, chunk: &[u8]) -> io::Result<()> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.append(file, chunk)
+        }
+
+        fn set(&self, file: &str, data: &[u8]) -> io::Result<()> {
+            self.inner.set(file, data)
+        }
+
+        fn load(&self, file: &str, f: Box<dyn FnMut(&[u8]) + '_>) -> io::Result<()> {
+            self.inner.load(file, f)
+        }
+    }
+
+    #[async_std::test]
+    async fn test_flusher_waits_for_queued_writes() -> anyhow::Result<()> {
+        let storage = Arc::new(MemStorage::default());
+        let flusher = Flusher::new(storage.clone());
+        for i in 0..100u8 {
+            drop(flusher.append("file", &[i]));
+        }
+        let mut data = vec![];
+        flusher.load("file", |chunk| data.extend_from_slice(chunk))?;
+        assert_eq!(data.len(), 100);
+
+        drop(flusher.append("other", &[0]));
+        flusher.sync().await?;
+        let mut data = vec![];
+        storage.load("other", Box::new(|chunk| data.extend_from_slice(chunk)))?;
+        assert_eq!(data, vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_append() -> anyhow::Result<()> {
+        let storage = Arc::new(FlakyStorage::default());
+        let reload = || BlobMap::load(Flusher::new(storage.clone()), "map");
+        let map = BlobMap::load(Flusher::new(storage.clone()), "map")?;
+        map.insert(b"a", b"b")?;
+        storage.fail.store(true, Ordering::SeqCst);
+        assert!(map.insert(b"c", b"d").is_err());
+        storage.fail.store(false, Ordering::SeqCst);
+        map.insert(b"e", b"f")?;
+        let map = reload()?;
+        assert_eq!(map.get(b"a")?.as_deref(), Some(&b"b"[..]));
+        assert_eq!(map.get(b"c")?.as_deref(), Some(&b"d"[..]));
+        assert_eq!(map.get(b"e")?.as_deref(), Some(&b"f"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_storage_layout() -> anyhow::Result<()> {
+        let mut nonce = [0; 8];
+        getrandom::getrandom(&mut nonce)?;
+        let base = std::env::temp_dir().join(format!("tlfs-{}", hex::encode(nonce)));
+        fs::create_dir_all(&base)?;
+        fs::write(base.join("docs"), [0, 1])?;
+
+        let storage = FileStorage::new(&base);
+        let mut data = vec![];
+        storage.load("docs", Box::new(|chunk| data.extend_from_slice(chunk)))?;
+        assert_eq!(data, vec![0, 1]);
+        assert!(base.join("docs").join("data").is_file());
+
+        storage.append("docs", &[2])?;
+        storage.set("acl", &[3])?;
+        storage.set("acl", &[4])?;
+        assert_eq!(fs::read(base.join("docs").join("data"))?, vec![0, 1, 2]);
+        assert_eq!(fs::read(base.join("acl").join("data"))?, vec![4]);
+        assert!(!base.join("acl").join("data.tmp").exists());
+        fs::remove_dir_all(base)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_encrypted_storage() -> anyhow::Result<()> {
+        let inner = Arc::new(MemStorage::default());
+        let key = EncryptedStorage::generate_key();
+        let storage = EncryptedStorage::new(inner.clone(), key);
+        storage.append("file", &[0, 1])?;
+        storage.append("file", &[2])?;
+        let load = |storage: &dyn Storage, file: &str| -> io::Result<Vec<u8>> {
+            let mut data = vec![];
+            storage.load(file, Box::new(|chunk| data.extend_from_slice(chunk)))?;
+            Ok(data)
+        };
+        assert_eq!(load(&storage, "file")?, vec![0, 1, 2]);
+
+        // a torn tail is removed
+        let raw = load(&*inner, "file")?;
+        assert_eq!(raw.len(), ENCRYPTED_MAGIC.len() + 2 * (4 + OVERHEAD) + 3);
+        inner.append("file", &raw[8..13])?;
+        let storage = EncryptedStorage::new(inner.clone(), key);
+        assert_eq!(load(&storage, "file")?, vec![0, 1, 2]);
+        assert_eq!(load(&*inner, "file")?, raw);
+        storage.append("file", &[3])?;
+        assert_eq!(load(&storage, "file")?, vec![0, 1, 2, 3]);
+        let storage = EncryptedStorage::new(inner.clone(), key);
+        assert_eq!(load(&storage, "file")?, vec![0, 1, 2, 3]);
+
+        // frames can't be reordered or moved to another file
+        let frame = 4 + OVERHEAD;
+        let mut swapped = raw[..8].to_vec();
+        swapped.extend_from_slice(&raw[8 + frame + 2..]);
+        swapped.extend_from_slice(&raw[8..8 + frame + 2]);
+        inner.set("swapped", &swapped)?;
+        assert!(load(&storage, "swapped").is_err());
+        inner.set("moved", &raw)?;
+        assert!(load(&storage, "moved").is_err());
+
+        // unencrypted files are rejected
+        inner.set("plain", &[0; 100])?;
+        assert!(load(&storage, "plain").is_err());
+
+        let other = EncryptedStorage::new(inner.clone(), EncryptedStorage::generate_key());
+        assert!(other.load("file", Box::new(|_| {})).is_err());
+
+        let flusher = Flusher::new(Arc::new(storage.clone()));
+        let map = BlobMap::load(flusher.clone(), "map")?;
+        map.insert(b"a", b"b")?;
+        map.insert(b"c", b"d")?;
+        flusher.sync().await?;
+        let map = BlobMap::load(Flusher::new(Arc::new(storage)), "map")?;
+        assert_eq!(map.get(b"a")?.as_deref(), Some(&b"b"[..]));
+        assert_eq!(map.get(b"c")?.as_deref(), Some(&b"d"[..]));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_manual_durability() -> anyhow::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let reload = || BlobMap::load(Flusher::new(storage.clone()), "map");
+        let flusher = Flusher::new(storage.clone());
+        flusher.set_durability(Durability::Manual);
+        let map = BlobMap::load(flusher.clone(), "map")?;
+        map.insert(b"a", b"b")?;
+        assert!(map.get(b"a")?.is_some());
+        assert!(reload()?.get(b"a")?.is_none());
+        flusher.sync().await?;
+        assert_eq!(reload()?.get(b"a")?.as_deref(), Some(&b"b"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_overwritten_root_value() -> anyhow::Result<()> {
+        let storage: Arc<dyn Storage> = Arc::new(MemStorage::default());
+        let map = BlobMap::load(Flusher::new(storage.clone()), "map")?;
+        // a single key is stored in the root, overwritten values must not be reused.
+        for i in 0..20u8 {
+            map.insert(b"a", [i; 4])?;
+        }
+        map.insert(b"b", b"2222")?;
+        let map = BlobMap::load(Flusher::new(storage), "map")?;
+        assert_eq!(map.get(b"a")?.as_deref(), Some(&[19; 4][..]));
+        assert_eq!(map.get(b"b")?.as_deref(), Some(&b"2222"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_blobset_concurrent() -> anyhow::Result<()> {
+        let flusher = Flusher::new(Arc::new(MemStorage::default()));
+        let set = BlobSet::load(flusher.clone(), "set")?;
+        let writers = (0..4u8)
+            .map(|i| {
+                let set = set.clone();
+                std::thread::spawn(move || {
+                    for j in 0..250u8 {
+                        set.insert([i, j]);
+                        assert!(set.contains([i, j]));
+                        assert_eq!(set.scan_prefix([i]).count(), j as usize + 1);
+                    }
+                    set.flush().unwrap();
+                })
+            })
+            .collect::<Vec<_>>();
+        let reader = {
+            let set = set.clone();
+            std::thread::spawn(move || {
+                let mut len = 0;
+                while len < 1000 {
+                    let len2 = set.keys().count();
+                    assert!(len2 >= len);
+                    len = len2;
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+        assert_eq!(set.keys().count(), 1000);
+        async_std::task::block_on(flusher.sync())?;
+        assert_eq!(BlobSet::load(flusher, "set")?.keys().count(), 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_after() -> anyhow::Result<()> {
+        let set = BlobSet::load(Flusher::new(Arc::new(MemStorage::default())), "set")?;
+        let mut keys = vec![vec![2], vec![1, 5]];
+        for i in 0..1000u16 {
+            keys.push([&[1][..], &i.to_be_bytes()[..]].concat());
+        }
+        for key in &keys {
+            set.insert(key);
+        }
+        keys.sort();
+        let scan = |after: Option<&[u8]>| set.scan_after([1], after).collect::<Vec<_>>();
+        assert_eq!(scan(None), keys[..keys.len() - 1]);
+        for (i, key) in keys[..keys.len() - 1].iter().enumerate() {
+            assert_eq!(scan(Some(key)), keys[i + 1..keys.len() - 1]);
+        }
+        assert_eq!(scan(Some(&[1])), scan(None));
+        assert_eq!(scan(Some(&[0, 9])), scan(None));
+        assert!(scan(Some(&[1, 255])).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_blobmap_concurrent() -> anyhow::Result<()> {
+        let flusher = Flusher::new(Arc::new(MemStorage::default()));
+        let map = BlobMap::load(flusher.clone(), "map")?;
+        let writers = (0..4u8)
+            .map(|i| {
+                let map = map.clone();
+                std::thread::spawn(move || {
+                    for j in 0..100u8 {
+                        map.insert([i, j], [j]).unwrap();
+                        assert_eq!(map.get([i, j]).unwrap().as_deref(), Some(&[j][..]));
+                        assert_eq!(map.scan_prefix([i]).count(), j as usize + 1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        assert_eq!(map.iter().count(), 400);
+        async_std::task::block_on(flusher.sync())?;
+        assert_eq!(BlobMap::load(flusher, "map")?.iter().count(), 400);
+        Ok(())
+    }
 }
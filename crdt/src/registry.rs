@@ -1,8 +1,9 @@
-use crate::lens::Lenses;
+use crate::lens::{ArchivedLens, Lenses};
 use crate::schema::Schema;
 use crate::util::Ref;
 use anyhow::Result;
 pub use blake3::Hash;
+use bytecheck::CheckBytes;
 use parking_lot::RwLock;
 use rkyv::{Archive, Archived, Deserialize, Serialize};
 use std::collections::BTreeMap;
@@ -10,7 +11,7 @@ use std::sync::Arc;
 
 /// A package of lenses.
 #[derive(Clone, Debug, Eq, PartialEq, Archive, Deserialize, Serialize)]
-#[archive_attr(derive(Debug, Eq, PartialEq))]
+#[archive_attr(derive(Debug, Eq, PartialEq, CheckBytes))]
 #[archive(bound(serialize = "__S: rkyv::ser::ScratchSpace + rkyv::ser::Serializer"))]
 #[repr(C)]
 pub struct Package {
@@ -95,32 +96,255 @@ impl std::fmt::Debug for Expanded {
     }
 }
 
+/// Error returned when a package doesn't evolve its lenses by appending.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RegistryError {
+    /// A version of the package is not newer than the previous one.
+    VersionNotIncreasing {
+        /// Name of the package.
+        name: String,
+        /// Previous version.
+        prev: u32,
+        /// Offending version.
+        version: u32,
+    },
+    /// A version of the package doesn't add any lenses.
+    NotAnAppend {
+        /// Name of the package.
+        name: String,
+        /// Offending version.
+        version: u32,
+    },
+    /// A version of the package changes a lens of a previous version.
+    HistoryRewritten {
+        /// Name of the package.
+        name: String,
+        /// Offending version.
+        version: u32,
+        /// Index of the first lens that differs.
+        index: usize,
+    },
+    /// The lenses a stored document was written with are not a prefix of the package.
+    DocumentRewritten {
+        /// Name of the package.
+        name: String,
+        /// Version of the document.
+        version: u32,
+    },
+}
+
+impl std::fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::VersionNotIncreasing {
+                name,
+                prev,
+                version,
+            } => write!(
+                f,
+                "package {} version {} doesn't follow version {}",
+                name, version, prev
+            ),
+            Self::NotAnAppend { name, version } => {
+                write!(f, "package {} version {} doesn't add lenses", name, version)
+            }
+            Self::HistoryRewritten {
+                name,
+                version,
+                index,
+            } => write!(
+                f,
+                "package {} version {} rewrites lens {}",
+                name, version, index
+            ),
+            Self::DocumentRewritten { name, version } => write!(
+                f,
+                "package {} rewrites the lenses of stored documents at version {}",
+                name, version
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
 /// Lens registry.
 #[derive(Clone)]
 pub struct Registry {
-    table: Arc<BTreeMap<String, Hash>>,
+    /// Hashes of the lenses of each version of a package.
+    table: Arc<RwLock<BTreeMap<String, BTreeMap<u32, Hash>>>>,
     expanded: Arc<RwLock<BTreeMap<[u8; 32], Arc<Expanded>>>>,
 }
 
 impl Registry {
     /// Creates a new lens registry.
+    ///
+    /// A package name can appear multiple times, in which case the versions are checked with
+    /// [`Registry::verify_chain`] and the last one is used for new documents.
     pub fn new(packages: &[u8]) -> Result<Self> {
+        Self::verify_chain(packages)?;
+        let packages = unsafe { rkyv::archived_root::<Vec<Package>>(packages) };
+        let me = Self {
+            table: Default::default(),
+            expanded: Default::default(),
+        };
+        for package in packages.as_ref() {
+            me.insert(package)?;
+        }
+        Ok(me)
+    }
+
+    fn insert(&self, package: &ArchivedPackage) -> Result<Hash> {
+        self.insert_locked(&mut self.table.write(), package)
+    }
+
+    fn insert_locked(
+        &self,
+        table: &mut BTreeMap<String, BTreeMap<u32, Hash>>,
+        package: &ArchivedPackage,
+    ) -> Result<Hash> {
+        let lenses = Ref::new(package.lenses().into());
+        let hash = blake3::hash(lenses.as_bytes());
+        tracing::info!(
+            "Loaded package {} version {}",
+            package.name(),
+            package.version()
+        );
+        tracing::debug!("Lenses {:#?}", lenses);
+        let expanded = Arc::new(Expanded::new(lenses)?);
+        self.expanded.write().insert(hash.into(), expanded);
+        table
+            .entry(package.name().into())
+            .or_default()
+            .insert(package.version(), hash);
+        Ok(hash)
+    }
+
+    /// Registers an archived [`Package`] at runtime and returns the [`struct@Hash`] of it's
+    /// lenses.
+    ///
+    /// Fails with a [`RegistryError`] if the package doesn't append to the latest registered
+    /// version with the same name.
+    pub fn add_package(&self, package: &[u8]) -> Result<Hash> {
+        let package = Ref::<Package>::checked(package)?;
+        let package = package.as_ref();
+        Ref::<Lenses>::checked(package.lenses())?;
+        // the table stays locked until the package is inserted, so that concurrent calls can't
+        // both append to the same version.
+        let mut table = self.table.write();
+        let latest = table
+            .get(package.name())
+            .and_then(|versions| versions.iter().next_back());
+        if let Some((&version, hash)) = latest {
+            let prev = self.get(hash).unwrap();
+            let lenses = Ref::<Lenses>::new(package.lenses().into());
+            Self::verify_append(
+                package.name(),
+                (version, prev.lenses().lenses()),
+                (package.version(), lenses.as_ref().lenses()),
+            )?;
+        }
+        self.insert_locked(&mut table, package)
+    }
+
+    /// Returns the latest package version and the hash of it's lenses.
+    fn latest(&self, name: &str) -> Option<(u32, Hash)> {
+        let table = self.table.read();
+        let (version, hash) = table.get(name)?.iter().next_back()?;
+        Some((*version, *hash))
+    }
+
+    /// Returns the name, version and lenses hash of all registered packages, ordered by name
+    /// and version.
+    pub fn packages(&self) -> Vec<(String, u32, Hash)> {
+        let table = self.table.read();
+        table
+            .iter()
+            .flat_map(|(name, versions)| {
+                versions
+                    .iter()
+                    .map(move |(version, hash)| (name.clone(), *version, *hash))
+            })
+            .collect()
+    }
+
+    /// Verifies that each version of a package is a strict append of the previous version.
+    ///
+    /// Migrations assume that a document written with `n` lenses can be upgraded by applying
+    /// the lenses after `n`, so a package must never change or remove lenses it shipped.
+    pub fn verify_chain(packages: &[u8]) -> Result<(), RegistryError> {
         let packages = unsafe { rkyv::archived_root::<Vec<Package>>(packages) };
-        let mut table = BTreeMap::new();
-        let mut expanded = BTreeMap::new();
+        let mut prev = BTreeMap::<&str, &ArchivedPackage>::new();
         for package in packages.as_ref() {
-            let lenses = Ref::new(package.lenses().into());
-            let hash = blake3::hash(lenses.as_bytes());
-            let name = package.name().into();
-            tracing::info!("Loaded package {}", name);
-            tracing::debug!("Lenses {:#?}", lenses);
-            table.insert(name, hash);
-            expanded.insert(hash.into(), Arc::new(Expanded::new(lenses)?));
+            if let Some(prev) = prev.insert(package.name(), package) {
+                let lenses = Ref::<Lenses>::new(package.lenses().into());
+                let prev_lenses = Ref::<Lenses>::new(prev.lenses().into());
+                Self::verify_append(
+                    package.name(),
+                    (prev.version(), prev_lenses.as_ref().lenses()),
+                    (package.version(), lenses.as_ref().lenses()),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Verifies that the version and lenses `next` of the package `name` are a strict append
+    /// of `prev`.
+    fn verify_append(
+        name: &str,
+        prev: (u32, &[ArchivedLens]),
+        next: (u32, &[ArchivedLens]),
+    ) -> Result<(), RegistryError> {
+        let ((prev, prev_lenses), (version, lenses)) = (prev, next);
+        let name = name.to_string();
+        if version <= prev {
+            return Err(RegistryError::VersionNotIncreasing {
+                name,
+                prev,
+                version,
+            });
         }
-        Ok(Self {
-            table: Arc::new(table),
-            expanded: Arc::new(RwLock::new(expanded)),
-        })
+        if let Some(index) = prev_lenses.iter().zip(lenses).position(|(a, b)| a != b) {
+            return Err(RegistryError::HistoryRewritten {
+                name,
+                version,
+                index,
+            });
+        }
+        if lenses.len() <= prev_lenses.len() {
+            return Err(RegistryError::NotAnAppend { name, version });
+        }
+        Ok(())
+    }
+
+    /// Verifies that a document written with the first `version` lenses of the package `name`
+    /// identified by `hash` can be migrated to the current version of the package.
+    ///
+    /// When the lenses identified by `hash` are known they have to be a prefix of the current
+    /// lenses, otherwise only the version is checked.
+    pub fn verify_doc(&self, name: &str, version: u32, hash: &Hash) -> Result<(), RegistryError> {
+        let err = || RegistryError::DocumentRewritten {
+            name: name.to_string(),
+            version,
+        };
+        let (_, current) = self.latest(name).ok_or_else(err)?;
+        let current = &current;
+        if current == hash {
+            return Ok(());
+        }
+        let expanded = self.get(current).ok_or_else(err)?;
+        let lenses = expanded.lenses().lenses();
+        if version as usize > lenses.len() {
+            return Err(err());
+        }
+        if let Some(prev) = self.get(hash) {
+            let prev = prev.lenses().lenses();
+            if prev.len() != version as usize || prev != &lenses[..prev.len()] {
+                return Err(err());
+            }
+        }
+        Ok(())
     }
 
     /// Registers archived [`Lenses`] and returns the [`struct@Hash`].
@@ -140,7 +364,17 @@ impl Registry {
 
     /// Returns the schema by name.
     pub fn lookup(&self, id: &str) -> Option<(u32, Hash)> {
-        let hash = *self.table.get(id)?;
+        let (_, hash) = self.latest(id)?;
+        self.lookup_hash(hash)
+    }
+
+    /// Returns the schema of a specific package version by name.
+    pub fn lookup_version(&self, id: &str, version: u32) -> Option<(u32, Hash)> {
+        let hash = *self.table.read().get(id)?.get(&version)?;
+        self.lookup_hash(hash)
+    }
+
+    fn lookup_hash(&self, hash: Hash) -> Option<(u32, Hash)> {
         let len = self
             .expanded
             .read()
@@ -151,6 +385,22 @@ impl Registry {
         Some((len as u32, hash))
     }
 
+    /// Returns the number of leading lenses shared by the lenses identified by `a` and `b`.
+    pub fn common_prefix(&self, a: &Hash, b: &Hash) -> Option<usize> {
+        let (a, b) = (self.get(a)?, self.get(b)?);
+        let (a, b) = (a.lenses().lenses(), b.lenses().lenses());
+        Some(a.iter().zip(b).take_while(|(a, b)| a == b).count())
+    }
+
+    /// Returns true if the lenses identified by `ancestor` are a prefix of the lenses
+    /// identified by `hash`.
+    pub fn is_ancestor(&self, ancestor: &Hash, hash: &Hash) -> bool {
+        let len = self
+            .get(ancestor)
+            .map(|ancestor| ancestor.lenses().lenses().len());
+        len.is_some() && self.common_prefix(ancestor, hash) == len
+    }
+
     /// Returns true if the registry contains the [`Schema`] identified by [`struct@Hash`].
     pub fn contains(&self, hash: &Hash) -> bool {
         self.expanded.read().contains_key(hash.as_bytes())
@@ -0,0 +1,281 @@
+use crate::crdt::Causal;
+use crate::doc::Backend;
+use crate::id::{DocId, PeerId};
+use crate::radixdb::{BlobMap, Flusher};
+use crate::registry::Hash;
+use crate::util::Ref;
+use anyhow::Result;
+use bytecheck::CheckBytes;
+use rkyv::{Archive, Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// How a [`ReplayEntry`] was applied by the [`Backend`] that recorded it.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(CheckBytes))]
+#[repr(u8)]
+pub enum ReplayKind {
+    /// A change received from a peer.
+    Remote,
+    /// A change made on the device.
+    Local,
+    /// The state of the document when the log was enabled.
+    State,
+}
+
+/// A change recorded by the replay log of a [`Backend`], see [`Backend::set_replay_log`].
+#[derive(Clone, Debug, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(CheckBytes))]
+#[repr(C)]
+pub struct ReplayEntry {
+    /// How the change was applied.
+    pub kind: ReplayKind,
+    /// Peer that sent or made the change.
+    pub peer: PeerId,
+    /// Document the change was joined into.
+    pub doc: DocId,
+    /// Name of the schema of the document.
+    pub doc_schema: String,
+    /// Hash of the schema the change was made with.
+    pub schema: [u8; 32],
+    /// The change as it was applied.
+    pub causal: Causal,
+}
+
+impl ReplayEntry {
+    /// Returns the hash of the schema the change was made with.
+    pub fn schema(&self) -> Hash {
+        self.schema.into()
+    }
+}
+
+/// Persistent log of the changes joined by a [`Backend`], keyed by a sequence number.
+pub(crate) struct ReplayLog {
+    tree: BlobMap,
+    next: u64,
+    pub enabled: bool,
+}
+
+impl ReplayLog {
+    pub fn load(flusher: Flusher) -> Result<Self> {
+        let tree = BlobMap::load(flusher, "replay")?;
+        let next = tree
+            .iter()
+            .last()
+            .map(|(k, _)| u64::from_be_bytes(k[..8].try_into().unwrap()) + 1)
+            .unwrap_or_default();
+        Ok(Self {
+            tree,
+            next,
+            enabled: false,
+        })
+    }
+
+    pub fn record(&mut self, entry: &ReplayEntry) -> Result<()> {
+        self.tree.insert_archived(self.next.to_be_bytes(), entry)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    pub fn entries(&self) -> Result<Vec<ReplayEntry>> {
+        self.tree
+            .iter()
+            .map(|(_, v)| Ref::<ReplayEntry>::new(v.clone()).to_owned())
+            .collect()
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        let keys = self
+            .tree
+            .iter()
+            .map(|(k, _)| k.to_vec())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.tree.remove(key)?;
+        }
+        self.tree.vacuum()
+    }
+}
+
+/// Rebuilds documents from the entries of a replay log, to find the change that made the
+/// state of two devices diverge.
+pub struct Replay {
+    package: Vec<u8>,
+    entries: Vec<ReplayEntry>,
+}
+
+impl Replay {
+    /// Creates a replay of `entries` with the lenses of `package`. Lenses received from peers
+    /// at runtime need to be part of the package.
+    pub fn new(package: &[u8], entries: Vec<ReplayEntry>) -> Self {
+        Self {
+            package: package.to_vec(),
+            entries,
+        }
+    }
+
+    /// Returns the entries of the replay.
+    pub fn entries(&self) -> &[ReplayEntry] {
+        &self.entries
+    }
+
+    /// Joins the first `n` entries into a new in-memory [`Backend`]. Local changes are joined
+    /// as if they were sent by the peer that made them. Entries that fail to join are skipped,
+    /// as they were when they were recorded.
+    pub fn replay(&self, n: usize) -> Result<Backend> {
+        let mut backend = Backend::memory(&self.package)?;
+        let peer = backend.frontend().default_keypair()?.peer_id();
+        for entry in &self.entries[..n.min(self.entries.len())] {
+            if entry.kind == ReplayKind::State {
+                backend.join_state(
+                    entry.doc,
+                    entry.doc_schema.clone(),
+                    entry.schema(),
+                    entry.causal.clone(),
+                )?;
+                continue;
+            }
+            if !backend.contains(&entry.doc)? {
+                backend
+                    .frontend()
+                    .add_doc(entry.doc, &peer, &entry.doc_schema)?;
+            }
+            let res = backend.join(
+                &entry.peer,
+                &entry.doc,
+                &entry.schema(),
+                entry.causal.clone(),
+            );
+            if let Err(err) = res {
+                tracing::debug!("replayed change of {} failed: {}", entry.peer, err);
+            }
+        }
+        Ok(backend)
+    }
+
+    /// Returns the index of the entry after which `diverged` first holds, by bisecting over
+    /// replays of the entries. `diverged` has to keep holding once it held. Returns `None` if
+    /// it doesn't hold after replaying all entries or already holds before the first one.
+    pub fn bisect(
+        &self,
+        mut diverged: impl FnMut(&Backend) -> Result<bool>,
+    ) -> Result<Option<usize>> {
+        let (mut lo, mut hi) = (0, self.entries.len());
+        if !diverged(&self.replay(hi)?)? {
+            return Ok(None);
+        }
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            if diverged(&self.replay(mid)?)? {
+                hi = mid;
+            } else {
+                lo = mid + 1;
+            }
+        }
+        Ok(lo.checked_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{CausalContext, Keypair, Permission};
+    use std::pin::Pin;
+
+    #[async_std::test]
+    async fn test_replay() -> Result<()> {
+        let schema = "todoapp { 0.1.0 { .: Struct .title: MVReg<String> } }";
+        let mut sdk = Backend::test(schema)?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let hash = sdk.registry().lookup("todoapp").unwrap().1;
+
+        let mut sdk2 = Backend::test(schema)?;
+        sdk2.set_replay_log(true)?;
+        let peer2 = sdk2.frontend().default_keypair()?.peer_id();
+        let doc2 = sdk2.frontend().add_doc(*doc.id(), &peer2, "todoapp")?;
+        doc.apply(&doc.cursor().say_can(Some(peer2), Permission::Read)?)?;
+        Pin::new(&mut sdk).await?;
+        for title in ["a", "b", "c"] {
+            doc.apply(&doc.cursor().field("title")?.assign_str(title)?)?;
+            let ctx = Ref::archive(&sdk2.frontend().ctx(doc.id())?);
+            let delta = sdk.unjoin(&peer2, doc.id(), ctx.as_ref())?;
+            sdk2.join(&peer, doc.id(), &hash, delta)?;
+        }
+        let title = doc2.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "c");
+
+        let package = Ref::archive(&tlfsc::compile_lenses(schema)?);
+        let replay = Replay::new(package.as_bytes(), sdk2.replay_log()?);
+        assert_eq!(replay.entries().len(), 3);
+        let backend = replay.replay(replay.entries().len())?;
+        let replayed = backend.frontend().doc(*doc.id())?;
+        let title = replayed.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "c");
+        assert_eq!(
+            backend.frontend().ctx(doc.id())?,
+            sdk2.frontend().ctx(doc.id())?
+        );
+
+        let id = *doc.id();
+        let has_title = |title: &'static str| {
+            move |backend: &Backend| -> Result<bool> {
+                if !backend.contains(&id)? {
+                    return Ok(false);
+                }
+                let doc = backend.frontend().doc(id)?;
+                let titles = doc.cursor().field("title")?.strs()?;
+                Ok(titles.collect::<Result<Vec<_>>>()? == vec![title.to_string()])
+            }
+        };
+        assert_eq!(replay.bisect(has_title("c"))?, Some(2));
+        assert_eq!(replay.bisect(has_title("d"))?, None);
+
+        sdk2.set_replay_log(false)?;
+        let ctx = Ref::archive(&CausalContext::new());
+        let delta = sdk.unjoin(&peer2, doc.id(), ctx.as_ref())?;
+        sdk2.join(&peer, doc.id(), &hash, delta)?;
+        assert_eq!(sdk2.replay_log()?.len(), 3);
+        sdk2.clear_replay_log()?;
+        assert!(sdk2.replay_log()?.is_empty());
+
+        // the log starts from the state of the docs and records local changes
+        sdk.set_replay_log(true)?;
+        doc.apply(&doc.cursor().field("title")?.assign_str("d")?)?;
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc3 = fut.await;
+        doc3.apply(&doc3.cursor().field("title")?.assign_str("e")?)?;
+        let kinds = sdk
+            .replay_log()?
+            .iter()
+            .map(|entry| entry.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            vec![
+                ReplayKind::State,
+                ReplayKind::Local,
+                ReplayKind::Local,
+                ReplayKind::Local,
+                ReplayKind::Local,
+            ]
+        );
+        let backend = Replay::new(package.as_bytes(), sdk.replay_log()?).replay(kinds.len())?;
+        for doc in [&doc, &doc3] {
+            assert_eq!(
+                backend.frontend().ctx(doc.id())?,
+                sdk.frontend().ctx(doc.id())?
+            );
+        }
+        let replayed = backend.frontend().doc(*doc3.id())?;
+        let title = replayed.cursor().field("title")?.strs()?.next().unwrap()?;
+        assert_eq!(title, "e");
+        Ok(())
+    }
+}
@@ -1,11 +1,42 @@
 use crate::crdt::Causal;
-use crate::path::{Path, Segment};
+use crate::id::DocId;
+use crate::path::{Path, Segment, RESERVED};
+use crate::util::Ref;
 use crate::PathBuf;
 use bytecheck::CheckBytes;
 use ed25519_dalek::{PublicKey, Verifier};
 use rkyv::{Archive, Serialize};
 use std::collections::BTreeMap;
 use std::fmt;
+use std::sync::OnceLock;
+
+/// Field below the document root holding the document metadata.
+pub(crate) const METADATA: &str = RESERVED[2];
+/// Metadata field holding the time after which a document is deleted on every peer. Only
+/// owners of the document may write it.
+pub(crate) const DELETED_AT: &str = "deleted_at";
+/// Metadata field holding the ids of revoked capability tokens.
+pub(crate) const REVOKED_TOKENS: &str = "revoked_tokens";
+
+/// Returns the schema of the metadata every document has, independent of it's schema.
+pub(crate) fn metadata_schema() -> &'static ArchivedSchema {
+    static SCHEMA: OnceLock<Ref<Schema>> = OnceLock::new();
+    SCHEMA
+        .get_or_init(|| {
+            let mut fields = BTreeMap::new();
+            fields.insert("title".into(), Schema::Reg(PrimitiveKind::Str));
+            fields.insert("icon".into(), Schema::Reg(PrimitiveKind::Str));
+            fields.insert("created_at".into(), Schema::Reg(PrimitiveKind::U64));
+            fields.insert(DELETED_AT.into(), Schema::Reg(PrimitiveKind::U64));
+            fields.insert(REVOKED_TOKENS.into(), Schema::Set(PrimitiveKind::Bytes));
+            Ref::archive(&Schema::Struct(fields))
+        })
+        .as_ref()
+}
+
+/// Maximum length of a [`PrimitiveKind::Bytes`] value. Larger files are stored as
+/// attachments and referenced by their hash.
+pub const MAX_BYTES: usize = u16::MAX as usize;
 
 /// Kind of a primitive value.
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Archive, CheckBytes, Serialize)]
@@ -20,6 +51,10 @@ pub enum PrimitiveKind {
     I64,
     /// Kind of [`String`].
     Str,
+    /// Kind of [`f64`].
+    F64,
+    /// Kind of byte vectors of at most [`MAX_BYTES`] bytes.
+    Bytes,
 }
 
 impl fmt::Display for PrimitiveKind {
@@ -29,22 +64,100 @@ impl fmt::Display for PrimitiveKind {
             PrimitiveKind::U64 => "u64",
             PrimitiveKind::I64 => "i64",
             PrimitiveKind::Str => "string",
+            PrimitiveKind::F64 => "f64",
+            PrimitiveKind::Bytes => "bytes",
         })
     }
 }
 
 impl PrimitiveKind {
-    fn validate(self, seg: Segment) -> bool {
+    pub(crate) fn validate(self, seg: Segment) -> bool {
         matches!(
             (self, seg),
             (Self::Bool, Segment::Bool(_))
                 | (Self::U64, Segment::U64(_))
                 | (Self::I64, Segment::I64(_))
                 | (Self::Str, Segment::Str(_))
+                | (Self::F64, Segment::F64(_))
+                | (Self::Bytes, Segment::Bytes(_))
         )
     }
 }
 
+/// Strategy for resolving the concurrent values of a register when reading.
+///
+/// The register is still stored as an MVReg, so merging never affects convergence.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Archive, CheckBytes, Serialize)]
+#[archive(as = "Merge")]
+#[repr(u8)]
+pub enum Merge {
+    /// Resolves to the largest value.
+    Max,
+    /// Resolves to the smallest value.
+    Min,
+    /// Resolves to the sum of all values. Only valid for numeric registers.
+    Sum,
+    /// Concurrent values are resolved by the application.
+    Custom,
+}
+
+impl fmt::Display for Merge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Merge::Max => "max",
+            Merge::Min => "min",
+            Merge::Sum => "sum",
+            Merge::Custom => "custom",
+        })
+    }
+}
+
+/// Invariant of the values of a register.
+///
+/// Only invariants that hold for the union of two valid states can be declared, so checking
+/// every joined change is enough to keep them without coordination.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Archive, CheckBytes, Serialize)]
+#[archive(as = "Invariant")]
+#[repr(C, u8)]
+pub enum Invariant {
+    /// Values of a [`PrimitiveKind::U64`] register lie in the inclusive range.
+    U64Range(u64, u64),
+    /// Values of a [`PrimitiveKind::I64`] register lie in the inclusive range.
+    I64Range(i64, i64),
+    /// Values of a [`PrimitiveKind::Str`] register have at most this many characters.
+    MaxLen(u64),
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::U64Range(min, max) => write!(f, "range({}, {})", min, max),
+            Self::I64Range(min, max) => write!(f, "range({}, {})", min, max),
+            Self::MaxLen(len) => write!(f, "maxlen({})", len),
+        }
+    }
+}
+
+impl Invariant {
+    /// Returns the [`PrimitiveKind`] of the register values the invariant applies to.
+    pub fn kind(&self) -> PrimitiveKind {
+        match self {
+            Self::U64Range(_, _) => PrimitiveKind::U64,
+            Self::I64Range(_, _) => PrimitiveKind::I64,
+            Self::MaxLen(_) => PrimitiveKind::Str,
+        }
+    }
+
+    pub(crate) fn validate(&self, seg: Segment) -> bool {
+        match (self, seg) {
+            (Self::U64Range(min, max), Segment::U64(v)) => *min <= v && v <= *max,
+            (Self::I64Range(min, max), Segment::I64(v)) => *min <= v && v <= *max,
+            (Self::MaxLen(len), Segment::Str(s)) => s.chars().count() as u64 <= *len,
+            _ => false,
+        }
+    }
+}
+
 /// Schema defines the set of allowable paths.
 #[derive(Clone, Debug, Eq, PartialEq, Archive, Serialize)]
 #[archive_attr(allow(missing_docs))]
@@ -66,6 +179,34 @@ pub enum Schema {
     /// Struct schema contains paths with a primitive of kind [`PrimitiveKind::Str`] and a
     /// sequence of segments matching [`Schema`].
     Struct(#[omit_bounds] BTreeMap<String, Schema>),
+    /// Set schema contains paths with a primitive of kind [`PrimitiveKind`] and a nonce.
+    Set(PrimitiveKind),
+    /// Dynamic schema contains any non empty sequence of primitive and nonce segments.
+    Dynamic,
+    /// Local schema contains paths matching [`Schema`] which are persisted but never
+    /// replicated.
+    Local(#[omit_bounds] Box<Schema>),
+    /// MergeReg schema contains the same paths as [`Schema::Reg`] and resolves concurrent
+    /// values using [`Merge`].
+    MergeReg(PrimitiveKind, Merge),
+    /// Counter schema contains a path per peer with a nonce and the totals of the increments
+    /// and decrements of kind [`PrimitiveKind::U64`].
+    Counter,
+    /// Text schema contains paths with a position and a single character of kind
+    /// [`PrimitiveKind::Str`].
+    Text,
+    /// CheckedReg schema contains the paths of [`Schema::Reg`] whose values satisfy the
+    /// [`Invariant`].
+    CheckedReg(PrimitiveKind, Invariant),
+    /// LwwReg schema contains the same paths as [`Schema::Reg`]. Concurrent values resolve to
+    /// the one written last, ordered by a logical clock in the upper half of the nonce and
+    /// then by the peer id of the writer.
+    LwwReg(PrimitiveKind),
+    /// Encrypted schema contains paths with a nonce and a ciphertext of kind
+    /// [`PrimitiveKind::Bytes`]. The ciphertext encrypts a primitive of kind [`PrimitiveKind`]
+    /// with the path of the register as associated data. Only the value is encrypted, the
+    /// fields and table keys leading to the register are not.
+    Encrypted(PrimitiveKind),
 }
 
 impl Default for Schema {
@@ -86,6 +227,12 @@ impl ArchivedSchema {
             let path = verify_sig(path)?;
             let (doc, path) = path.split_first()?;
             doc.doc()?;
+            if validate_tag(path) == Some(true)
+                || validate_member(path) == Some(true)
+                || validate_metadata(path) == Some(true)
+            {
+                continue;
+            }
             if self.validate_path(path) != Some(true) {
                 tracing::error!("invalid path {}", path);
                 return Some(false);
@@ -101,6 +248,9 @@ impl ArchivedSchema {
                 tracing::error!("policy cannot be expired");
                 return Some(false);
             }
+            if validate_member(path) == Some(true) || validate_metadata(path) == Some(true) {
+                continue;
+            }
             if self.validate_path(path) != Some(true) {
                 tracing::error!("invalid expired path {}", path);
                 return Some(false);
@@ -109,23 +259,147 @@ impl ArchivedSchema {
         Some(true)
     }
 
+    /// Returns the [`ArchivedSchema`] wrapped by a [`Schema::Local`].
+    pub fn unwrap_local(&self) -> &Self {
+        if let Self::Local(schema) = self {
+            schema
+        } else {
+            self
+        }
+    }
+
+    /// Returns true if the document relative [`Path`] without signatures is part of the schema.
+    /// Unlike [`ArchivedSchema::validate`] this doesn't verify signatures.
+    pub(crate) fn contains(&self, path: Path) -> bool {
+        validate_tag(path) == Some(true)
+            || validate_member(path) == Some(true)
+            || validate_metadata(path) == Some(true)
+            || self.validate_path(path) == Some(true)
+    }
+
+    /// Returns true if the document relative [`Path`] is excluded from replication.
+    pub fn is_local(&self, path: Path) -> bool {
+        self._is_local(path) == Some(true)
+    }
+
+    fn _is_local(&self, path: Path) -> Option<bool> {
+        match self {
+            Self::Local(_) => Some(true),
+            Self::Table(_, schema) => schema._is_local(path.split_first()?.1),
+            Self::Struct(fields) => {
+                let (field, path) = path.split_first()?;
+                fields.get(field.prim_str()?)?._is_local(path)
+            }
+            Self::Array(schema) => {
+                let (prim, path) = path.split_first()?;
+                if prim.prim_str()? != "VALUES" {
+                    return Some(false);
+                }
+                let mut path = path.into_iter();
+                path.next()?.position()?;
+                path.next()?.prim_u64()?;
+                schema._is_local(path.collect::<PathBuf>().as_path())
+            }
+            _ => Some(false),
+        }
+    }
+
+    /// Returns true if the document relative [`Path`] is a value of a [`Schema::LwwReg`].
+    pub(crate) fn is_lww(&self, path: Path) -> bool {
+        self._is_lww(path) == Some(true)
+    }
+
+    fn _is_lww(&self, path: Path) -> Option<bool> {
+        match self {
+            Self::LwwReg(_) => Some(true),
+            Self::Local(schema) => schema._is_lww(path),
+            Self::Table(_, schema) => schema._is_lww(path.split_first()?.1),
+            Self::Struct(fields) => {
+                let (field, path) = path.split_first()?;
+                fields.get(field.prim_str()?)?._is_lww(path)
+            }
+            Self::Array(schema) => {
+                let (prim, path) = path.split_first()?;
+                if prim.prim_str()? != "VALUES" {
+                    return Some(false);
+                }
+                let mut path = path.into_iter();
+                path.next()?.position()?;
+                path.next()?.prim_u64()?;
+                schema._is_lww(path.collect::<PathBuf>().as_path())
+            }
+            _ => Some(false),
+        }
+    }
+
     fn validate_path(&self, path: Path) -> Option<bool> {
         if validate_policy(path) == Some(true) {
             return Some(true);
         }
         match self {
             Self::Null => Some(path.is_empty()),
+            Self::Local(schema) => schema.validate_path(path),
             Self::Flag => {
                 let (nonce, path) = path.split_first()?;
                 nonce.nonce()?;
                 Some(path.is_empty())
             }
-            Self::Reg(kind) => {
+            Self::Reg(kind) | Self::MergeReg(kind, _) | Self::LwwReg(kind) => {
                 let (nonce, path) = path.split_first()?;
                 nonce.nonce()?;
                 let (prim, path) = path.split_first()?;
                 Some(kind.validate(prim) && path.is_empty())
             }
+            Self::Encrypted(_) => {
+                let (nonce, path) = path.split_first()?;
+                nonce.nonce()?;
+                let (prim, path) = path.split_first()?;
+                Some(PrimitiveKind::Bytes.validate(prim) && path.is_empty())
+            }
+            Self::CheckedReg(kind, invariant) => {
+                let (nonce, path) = path.split_first()?;
+                nonce.nonce()?;
+                let (prim, path) = path.split_first()?;
+                Some(kind.validate(prim.clone()) && invariant.validate(prim) && path.is_empty())
+            }
+            Self::Dynamic => Some(
+                !path.is_empty()
+                    && path.into_iter().all(|seg| {
+                        matches!(
+                            seg,
+                            Segment::Nonce(_)
+                                | Segment::Bool(_)
+                                | Segment::U64(_)
+                                | Segment::I64(_)
+                                | Segment::F64(_)
+                                | Segment::Str(_)
+                                | Segment::Bytes(_)
+                        )
+                    }),
+            ),
+            Self::Set(kind) => {
+                let (prim, path) = path.split_first()?;
+                let (nonce, path) = path.split_first()?;
+                nonce.nonce()?;
+                Some(kind.validate(prim) && path.is_empty())
+            }
+            Self::Counter => {
+                let (nonce, path) = path.split_first()?;
+                nonce.nonce()?;
+                let (inc, path) = path.split_first()?;
+                let (dec, path) = path.split_first()?;
+                Some(
+                    PrimitiveKind::U64.validate(inc)
+                        && PrimitiveKind::U64.validate(dec)
+                        && path.is_empty(),
+                )
+            }
+            Self::Text => {
+                let (pos, path) = path.split_first()?;
+                pos.position()?;
+                let (prim, path) = path.split_first()?;
+                Some(prim.prim_str()?.chars().count() == 1 && path.is_empty())
+            }
             Self::Table(kind, schema) => {
                 let (key, path) = path.split_first()?;
                 Some(kind.validate(key) && schema.validate_path(path)?)
@@ -172,6 +446,68 @@ fn validate_policy(path: Path) -> Option<bool> {
     Some(path.is_empty())
 }
 
+fn validate_tag(path: Path) -> Option<bool> {
+    let (tx, path) = path.split_first()?;
+    tx.dot()?;
+    let (tag, path) = path.split_first()?;
+    tag.prim_str()?;
+    Some(path.is_empty())
+}
+
+fn validate_member(path: Path) -> Option<bool> {
+    let (doc, path) = path.split_first()?;
+    doc.doc()?;
+    let (schema, path) = path.split_first()?;
+    schema.prim_str()?;
+    Some(path.is_empty())
+}
+
+/// Returns true if the document relative [`Path`] without signatures is part of the document
+/// metadata.
+pub(crate) fn is_metadata(path: Path) -> bool {
+    validate_metadata(path) == Some(true)
+}
+
+fn validate_metadata(path: Path) -> Option<bool> {
+    let (field, path) = path.split_first()?;
+    if field.prim_str()? != METADATA {
+        return Some(false);
+    }
+    metadata_schema().validate_path(path)
+}
+
+/// Returns true if `path` is below the [`DELETED_AT`] field of a document.
+pub(crate) fn is_deletion(path: Path) -> bool {
+    let mut segments = path.into_iter().skip(1);
+    segments.next().and_then(|s| s.prim_string()).as_deref() == Some(METADATA)
+        && segments.next().and_then(|s| s.prim_string()).as_deref() == Some(DELETED_AT)
+}
+
+/// Returns the id of the token revoked by `path` if it is below the [`REVOKED_TOKENS`] field
+/// of a document.
+pub(crate) fn revoked_token(path: Path) -> Option<[u8; 32]> {
+    let mut segments = path.into_iter().skip(1);
+    if segments.next()?.prim_str()? != METADATA || segments.next()?.prim_str()? != REVOKED_TOKENS {
+        return None;
+    }
+    segments.next()?.prim_bytes()?.try_into().ok()
+}
+
+/// Returns true if all paths of [`Causal`] belong to `doc` and are signed. Unlike
+/// [`ArchivedSchema::validate`] this doesn't require the schema to be known.
+pub(crate) fn verify_signatures(doc: &DocId, causal: &Causal) -> bool {
+    let in_doc =
+        |path: Option<Path>| path.and_then(|path| path.first()?.doc()).as_ref() == Some(doc);
+    causal
+        .store
+        .iter()
+        .all(|buf| in_doc(verify_sig(buf.as_path())))
+        && causal
+            .expired
+            .iter()
+            .all(|buf| in_doc(verify_sig(buf.as_path()).and_then(verify_sig)))
+}
+
 fn verify_sig(path: Path) -> Option<Path> {
     let (path, sig) = path.split_last()?;
     let (path, peer) = path.split_last()?;
@@ -1,10 +1,13 @@
-use crate::acl::{Permission, Rule};
+use crate::acl::{rule_key_path, Permission, Rule};
+use crate::cursor::Cursor;
 use crate::id::PeerId;
-use crate::path::Path;
-use crate::PathBuf;
+use crate::path::{Path, Segment};
+use crate::schema::ArchivedSchema;
+use crate::{PathBuf, RESERVED};
 use futures::stream::BoxStream;
 use futures::Stream;
 use rkyv::archived_root;
+use std::collections::BTreeMap;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::task::{Context, Poll};
@@ -23,6 +26,242 @@ pub enum Event {
     Revoked(PathBuf, Option<PeerId>),
 }
 
+/// A step from a subscribed [`Cursor`] to a changed value.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Step {
+    /// Field of a struct.
+    Field(String),
+    /// Key of a table.
+    Key(Segment),
+    /// Element of an array identified by it's stable uid.
+    Element(u64),
+}
+
+/// Typed change decoded from a [`Batch`] relative to a subscribed [`Cursor`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Change {
+    /// A flag was enabled or disabled.
+    Flag(Vec<Step>, bool),
+    /// A value was assigned to a register.
+    Assigned(Vec<Step>, Segment),
+    /// All values of a register were removed.
+    Cleared(Vec<Step>),
+    /// An element was added to a set.
+    Added(Vec<Step>, Segment),
+    /// An element was removed from a set.
+    Removed(Vec<Step>, Segment),
+    /// A counter was incremented by a delta.
+    Incremented(Vec<Step>, i64),
+    /// A key was inserted into a table.
+    KeyInserted(Vec<Step>, Segment),
+    /// A key was removed from a table.
+    KeyRemoved(Vec<Step>, Segment),
+    /// An element with uid was inserted into an array at index.
+    ElementInserted(Vec<Step>, u64, usize),
+    /// An element with uid was moved to index.
+    ElementMoved(Vec<Step>, u64, usize),
+    /// An element with uid was removed from an array.
+    ElementRemoved(Vec<Step>, u64),
+    /// A text or dynamic value changed and needs to be read again.
+    Changed(Vec<Step>),
+}
+
+/// Inserted or removed value relative to the path being decoded.
+type Value = (bool, Vec<Segment>);
+
+/// Groups values by their first segment, in the order of the encoded segments.
+fn group(values: &[Value]) -> Vec<(Segment, Vec<Value>)> {
+    let mut groups: BTreeMap<PathBuf, (Segment, Vec<Value>)> = BTreeMap::new();
+    for (insert, value) in values {
+        let (first, rest) = match value.split_first() {
+            Some(split) => split,
+            None => continue,
+        };
+        let mut key = PathBuf::new();
+        key.push_segment(first.clone());
+        groups
+            .entry(key)
+            .or_insert_with(|| (first.clone(), vec![]))
+            .1
+            .push((*insert, rest.to_vec()));
+    }
+    groups.into_values().collect()
+}
+
+fn decode(
+    cursor: &Cursor,
+    schema: &ArchivedSchema,
+    base: &PathBuf,
+    steps: &mut Vec<Step>,
+    values: &[Value],
+    changes: &mut Vec<Change>,
+) {
+    if values.is_empty() {
+        return;
+    }
+    let inserted = || {
+        values
+            .iter()
+            .filter(|(insert, _)| *insert)
+            .map(|(_, value)| value.as_slice())
+    };
+    match schema {
+        ArchivedSchema::Null => {}
+        ArchivedSchema::Flag => {
+            let enabled = cursor.scan_path(base.as_path()).next().is_some();
+            changes.push(Change::Flag(steps.clone(), enabled));
+        }
+        ArchivedSchema::Reg(_)
+        | ArchivedSchema::MergeReg(_, _)
+        | ArchivedSchema::CheckedReg(_, _)
+        | ArchivedSchema::LwwReg(_)
+        | ArchivedSchema::Encrypted(_) => {
+            let mut assigned = false;
+            for value in inserted() {
+                if let [Segment::Nonce(_), prim] = value {
+                    changes.push(Change::Assigned(steps.clone(), prim.clone()));
+                    assigned = true;
+                }
+            }
+            if !assigned {
+                // removing one of several concurrent values leaves the others assigned.
+                for path in cursor.scan_path(base.as_path()) {
+                    let path = Path::new(&path);
+                    let value = path
+                        .parent()
+                        .and_then(|path| path.parent())
+                        .and_then(|path| path.strip_prefix(base.as_path()).ok())
+                        .map(|path| path.into_iter().collect::<Vec<_>>());
+                    if let Some([Segment::Nonce(_), prim]) = value.as_deref() {
+                        changes.push(Change::Assigned(steps.clone(), prim.clone()));
+                        assigned = true;
+                    }
+                }
+            }
+            if !assigned {
+                changes.push(Change::Cleared(steps.clone()));
+            }
+        }
+        ArchivedSchema::Set(_) => {
+            for (elem, group) in group(values) {
+                if group.iter().any(|(insert, _)| *insert) {
+                    changes.push(Change::Added(steps.clone(), elem));
+                } else {
+                    changes.push(Change::Removed(steps.clone(), elem));
+                }
+            }
+        }
+        ArchivedSchema::Counter => {
+            let delta = values
+                .iter()
+                .filter_map(|(insert, value)| match value.as_slice() {
+                    // a peer's path with it's new totals replaces the previous one.
+                    [Segment::Nonce(_), Segment::U64(inc), Segment::U64(dec)] => {
+                        let total = *inc as i128 - *dec as i128;
+                        Some(if *insert { total } else { -total })
+                    }
+                    _ => None,
+                })
+                .sum::<i128>()
+                .clamp(i64::MIN as i128, i64::MAX as i128) as i64;
+            changes.push(Change::Incremented(steps.clone(), delta));
+        }
+        ArchivedSchema::Struct(fields) => {
+            for (field, group) in group(values) {
+                let name = match field.clone().prim_key() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let schema = match fields.get(name.as_str()) {
+                    Some(schema) => schema,
+                    None => continue,
+                };
+                let mut base = base.clone();
+                base.push_segment(field);
+                steps.push(Step::Field(name));
+                decode(cursor, schema, &base, steps, &group, changes);
+                steps.pop();
+            }
+        }
+        ArchivedSchema::Table(_, schema) => {
+            for (key, group) in group(values) {
+                let mut base = base.clone();
+                base.push_segment(key.clone());
+                let current = cursor.scan_path(base.as_path()).count();
+                let inserts = group.iter().filter(|(insert, _)| *insert).count();
+                if current == 0 {
+                    changes.push(Change::KeyRemoved(steps.clone(), key));
+                    continue;
+                }
+                if inserts == group.len() && inserts == current {
+                    changes.push(Change::KeyInserted(steps.clone(), key.clone()));
+                }
+                steps.push(Step::Key(key));
+                decode(cursor, schema, &base, steps, &group, changes);
+                steps.pop();
+            }
+        }
+        ArchivedSchema::Array(schema) => {
+            let items = match cursor.array_items(base.as_path()) {
+                Ok(items) => items,
+                Err(_) => return,
+            };
+            // only the value paths are decoded, the meta paths don't carry a value.
+            let mut elements: BTreeMap<u64, Vec<Value>> = BTreeMap::new();
+            for (insert, value) in values {
+                if let [Segment::Str(s), Segment::Position(pos), Segment::U64(uid), rest @ ..] =
+                    value.as_slice()
+                {
+                    if s != RESERVED[0] {
+                        continue;
+                    }
+                    let mut value = vec![Segment::Position(pos.clone())];
+                    value.extend_from_slice(rest);
+                    elements.entry(*uid).or_default().push((*insert, value));
+                }
+            }
+            for (uid, group) in elements {
+                let ix = match items.iter().position(|(_, id)| *id == uid) {
+                    Some(ix) => ix,
+                    None => {
+                        changes.push(Change::ElementRemoved(steps.clone(), uid));
+                        continue;
+                    }
+                };
+                let removed: Vec<&Segment> = group
+                    .iter()
+                    .filter(|(insert, _)| !*insert)
+                    .map(|(_, value)| &value[0])
+                    .collect();
+                if removed.is_empty() {
+                    changes.push(Change::ElementInserted(steps.clone(), uid, ix));
+                } else if group
+                    .iter()
+                    .any(|(insert, value)| *insert && !removed.contains(&&value[0]))
+                {
+                    changes.push(Change::ElementMoved(steps.clone(), uid, ix));
+                    continue;
+                }
+                let mut base = base.clone();
+                base.prim_str(RESERVED[0]);
+                base.position(&items[ix].0);
+                base.prim_u64(uid);
+                let group: Vec<Value> = group
+                    .into_iter()
+                    .map(|(insert, value)| (insert, value[1..].to_vec()))
+                    .collect();
+                steps.push(Step::Element(uid));
+                decode(cursor, schema, &base, steps, &group, changes);
+                steps.pop();
+            }
+        }
+        ArchivedSchema::Local(schema) => decode(cursor, schema, base, steps, values, changes),
+        ArchivedSchema::Text | ArchivedSchema::Dynamic => {
+            changes.push(Change::Changed(steps.clone()));
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 enum InnerIter<'a> {
     State(Box<dyn Iterator<Item = (IterKey<u8>, Option<&'a ()>)> + 'a>),
@@ -44,8 +283,7 @@ impl<'a> Iterator for Iter<'a> {
             },
             InnerIter::Acl(acl) => match acl.next() {
                 Some((k, Some(v))) => {
-                    let (peer, path) = Path::new(&k).child().unwrap().split_first().unwrap();
-                    let peer = peer.peer().unwrap();
+                    let (peer, path) = rule_key_path(Path::new(&k));
                     let actor = if peer == PeerId::new([0; 32]) {
                         None
                     } else {
@@ -55,8 +293,7 @@ impl<'a> Iterator for Iter<'a> {
                     Some(Event::Granted(path.to_owned(), actor, perm))
                 }
                 Some((k, None)) => {
-                    let (peer, path) = Path::new(&k).child().unwrap().split_first().unwrap();
-                    let peer = peer.peer().unwrap();
+                    let (peer, path) = rule_key_path(Path::new(&k));
                     let actor = if peer == PeerId::new([0; 32]) {
                         None
                     } else {
@@ -90,6 +327,48 @@ impl<'a> IntoIterator for &'a Batch {
     }
 }
 
+impl Batch {
+    pub(crate) fn acl(diff: crate::radixdb::Diff<u8, Arc<[u8]>>) -> Self {
+        Self(InnerBatch::Acl(diff))
+    }
+
+    /// Decodes the inserted and removed paths of the batch into [`Change`]s relative to the
+    /// subscribed `cursor`. Table keys and array indices are resolved against the current
+    /// state of the document. Acl events are skipped.
+    pub fn changes(&self, cursor: &Cursor) -> Vec<Change> {
+        let base = cursor.path().to_owned();
+        let values: Vec<Value> = self
+            .into_iter()
+            .filter_map(|event| {
+                let (insert, path) = match event {
+                    Event::Insert(path) => (true, path),
+                    Event::Remove(path) => (false, path),
+                    _ => return None,
+                };
+                let value = path
+                    .as_path()
+                    .parent()?
+                    .parent()?
+                    .strip_prefix(base.as_path())
+                    .ok()?
+                    .into_iter()
+                    .collect();
+                Some((insert, value))
+            })
+            .collect();
+        let mut changes = vec![];
+        decode(
+            cursor,
+            cursor.schema(),
+            &base,
+            &mut vec![],
+            &values,
+            &mut changes,
+        );
+        changes
+    }
+}
+
 /// [`Event`] [`Stream`] subscription.
 pub struct Subscriber {
     state: BoxStream<'static, crate::radixdb::Diff<u8, ()>>,
@@ -16,6 +16,21 @@ where
     ser.into_serializer().into_inner().to_vec()
 }
 
+/// Returns the current time in seconds since the unix epoch.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the current time in seconds since the unix epoch.
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn now() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
 /// Owned zero copy bytes encoding `T`.
 #[derive(Clone, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Ref<T> {
@@ -0,0 +1,210 @@
+use crate::crdt::{Causal, DotStore};
+use crate::id::PeerId;
+use crate::path::{split_sig, Path, PathBuf};
+use anyhow::{anyhow, Result};
+use bytecheck::CheckBytes;
+use ed25519_dalek::Signature;
+use rkyv::{Archive, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Compact wire encoding of a [`Causal`].
+///
+/// Every path in a transaction starts with the same doc id and ends with a peer id and a
+/// signature. Paths are front coded against the previous path after stripping the trailing
+/// signatures, and peer ids are replaced by an index into a peer table.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct CompressedCausal {
+    peers: Vec<PeerId>,
+    store: Vec<CompressedPath>,
+    expired: Vec<CompressedPath>,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+struct CompressedPath {
+    /// Length of the prefix shared with the previous path.
+    shared: u32,
+    /// Remaining bytes of the path without trailing signatures.
+    suffix: Vec<u8>,
+    /// Trailing `peer sig` pairs in path order.
+    sigs: Vec<CompressedSig>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+struct CompressedSig {
+    peer: u32,
+    sig: [u8; 64],
+}
+
+/// Encoded size of a trailing `peer sig` pair.
+const SIG_SIZE: usize = 1 + 32 + 1 + 64;
+
+#[derive(Default)]
+struct Encoder {
+    peers: Vec<PeerId>,
+    index: BTreeMap<PeerId, u32>,
+}
+
+impl Encoder {
+    fn peer(&mut self, peer: PeerId) -> u32 {
+        let peers = &mut self.peers;
+        *self.index.entry(peer).or_insert_with(|| {
+            peers.push(peer);
+            peers.len() as u32 - 1
+        })
+    }
+
+    fn encode(&mut self, store: &DotStore) -> Vec<CompressedPath> {
+        let mut prev = PathBuf::new();
+        let mut paths = vec![];
+        for buf in store.iter() {
+            let mut body = buf.as_path();
+            let mut sigs = vec![];
+            while let Some((peer, sig)) = split_sig(&mut body) {
+                sigs.push(CompressedSig {
+                    peer: self.peer(peer),
+                    sig: sig.to_bytes(),
+                });
+            }
+            sigs.reverse();
+            let shared = prev
+                .as_ref()
+                .iter()
+                .zip(body.as_ref())
+                .take_while(|(a, b)| a == b)
+                .count();
+            paths.push(CompressedPath {
+                shared: shared as u32,
+                suffix: body.as_ref()[shared..].to_vec(),
+                sigs,
+            });
+            prev = body.to_owned();
+        }
+        paths
+    }
+}
+
+impl From<&Causal> for CompressedCausal {
+    fn from(causal: &Causal) -> Self {
+        let mut encoder = Encoder::default();
+        let store = encoder.encode(&causal.store);
+        let expired = encoder.encode(&causal.expired);
+        Self {
+            peers: encoder.peers,
+            store,
+            expired,
+        }
+    }
+}
+
+impl ArchivedCompressedCausal {
+    /// Decodes the compressed paths into a [`Causal`]. Since paths share prefixes a small
+    /// message can decode to a lot of paths, so decoding fails once the decoded paths exceed
+    /// `max_size` bytes.
+    pub fn decompress(&self, max_size: usize) -> Result<Causal> {
+        let mut size = 0;
+        Ok(Causal {
+            store: self.decode(&self.store, max_size, &mut size)?,
+            expired: self.decode(&self.expired, max_size, &mut size)?,
+        })
+    }
+
+    fn decode(
+        &self,
+        paths: &[ArchivedCompressedPath],
+        max_size: usize,
+        size: &mut usize,
+    ) -> Result<DotStore> {
+        let mut store = DotStore::new();
+        let mut prev: Vec<u8> = vec![];
+        for path in paths {
+            let shared = path.shared as usize;
+            if shared > prev.len() {
+                return Err(anyhow!("invalid shared prefix length"));
+            }
+            *size = size
+                .saturating_add(shared + path.suffix.len())
+                .saturating_add(path.sigs.len() * SIG_SIZE);
+            if *size > max_size {
+                return Err(anyhow!("decompressed causal exceeds {} bytes", max_size));
+            }
+            prev.truncate(shared);
+            prev.extend_from_slice(&path.suffix);
+            let mut buf = PathBuf::new();
+            buf.extend(Path::checked(&prev)?);
+            for sig in path.sigs.iter() {
+                let peer = self
+                    .peers
+                    .get(sig.peer as usize)
+                    .ok_or_else(|| anyhow!("invalid peer index"))?;
+                buf.peer(peer);
+                buf.sig(Signature::from_bytes(&sig.sig)?);
+            }
+            store.insert(buf);
+        }
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::props::*;
+    use crate::util::Ref;
+    use crate::{Backend, Keypair};
+    use proptest::prelude::*;
+    use std::pin::Pin;
+
+    proptest! {
+        #[test]
+        fn compressed_causal_roundtrip(causal in arb_causal()) {
+            let compressed = Ref::archive(&CompressedCausal::from(&causal));
+            let causal2 = compressed.as_ref().decompress(usize::MAX).unwrap();
+            prop_assert_eq!(causal, causal2);
+        }
+    }
+
+    #[async_std::test]
+    async fn test_compressed_signed() -> Result<()> {
+        let mut sdk = Backend::test(
+            r#"
+            todoapp {
+                0.1.0 {
+                    .: Table<u64>
+                    .{}: MVReg<String>
+                }
+            }
+        "#,
+        )?;
+        let peer = sdk.frontend().default_keypair()?.peer_id();
+        let fut = sdk
+            .frontend()
+            .create_doc(peer, "todoapp", Keypair::generate())?;
+        Pin::new(&mut sdk).await?;
+        let doc = fut.await;
+        let mut causal = Causal::default();
+        for i in 0..10 {
+            causal.join(&doc.cursor().key_u64(i)?.assign_str("todo")?);
+        }
+        doc.apply(&causal)?;
+        causal.join(&doc.cursor().key_u64(0)?.remove()?);
+        assert!(!causal.expired().is_empty());
+        let compressed = Ref::archive(&CompressedCausal::from(&causal));
+        assert!(compressed.as_bytes().len() < Ref::archive(&causal).as_bytes().len());
+        assert_eq!(compressed.as_ref().decompress(usize::MAX)?, causal);
+        let size = causal
+            .store()
+            .iter()
+            .chain(causal.expired().iter())
+            .map(|path| path.as_path().as_ref().len())
+            .sum::<usize>();
+        assert_eq!(compressed.as_ref().decompress(size)?, causal);
+        assert!(compressed.as_ref().decompress(size - 1).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,454 @@
+//! Derive macro mapping rust structs to tlfs schemas.
+//!
+//! `#[derive(TlfsDocument)]` on a struct with named fields generates:
+//!
+//! - `lenses()` returning the lenses that construct the schema of the struct.
+//! - `read(&Cursor)` reading the current state of a document into the struct.
+//! - a `{Name}Cursor` wrapping a `Cursor` with a typed getter and setters for each field.
+//!
+//! Fields map to crdts as follows:
+//!
+//! - `bool`, `u64`, `i64` and `String` to `MVReg`. Unset registers read as the default value.
+//! - `Option<T>` of a primitive to `MVReg`, where `None` removes the value.
+//! - `Vec<T>` of a primitive to `Array` of `MVReg`.
+//! - `HashMap<K, V>` and `BTreeMap<K, V>` of primitives to `Table` of `MVReg`.
+//! - any other struct deriving `TlfsDocument` to a nested `Struct`.
+//!
+//! Concurrent register values are read in path order. The generated code refers to the
+//! `tlfs_crdt` and `anyhow` crates, so both need to be dependencies of the crate using the
+//! derive.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::spanned::Spanned;
+use syn::{
+    parse_macro_input, Data, DeriveInput, Error, Fields, GenericArgument, Ident, PathArguments,
+    Type,
+};
+
+#[derive(Clone, Copy)]
+enum Prim {
+    Bool,
+    U64,
+    I64,
+    F64,
+    Str,
+}
+
+impl Prim {
+    fn parse(ty: &Type) -> Option<Self> {
+        match type_args(ty)? {
+            (name, args) if args.is_empty() => match name.to_string().as_str() {
+                "bool" => Some(Self::Bool),
+                "u64" => Some(Self::U64),
+                "i64" => Some(Self::I64),
+                "f64" => Some(Self::F64),
+                "String" => Some(Self::Str),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn expect(ty: &Type) -> syn::Result<Self> {
+        Self::parse(ty)
+            .ok_or_else(|| Error::new(ty.span(), "expected bool, u64, i64, f64 or String"))
+    }
+
+    fn kind(self) -> TokenStream2 {
+        match self {
+            Self::Bool => quote!(::tlfs_crdt::PrimitiveKind::Bool),
+            Self::U64 => quote!(::tlfs_crdt::PrimitiveKind::U64),
+            Self::I64 => quote!(::tlfs_crdt::PrimitiveKind::I64),
+            Self::F64 => quote!(::tlfs_crdt::PrimitiveKind::F64),
+            Self::Str => quote!(::tlfs_crdt::PrimitiveKind::Str),
+        }
+    }
+
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Bool => "bool",
+            Self::U64 => "u64",
+            Self::I64 => "i64",
+            Self::F64 => "f64",
+            Self::Str => "str",
+        }
+    }
+
+    /// Type of setter arguments.
+    fn arg(self) -> TokenStream2 {
+        match self {
+            Self::Bool => quote!(bool),
+            Self::U64 => quote!(u64),
+            Self::I64 => quote!(i64),
+            Self::F64 => quote!(f64),
+            Self::Str => quote!(&str),
+        }
+    }
+
+    /// Passes an owned `value` to a cursor method.
+    fn pass(self, value: &Ident) -> TokenStream2 {
+        match self {
+            Self::Str => quote!(&#value),
+            _ => quote!(#value),
+        }
+    }
+
+    /// Returns the first value of the register `cursor` points to as a `Result<Option<_>>`.
+    fn try_read(self, cursor: TokenStream2) -> TokenStream2 {
+        let values = match self {
+            Self::Bool => format_ident!("bools"),
+            Self::U64 => format_ident!("u64s"),
+            Self::I64 => format_ident!("i64s"),
+            Self::F64 => format_ident!("f64s"),
+            Self::Str => format_ident!("strs"),
+        };
+        quote!(#cursor.#values()?.next().transpose())
+    }
+
+    /// Returns the first value of the register `cursor` points to as an `Option<_>`.
+    fn read(self, cursor: TokenStream2) -> TokenStream2 {
+        let read = self.try_read(cursor);
+        quote!(#read?)
+    }
+
+    fn assign(self) -> Ident {
+        format_ident!("assign_{}", self.suffix())
+    }
+
+    fn key(self) -> Ident {
+        format_ident!("key_{}", self.suffix())
+    }
+
+    fn keys(self) -> Ident {
+        format_ident!("keys_{}", self.suffix())
+    }
+}
+
+enum FieldKind {
+    Reg(Prim),
+    Opt(Prim),
+    Array(Prim),
+    Table(Prim, Prim),
+    Struct(syn::Path),
+}
+
+impl FieldKind {
+    fn parse(ty: &Type) -> syn::Result<Self> {
+        if let Some(prim) = Prim::parse(ty) {
+            return Ok(Self::Reg(prim));
+        }
+        let (name, args) =
+            type_args(ty).ok_or_else(|| Error::new(ty.span(), "unsupported field type"))?;
+        Ok(match (name.to_string().as_str(), args.as_slice()) {
+            ("Option", [inner]) => Self::Opt(Prim::expect(inner)?),
+            ("Vec", [inner]) => Self::Array(Prim::expect(inner)?),
+            ("HashMap" | "BTreeMap", [key, value]) => {
+                Self::Table(Prim::expect(key)?, Prim::expect(value)?)
+            }
+            (_, []) => match ty {
+                Type::Path(path) => Self::Struct(path.path.clone()),
+                _ => unreachable!(),
+            },
+            _ => return Err(Error::new(ty.span(), "unsupported field type")),
+        })
+    }
+}
+
+/// Returns the last identifier of a type path and it's generic type arguments.
+fn type_args(ty: &Type) -> Option<(&Ident, Vec<&Type>)> {
+    let path = match ty {
+        Type::Path(path) if path.qself.is_none() => &path.path,
+        _ => return None,
+    };
+    let segment = path.segments.last()?;
+    let args = match &segment.arguments {
+        PathArguments::None => vec![],
+        PathArguments::AngleBracketed(args) => args
+            .args
+            .iter()
+            .filter_map(|arg| match arg {
+                GenericArgument::Type(ty) => Some(ty),
+                _ => None,
+            })
+            .collect(),
+        PathArguments::Parenthesized(_) => return None,
+    };
+    Some((&segment.ident, args))
+}
+
+/// Returns the path of the cursor generated for a nested struct.
+fn cursor_path(path: &syn::Path) -> syn::Path {
+    let mut path = path.clone();
+    let last = path.segments.last_mut().unwrap();
+    last.ident = format_ident!("{}Cursor", last.ident);
+    path
+}
+
+/// Maps a struct to a tlfs schema and generates a typed cursor. See the crate documentation
+/// for the supported field types.
+#[proc_macro_derive(TlfsDocument)]
+pub fn derive_tlfs_document(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match derive(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+fn derive(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.span(),
+                    "expected a struct with named fields",
+                ))
+            }
+        },
+        _ => return Err(Error::new(input.span(), "expected a struct")),
+    };
+    if !input.generics.params.is_empty() {
+        return Err(Error::new(
+            input.generics.span(),
+            "generic documents aren't supported",
+        ));
+    }
+    let vis = &input.vis;
+    let name = &input.ident;
+    let cursor = format_ident!("{}Cursor", name);
+
+    let mut lenses = vec![];
+    let mut methods = vec![];
+    let mut reads = vec![];
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let prop = ident.to_string().trim_start_matches("r#").to_string();
+        let ty = &field.ty;
+        let kind = FieldKind::parse(ty)?;
+        let at = quote! {
+            let mut cursor = self.0.clone();
+            cursor.field(#prop)?;
+        };
+        let value = format_ident!("value");
+        lenses.push(quote!(lenses.push(::tlfs_crdt::Lens::AddProperty(#prop.into()));));
+        match kind {
+            FieldKind::Reg(prim) | FieldKind::Opt(prim) => {
+                let make = prim.kind();
+                lenses.push(quote! {
+                    lenses.push(
+                        ::tlfs_crdt::Lens::Make(::tlfs_crdt::Kind::Reg(#make)).lens_in(#prop),
+                    );
+                });
+                let read = prim.read(quote!(cursor));
+                let try_read = prim.try_read(quote!(cursor));
+                let arg = prim.arg();
+                let assign = prim.assign();
+                let setter = format_ident!("set_{}", ident);
+                if let FieldKind::Reg(_) = kind {
+                    methods.push(quote! {
+                        /// Returns the value of the field, or the default value if it isn't set.
+                        pub fn #ident(&self) -> ::anyhow::Result<#ty> {
+                            #at
+                            Ok(#read.unwrap_or_default())
+                        }
+
+                        /// Assigns a value to the field.
+                        pub fn #setter(&self, #value: #arg) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                            #at
+                            cursor.#assign(#value)
+                        }
+                    });
+                } else {
+                    methods.push(quote! {
+                        /// Returns the value of the field.
+                        pub fn #ident(&self) -> ::anyhow::Result<#ty> {
+                            #at
+                            #try_read
+                        }
+
+                        /// Assigns a value to the field or removes it when `None`.
+                        pub fn #setter(
+                            &self,
+                            #value: Option<#arg>,
+                        ) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                            #at
+                            match #value {
+                                Some(#value) => cursor.#assign(#value),
+                                None => cursor.remove(),
+                            }
+                        }
+                    });
+                }
+                reads.push(quote!(#ident: cursor.#ident()?,));
+            }
+            FieldKind::Array(prim) => {
+                let make = prim.kind();
+                lenses.push(quote! {
+                    lenses.push(::tlfs_crdt::Lens::Make(::tlfs_crdt::Kind::Array).lens_in(#prop));
+                    lenses.push(
+                        ::tlfs_crdt::Lens::LensMap(Box::new(::tlfs_crdt::Lens::Make(
+                            ::tlfs_crdt::Kind::Reg(#make),
+                        )))
+                        .lens_in(#prop),
+                    );
+                });
+                let read = prim.read(quote!(element));
+                let arg = prim.arg();
+                let assign = prim.assign();
+                let push = format_ident!("push_{}", ident);
+                let set = format_ident!("set_{}", ident);
+                let remove = format_ident!("remove_{}", ident);
+                methods.push(quote! {
+                    /// Returns the elements of the array.
+                    pub fn #ident(&self) -> ::anyhow::Result<#ty> {
+                        #at
+                        let elements = cursor
+                            .iter_array()?
+                            .map(|element| Ok(#read.unwrap_or_default()))
+                            .collect();
+                        elements
+                    }
+
+                    /// Appends an element to the array.
+                    pub fn #push(&self, #value: #arg) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                        #at
+                        let len = cursor.len()? as usize;
+                        cursor.index(len)?.#assign(#value)
+                    }
+
+                    /// Assigns a value to the element at `ix`.
+                    pub fn #set(
+                        &self,
+                        ix: usize,
+                        #value: #arg,
+                    ) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                        #at
+                        if ix >= cursor.len()? as usize {
+                            ::anyhow::bail!("index {} out of bounds", ix);
+                        }
+                        cursor.index(ix)?.#assign(#value)
+                    }
+
+                    /// Removes the element at `ix`.
+                    pub fn #remove(&self, ix: usize) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                        #at
+                        if ix >= cursor.len()? as usize {
+                            ::anyhow::bail!("index {} out of bounds", ix);
+                        }
+                        cursor.index(ix)?.delete()
+                    }
+                });
+                reads.push(quote!(#ident: cursor.#ident()?,));
+            }
+            FieldKind::Table(key, val) => {
+                let make_key = key.kind();
+                let make_val = val.kind();
+                lenses.push(quote! {
+                    lenses.push(
+                        ::tlfs_crdt::Lens::Make(::tlfs_crdt::Kind::Table(#make_key)).lens_in(#prop),
+                    );
+                    lenses.push(
+                        ::tlfs_crdt::Lens::Make(::tlfs_crdt::Kind::Reg(#make_val))
+                            .lens_map_value()
+                            .lens_in(#prop),
+                    );
+                });
+                let read = val.read(quote!(entry));
+                let key_arg = key.arg();
+                let val_arg = val.arg();
+                let keys = key.keys();
+                let key_fn = key.key();
+                let k = format_ident!("key");
+                let pass_key = key.pass(&k);
+                let assign = val.assign();
+                let insert = format_ident!("insert_{}", ident);
+                let remove = format_ident!("remove_{}", ident);
+                methods.push(quote! {
+                    /// Returns the entries of the table.
+                    pub fn #ident(&self) -> ::anyhow::Result<#ty> {
+                        #at
+                        let mut entries = <#ty>::default();
+                        for #k in cursor.#keys()? {
+                            let mut entry = cursor.clone();
+                            entry.#key_fn(#pass_key)?;
+                            if let Some(value) = #read {
+                                entries.insert(#k, value);
+                            }
+                        }
+                        Ok(entries)
+                    }
+
+                    /// Assigns a value to the entry at `key`.
+                    pub fn #insert(
+                        &self,
+                        #k: #key_arg,
+                        #value: #val_arg,
+                    ) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                        #at
+                        cursor.#key_fn(#k)?.#assign(#value)
+                    }
+
+                    /// Removes the entry at `key`.
+                    pub fn #remove(&self, #k: #key_arg) -> ::anyhow::Result<::tlfs_crdt::Causal> {
+                        #at
+                        cursor.#key_fn(#k)?.remove()
+                    }
+                });
+                reads.push(quote!(#ident: cursor.#ident()?,));
+            }
+            FieldKind::Struct(path) => {
+                let nested = cursor_path(&path);
+                lenses.push(quote! {
+                    lenses.extend(<#path>::lenses().into_iter().map(|lens| lens.lens_in(#prop)));
+                });
+                methods.push(quote! {
+                    /// Returns a cursor pointing to the nested struct.
+                    pub fn #ident(&self) -> ::anyhow::Result<#nested<'a>> {
+                        #at
+                        Ok(#nested::new(cursor))
+                    }
+                });
+                reads.push(quote!(#ident: <#path>::read(cursor.#ident()?.cursor())?,));
+            }
+        }
+    }
+
+    Ok(quote! {
+        impl #name {
+            /// Returns the lenses constructing the schema of the document.
+            #vis fn lenses() -> Vec<::tlfs_crdt::Lens> {
+                let mut lenses = vec![::tlfs_crdt::Lens::Make(::tlfs_crdt::Kind::Struct)];
+                #(#lenses)*
+                lenses
+            }
+
+            /// Reads the current state of the document `cursor` points to.
+            #vis fn read(cursor: &::tlfs_crdt::Cursor<'_>) -> ::anyhow::Result<Self> {
+                let cursor = #cursor::new(cursor.clone());
+                Ok(Self {
+                    #(#reads)*
+                })
+            }
+        }
+
+        #[doc = concat!("Typed cursor of [`", stringify!(#name), "`].")]
+        #[derive(Clone, Debug)]
+        #vis struct #cursor<'a>(::tlfs_crdt::Cursor<'a>);
+
+        impl<'a> #cursor<'a> {
+            /// Wraps a cursor pointing to the document.
+            #vis fn new(cursor: ::tlfs_crdt::Cursor<'a>) -> Self {
+                Self(cursor)
+            }
+
+            /// Returns the wrapped cursor.
+            #vis fn cursor(&self) -> &::tlfs_crdt::Cursor<'a> {
+                &self.0
+            }
+
+            #(#methods)*
+        }
+    })
+}
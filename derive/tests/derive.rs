@@ -0,0 +1,72 @@
+use anyhow::Result;
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use tlfs_crdt::{Backend, Keypair, Lenses, Package, Ref};
+use tlfs_derive::TlfsDocument;
+
+#[derive(Debug, Default, Eq, PartialEq, TlfsDocument)]
+pub struct Todo {
+    title: String,
+    done: bool,
+    due: Option<u64>,
+    tags: Vec<String>,
+    notes: HashMap<String, String>,
+    votes: BTreeMap<u64, i64>,
+    meta: Meta,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, TlfsDocument)]
+pub struct Meta {
+    author: String,
+}
+
+#[async_std::test]
+async fn test_typed_document() -> Result<()> {
+    let packages = vec![Package::new("todo".into(), 1, &Lenses::new(Todo::lenses()))];
+    let mut sdk = Backend::memory(Ref::archive(&packages).as_bytes())?;
+    let peer = sdk.frontend().default_keypair()?.peer_id();
+    let fut = sdk
+        .frontend()
+        .create_doc(peer, "todo", Keypair::generate())?;
+    Pin::new(&mut sdk).await?;
+    let doc = fut.await;
+
+    assert_eq!(Todo::read(&doc.cursor())?, Todo::default());
+
+    let todo = TodoCursor::new(doc.cursor());
+    doc.apply(&todo.set_title("write tests")?)?;
+    doc.apply(&todo.set_done(true)?)?;
+    doc.apply(&todo.set_due(Some(42))?)?;
+    doc.apply(&todo.push_tags("a")?)?;
+    doc.apply(&todo.push_tags("b")?)?;
+    doc.apply(&todo.push_tags("c")?)?;
+    doc.apply(&todo.set_tags(0, "z")?)?;
+    doc.apply(&todo.remove_tags(1)?)?;
+    doc.apply(&todo.insert_notes("k", "v")?)?;
+    doc.apply(&todo.insert_votes(1, -1)?)?;
+    doc.apply(&todo.insert_votes(2, 3)?)?;
+    doc.apply(&todo.remove_votes(1)?)?;
+    doc.apply(&todo.meta()?.set_author("me")?)?;
+    assert!(todo.set_tags(5, "x").is_err());
+
+    let mut notes = HashMap::new();
+    notes.insert("k".to_string(), "v".to_string());
+    let mut votes = BTreeMap::new();
+    votes.insert(2, 3);
+    let expected = Todo {
+        title: "write tests".into(),
+        done: true,
+        due: Some(42),
+        tags: vec!["z".into(), "c".into()],
+        notes,
+        votes,
+        meta: Meta {
+            author: "me".into(),
+        },
+    };
+    assert_eq!(Todo::read(&doc.cursor())?, expected);
+
+    doc.apply(&todo.set_due(None)?)?;
+    assert_eq!(todo.due()?, None);
+    Ok(())
+}
@@ -0,0 +1,268 @@
+//! Conversion of documents to and from [`serde_json::Value`].
+use crate::Doc;
+use anyhow::{anyhow, Context, Result};
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeSet;
+use tlfs_crdt::{ArchivedSchema, Causal, Cursor, PrimitiveKind};
+
+fn prim(value: &Value, kind: PrimitiveKind) -> Result<Prim> {
+    Ok(match kind {
+        PrimitiveKind::Bool => Prim::Bool(value.as_bool().context("expected a bool")?),
+        PrimitiveKind::U64 => Prim::U64(value.as_u64().context("expected an u64")?),
+        PrimitiveKind::I64 => Prim::I64(value.as_i64().context("expected an i64")?),
+        PrimitiveKind::F64 => Prim::F64(match value.as_str() {
+            Some("NaN") => f64::NAN,
+            Some("Infinity") => f64::INFINITY,
+            Some("-Infinity") => f64::NEG_INFINITY,
+            _ => value.as_f64().context("expected a f64")?,
+        }),
+        PrimitiveKind::Str => Prim::Str(value.as_str().context("expected a string")?.into()),
+        PrimitiveKind::Bytes => Prim::Bytes(
+            value
+                .as_array()
+                .context("expected an array of bytes")?
+                .iter()
+                .map(|b| {
+                    b.as_u64()
+                        .and_then(|b| u8::try_from(b).ok())
+                        .context("expected a byte")
+                })
+                .collect::<Result<_>>()?,
+        ),
+    })
+}
+
+enum Prim {
+    Bool(bool),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<Prim> for Value {
+    fn from(prim: Prim) -> Self {
+        match prim {
+            Prim::Bool(b) => Value::Bool(b),
+            Prim::U64(n) => Value::Number(n.into()),
+            Prim::I64(n) => Value::Number(n.into()),
+            // json has no representation of nan and infinity, they are written like in js.
+            Prim::F64(n) => match Number::from_f64(n) {
+                Some(n) => Value::Number(n),
+                None if n.is_nan() => Value::String("NaN".into()),
+                None if n > 0.0 => Value::String("Infinity".into()),
+                None => Value::String("-Infinity".into()),
+            },
+            Prim::Str(s) => Value::String(s),
+            Prim::Bytes(b) => Value::Array(b.into_iter().map(Into::into).collect()),
+        }
+    }
+}
+
+fn values(cursor: &Cursor<'_>, kind: PrimitiveKind) -> Result<Vec<Prim>> {
+    match kind {
+        PrimitiveKind::Bool => cursor.bools()?.map(|v| Ok(Prim::Bool(v?))).collect(),
+        PrimitiveKind::U64 => cursor.u64s()?.map(|v| Ok(Prim::U64(v?))).collect(),
+        PrimitiveKind::I64 => cursor.i64s()?.map(|v| Ok(Prim::I64(v?))).collect(),
+        PrimitiveKind::F64 => cursor.f64s()?.map(|v| Ok(Prim::F64(v?))).collect(),
+        PrimitiveKind::Str => cursor.strs()?.map(|v| Ok(Prim::Str(v?))).collect(),
+        PrimitiveKind::Bytes => cursor.bytes()?.map(|v| Ok(Prim::Bytes(v?))).collect(),
+    }
+}
+
+fn merged(cursor: &Cursor<'_>, kind: PrimitiveKind) -> Result<Option<Prim>> {
+    Ok(match kind {
+        PrimitiveKind::Bool => cursor.merged_bool()?.map(Prim::Bool),
+        PrimitiveKind::U64 => cursor.merged_u64()?.map(Prim::U64),
+        PrimitiveKind::I64 => cursor.merged_i64()?.map(Prim::I64),
+        PrimitiveKind::F64 => cursor.merged_f64()?.map(Prim::F64),
+        PrimitiveKind::Str => cursor.merged_str()?.map(Prim::Str),
+        PrimitiveKind::Bytes => cursor.merged_bytes()?.map(Prim::Bytes),
+    })
+}
+
+fn keys(cursor: &Cursor<'_>, kind: PrimitiveKind) -> Result<BTreeSet<String>> {
+    Ok(match kind {
+        PrimitiveKind::Bool => cursor.keys_bool()?.map(|k| k.to_string()).collect(),
+        PrimitiveKind::U64 => cursor.keys_u64()?.map(|k| k.to_string()).collect(),
+        PrimitiveKind::I64 => cursor.keys_i64()?.map(|k| k.to_string()).collect(),
+        PrimitiveKind::Str => cursor.keys_str()?.collect(),
+        PrimitiveKind::F64 | PrimitiveKind::Bytes => {
+            return Err(anyhow!("tables can't be keyed by {:?}", kind))
+        }
+    })
+}
+
+fn key(cursor: &mut Cursor<'_>, kind: PrimitiveKind, key: &str) -> Result<()> {
+    let err = || anyhow!("invalid key {} of Table<{:?}, _>", key, kind);
+    match kind {
+        PrimitiveKind::Bool => cursor.key_bool(key.parse().map_err(|_| err())?)?,
+        PrimitiveKind::U64 => cursor.key_u64(key.parse().map_err(|_| err())?)?,
+        PrimitiveKind::I64 => cursor.key_i64(key.parse().map_err(|_| err())?)?,
+        PrimitiveKind::Str => cursor.key_str(key)?,
+        PrimitiveKind::F64 | PrimitiveKind::Bytes => return Err(err()),
+    };
+    Ok(())
+}
+
+/// Returns the json representation of the value the cursor points to.
+///
+/// Tables and structs become objects, arrays and sets become arrays. Registers become a
+/// single value, `null` when empty or an array of the concurrently assigned values. Bytes
+/// become an array of numbers. Non finite f64s become the strings `NaN`, `Infinity` and
+/// `-Infinity`.
+pub(crate) fn export(cursor: &Cursor<'_>) -> Result<Value> {
+    Ok(match cursor.schema() {
+        ArchivedSchema::Null => Value::Null,
+        ArchivedSchema::Flag => Value::Bool(cursor.enabled()?),
+        ArchivedSchema::Reg(kind)
+        | ArchivedSchema::CheckedReg(kind, _)
+        | ArchivedSchema::LwwReg(kind)
+        | ArchivedSchema::Encrypted(kind) => {
+            let mut values = values(cursor, *kind)?;
+            match values.len() {
+                0 => Value::Null,
+                1 => values.pop().unwrap().into(),
+                _ => Value::Array(values.into_iter().map(Into::into).collect()),
+            }
+        }
+        ArchivedSchema::MergeReg(kind, _) => merged(cursor, *kind)?
+            .map(Into::into)
+            .unwrap_or(Value::Null),
+        ArchivedSchema::Table(kind, _) => {
+            let mut map = Map::new();
+            for k in keys(cursor, *kind)? {
+                let mut value = cursor.clone();
+                key(&mut value, *kind, &k)?;
+                map.insert(k, export(&value)?);
+            }
+            Value::Object(map)
+        }
+        ArchivedSchema::Array(_) => Value::Array(
+            cursor
+                .iter_array()?
+                .map(|value| export(&value))
+                .collect::<Result<_>>()?,
+        ),
+        ArchivedSchema::Struct(fields) => {
+            let mut map = Map::new();
+            for field in fields.keys() {
+                map.insert(field.to_string(), export(&cursor.at_field(field)?)?);
+            }
+            Value::Object(map)
+        }
+        ArchivedSchema::Set(kind) => {
+            let elements: Vec<Value> = match kind {
+                PrimitiveKind::Bool => cursor.elements_bool()?.map(Value::Bool).collect(),
+                PrimitiveKind::U64 => cursor
+                    .elements_u64()?
+                    .map(|n| Value::Number(n.into()))
+                    .collect(),
+                PrimitiveKind::I64 => cursor
+                    .elements_i64()?
+                    .map(|n| Value::Number(n.into()))
+                    .collect(),
+                PrimitiveKind::F64 => cursor
+                    .elements_f64()?
+                    .map(|n| Prim::F64(n).into())
+                    .collect(),
+                PrimitiveKind::Str => cursor.elements_str()?.map(Value::String).collect(),
+                PrimitiveKind::Bytes => cursor
+                    .elements_bytes()?
+                    .map(|b| Prim::Bytes(b).into())
+                    .collect(),
+            };
+            Value::Array(elements)
+        }
+        ArchivedSchema::Counter => Value::Number(cursor.counter_value()?.into()),
+        ArchivedSchema::Text => Value::String(cursor.text()?),
+        ArchivedSchema::Dynamic | ArchivedSchema::Local(_) => {
+            return Err(anyhow!("can't export {:?}", cursor.schema()))
+        }
+    })
+}
+
+/// Writes `value` to the document. All writes are applied as a single transaction, so a value
+/// that doesn't match the schema leaves the document unchanged.
+pub(crate) fn import(doc: &Doc, value: &Value) -> Result<()> {
+    let mut causal = Causal::default();
+    import_at(&doc.cursor(), value, &mut causal)?;
+    doc.apply(causal)
+}
+
+fn import_at(cursor: &Cursor<'_>, value: &Value, causal: &mut Causal) -> Result<()> {
+    if value.is_null() {
+        return Ok(());
+    }
+    let delta = match (cursor.schema(), value) {
+        (ArchivedSchema::Flag, Value::Bool(true)) => cursor.enable()?,
+        (ArchivedSchema::Flag, Value::Bool(false)) => cursor.disable()?,
+        (
+            ArchivedSchema::Reg(kind)
+            | ArchivedSchema::MergeReg(kind, _)
+            | ArchivedSchema::CheckedReg(kind, _)
+            | ArchivedSchema::LwwReg(kind)
+            | ArchivedSchema::Encrypted(kind),
+            value,
+        ) => match prim(value, *kind)? {
+            Prim::Bool(b) => cursor.assign_bool(b)?,
+            Prim::U64(n) => cursor.assign_u64(n)?,
+            Prim::I64(n) => cursor.assign_i64(n)?,
+            Prim::F64(n) => cursor.assign_f64(n)?,
+            Prim::Str(s) => cursor.assign_str(&s)?,
+            Prim::Bytes(b) => cursor.assign_bytes(&b)?,
+        },
+        (ArchivedSchema::Counter, value) => {
+            let value = value.as_i64().context("expected an i64")?;
+            cursor.increment(value.saturating_sub(cursor.counter_value()?))?
+        }
+        (ArchivedSchema::Text, Value::String(s)) => {
+            let len = cursor.text()?.chars().count();
+            let mut delta = cursor.delete_range(0, len)?;
+            delta.join(&cursor.insert_str(len, s)?);
+            delta
+        }
+        (ArchivedSchema::Set(kind), Value::Array(elements)) => {
+            let mut delta = Causal::default();
+            for element in elements {
+                delta.join(&match prim(element, *kind)? {
+                    Prim::Bool(b) => cursor.add_bool(b)?,
+                    Prim::U64(n) => cursor.add_u64(n)?,
+                    Prim::I64(n) => cursor.add_i64(n)?,
+                    Prim::F64(n) => cursor.add_f64(n)?,
+                    Prim::Str(s) => cursor.add_str(&s)?,
+                    Prim::Bytes(b) => cursor.add_bytes(&b)?,
+                });
+            }
+            delta
+        }
+        (ArchivedSchema::Table(kind, _), Value::Object(map)) => {
+            for (k, value) in map {
+                let mut cursor = cursor.clone();
+                key(&mut cursor, *kind, k)?;
+                import_at(&cursor, value, causal)?;
+            }
+            return Ok(());
+        }
+        (ArchivedSchema::Struct(_), Value::Object(map)) => {
+            for (field, value) in map {
+                let mut cursor = cursor.clone();
+                cursor.field(field)?;
+                import_at(&cursor, value, causal)?;
+            }
+            return Ok(());
+        }
+        (ArchivedSchema::Array(_), Value::Array(elements)) => {
+            // the elements are appended at the end of the array in order.
+            let cursors = cursor.append(elements.len())?;
+            for (cursor, value) in cursors.iter().zip(elements) {
+                import_at(cursor, value, causal)?;
+            }
+            return Ok(());
+        }
+        (schema, value) => return Err(anyhow!("can't import {} into {:?}", value, schema)),
+    };
+    causal.join(&delta);
+    Ok(())
+}
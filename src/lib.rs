@@ -1,31 +1,154 @@
 //! The Local First SDK.
 //!
-//! See the `tlfs_crdt` docs for details of how it works.
+//! See the `tlfs_crdt` docs for details of how it works. Applications should prefer the
+//! [`prelude`], low-level types are only re-exported with the `unstable` feature.
 #![deny(missing_docs)]
+mod json;
+pub mod prelude;
 mod sync;
 mod transport;
 
-pub use crate::sync::{libp2p_peer_id, Invite, ToLibp2pKeypair, ToLibp2pPublic};
-pub use libp2p::Multiaddr;
+use crate::sync::InviteRequest;
+pub use crate::sync::{
+    libp2p_peer_id, ConnectionEvent, Direction, Invite, PeerEntry, PeerInfo, PeerSource,
+    PeerSyncStatus, Priority, RejectedTraffic, SdkEvent, SyncLimits, ToLibp2pKeypair,
+    ToLibp2pPublic,
+};
+pub use libp2p::{swarm::AddressScore, Multiaddr};
 pub use tlfs_crdt::{
-    Actor, ArchivedSchema, Backend, Can, Causal, Cursor, DocId, Event, Frontend, Keypair, Kind,
-    Lens, Lenses, Package, PathBuf, PeerId, Permission, PrimitiveKind, Ref, Schema, Subscriber,
+    Actor, Batch, Can, Causal, CausalContext, Change, Cursor, Denied, DocId, DocState, Dot,
+    Durability, EffectivePermissions, EncryptedStorage, Event, Grant, Hash, InvalidChange,
+    InviteToken, Keypair, Merge, MetricsSnapshot, NotGranted, Path, PathBuf, PeerId, Permission,
+    PrimitiveKind, Replay, ReplayEntry, ReplayKind, ScanPage, SchemaUpgraded, Segment, Snapshot,
+    SnapshotDiff, Step, Subscriber, Token, MAX_BYTES,
 };
+#[cfg(feature = "unstable")]
+pub use tlfs_crdt::{ArchivedSchema, Backend, Frontend, Kind, Lens, Lenses, Package, Ref, Schema};
+#[cfg(not(feature = "unstable"))]
+use tlfs_crdt::{Backend, Frontend};
 
-use crate::sync::{notify, Behaviour};
+use crate::sync::Behaviour;
 use anyhow::Result;
 use futures::{
     channel::{mpsc, oneshot},
-    future::poll_fn,
-    Future, Stream, StreamExt,
+    future::{self, poll_fn},
+    stream, Future, FutureExt, Stream, StreamExt,
 };
+use futures_timer::Delay;
 use libp2p::{
     core::{muxing::StreamMuxerBox, transport::Boxed},
-    swarm::{AddressScore, SwarmEvent},
+    multiaddr::Protocol,
+    swarm::SwarmEvent,
     Swarm,
 };
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, BTreeSet};
 use std::task::Poll;
+use std::time::Duration;
+
+/// Interval in which ephemeral documents are checked for expiry.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Time before expiry at which subscribers are notified about an expiring document.
+pub const EXPIRY_GRACE: Duration = Duration::from_secs(60);
+/// Number of events buffered per subscriber before events are dropped.
+const EVENT_BUFFER: usize = 256;
+
+/// Location of the database of a persistent [`Sdk`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StoragePath {
+    /// A path to the database directory.
+    Path(std::path::PathBuf),
+    /// A relative path inside of a sandbox container, like an iOS app group container or the
+    /// android files directory. The path may not escape the container.
+    Container {
+        /// Path of the container provided by the platform.
+        container: std::path::PathBuf,
+        /// Relative path of the database directory.
+        path: std::path::PathBuf,
+    },
+}
+
+impl StoragePath {
+    /// Returns the path of the database directory.
+    pub fn resolve(&self) -> Result<std::path::PathBuf> {
+        match self {
+            Self::Path(path) => Ok(path.clone()),
+            Self::Container { container, path } => {
+                if !path
+                    .components()
+                    .all(|c| matches!(c, std::path::Component::Normal(_)))
+                {
+                    anyhow::bail!("{} escapes the container", path.display());
+                }
+                Ok(container.join(path))
+            }
+        }
+    }
+}
+
+/// Network and storage configuration of an [`Sdk`].
+#[derive(Clone, Debug)]
+pub struct SdkConfig {
+    /// Addresses to listen on.
+    pub listen_on: Vec<Multiaddr>,
+    /// Enables local peer discovery. Requires the `mdns` feature.
+    pub mdns: bool,
+    /// Enables the webrtc transport. Requires the `transport-webrtc` feature. When disabled,
+    /// webrtc-star addresses in `listen_on` are skipped.
+    pub webrtc: bool,
+    /// Peers and their addresses dialed on startup.
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// Key used to encrypt the database at rest. The key needs to be kept by the
+    /// application, for example in the platform keychain, and passed again when reopening
+    /// the database. Generate one with [`EncryptedStorage::generate_key`].
+    pub storage_key: Option<[u8; 32]>,
+    /// Password of the keystore. Opening a database whose keystore was encrypted with
+    /// [`Frontend::encrypt_keystore`] fails without it.
+    pub keystore_password: Option<String>,
+    /// Limits on the sync traffic accepted from a single peer.
+    pub limits: SyncLimits,
+}
+
+impl Default for SdkConfig {
+    /// Listens on the webrtc-star signalling server and on tcp on native targets.
+    fn default() -> Self {
+        let mut listen_on = vec![];
+        if cfg!(feature = "transport-webrtc") {
+            listen_on.push(
+                "/dns4/local1st.net/tcp/443/wss/p2p-webrtc-star"
+                    .parse()
+                    .unwrap(),
+            );
+        }
+        if !cfg!(target_family = "wasm") {
+            listen_on.push("/ip4/0.0.0.0/tcp/0".parse().unwrap());
+        }
+        Self {
+            listen_on,
+            mdns: cfg!(feature = "mdns"),
+            webrtc: cfg!(feature = "transport-webrtc"),
+            bootstrap: vec![],
+            storage_key: None,
+            keystore_password: None,
+            limits: SyncLimits::default(),
+        }
+    }
+}
+
+impl SdkConfig {
+    /// Syncs over tcp with peers in the local network only, without contacting the
+    /// signalling server.
+    pub fn lan() -> Self {
+        Self {
+            listen_on: vec!["/ip4/0.0.0.0/tcp/0".parse().unwrap()],
+            mdns: true,
+            webrtc: false,
+            bootstrap: vec![],
+            storage_key: None,
+            keystore_password: None,
+            limits: SyncLimits::default(),
+        }
+    }
+}
 
 /// Main entry point for `tlfs`.
 pub struct Sdk {
@@ -40,46 +163,102 @@ impl Sdk {
     /// Creates a new [`Sdk`] instance using browser persistence.
     #[cfg(target_family = "wasm")]
     pub async fn browser(name: &str, package: &[u8]) -> Result<Self> {
+        Self::browser_with_config(name, package, SdkConfig::default()).await
+    }
+
+    /// Creates a new [`Sdk`] instance using browser persistence and [`SdkConfig`].
+    #[cfg(target_family = "wasm")]
+    pub async fn browser_with_config(
+        name: &str,
+        package: &[u8],
+        config: SdkConfig,
+    ) -> Result<Self> {
         init_tracing();
         let package = package.to_vec();
         let name = name.to_owned();
         let storage = std::sync::Arc::new(tlfs_crdt::BrowserCacheStorage::new(name).await.unwrap());
-        Self::new(storage, &package).await
+        Self::new(storage, &package, config).await
     }
 
     /// Creates a new [`Sdk`] instance using file system persistence.
     #[cfg(not(target_family = "wasm"))]
     pub async fn filesystem(db: &std::path::Path, package: &[u8]) -> Result<Self> {
+        Self::filesystem_with_config(db, package, SdkConfig::default()).await
+    }
+
+    /// Creates a new [`Sdk`] instance using file system persistence and [`SdkConfig`].
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn filesystem_with_config(
+        db: &std::path::Path,
+        package: &[u8],
+        config: SdkConfig,
+    ) -> Result<Self> {
         init_tracing();
+        std::fs::create_dir_all(db)?;
         Self::new(
             std::sync::Arc::new(tlfs_crdt::FileStorage::new(db)),
             package,
+            config,
         )
         .await
     }
 
+    /// Creates a new [`Sdk`] instance using file system persistence at [`StoragePath`].
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn persistent(path: &StoragePath, package: &[u8]) -> Result<Self> {
+        Self::persistent_with_config(path, package, SdkConfig::default()).await
+    }
+
+    /// Creates a new [`Sdk`] instance using file system persistence at [`StoragePath`] and
+    /// [`SdkConfig`].
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn persistent_with_config(
+        path: &StoragePath,
+        package: &[u8],
+        config: SdkConfig,
+    ) -> Result<Self> {
+        Self::filesystem_with_config(&path.resolve()?, package, config).await
+    }
+
     /// Create a new in-memory [`Sdk`] instance.
     pub async fn memory(package: &[u8]) -> Result<Self> {
+        Self::memory_with_config(package, SdkConfig::default()).await
+    }
+
+    /// Create a new in-memory [`Sdk`] instance using [`SdkConfig`].
+    pub async fn memory_with_config(package: &[u8], config: SdkConfig) -> Result<Self> {
         init_tracing();
         let storage = std::sync::Arc::new(tlfs_crdt::MemStorage::default());
-        Self::new(storage, package).await
+        Self::new(storage, package, config).await
     }
 
-    async fn new(storage: std::sync::Arc<dyn tlfs_crdt::Storage>, package: &[u8]) -> Result<Self> {
+    async fn new(
+        storage: std::sync::Arc<dyn tlfs_crdt::Storage>,
+        package: &[u8],
+        mut config: SdkConfig,
+    ) -> Result<Self> {
+        if !config.webrtc {
+            config
+                .listen_on
+                .retain(|addr| !addr.iter().any(|p| p == Protocol::P2pWebRtcStar));
+        }
+        let storage: std::sync::Arc<dyn tlfs_crdt::Storage> = match config.storage_key {
+            Some(key) => std::sync::Arc::new(EncryptedStorage::new(storage, key)),
+            None => storage,
+        };
         let backend = Backend::new(storage, package)?;
         let frontend = backend.frontend();
+        if let Some(password) = &config.keystore_password {
+            frontend.unlock(password)?;
+        } else if frontend.is_locked()? {
+            anyhow::bail!("the keystore is encrypted, a keystore_password is required");
+        }
 
         let keypair = frontend.default_keypair()?;
         let peer = keypair.peer_id();
         tracing::info!("our peer id is: {}", peer);
 
-        let transport = transport::transport(keypair.to_libp2p())?;
-        let mut listen_on = vec!["/dns4/local1st.net/tcp/443/wss/p2p-webrtc-star"
-            .parse()
-            .unwrap()];
-        if !cfg!(target_family = "wasm") {
-            listen_on.push("/ip4/0.0.0.0/tcp/0".parse().unwrap());
-        }
+        let transport = transport::transport(keypair.to_libp2p(), config.webrtc)?;
 
         //TODO
         //        slf.add_external_address(
@@ -89,7 +268,7 @@ impl Sdk {
         //            // TODO
         //            AddressScore::Infinite,
         //        )
-        Self::new_with_transport(backend, frontend, peer, transport, listen_on.into_iter()).await
+        Self::new_with_config(backend, frontend, peer, transport, config).await
     }
 
     /// Creates a new [`Sdk`] instance from the given [`Backend`], [`Frontend`] and libp2p
@@ -101,16 +280,41 @@ impl Sdk {
         transport: Boxed<(libp2p::PeerId, StreamMuxerBox)>,
         listen_on: impl Iterator<Item = Multiaddr>,
     ) -> Result<Self> {
-        let behaviour = Behaviour::new(backend).await?;
+        let config = SdkConfig {
+            listen_on: listen_on.collect(),
+            ..Default::default()
+        };
+        Self::new_with_config(backend, frontend, peer, transport, config).await
+    }
+
+    /// Creates a new [`Sdk`] instance from the given [`Backend`], [`Frontend`], libp2p
+    /// transport and [`SdkConfig`]. The `webrtc`, `storage_key` and `keystore_password` options of the config are
+    /// ignored, as they only apply when building the transport and the [`Backend`].
+    pub async fn new_with_config(
+        backend: Backend,
+        frontend: Frontend,
+        peer: PeerId,
+        transport: Boxed<(libp2p::PeerId, StreamMuxerBox)>,
+        config: SdkConfig,
+    ) -> Result<Self> {
+        let behaviour = Behaviour::new(backend, config.mdns, config.limits).await?;
         let mut swarm = Swarm::new(transport, behaviour, peer.to_libp2p().to_peer_id());
-        for i in listen_on {
+        for i in config.listen_on {
             swarm.listen_on(i)?;
         }
+        for (peer, addr) in config.bootstrap {
+            swarm.behaviour_mut().add_address(&peer, addr);
+            if let Err(err) = swarm.dial(peer.to_libp2p().to_peer_id()) {
+                tracing::error!("{}", err);
+            }
+        }
 
         let (tx, mut rx) = mpsc::unbounded();
+        let docs = frontend.clone();
+        let mut expiry_timer = Delay::new(EXPIRY_INTERVAL);
+        let mut expiring = BTreeSet::new();
+        let mut sub_expiring = vec![];
         let driver = poll_fn::<(), _>(move |cx| {
-            let mut sub_addresses = vec![];
-            let mut sub_connected_peers = vec![];
             while let Poll::Ready(Some(cmd)) = rx.poll_next_unpin(cx) {
                 match cmd {
                     Command::AddAddress(peer, addr) => {
@@ -125,19 +329,37 @@ impl Sdk {
                     Command::RemoveAddress(peer, addr) => {
                         swarm.behaviour_mut().remove_address(&peer, &addr)
                     }
+                    Command::Disconnect(peer) => {
+                        swarm.disconnect_peer_id(peer.to_libp2p().to_peer_id()).ok();
+                    }
+                    Command::BlockPeer(peer) => {
+                        swarm.ban_peer_id(peer.to_libp2p().to_peer_id());
+                        swarm.behaviour_mut().block_peer(peer);
+                    }
+                    Command::UnblockPeer(peer) => {
+                        swarm.unban_peer_id(peer.to_libp2p().to_peer_id());
+                        swarm.behaviour_mut().unblock_peer(&peer);
+                    }
                     Command::Addresses(ch) => {
                         let addrs = swarm.listeners().cloned().collect::<Vec<_>>();
                         ch.send(addrs).ok();
                     }
-                    Command::SubscribeAddresses(ch) => {
-                        sub_addresses.push(ch);
+                    Command::ExternalAddresses(ch) => {
+                        let addrs = swarm
+                            .external_addresses()
+                            .map(|record| (record.addr.clone(), record.score))
+                            .collect();
+                        ch.send(addrs).ok();
+                    }
+                    Command::SubscribeEvents(ch) => {
+                        swarm.behaviour_mut().subscribe_events(ch);
                     }
                     Command::LocalPeers(ch) => {
                         let peers = swarm.behaviour_mut().local_peers();
                         ch.send(peers).ok();
                     }
-                    Command::SubscribeLocalPeers(ch) => {
-                        swarm.behaviour_mut().subscribe_local_peers(ch);
+                    Command::Peers(ch) => {
+                        ch.send(swarm.behaviour().peers()).ok();
                     }
                     Command::ConnectedPeers(ch) => {
                         let peers = swarm
@@ -146,34 +368,174 @@ impl Sdk {
                             .collect();
                         ch.send(peers).ok();
                     }
-                    Command::SubscribeConnectedPeers(ch) => {
-                        sub_connected_peers.push(ch);
+                    Command::PeerInfo(peer, ch) => {
+                        ch.send(swarm.behaviour().peer_info(&peer)).ok();
+                    }
+                    Command::RejectedTraffic(ch) => {
+                        ch.send(swarm.behaviour().rejected_traffic()).ok();
+                    }
+                    #[cfg(feature = "metrics")]
+                    Command::MetricsSnapshot(ch) => {
+                        ch.send(swarm.behaviour().metrics_snapshot()).ok();
+                    }
+                    Command::SyncStatus(doc, ch) => {
+                        ch.send(swarm.behaviour().sync_status(&doc)).ok();
+                    }
+                    Command::Delivered(id, ch) => {
+                        ch.send(swarm.behaviour().delivered(&id)).ok();
+                    }
+                    Command::SetUnjoinRedundancy(redundancy) => {
+                        swarm.behaviour_mut().set_unjoin_redundancy(redundancy);
+                    }
+                    Command::SetDocPriority(doc, prio) => {
+                        swarm.behaviour_mut().set_priority(&doc, prio);
+                    }
+                    Command::SetDocShards(doc, fields) => {
+                        swarm.behaviour_mut().set_shards(&doc, fields);
                     }
                     Command::Subscribe(doc) => {
                         swarm.behaviour_mut().subscribe(&doc);
                     }
-                    Command::Broadcast(doc, causal) => {
-                        swarm.behaviour_mut().broadcast(&doc, causal).ok();
+                    Command::Unsubscribe(doc) => {
+                        swarm.behaviour_mut().unsubscribe(&doc);
+                    }
+                    Command::Broadcast(doc, causal, entry) => {
+                        if let Err(err) = swarm.behaviour_mut().broadcast(&doc, causal, Some(entry))
+                        {
+                            tracing::error!("{}", err);
+                        }
+                    }
+                    Command::PresentToken(token) => {
+                        if let Err(err) = swarm.behaviour_mut().present_token(token) {
+                            tracing::error!("{}", err);
+                        }
                     }
-                    Command::Invite(peer, doc, schema) => {
-                        swarm.behaviour_mut().invite(&peer, doc, schema);
+                    Command::Invite(peer, invite) => {
+                        swarm.behaviour_mut().invite(&peer, invite);
                     }
                     Command::Invites(tx) => {
                         let invites = swarm.behaviour_mut().clear_invites();
                         tx.send(invites).ok();
                     }
-                    Command::SubscribeInvites(ch) => {
-                        swarm.behaviour_mut().subscribe_invites(ch);
+                    Command::SubscribeExpiring(ch) => {
+                        sub_expiring.push(ch);
+                    }
+                    Command::SubscribeConnections(ch) => {
+                        swarm.behaviour_mut().subscribe_connections(ch);
+                    }
+                    Command::RemoteChanges(tx) => {
+                        let docs = swarm.behaviour_mut().clear_remote_changes();
+                        tx.send(docs).ok();
+                    }
+                    Command::SyncOnce(tx) => {
+                        swarm.behaviour_mut().sync_once(tx);
+                    }
+                    Command::Denied(tx) => {
+                        let denied = swarm.behaviour_mut().clear_denied();
+                        tx.send(denied).ok();
+                    }
+                    Command::ExportDelta(peer, doc, ctx, tx) => {
+                        tx.send(swarm.behaviour().export_delta(&peer, &doc, &ctx))
+                            .ok();
+                    }
+                    Command::ImportDelta(peer, doc, schema, causal, tx) => {
+                        let res = swarm
+                            .behaviour_mut()
+                            .import_delta(peer, doc, schema, causal);
+                        tx.send(res).ok();
+                    }
+                    Command::ExportSnapshot(doc, tx) => {
+                        tx.send(swarm.behaviour().export_snapshot(&doc)).ok();
+                    }
+                    Command::ImportSnapshot(snapshot, tx) => {
+                        let res = swarm.behaviour_mut().import_snapshot(&snapshot);
+                        tx.send(res).ok();
+                    }
+                    Command::AddPackage(package, tx) => {
+                        let res = swarm.behaviour_mut().add_package(&package);
+                        tx.send(res).ok();
+                    }
+                    Command::FetchAttachment(doc, hash, tx) => {
+                        swarm.behaviour_mut().fetch_attachment(doc, hash, tx);
                     }
                 };
             }
+            while expiry_timer.poll_unpin(cx).is_ready() {
+                expiry_timer.reset(EXPIRY_INTERVAL);
+                for doc in expire_docs(&docs, &mut expiring, &mut sub_expiring) {
+                    swarm.behaviour_mut().unsubscribe(&doc);
+                }
+                swarm.behaviour_mut().expire_pending();
+                let connected = swarm
+                    .connected_peers()
+                    .filter_map(|peer| libp2p_peer_id(peer).ok())
+                    .collect();
+                swarm.behaviour_mut().expire_addresses(&connected);
+            }
             while swarm.behaviour_mut().poll_backend(cx).is_ready() {}
             while let Poll::Ready(Some(ev)) = swarm.poll_next_unpin(cx) {
                 match ev {
-                    SwarmEvent::NewListenAddr { .. } => notify(&mut sub_addresses),
-                    SwarmEvent::ExpiredListenAddr { .. } => notify(&mut sub_addresses),
-                    SwarmEvent::ConnectionEstablished { .. } => notify(&mut sub_connected_peers),
-                    SwarmEvent::ConnectionClosed { .. } => notify(&mut sub_connected_peers),
+                    SwarmEvent::NewListenAddr { .. } | SwarmEvent::ExpiredListenAddr { .. } => {
+                        swarm.behaviour_mut().emit(SdkEvent::AddressChanged);
+                    }
+                    SwarmEvent::ConnectionEstablished {
+                        peer_id,
+                        endpoint,
+                        num_established,
+                        ..
+                    } => {
+                        if let Ok(peer) = libp2p_peer_id(&peer_id) {
+                            let addr = endpoint.get_remote_address().clone();
+                            let direction = if endpoint.is_dialer() {
+                                Direction::Outbound
+                            } else {
+                                Direction::Inbound
+                            };
+                            swarm
+                                .behaviour_mut()
+                                .emit_connection(ConnectionEvent::PeerConnected {
+                                    peer,
+                                    addr: addr.clone(),
+                                    direction,
+                                });
+                            // the remote address of inbound connections isn't dialable.
+                            if direction == Direction::Outbound {
+                                swarm.behaviour_mut().dialed_address(&peer, addr);
+                            }
+                            if num_established.get() == 1 {
+                                swarm.behaviour_mut().peer_connected(&peer);
+                                swarm.behaviour_mut().emit(SdkEvent::PeerConnected(peer));
+                            }
+                        }
+                    }
+                    SwarmEvent::ConnectionClosed {
+                        peer_id,
+                        num_established,
+                        cause,
+                        ..
+                    } => {
+                        if let Ok(peer) = libp2p_peer_id(&peer_id) {
+                            swarm.behaviour_mut().emit_connection(
+                                ConnectionEvent::PeerDisconnected {
+                                    peer,
+                                    reason: cause.map(|err| err.to_string()),
+                                },
+                            );
+                            if num_established == 0 {
+                                swarm.behaviour_mut().peer_disconnected(&peer);
+                                swarm.behaviour_mut().emit(SdkEvent::PeerDisconnected(peer));
+                            }
+                        }
+                    }
+                    SwarmEvent::OutgoingConnectionError { peer_id, error } => {
+                        let peer = peer_id.and_then(|peer_id| libp2p_peer_id(&peer_id).ok());
+                        swarm
+                            .behaviour_mut()
+                            .emit_connection(ConnectionEvent::DialFailure {
+                                peer,
+                                error: error.to_string(),
+                            });
+                    }
                     _ => {}
                 }
             }
@@ -222,6 +584,23 @@ impl Sdk {
             .ok();
     }
 
+    /// Closes all connections to a peer. The peer is dialed again when it is discovered or
+    /// needed for syncing.
+    pub fn disconnect(&self, peer: PeerId) {
+        self.swarm.unbounded_send(Command::Disconnect(peer)).ok();
+    }
+
+    /// Disconnects a peer and refuses it's connections, requests and changes until it is
+    /// unblocked. Blocks are not persisted.
+    pub fn block_peer(&self, peer: PeerId) {
+        self.swarm.unbounded_send(Command::BlockPeer(peer)).ok();
+    }
+
+    /// Accepts connections of a blocked peer again.
+    pub fn unblock_peer(&self, peer: PeerId) {
+        self.swarm.unbounded_send(Command::UnblockPeer(peer)).ok();
+    }
+
     /// Returns the list of [`Multiaddr`] the [`Sdk`] is listening on.
     pub fn addresses(&self) -> impl Future<Output = Vec<Multiaddr>> {
         let (tx, rx) = oneshot::channel();
@@ -229,29 +608,60 @@ impl Sdk {
         async move { rx.await.unwrap() }
     }
 
-    /// Subscribe to address changes.
-    pub fn subscribe_addresses(&self) -> impl Stream<Item = ()> {
-        let (tx, rx) = mpsc::channel(1);
+    /// Returns the external [`Multiaddr`] records of the local node and their score.
+    pub fn external_addresses(&self) -> impl Future<Output = Vec<(Multiaddr, AddressScore)>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::ExternalAddresses(tx))
+            .unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Subscribes to all [`SdkEvent`]s. Events are dropped when the stream falls behind by
+    /// more than a few hundred events.
+    pub fn events(&self) -> impl Stream<Item = SdkEvent> {
+        let acls = self.frontend.subscribe_acls().map(SdkEvent::AclChanged);
+        stream::select(self.swarm_events(), acls)
+    }
+
+    fn swarm_events(&self) -> impl Stream<Item = SdkEvent> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
         self.swarm
-            .unbounded_send(Command::SubscribeAddresses(tx))
+            .unbounded_send(Command::SubscribeEvents(tx))
             .unwrap();
         rx
     }
 
-    /// Returns the local peers.
+    fn subscribe_events(&self, f: fn(&SdkEvent) -> bool) -> impl Stream<Item = ()> {
+        self.swarm_events()
+            .filter_map(move |ev| future::ready(if f(&ev) { Some(()) } else { None }))
+    }
+
+    /// Subscribe to address changes.
+    pub fn subscribe_addresses(&self) -> impl Stream<Item = ()> {
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::AddressChanged))
+    }
+
+    /// Returns the peers in the peer table, see [`Sdk::peers`].
     pub fn local_peers(&self) -> impl Future<Output = BTreeSet<PeerId>> {
         let (tx, rx) = oneshot::channel();
         self.swarm.unbounded_send(Command::LocalPeers(tx)).unwrap();
         async move { rx.await.unwrap() }
     }
 
-    /// Subscribes to local peer changes.
+    /// Returns the peer table. It contains peers discovered on the local network, added with
+    /// [`Sdk::add_address`] or dialed directly or through a relay, together with their
+    /// addresses tagged with where they were learned from. Relayed and dialed addresses are
+    /// forgotten an hour after the last connection to the peer.
+    pub fn peers(&self) -> impl Future<Output = BTreeMap<PeerId, PeerEntry>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm.unbounded_send(Command::Peers(tx)).unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Subscribes to peer table changes.
     pub fn subscribe_local_peers(&self) -> impl Stream<Item = ()> {
-        let (tx, rx) = mpsc::channel(1);
-        self.swarm
-            .unbounded_send(Command::SubscribeLocalPeers(tx))
-            .unwrap();
-        rx
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::LocalPeersChanged))
     }
 
     /// Returns the connected peers.
@@ -265,13 +675,87 @@ impl Sdk {
 
     /// Subscribes to local peer changes.
     pub fn subscribe_connected_peers(&self) -> impl Stream<Item = ()> {
-        let (tx, rx) = mpsc::channel(1);
+        self.subscribe_events(|ev| {
+            matches!(
+                ev,
+                SdkEvent::PeerConnected(_) | SdkEvent::PeerDisconnected(_)
+            )
+        })
+    }
+
+    /// Subscribes to connection events. Unlike [`Sdk::subscribe_connected_peers`] every
+    /// connection is reported, together with its address, direction and close reason, as well
+    /// as failed dials. Events are dropped while the subscriber lags behind.
+    pub fn connection_events(&self) -> impl Stream<Item = ConnectionEvent> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
         self.swarm
-            .unbounded_send(Command::SubscribeConnectedPeers(tx))
+            .unbounded_send(Command::SubscribeConnections(tx))
             .unwrap();
         rx
     }
 
+    /// Returns liveness information about a peer.
+    pub fn peer_info(&self, peer: PeerId) -> impl Future<Output = Option<PeerInfo>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::PeerInfo(peer, tx))
+            .unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Returns the sync traffic dropped per peer since startup, because it exceeded the
+    /// [`SyncLimits`] of the [`SdkConfig`].
+    pub fn rejected_traffic(&self) -> impl Future<Output = BTreeMap<PeerId, RejectedTraffic>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::RejectedTraffic(tx))
+            .unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Returns counters of joins, unjoins, broadcasts, acl updates and storage writes since
+    /// startup and the current length of the storage logs, for embedding into app telemetry.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> impl Future<Output = MetricsSnapshot> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::MetricsSnapshot(tx))
+            .unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Subscribes to peer liveness changes.
+    pub fn subscribe_peer_info(&self) -> impl Stream<Item = ()> {
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::PeerInfoChanged(_)))
+    }
+
+    /// Sets the number of peers an unjoin is requested from when subscribing to a document.
+    /// Peers are picked by reachability and latency. Defaults to 2.
+    pub fn set_unjoin_redundancy(&self, redundancy: usize) {
+        self.swarm
+            .unbounded_send(Command::SetUnjoinRedundancy(redundancy))
+            .ok();
+    }
+
+    /// Sets the priority of a document. When many documents are syncing, unjoins and
+    /// broadcasts of documents with a higher priority are sent first. Defaults to
+    /// [`Priority::Normal`].
+    pub fn set_doc_priority(&self, doc: &DocId, prio: Priority) {
+        self.swarm
+            .unbounded_send(Command::SetDocPriority(*doc, prio))
+            .ok();
+    }
+
+    /// Only receives the broadcasts of the top-level `fields` of a large document, so that
+    /// changes to other fields aren't sent to this peer. Peers that don't support sharding keep
+    /// sending all broadcasts. Passing `None` receives all broadcasts again.
+    pub fn set_doc_shards(&self, doc: &DocId, fields: Option<&[&str]>) {
+        let fields = fields.map(|fields| fields.iter().map(|field| field.to_string()).collect());
+        self.swarm
+            .unbounded_send(Command::SetDocShards(*doc, fields))
+            .ok();
+    }
+
     /// Clears and returns pending invitations.
     pub fn invites(&self) -> impl Future<Output = Vec<Invite>> {
         let (tx, rx) = oneshot::channel();
@@ -281,11 +765,169 @@ impl Sdk {
 
     /// Subscribe to invitations.
     pub fn subscribe_invites(&self) -> impl Stream<Item = ()> {
-        let (tx, rx) = mpsc::channel(1);
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::InviteReceived))
+    }
+
+    /// Clears and returns the documents that received remote changes since the last call.
+    pub fn remote_changes(&self) -> impl Future<Output = Vec<DocId>> {
+        let (tx, rx) = oneshot::channel();
         self.swarm
-            .unbounded_send(Command::SubscribeInvites(tx))
+            .unbounded_send(Command::RemoteChanges(tx))
             .unwrap();
-        rx
+        async move { rx.await.unwrap() }
+    }
+
+    /// Returns the schema hash of a document and the changes missing from `remote_ctx`, the
+    /// [`CausalContext`] of a store of `peer`. Only paths `peer` is allowed to read are
+    /// included. Together with [`Sdk::import_delta`] this allows replicating documents over
+    /// custom transports, for example between a hub and it's spokes over HTTPS.
+    pub async fn export_delta(
+        &self,
+        peer: &PeerId,
+        doc: &DocId,
+        remote_ctx: CausalContext,
+    ) -> Result<(Hash, Causal)> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::ExportDelta(*peer, *doc, remote_ctx, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Applies changes to a document exported by `peer` with [`Sdk::export_delta`]. The
+    /// changes are validated against the schema with hash `schema`, and paths `peer` isn't
+    /// allowed to write are dropped. Changes in an unknown schema are buffered until the
+    /// lenses are available.
+    pub async fn import_delta(
+        &self,
+        peer: &PeerId,
+        doc: &DocId,
+        schema: Hash,
+        causal: Causal,
+    ) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::ImportDelta(*peer, *doc, schema, causal, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Serializes the full state of a document, including it's acl and expired paths. Together
+    /// with [`Sdk::import_snapshot`] this allows backing up documents and seeding new devices
+    /// without syncing with the network.
+    pub async fn export_snapshot(&self, doc: &DocId) -> Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::ExportSnapshot(*doc, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Restores a document exported with [`Sdk::export_snapshot`]. The package of the document
+    /// must be known.
+    pub async fn import_snapshot(&self, snapshot: Vec<u8>) -> Result<Doc> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::ImportSnapshot(snapshot, tx))
+            .unwrap();
+        let doc = rx.await??;
+        self.doc(doc)
+    }
+
+    /// Registers an archived `Package` at runtime and returns the hash of it's lenses. A new
+    /// version of a known package must append to the lenses of the latest version. Packages
+    /// registered at runtime are persisted and loaded again after a restart.
+    pub async fn add_package(&self, package: Vec<u8>) -> Result<Hash> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::AddPackage(package, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Returns the name, version and lenses hash of all registered packages.
+    pub fn packages(&self) -> Vec<(String, u32, Hash)> {
+        self.frontend.registry().packages()
+    }
+
+    /// Stores a file too large for a [`PrimitiveKind::Bytes`] register as an attachment of
+    /// `doc` and returns it's hash. The document references the attachment by storing the
+    /// hash, peers fetch it with [`Sdk::attachment`] when they need it. Attachments are only
+    /// served to peers that can read the document.
+    pub fn add_attachment(&self, doc: &DocId, data: &[u8]) -> Result<Hash> {
+        self.frontend.attachments().insert(doc, data)
+    }
+
+    /// Returns the attachment with `hash` of `doc`. Attachments that aren't stored locally are
+    /// fetched from the connected peers and stored. Fails if no connected peer serves it.
+    pub async fn attachment(&self, doc: &DocId, hash: Hash) -> Result<Vec<u8>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::FetchAttachment(*doc, hash, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Clears and returns the changes that were dropped because a peer lacked permission since
+    /// the last call. When a remote peer drops our changes, [`Denied::peer`] is our own peer.
+    pub fn denied(&self) -> impl Future<Output = Vec<Denied>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm.unbounded_send(Command::Denied(tx)).unwrap();
+        async move { rx.await.unwrap() }
+    }
+
+    /// Subscribes to changes being dropped because a peer lacked permission. Apps can use this
+    /// to prompt for re-granting access.
+    pub fn subscribe_denied(&self) -> impl Stream<Item = ()> {
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::PermissionDenied(_)))
+    }
+
+    /// Subscribes to remote changes. Mobile apps can use this to surface changes received
+    /// while backgrounded.
+    pub fn subscribe_remote_changes(&self) -> impl Stream<Item = ()> {
+        self.subscribe_events(|ev| matches!(ev, SdkEvent::DocChanged(_)))
+    }
+
+    /// Requests an unjoin of every document from the connected peers and writes the result
+    /// to storage. Resolves once all peers answered or failed. Intended to be called when
+    /// the app is woken up by a platform push notification.
+    pub async fn sync_once(&self) -> Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm.unbounded_send(Command::SyncOnce(tx)).unwrap();
+        rx.await?;
+        self.frontend.sync().await
+    }
+
+    /// Returns the local changes that no peer acknowledged yet. They are sent again when the
+    /// [`Sdk`] is started again.
+    pub fn journal(&self) -> Result<Vec<(DocId, Causal)>> {
+        self.frontend
+            .journal()
+            .map(|res| res.map(|(doc, _, causal)| (doc, causal)))
+            .collect()
+    }
+
+    /// Clears the local changes that no peer acknowledged yet. They are still sent to peers that
+    /// request an unjoin.
+    pub fn clear_journal(&self) -> Result<()> {
+        self.frontend.clear_journal()
+    }
+
+    /// Enables or disables recording the changes of all documents in a persistent replay log.
+    /// The log can be rebuilt with a [`Replay`] to find the change that made two devices
+    /// diverge.
+    pub fn set_replay_log(&self, enabled: bool) -> Result<()> {
+        self.frontend.set_replay_log(enabled)
+    }
+
+    /// Returns the changes recorded by the replay log in the order they were applied.
+    pub fn replay_log(&self) -> Result<Vec<ReplayEntry>> {
+        self.frontend.replay_log()
+    }
+
+    /// Removes the changes recorded by the replay log.
+    pub fn clear_replay_log(&self) -> Result<()> {
+        self.frontend.clear_replay_log()
     }
 
     /// Returns an iterator of [`DocId`].
@@ -293,12 +935,17 @@ impl Sdk {
         self.frontend.docs_by_schema(schema)
     }
 
-    /// Subscribes to document changes.
+    /// Returns an iterator of all documents with their schema name and version.
+    pub fn all_docs(&self) -> impl Iterator<Item = Result<(DocId, String, u32)>> + '_ {
+        self.frontend.all_docs()
+    }
+
+    /// Subscribes to documents being added or removed.
     pub fn subscribe_docs(&self) -> impl Stream<Item = ()> {
-        self.frontend.subscribe()
+        self.frontend.subscribe_docs()
     }
 
-    /// Creates a new document with an initial [`Schema`].
+    /// Creates a new document with an initial [`Schema`](tlfs_crdt::Schema).
     pub async fn create_doc(&self, schema: &str) -> Result<Doc> {
         let peer_id = self.peer_id();
         let doc = self
@@ -311,35 +958,215 @@ impl Sdk {
         Ok(Doc::new(doc, self.swarm.clone()))
     }
 
-    /// Adds a document with a [`Schema`].
-    pub fn add_doc(&self, id: DocId, schema: &str) -> Result<Doc> {
+    /// Creates a new document with an initial [`Schema`](tlfs_crdt::Schema) and the content and
+    /// acls returned by `init`. The document is only announced after the initial transaction was applied.
+    pub async fn create_doc_with(
+        &self,
+        schema: &str,
+        init: impl FnOnce(&mut Cursor) -> Result<Causal>,
+    ) -> Result<Doc> {
         let peer_id = self.peer_id();
-        let doc = self.frontend.add_doc(id, peer_id, schema)?;
+        let doc = self
+            .frontend
+            .create_doc_with(*peer_id, schema, Keypair::generate(), init)?
+            .await;
         self.swarm
             .unbounded_send(Command::Subscribe(*doc.id()))
             .ok();
         Ok(Doc::new(doc, self.swarm.clone()))
     }
 
-    /// Returns a document handle.
-    pub fn doc(&self, id: DocId) -> Result<Doc> {
-        let doc = self.frontend.doc(id)?;
+    /// Creates a new workspace. A workspace is a document listing member documents, peers
+    /// syncing the workspace automatically subscribe to it's members.
+    pub async fn create_workspace(&self, schema: &str) -> Result<Doc> {
+        self.create_doc(schema).await
+    }
+
+    /// Creates a new document in a workspace. Permissions on the workspace apply to the
+    /// document too.
+    pub async fn create_doc_in(&self, workspace: &DocId, schema: &str) -> Result<Doc> {
+        let ws = self.doc(*workspace)?;
+        let doc = self
+            .create_doc_with(schema, |cursor| cursor.inherit(workspace))
+            .await?;
+        ws.apply(ws.cursor().add_member(doc.id(), schema)?)?;
+        Ok(doc)
+    }
+
+    /// Creates a new ephemeral document. After `ttl` the document stops being replicated and
+    /// it's local state is deleted. Subscribers of [`Sdk::subscribe_expiring`] are notified
+    /// [`EXPIRY_GRACE`] before the document expires.
+    pub async fn create_ephemeral_doc(&self, schema: &str, ttl: Duration) -> Result<Doc> {
+        let peer_id = self.peer_id();
+        let doc = self
+            .frontend
+            .create_doc(*peer_id, schema, Keypair::generate())?
+            .await;
+        self.frontend.set_ttl(doc.id(), ttl)?;
+        self.swarm
+            .unbounded_send(Command::Subscribe(*doc.id()))
+            .ok();
         Ok(Doc::new(doc, self.swarm.clone()))
     }
 
-    /// Removes a document.
-    pub fn remove_doc(&self, id: &DocId) -> Result<()> {
-        self.frontend.remove_doc(id)
+    /// Returns the remaining time to live of an ephemeral document.
+    pub fn ttl(&self, id: &DocId) -> Result<Option<Duration>> {
+        self.frontend.ttl(id)
     }
-}
 
-#[allow(clippy::if_same_then_else)]
-fn init_tracing() {
-    use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
-    // FIXME: replace by tracing feature
-    tracing_log::LogTracer::init().ok();
-    let env = std::env::var(EnvFilter::DEFAULT_ENV)
-        .unwrap_or_else(|_| "tlfs,info,libp2p_swarm".to_owned());
+    /// Subscribes to ephemeral documents that are about to expire.
+    pub fn subscribe_expiring(&self) -> impl Stream<Item = DocId> {
+        let (tx, rx) = mpsc::channel(1);
+        self.swarm
+            .unbounded_send(Command::SubscribeExpiring(tx))
+            .unwrap();
+        rx
+    }
+
+    /// Adds a document with a [`Schema`](tlfs_crdt::Schema).
+    pub fn add_doc(&self, id: DocId, schema: &str) -> Result<Doc> {
+        let peer_id = self.peer_id();
+        let doc = self.frontend.add_doc(id, peer_id, schema)?;
+        self.swarm
+            .unbounded_send(Command::Subscribe(*doc.id()))
+            .ok();
+        Ok(Doc::new(doc, self.swarm.clone()))
+    }
+
+    /// Adds a capability token minted with [`Cursor::mint_token`] and presents it to the
+    /// peers of the document. The document needs to be added with [`Sdk::add_doc`] first.
+    /// Peers accepting the token serve the document, peers that can delegate the permission
+    /// also record it as a policy statement granting it on every replica.
+    pub fn add_token(&self, token: Token) -> Result<()> {
+        self.frontend.add_token(token.clone())?;
+        self.swarm.unbounded_send(Command::PresentToken(token)).ok();
+        Ok(())
+    }
+
+    /// Redeems an invite token created with [`Doc::create_invite_token`], for example scanned
+    /// from a QR code. Adds the document if needed and presents the granted permission to the
+    /// peers of the document.
+    pub fn redeem_invite_token(&self, token: &[u8]) -> Result<Doc> {
+        let invite = InviteToken::from_bytes(token)?;
+        self.frontend.lenses(&invite.hash().into())?;
+        let id = invite.doc()?;
+        let doc = match self.doc(id) {
+            Ok(doc) => doc,
+            Err(_) => self.add_doc(id, invite.schema())?,
+        };
+        self.add_token(invite.token().clone())?;
+        Ok(doc)
+    }
+
+    /// Returns a document handle.
+    pub fn doc(&self, id: DocId) -> Result<Doc> {
+        let doc = self.frontend.doc(id)?;
+        Ok(Doc::new(doc, self.swarm.clone()))
+    }
+
+    /// Returns a document handle viewing the document through another registered schema,
+    /// for example a plugin extending the schema of the document. See
+    /// [`Frontend::doc_with_schema`](tlfs_crdt::Frontend::doc_with_schema).
+    pub fn doc_with_schema(&self, id: DocId, schema: &str) -> Result<Doc> {
+        let doc = self.frontend.doc_with_schema(id, schema)?;
+        Ok(Doc::new(doc, self.swarm.clone()))
+    }
+
+    /// Removes the local state of a document. Use [`Doc::delete_everywhere`] to delete it on
+    /// every peer.
+    pub fn remove_doc(&self, id: &DocId) -> Result<()> {
+        self.frontend.remove_doc(id)?;
+        self.swarm.unbounded_send(Command::Unsubscribe(*id)).ok();
+        Ok(())
+    }
+
+    /// Returns the [`DocState`] of a document.
+    pub fn doc_state(&self, id: &DocId) -> Result<DocState> {
+        self.frontend.doc_state(id)
+    }
+
+    /// Archives a document. Archived documents keep syncing but reject local changes.
+    pub fn archive_doc(&self, id: &DocId) -> Result<()> {
+        self.frontend.archive_doc(id)
+    }
+
+    /// Makes an archived document accept local changes again.
+    pub fn unarchive_doc(&self, id: &DocId) -> Result<()> {
+        self.frontend.unarchive_doc(id)
+    }
+
+    /// Subscribes to documents being added, archived, unarchived or removed.
+    pub fn subscribe_lifecycle(&self) -> impl Stream<Item = (DocId, DocState)> {
+        self.frontend.subscribe_lifecycle()
+    }
+
+    /// Subscribes to documents being upgraded to a newer schema, including the documents
+    /// migrated on startup. Open [`Doc`]s need to be [refreshed](Doc::refresh).
+    pub fn subscribe_upgrades(&self) -> impl Stream<Item = SchemaUpgraded> {
+        self.frontend.subscribe_upgrades()
+    }
+
+    /// Sets the [`Durability`] mode.
+    pub fn set_durability(&self, durability: Durability) {
+        self.frontend.set_durability(durability);
+    }
+
+    /// Writes all pending changes to storage.
+    pub async fn sync(&self) -> Result<()> {
+        self.frontend.sync().await
+    }
+
+    /// Waits until all prior local changes, including their effect on the acl, are
+    /// observable by every [`Doc`] handle.
+    pub async fn barrier(&self) -> Result<()> {
+        self.frontend.barrier()?.await;
+        Ok(())
+    }
+}
+
+/// Notifies subscribers about documents entering the grace period and removes expired
+/// documents. Returns the removed documents.
+fn expire_docs(
+    frontend: &Frontend,
+    expiring: &mut BTreeSet<DocId>,
+    subs: &mut Vec<mpsc::Sender<DocId>>,
+) -> Vec<DocId> {
+    let mut expired = vec![];
+    let ttls = frontend.ttls();
+    // documents whose deletion was cancelled can expire again.
+    expiring.retain(|doc| ttls.iter().any(|(id, _)| id == doc));
+    for (doc, ttl) in ttls {
+        if ttl.is_zero() {
+            tracing::info!("removing expired doc {}", doc);
+            if let Err(err) = frontend.remove_doc(&doc) {
+                tracing::error!("{}", err);
+            }
+            expiring.remove(&doc);
+            expired.push(doc);
+        } else if ttl <= EXPIRY_GRACE && expiring.insert(doc) {
+            subs.retain(|tx| !tx.is_closed());
+            for tx in subs.iter_mut() {
+                tx.try_send(doc).ok();
+            }
+        }
+    }
+    expired
+}
+
+#[cfg(not(feature = "tracing-fmt"))]
+fn init_tracing() {
+    tracing_log::LogTracer::init().ok();
+    log_panics::init();
+}
+
+#[cfg(feature = "tracing-fmt")]
+#[allow(clippy::if_same_then_else)]
+fn init_tracing() {
+    use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+    // FIXME: replace by tracing feature
+    tracing_log::LogTracer::init().ok();
+    let env = std::env::var(EnvFilter::DEFAULT_ENV)
+        .unwrap_or_else(|_| "tlfs,info,libp2p_swarm".to_owned());
     let subscriber = {
         let b = tracing_subscriber::FmtSubscriber::builder()
             .with_span_events(FmtSpan::ACTIVE | FmtSpan::CLOSE)
@@ -393,25 +1220,272 @@ impl Doc {
         self.doc.cursor()
     }
 
+    /// Returns a cursor to the metadata of the document. See
+    /// [`Doc::metadata`](tlfs_crdt::Doc::metadata).
+    pub fn metadata(&self) -> Cursor<'_> {
+        self.doc.metadata()
+    }
+
+    /// Returns a cursor at a path returned from [`Cursor::path_string`].
+    pub fn cursor_at(&self, pointer: &str) -> Result<Cursor<'_>> {
+        self.doc.cursor_at(pointer)
+    }
+
+    /// Returns the name of the document's schema.
+    pub fn schema_name(&self) -> Result<String> {
+        Ok(self.doc.schema()?.as_ref().name().to_string())
+    }
+
+    /// Returns the version of the document's schema, which is the number of lenses applied.
+    pub fn schema_version(&self) -> Result<u32> {
+        Ok(self.doc.schema()?.as_ref().version())
+    }
+
+    /// Returns the hash of the lenses of the document's schema.
+    pub fn schema_hash(&self) -> Result<[u8; 32]> {
+        Ok(self.doc.schema()?.as_ref().hash().into())
+    }
+
+    /// Returns the document as json. Tables and structs become objects, arrays and sets
+    /// become arrays and registers with concurrent values become an array of the values.
+    /// Non finite f64s are written as the strings `NaN`, `Infinity` and `-Infinity`, which
+    /// [`Doc::import_json`] accepts.
+    pub fn export_json(&self) -> Result<serde_json::Value> {
+        json::export(&self.cursor())
+    }
+
+    /// Writes a json value matching the schema to the document. Existing entries are kept,
+    /// array elements are appended.
+    pub fn import_json(&self, value: &serde_json::Value) -> Result<()> {
+        json::import(self, value)
+    }
+
+    /// Returns a page of raw paths starting with `prefix` relative to the document root.
+    pub fn scan(&self, prefix: Path, token: Option<&[u8]>, limit: usize) -> ScanPage {
+        self.doc.scan(prefix, token, limit)
+    }
+
+    /// Returns the members of the workspace and their schema.
+    pub fn members(&self) -> impl Iterator<Item = (DocId, String)> {
+        self.doc.members()
+    }
+
+    /// Returns a snapshot of the document. Diffing two snapshots yields the paths added and
+    /// removed in between.
+    pub fn snapshot(&self) -> Snapshot {
+        self.doc.snapshot()
+    }
+
+    /// Returns the tags of a transaction identified by [`Causal::id`].
+    pub fn tags(&self, tx: &Dot) -> impl Iterator<Item = String> {
+        self.doc.tags(tx)
+    }
+
+    /// Returns the [`CausalContext`] of the document. Keep it to later roll back the changes
+    /// of a compromised key with [`Doc::rollback_author`].
+    pub fn ctx(&self) -> Result<CausalContext> {
+        self.doc.ctx()
+    }
+
+    /// Returns the [`DocState`] of the document. Only active documents accept transactions.
+    pub fn state(&self) -> Result<DocState> {
+        self.doc.state()
+    }
+
+    /// Rebinds the document to it's current schema after a [`SchemaUpgraded`].
+    pub fn refresh(&mut self) -> Result<()> {
+        self.doc.refresh()
+    }
+
+    /// Returns a transaction tombstoning every path authored by `peer` after `after`.
+    /// Requires [`Permission::Own`] on the document.
+    pub fn rollback_author(&self, peer: &PeerId, after: &CausalContext) -> Result<Causal> {
+        self.doc.rollback_author(peer, after)
+    }
+
+    /// Deletes the document on every peer once `grace` elapsed. Subscribers of
+    /// [`Sdk::subscribe_expiring`] are notified [`EXPIRY_GRACE`] before the document is
+    /// deleted. See [`Doc::delete_everywhere`](tlfs_crdt::Doc::delete_everywhere).
+    pub fn delete_everywhere(&self, grace: Duration) -> Result<()> {
+        self.apply(self.doc.delete_everywhere(grace)?)
+    }
+
+    /// Cancels a pending [`Doc::delete_everywhere`].
+    pub fn cancel_delete(&self) -> Result<()> {
+        self.apply(self.doc.cancel_delete()?)
+    }
+
+    /// Returns the time in seconds since the unix epoch at which the document is deleted on
+    /// every peer.
+    pub fn deleted_at(&self) -> Result<Option<u64>> {
+        self.doc.deleted_at()
+    }
+
     /// Applies a transaction to the document.
     pub fn apply(&self, causal: Causal) -> Result<()> {
-        self.doc.apply(&causal)?;
+        // journaled so that the change is sent after a restart if the app is killed first.
+        let entry = self.doc.apply_journaled(&causal)?;
         self.swarm
-            .unbounded_send(Command::Broadcast(*self.id(), causal))
+            .unbounded_send(Command::Broadcast(*self.id(), causal, entry))
             .ok();
         Ok(())
     }
 
+    /// Applies several transactions to the document as a single transaction, which is
+    /// journaled and broadcast once.
+    pub fn apply_batch(&self, causals: Vec<Causal>) -> Result<()> {
+        let mut batch = Causal::default();
+        for causal in &causals {
+            batch.join(causal);
+        }
+        self.apply(batch)
+    }
+
+    /// Reverts the last local change and broadcasts the compensating change. Returns `false`
+    /// if there is nothing to undo. See [`Doc::undo`](tlfs_crdt::Doc::undo).
+    pub fn undo(&self) -> Result<bool> {
+        let causal = self.doc.undo()?;
+        self.broadcast(causal)
+    }
+
+    /// Reverts the last [`Doc::undo`] and broadcasts the compensating change. Returns `false`
+    /// if there is nothing to redo.
+    pub fn redo(&self) -> Result<bool> {
+        let causal = self.doc.redo()?;
+        self.broadcast(causal)
+    }
+
+    fn broadcast(&self, causal: Option<Causal>) -> Result<bool> {
+        if let Some(causal) = causal {
+            let entry = self.doc.journal(&causal)?;
+            self.swarm
+                .unbounded_send(Command::Broadcast(*self.id(), causal, entry))
+                .ok();
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Returns the sync status of the document with each peer it was exchanged with or that is
+    /// subscribed to it.
+    pub async fn sync_status(&self) -> Result<Vec<PeerSyncStatus>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::SyncStatus(*self.id(), tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Returns the peers known to have applied the local change with transaction id `id`,
+    /// see [`Causal::id`]. Only changes broadcast recently are tracked, for others an error is
+    /// returned.
+    pub async fn delivered(&self, id: Dot) -> Result<BTreeSet<PeerId>> {
+        let (tx, rx) = oneshot::channel();
+        self.swarm
+            .unbounded_send(Command::Delivered(id, tx))
+            .unwrap();
+        rx.await?
+    }
+
+    /// Waits until `quorum` peers applied the local change with transaction id `id`, for
+    /// example to show that a change was saved to other devices. Peers report the changes they
+    /// have by acknowledging broadcasts and after unjoins, so changes made before a peer
+    /// subscribed count as well.
+    pub async fn wait_delivered(&self, id: Dot, quorum: usize) -> Result<()> {
+        let (tx, mut rx) = mpsc::channel(EVENT_BUFFER);
+        self.swarm
+            .unbounded_send(Command::SubscribeEvents(tx))
+            .unwrap();
+        while self.delivered(id).await?.len() < quorum {
+            loop {
+                match rx.next().await {
+                    Some(SdkEvent::Delivered(doc, _)) | Some(SdkEvent::SyncStatusChanged(doc))
+                        if doc == *self.id() =>
+                    {
+                        break
+                    }
+                    Some(_) => {}
+                    None => anyhow::bail!("event stream closed"),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Subscribes to changes of the sync status of the document.
+    pub fn subscribe_sync_status(&self) -> impl Stream<Item = ()> {
+        let (tx, rx) = mpsc::channel(EVENT_BUFFER);
+        self.swarm
+            .unbounded_send(Command::SubscribeEvents(tx))
+            .unwrap();
+        let id = *self.id();
+        rx.filter_map(move |ev| {
+            future::ready(match ev {
+                SdkEvent::SyncStatusChanged(doc) if doc == id => Some(()),
+                _ => None,
+            })
+        })
+    }
+
+    /// Subscribes to acl changes of the document. See
+    /// [`Doc::subscribe_acl`](tlfs_crdt::Doc::subscribe_acl).
+    pub fn subscribe_acl(&self) -> impl Stream<Item = Batch> {
+        self.doc.subscribe_acl()
+    }
+
+    /// Waits until [`PeerId`] has [`Permission`] on the document.
+    pub async fn wait_until_can(&self, peer: PeerId, perm: Permission) -> Result<()> {
+        self.doc.wait_until_can(peer, perm).await
+    }
+
+    /// Creates an invite token that can be shared out-of-band and redeemed with
+    /// [`Sdk::redeem_invite_token`]. Anyone redeeming it is granted `perm` on the document until
+    /// `expiry` (seconds since the unix epoch) unless it is revoked earlier.
+    pub fn create_invite_token(&self, perm: Permission, expiry: u64) -> Result<Vec<u8>> {
+        Ok(self.doc.create_invite_token(perm, expiry)?.to_bytes())
+    }
+
+    /// Revokes an invite token created with [`Doc::create_invite_token`]. See
+    /// [`Doc::revoke_token`](tlfs_crdt::Doc::revoke_token).
+    pub fn revoke_invite_token(&self, token: &[u8]) -> Result<()> {
+        let invite = InviteToken::from_bytes(token)?;
+        self.apply(self.doc.revoke_token(invite.token())?)
+    }
+
     /// Invite peer. Make sure the peer has at least read permission before
     /// doing this.
     pub fn invite(&self, peer: PeerId) -> Result<()> {
-        let schema = self.doc.schema()?;
+        self.send_invite(peer, None)
+    }
+
+    /// Invite peer with a message that is shown to the invitee.
+    pub fn invite_with_message(&self, peer: PeerId, message: impl Into<String>) -> Result<()> {
+        self.send_invite(peer, Some(message.into()))
+    }
+
+    fn send_invite(&self, peer: PeerId, message: Option<String>) -> Result<()> {
+        let cursor = self.cursor();
+        let mut permission = None;
+        for perm in [
+            Permission::Own,
+            Permission::Control,
+            Permission::Write,
+            Permission::Read,
+        ] {
+            if cursor.can(&peer, perm)? {
+                permission = Some(perm);
+                break;
+            }
+        }
+        let invite = InviteRequest {
+            doc: *self.id(),
+            schema: self.schema_name()?,
+            message,
+            permission,
+        };
         self.swarm
-            .unbounded_send(Command::Invite(
-                peer,
-                *self.id(),
-                schema.as_ref().name.to_string(),
-            ))
+            .unbounded_send(Command::Invite(peer, invite))
             .unwrap();
         Ok(())
     }
@@ -421,17 +1495,46 @@ enum Command {
     AddAddress(PeerId, Multiaddr),
     AddExternalAddress(Multiaddr, AddressScore),
     RemoveAddress(PeerId, Multiaddr),
+    Disconnect(PeerId),
+    BlockPeer(PeerId),
+    UnblockPeer(PeerId),
     Addresses(oneshot::Sender<Vec<Multiaddr>>),
-    SubscribeAddresses(mpsc::Sender<()>),
+    ExternalAddresses(oneshot::Sender<Vec<(Multiaddr, AddressScore)>>),
+    SubscribeEvents(mpsc::Sender<SdkEvent>),
     LocalPeers(oneshot::Sender<BTreeSet<PeerId>>),
-    SubscribeLocalPeers(mpsc::Sender<()>),
+    Peers(oneshot::Sender<BTreeMap<PeerId, PeerEntry>>),
     ConnectedPeers(oneshot::Sender<Vec<PeerId>>),
-    SubscribeConnectedPeers(mpsc::Sender<()>),
+    PeerInfo(PeerId, oneshot::Sender<Option<PeerInfo>>),
+    RejectedTraffic(oneshot::Sender<BTreeMap<PeerId, RejectedTraffic>>),
+    #[cfg(feature = "metrics")]
+    MetricsSnapshot(oneshot::Sender<MetricsSnapshot>),
+    SyncStatus(DocId, oneshot::Sender<Result<Vec<PeerSyncStatus>>>),
+    Delivered(Dot, oneshot::Sender<Result<BTreeSet<PeerId>>>),
+    SetUnjoinRedundancy(usize),
+    SetDocPriority(DocId, Priority),
+    SetDocShards(DocId, Option<BTreeSet<String>>),
     Subscribe(DocId),
-    Broadcast(DocId, Causal),
-    Invite(PeerId, DocId, String),
+    Unsubscribe(DocId),
+    Broadcast(DocId, Causal, Dot),
+    Invite(PeerId, InviteRequest),
     Invites(oneshot::Sender<Vec<Invite>>),
-    SubscribeInvites(mpsc::Sender<()>),
+    PresentToken(Token),
+    SubscribeExpiring(mpsc::Sender<DocId>),
+    SubscribeConnections(mpsc::Sender<ConnectionEvent>),
+    RemoteChanges(oneshot::Sender<Vec<DocId>>),
+    SyncOnce(oneshot::Sender<()>),
+    Denied(oneshot::Sender<Vec<Denied>>),
+    ExportDelta(
+        PeerId,
+        DocId,
+        CausalContext,
+        oneshot::Sender<Result<(Hash, Causal)>>,
+    ),
+    ImportDelta(PeerId, DocId, Hash, Causal, oneshot::Sender<Result<()>>),
+    ExportSnapshot(DocId, oneshot::Sender<Result<Vec<u8>>>),
+    ImportSnapshot(Vec<u8>, oneshot::Sender<Result<DocId>>),
+    AddPackage(Vec<u8>, oneshot::Sender<Result<Hash>>),
+    FetchAttachment(DocId, Hash, oneshot::Sender<Result<Vec<u8>>>),
 }
 
 #[cfg(test)]
@@ -440,7 +1543,7 @@ mod tests {
     use super::*;
     use futures::StreamExt;
     use std::pin::Pin;
-    use std::time::Duration;
+    use tlfs_crdt::{Kind, Lens, Lenses, Package, Ref};
 
     #[async_std::test]
     async fn test_api() -> Result<()> {
@@ -472,12 +1575,15 @@ mod tests {
         let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
         let doc = sdk.create_doc("todoapp").await?;
 
-        async_std::task::sleep(Duration::from_millis(100)).await;
+        doc.wait_until_can(*sdk.peer_id(), Permission::Write)
+            .await?;
         assert!(doc.cursor().can(sdk.peer_id(), Permission::Write)?);
 
         let docs = sdk.docs("todoapp".into()).collect::<Result<Vec<_>>>()?;
         assert_eq!(docs.len(), 1);
         assert_eq!(docs[0], *doc.id());
+        assert_eq!(doc.schema_name()?, "todoapp");
+        assert_eq!(doc.schema_version()?, 8);
 
         let title = "something that needs to be done";
         let op = doc
@@ -518,6 +1624,8 @@ mod tests {
         let invite = &sdk2.invites().await[0];
         assert_eq!(&invite.doc, doc.id());
         assert_eq!(&invite.schema, "todoapp");
+        assert_eq!(invite.inviter, *sdk.peer_id());
+        assert_eq!(invite.message, None);
         tracing::info!("received invite");
         let doc2 = sdk2.add_doc(invite.doc, &invite.schema)?;
         let mut sub = doc2.cursor().field("tasks")?.subscribe();
@@ -545,4 +1653,537 @@ mod tests {
 
         Ok(())
     }
+
+    #[async_std::test]
+    async fn test_json() -> Result<()> {
+        let lenses = vec![
+            Lens::Make(Kind::Struct),
+            Lens::AddProperty("todos".into()),
+            Lens::Make(Kind::Table(PrimitiveKind::U64)).lens_in("todos"),
+            Lens::Make(Kind::Struct).lens_map_value().lens_in("todos"),
+            Lens::AddProperty("title".into())
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::Make(Kind::Reg(PrimitiveKind::Str))
+                .lens_in("title")
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::AddProperty("complete".into())
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::Make(Kind::Flag)
+                .lens_in("complete")
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::AddProperty("estimate".into())
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::Make(Kind::Reg(PrimitiveKind::F64))
+                .lens_in("estimate")
+                .lens_map_value()
+                .lens_in("todos"),
+            Lens::AddProperty("tags".into()),
+            Lens::Make(Kind::Array).lens_in("tags"),
+            Lens::LensMap(Box::new(Lens::Make(Kind::Reg(PrimitiveKind::Str)))).lens_in("tags"),
+        ];
+        let packages = vec![Package::new("todoapp".into(), 13, &Lenses::new(lenses))];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("todoapp").await?;
+        let value = serde_json::json!({
+            "todos": {
+                "0": { "title": "first", "complete": true, "estimate": 0.5 },
+                "1": { "title": "second", "complete": false, "estimate": "NaN" },
+                "2": { "title": "third", "complete": false, "estimate": "-Infinity" },
+            },
+            "tags": ["b", "a", "c"],
+        });
+        doc.import_json(&value)?;
+        assert_eq!(doc.export_json()?, value);
+
+        assert!(doc
+            .import_json(&serde_json::json!({ "tags": [1] }))
+            .is_err());
+        assert!(doc
+            .import_json(&serde_json::json!({ "todos": { "x": {} } }))
+            .is_err());
+        // a failed import doesn't write the values before the error.
+        assert!(doc
+            .import_json(&serde_json::json!({ "tags": ["d", 1] }))
+            .is_err());
+        assert_eq!(doc.export_json()?, value);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_reconnect() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Write)?,
+        )?;
+        let doc2 = sdk2.add_doc(*doc.id(), "counter")?;
+
+        let count = |doc: &Doc| -> Result<Option<u64>> {
+            doc.cursor().field("count")?.u64s()?.next().transpose()
+        };
+        let mut sub = doc2.cursor().field("count")?.subscribe();
+        doc.apply(doc.cursor().field("count")?.assign_u64(1)?)?;
+        while count(&doc2)? != Some(1) {
+            Pin::new(&mut sub).next().await;
+        }
+
+        let mut connected = sdk.subscribe_connected_peers();
+        sdk.disconnect(*sdk2.peer_id());
+        while !sdk.connected_peers().await.is_empty() {
+            connected.next().await;
+        }
+        // not broadcast to sdk2, it has to be unjoined after reconnecting
+        doc.apply(doc.cursor().field("count")?.assign_u64(2)?)?;
+        while count(&doc2)? != Some(2) {
+            Pin::new(&mut sub).next().await;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_events() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let mut events = sdk2.events();
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        let doc2 = sdk2.add_doc(*doc.id(), "counter")?;
+
+        let (mut acl_changed, mut synced) = (false, false);
+        while !acl_changed || !synced {
+            match events.next().await {
+                Some(SdkEvent::AclChanged(id)) if id == *doc2.id() => acl_changed = true,
+                Some(SdkEvent::SyncCompleted(id)) if id == *doc2.id() => synced = true,
+                Some(_) => {}
+                None => anyhow::bail!("event stream closed"),
+            }
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_sync_status() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        let mut sub = doc.subscribe_sync_status();
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        assert!(doc.sync_status().await?.is_empty());
+        sdk2.add_doc(*doc.id(), "counter")?;
+        loop {
+            let status = doc.sync_status().await?;
+            if let Some(status) = status.iter().find(|s| s.peer == *sdk2.peer_id()) {
+                if status.ahead == Some(0) && status.delivering {
+                    assert!(status.last_sync.is_some());
+                    break;
+                }
+            }
+            sub.next().await;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_wait_delivered() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        let mut sub = doc.subscribe_sync_status();
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        // made before the peer subscribed, so it is delivered with an unjoin.
+        let causal = doc.cursor().field("count")?.assign_u64(1)?;
+        let early = causal.id();
+        doc.apply(causal)?;
+        sdk2.add_doc(*doc.id(), "counter")?;
+        doc.wait_delivered(early, 1).await?;
+        while !doc
+            .sync_status()
+            .await?
+            .iter()
+            .any(|s| s.peer == *sdk2.peer_id() && s.delivering)
+        {
+            sub.next().await;
+        }
+        let causal = doc.cursor().field("count")?.assign_u64(42)?;
+        let id = causal.id();
+        doc.apply(causal)?;
+        doc.wait_delivered(id, 1).await?;
+        assert!(doc.delivered(id).await?.contains(sdk2.peer_id()));
+        assert!(doc.delivered(Dot::new([0; 32])).await.is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_export_import_delta() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        doc.apply(doc.cursor().field("count")?.assign_u64(5)?)?;
+        let doc2 = sdk2.add_doc(*doc.id(), "counter")?;
+
+        let (schema, causal) = sdk
+            .export_delta(sdk2.peer_id(), doc.id(), doc2.ctx()?)
+            .await?;
+        sdk2.import_delta(sdk.peer_id(), doc.id(), schema, causal)
+            .await?;
+        let count = doc2.cursor().field("count")?.u64s()?.next().transpose()?;
+        assert_eq!(count, Some(5));
+
+        // sdk2 can only read, so the changes it sends are dropped.
+        let (schema, causal) = sdk2
+            .export_delta(sdk.peer_id(), doc.id(), doc.ctx()?)
+            .await?;
+        assert!(causal.is_empty());
+        let op = doc.cursor().field("count")?.assign_u64(6)?;
+        sdk.import_delta(sdk2.peer_id(), doc.id(), schema, op)
+            .await?;
+        let denied = sdk.denied().await;
+        assert_eq!(denied.len(), 1);
+        assert_eq!(denied[0].peer, *sdk2.peer_id());
+        let count = doc.cursor().field("count")?.u64s()?.next().transpose()?;
+        assert_eq!(count, Some(5));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_block_peer() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let mut events = sdk2.events();
+        let doc = sdk.create_doc("counter").await?;
+        let other = sdk.create_doc("counter").await?;
+        doc.apply(
+            doc.cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        other.apply(
+            other
+                .cursor()
+                .say_can(Some(*sdk2.peer_id()), Permission::Read)?,
+        )?;
+        let doc2 = sdk2.add_doc(*doc.id(), "counter")?;
+
+        // a change to another doc sent as a change to `doc`.
+        let (schema, causal) = sdk
+            .export_delta(sdk2.peer_id(), other.id(), doc2.ctx()?)
+            .await?;
+        assert!(sdk2
+            .import_delta(sdk.peer_id(), doc.id(), schema, causal)
+            .await
+            .is_err());
+        loop {
+            match events.next().await {
+                Some(SdkEvent::ChangeRejected(peer, id, reason)) => {
+                    assert_eq!(peer, *sdk.peer_id());
+                    assert_eq!(id, *doc.id());
+                    assert_eq!(reason, InvalidChange::ForeignPaths);
+                    break;
+                }
+                Some(_) => {}
+                None => anyhow::bail!("event stream closed"),
+            }
+        }
+
+        let mut connected = sdk2.subscribe_connected_peers();
+        while sdk2.connected_peers().await.is_empty() {
+            connected.next().await;
+        }
+        sdk2.block_peer(*sdk.peer_id());
+        while !sdk2.connected_peers().await.is_empty() {
+            connected.next().await;
+        }
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_export_import_snapshot() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(doc.cursor().field("count")?.assign_u64(5)?)?;
+        let snapshot = sdk.export_snapshot(doc.id()).await?;
+        let doc2 = sdk2.import_snapshot(snapshot).await?;
+        assert_eq!(doc2.id(), doc.id());
+        let count = doc2.cursor().field("count")?.u64s()?.next().transpose()?;
+        assert_eq!(count, Some(5));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_undo() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(doc.cursor().field("count")?.assign_u64(5)?)?;
+        doc.apply(doc.cursor().field("count")?.assign_u64(6)?)?;
+        assert!(doc.undo()?);
+        let count = doc.cursor().field("count")?.u64s()?.next().transpose()?;
+        assert_eq!(count, Some(5));
+        assert!(doc.redo()?);
+        assert!(!doc.redo()?);
+        let count = doc.cursor().field("count")?.u64s()?.next().transpose()?;
+        assert_eq!(count, Some(6));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_resolve_with() -> Result<()> {
+        use crate::prelude::{CursorExt, Value};
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        let mut cursor = doc.cursor();
+        cursor.field("count")?;
+        assert!(cursor.resolve_with(|_| unreachable!())?.is_none());
+        let a = cursor.assign_u64(1)?;
+        let b = cursor.assign_u64(2)?;
+        doc.apply(a)?;
+        doc.apply(b)?;
+        assert_eq!(cursor.conflicts()?.len(), 2);
+        let causal = cursor.resolve_with(|values| {
+            let max = values.iter().filter_map(|value| match value {
+                Value::U64(value) => Some(*value),
+                _ => None,
+            });
+            Value::U64(max.max().unwrap())
+        })?;
+        doc.apply(causal.unwrap())?;
+        assert!(cursor.conflicts()?.is_empty());
+        assert_eq!(cursor.values()?, vec![Value::U64(2)]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_add_package() -> Result<()> {
+        let packages: Vec<Package> = vec![];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        assert!(sdk.create_doc("counter").await.is_err());
+        let package = Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        );
+        let hash = sdk
+            .add_package(Ref::archive(&package).as_bytes().to_vec())
+            .await?;
+        assert_eq!(sdk.packages(), vec![("counter".to_string(), 3, hash)]);
+        let doc = sdk.create_doc("counter").await?;
+        doc.apply(doc.cursor().field("count")?.assign_u64(5)?)?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_invite_token() -> Result<()> {
+        let packages = vec![Package::new(
+            "counter".into(),
+            3,
+            &Lenses::new(vec![
+                Lens::Make(Kind::Struct),
+                Lens::AddProperty("count".into()),
+                Lens::Make(Kind::Reg(PrimitiveKind::U64)).lens_in("count"),
+            ]),
+        )];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let doc = sdk.create_doc("counter").await?;
+        let expiry = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs()
+            + 60;
+        assert!(doc
+            .create_invite_token(Permission::Write, u64::MAX)
+            .is_err());
+        let token = doc.create_invite_token(Permission::Write, expiry)?;
+        assert!(sdk2.redeem_invite_token(&token[1..]).is_err());
+        let doc2 = sdk2.redeem_invite_token(&token)?;
+        assert_eq!(doc2.id(), doc.id());
+        assert_eq!(doc2.schema_name()?, "counter");
+        // the token only grants write access once a peer of the document redeemed it.
+        assert!(!doc2.cursor().can(sdk2.peer_id(), Permission::Write)?);
+
+        doc.revoke_invite_token(&token)?;
+        assert!(sdk
+            .add_token(InviteToken::from_bytes(&token)?.token().clone())
+            .is_err());
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_table() -> Result<()> {
+        let packages: Vec<Package> = vec![];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let peer = Keypair::generate().peer_id();
+        let addr: Multiaddr = "/memory/42".parse()?;
+        sdk.add_address(peer, addr.clone());
+        let peers = sdk.peers().await;
+        assert_eq!(
+            peers[&peer].addresses_from(PeerSource::Manual).next(),
+            Some(&addr)
+        );
+        assert!(sdk.local_peers().await.contains(&peer));
+        sdk.remove_address(peer, addr);
+        assert!(!sdk.peers().await.contains_key(&peer));
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_config_without_webrtc() -> Result<()> {
+        let packages: Vec<Package> = vec![];
+        let config = SdkConfig {
+            webrtc: false,
+            ..Default::default()
+        };
+        Sdk::memory_with_config(Ref::archive(&packages).as_bytes(), config).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_peer_info() -> Result<()> {
+        let packages: Vec<Package> = vec![];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let mut changed = sdk.subscribe_peer_info();
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let peer2 = *sdk2.peer_id();
+        assert!(sdk.peer_info(Keypair::generate().peer_id()).await.is_none());
+        while !sdk
+            .peer_info(peer2)
+            .await
+            .map(|info| info.is_reachable())
+            .unwrap_or_default()
+        {
+            changed.next().await;
+        }
+        let info = sdk.peer_info(peer2).await.unwrap();
+        assert!(info.last_seen.is_some());
+        assert_eq!(info.failures, 0);
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_connection_events() -> Result<()> {
+        let packages: Vec<Package> = vec![];
+        let sdk = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let mut events = sdk.connection_events();
+        let sdk2 = Sdk::memory(Ref::archive(&packages).as_bytes()).await?;
+        let peer2 = *sdk2.peer_id();
+        loop {
+            match events.next().await {
+                Some(ConnectionEvent::PeerConnected { peer, .. }) if peer == peer2 => break,
+                Some(_) => {}
+                None => anyhow::bail!("event stream closed"),
+            }
+        }
+        sdk.disconnect(peer2);
+        loop {
+            match events.next().await {
+                Some(ConnectionEvent::PeerDisconnected { peer, .. }) if peer == peer2 => break,
+                Some(_) => {}
+                None => anyhow::bail!("event stream closed"),
+            }
+        }
+        Ok(())
+    }
 }
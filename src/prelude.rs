@@ -0,0 +1,177 @@
+//! A curated subset of the api that is expected to remain stable.
+//!
+//! Unlike the crate root the prelude doesn't expose archived types, so no rkyv lifetimes leak
+//! into application code. Use [`CursorExt`] to inspect schemas and read values.
+pub use crate::{
+    Doc, Invite, PeerEntry, PeerInfo, PeerSource, Priority, Sdk, SdkConfig, SdkEvent, StoragePath,
+};
+pub use libp2p::Multiaddr;
+pub use tlfs_crdt::{
+    Actor, Causal, Cursor, DocId, DocState, Dot, Durability, InvalidChange, Keypair, Merge, PeerId,
+    Permission, PrimitiveKind, ScanPage, SchemaUpgraded, Snapshot, SnapshotDiff, Token,
+};
+
+use anyhow::{anyhow, Result};
+use tlfs_crdt::ArchivedSchema;
+
+/// Owned description of the schema a [`Cursor`] points to.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SchemaKindView {
+    /// Empty schema.
+    Null,
+    /// EWFlag.
+    Flag,
+    /// MVReg with values of [`PrimitiveKind`].
+    Reg(PrimitiveKind),
+    /// Table with keys of [`PrimitiveKind`].
+    Table(PrimitiveKind),
+    /// ORArray.
+    Array,
+    /// Struct with the names of it's fields.
+    Struct(Vec<String>),
+    /// ORSet with elements of [`PrimitiveKind`].
+    Set(PrimitiveKind),
+    /// Schema-less value.
+    Dynamic,
+    /// PN-Counter.
+    Counter,
+    /// Collaborative text.
+    Text,
+}
+
+impl From<&ArchivedSchema> for SchemaKindView {
+    fn from(schema: &ArchivedSchema) -> Self {
+        match schema {
+            ArchivedSchema::Null => Self::Null,
+            ArchivedSchema::Flag => Self::Flag,
+            ArchivedSchema::Reg(kind)
+            | ArchivedSchema::MergeReg(kind, _)
+            | ArchivedSchema::CheckedReg(kind, _)
+            | ArchivedSchema::LwwReg(kind)
+            | ArchivedSchema::Encrypted(kind) => Self::Reg(*kind),
+            ArchivedSchema::Table(kind, _) => Self::Table(*kind),
+            ArchivedSchema::Array(_) => Self::Array,
+            ArchivedSchema::Struct(fields) => {
+                Self::Struct(fields.keys().map(|field| field.to_string()).collect())
+            }
+            ArchivedSchema::Set(kind) => Self::Set(*kind),
+            ArchivedSchema::Dynamic => Self::Dynamic,
+            ArchivedSchema::Counter => Self::Counter,
+            ArchivedSchema::Text => Self::Text,
+            ArchivedSchema::Local(schema) => Self::from(&**schema),
+        }
+    }
+}
+
+/// A primitive value.
+#[derive(Clone, Debug)]
+pub enum Value {
+    /// A [`bool`].
+    Bool(bool),
+    /// A [`u64`].
+    U64(u64),
+    /// An [`i64`].
+    I64(i64),
+    /// A [`String`].
+    Str(String),
+    /// A [`f64`]. Values are equal if their bits are equal.
+    F64(f64),
+    /// Small binary blob, see [`PrimitiveKind::Bytes`].
+    Bytes(Vec<u8>),
+}
+
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Bool(a), Self::Bool(b)) => a == b,
+            (Self::U64(a), Self::U64(b)) => a == b,
+            (Self::I64(a), Self::I64(b)) => a == b,
+            (Self::Str(a), Self::Str(b)) => a == b,
+            (Self::F64(a), Self::F64(b)) => a.to_bits() == b.to_bits(),
+            (Self::Bytes(a), Self::Bytes(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Value {}
+
+/// Stable accessors for [`Cursor`].
+pub trait CursorExt {
+    /// Returns the kind of schema the cursor points to.
+    fn kind(&self) -> SchemaKindView;
+
+    /// Returns the concurrently assigned values of the register the cursor points to.
+    fn values(&self) -> Result<Vec<Value>>;
+
+    /// Returns the value of the register resolved by it's [`Merge`] strategy.
+    fn merged(&self) -> Result<Option<Value>>;
+
+    /// Returns the concurrently assigned values of the register if there is more than one.
+    fn conflicts(&self) -> Result<Vec<Value>>;
+
+    /// Resolves the conflicting values of the register to the value chosen by `f`. The
+    /// returned [`Causal`] tombstones the conflicting values and assigns the chosen one. Returns
+    /// `None` if there is no conflict.
+    fn resolve_with(&self, f: impl FnOnce(&[Value]) -> Value) -> Result<Option<Causal>>;
+}
+
+impl CursorExt for Cursor<'_> {
+    fn kind(&self) -> SchemaKindView {
+        self.schema().into()
+    }
+
+    fn values(&self) -> Result<Vec<Value>> {
+        match reg_kind(self)? {
+            PrimitiveKind::Bool => self.bools()?.map(|v| Ok(Value::Bool(v?))).collect(),
+            PrimitiveKind::U64 => self.u64s()?.map(|v| Ok(Value::U64(v?))).collect(),
+            PrimitiveKind::I64 => self.i64s()?.map(|v| Ok(Value::I64(v?))).collect(),
+            PrimitiveKind::Str => self.strs()?.map(|v| Ok(Value::Str(v?))).collect(),
+            PrimitiveKind::F64 => self.f64s()?.map(|v| Ok(Value::F64(v?))).collect(),
+            PrimitiveKind::Bytes => self.bytes()?.map(|v| Ok(Value::Bytes(v?))).collect(),
+        }
+    }
+
+    fn merged(&self) -> Result<Option<Value>> {
+        Ok(match reg_kind(self)? {
+            PrimitiveKind::Bool => self.merged_bool()?.map(Value::Bool),
+            PrimitiveKind::U64 => self.merged_u64()?.map(Value::U64),
+            PrimitiveKind::I64 => self.merged_i64()?.map(Value::I64),
+            PrimitiveKind::Str => self.merged_str()?.map(Value::Str),
+            PrimitiveKind::F64 => self.merged_f64()?.map(Value::F64),
+            PrimitiveKind::Bytes => self.merged_bytes()?.map(Value::Bytes),
+        })
+    }
+
+    fn conflicts(&self) -> Result<Vec<Value>> {
+        let values = self.values()?;
+        Ok(if values.len() > 1 { values } else { vec![] })
+    }
+
+    fn resolve_with(&self, f: impl FnOnce(&[Value]) -> Value) -> Result<Option<Causal>> {
+        let conflicts = self.conflicts()?;
+        if conflicts.is_empty() {
+            return Ok(None);
+        }
+        let causal = match f(&conflicts) {
+            Value::Bool(value) => self.assign_bool(value)?,
+            Value::U64(value) => self.assign_u64(value)?,
+            Value::I64(value) => self.assign_i64(value)?,
+            Value::Str(value) => self.assign_str(&value)?,
+            Value::F64(value) => self.assign_f64(value)?,
+            Value::Bytes(value) => self.assign_bytes(&value)?,
+        };
+        Ok(Some(causal))
+    }
+}
+
+fn reg_kind(cursor: &Cursor<'_>) -> Result<PrimitiveKind> {
+    match cursor.schema() {
+        ArchivedSchema::Reg(kind)
+        | ArchivedSchema::MergeReg(kind, _)
+        | ArchivedSchema::CheckedReg(kind, _)
+        | ArchivedSchema::LwwReg(kind)
+        | ArchivedSchema::Encrypted(kind) => Ok(*kind),
+        _ => Err(anyhow!("not a Reg<_>")),
+    }
+}
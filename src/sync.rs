@@ -3,17 +3,19 @@ use async_trait::async_trait;
 use bytecheck::CheckBytes;
 use fnv::FnvHashMap;
 use futures::{
-    channel::mpsc,
+    channel::{mpsc, oneshot},
     io::{AsyncRead, AsyncWrite},
     prelude::*,
 };
-#[cfg(not(target_family = "wasm"))]
-use libp2p::mdns;
+use instant::Instant;
+#[cfg(all(feature = "mdns", not(target_family = "wasm")))]
+use libp2p::{mdns, swarm::toggle::Toggle};
 use libp2p::{
+    multiaddr::Protocol,
     ping,
     request_response::{
         self, ProtocolName, ProtocolSupport, RequestId, RequestResponse, RequestResponseCodec,
-        RequestResponseConfig,
+        RequestResponseConfig, ResponseChannel,
     },
     swarm::{
         dial_opts::{DialOpts, PeerCondition},
@@ -24,14 +26,18 @@ use libp2p::{
 use libp2p_broadcast::{Broadcast, BroadcastConfig, BroadcastEvent, Topic};
 use rkyv::{Archive, Deserialize, Serialize};
 use std::{
-    collections::{BTreeSet, VecDeque},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     convert::TryInto,
     io,
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
-use tlfs_crdt::{Backend, Causal, CausalContext, DocId, Hash, Keypair, PeerId, Ref};
+use tlfs_crdt::{
+    Backend, Causal, CausalContext, CompressedCausal, Denied, DocId, Dot, Hash, InvalidChange,
+    Keypair, Path, PeerId, Permission, Ref, Token,
+};
 
 macro_rules! unwrap {
     ($r:expr) => {
@@ -50,7 +56,7 @@ pub struct SyncProtocol;
 
 impl ProtocolName for SyncProtocol {
     fn protocol_name(&self) -> &[u8] {
-        "/tlfs/sync/1.0.0".as_bytes()
+        "/tlfs/sync/1.1.0".as_bytes()
     }
 }
 
@@ -58,9 +64,18 @@ impl ProtocolName for SyncProtocol {
 #[archive_attr(derive(Debug, CheckBytes))]
 #[repr(C)]
 pub enum SyncRequest {
-    Invite(DocId, String),
+    Invite(InviteRequest),
     Lenses([u8; 32]),
     Unjoin(DocId, CausalContext),
+    Token(Token),
+    Features(u32),
+    Denied(DocId, Vec<DeniedNotice>),
+    /// Unjoin answered in pages, continuing at the cursor returned with the previous page.
+    UnjoinPage(DocId, CausalContext, Option<Vec<u8>>),
+    /// Acknowledges that the broadcast with the hash was applied.
+    Ack(DocId, [u8; 32]),
+    /// Reports the context of the document after applying an unjoin.
+    Synced(DocId, CausalContext),
 }
 
 #[derive(Debug, Archive, Deserialize, Serialize)]
@@ -69,15 +84,338 @@ pub enum SyncRequest {
 pub enum SyncResponse {
     Invite,
     Lenses(Vec<u8>),
-    Unjoin([u8; 32], Causal),
+    Unjoin([u8; 32], CompressedCausal),
+    Token(DocId),
+    Features(u32),
+    Denied,
+    /// Page of an unjoin and the cursor of the next page if more paths follow.
+    UnjoinPage([u8; 32], CompressedCausal, Option<Vec<u8>>),
+    Ack,
+    /// The request was rejected for the reason.
+    Error(String),
+}
+
+#[derive(Clone)]
+pub struct AttachmentProtocol;
+
+impl ProtocolName for AttachmentProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        "/tlfs/attachment/1.0.0".as_bytes()
+    }
 }
 
+/// Asks a peer for the attachment with the hash of a document. Attachments are only served
+/// to peers that can read the document.
+#[derive(Debug, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct AttachmentRequest {
+    doc: DocId,
+    hash: [u8; 32],
+}
+
+/// The requested attachment, `None` if the peer doesn't have it or the requesting peer
+/// can't read the document.
+#[derive(Debug, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct AttachmentResponse(Option<Vec<u8>>);
+
 #[derive(Debug, Archive, Deserialize, Serialize)]
 #[archive_attr(derive(Debug, CheckBytes))]
 #[repr(C)]
 pub struct Delta {
     schema: [u8; 32],
-    causal: Causal,
+    causal: CompressedCausal,
+}
+
+/// Liveness information about a peer.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PeerInfo {
+    /// Last time the peer answered a ping or sent us a message.
+    pub last_seen: Option<Instant>,
+    /// Round trip time of the last successful ping.
+    pub rtt: Option<Duration>,
+    /// Number of consecutive failed pings.
+    pub failures: u32,
+}
+
+/// Where an address of a peer was learned from.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum PeerSource {
+    /// Discovered on the local network.
+    Mdns,
+    /// Added with [`Sdk::add_address`](crate::Sdk::add_address).
+    Manual,
+    /// Dialed through a relay.
+    Relay,
+    /// Dialed directly.
+    Connection,
+}
+
+/// Entry of the peer table. Relayed and dialed addresses are forgotten an hour after the
+/// last connection to the peer, mdns addresses when mdns expires them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerEntry {
+    /// Known addresses of the peer and where they were learned from.
+    pub addresses: BTreeSet<(Multiaddr, PeerSource)>,
+}
+
+impl PeerEntry {
+    /// Returns the sources the peer was learned from.
+    pub fn sources(&self) -> BTreeSet<PeerSource> {
+        self.addresses.iter().map(|(_, source)| *source).collect()
+    }
+
+    /// Returns the addresses learned from `source`.
+    pub fn addresses_from(&self, source: PeerSource) -> impl Iterator<Item = &Multiaddr> {
+        self.addresses
+            .iter()
+            .filter(move |(_, s)| *s == source)
+            .map(|(addr, _)| addr)
+    }
+}
+
+/// Event emitted by the [`Sdk`](crate::Sdk).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SdkEvent {
+    /// The listen addresses changed.
+    AddressChanged,
+    /// The first connection to a peer was established.
+    PeerConnected(PeerId),
+    /// The last connection to a peer was closed.
+    PeerDisconnected(PeerId),
+    /// The peer table changed.
+    LocalPeersChanged,
+    /// Liveness information of a peer changed.
+    PeerInfoChanged(PeerId),
+    /// An invitation was received.
+    InviteReceived,
+    /// A document received remote changes.
+    DocChanged(DocId),
+    /// The acl of a document changed.
+    AclChanged(DocId),
+    /// An unjoin of a document was answered by a peer.
+    SyncCompleted(DocId),
+    /// Changes to a document were dropped because a peer lacked permission. Either a remote
+    /// peer sent changes it wasn't allowed to make or a peer dropped our changes.
+    PermissionDenied(DocId),
+    /// The sync status of a document with a peer changed.
+    SyncStatusChanged(DocId),
+    /// A change to a document sent by a peer was rejected, because the peer is faulty or
+    /// malicious. Consider blocking the peer with [`Sdk::block_peer`](crate::Sdk::block_peer).
+    ChangeRejected(PeerId, DocId, InvalidChange),
+    /// A peer acknowledged that it applied the local change with the transaction id, see
+    /// [`Causal::id`].
+    Delivered(DocId, Dot),
+}
+
+/// Which side initiated a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    /// The remote peer dialed us.
+    Inbound,
+    /// We dialed the remote peer.
+    Outbound,
+}
+
+/// Connection level event emitted by the [`Sdk`](crate::Sdk), see
+/// [`Sdk::connection_events`](crate::Sdk::connection_events).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ConnectionEvent {
+    /// A connection to a peer was established. Emitted for every connection, not only the
+    /// first one.
+    PeerConnected {
+        /// The remote peer.
+        peer: PeerId,
+        /// The remote address of the connection. Not dialable for inbound connections.
+        addr: Multiaddr,
+        /// Which side dialed.
+        direction: Direction,
+    },
+    /// A connection to a peer was closed.
+    PeerDisconnected {
+        /// The remote peer.
+        peer: PeerId,
+        /// Why the connection was closed, `None` if it was closed gracefully.
+        reason: Option<String>,
+    },
+    /// An outgoing dial failed.
+    DialFailure {
+        /// The dialed peer, if known.
+        peer: Option<PeerId>,
+        /// Why the dial failed.
+        error: String,
+    },
+}
+
+/// Limits on the traffic accepted from a single peer. Traffic exceeding them is dropped and
+/// counted in the [`RejectedTraffic`] of the peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SyncLimits {
+    /// Maximum size in bytes of a received message carrying changes. Larger sync messages
+    /// aren't read.
+    pub max_causal_size: usize,
+    /// Maximum number of requests and broadcasts per second.
+    pub max_requests_per_sec: u32,
+    /// Maximum number of unknown schemas changes of the peer are buffered for while the
+    /// lenses are requested.
+    pub max_buffered_lens_requests: usize,
+    /// Maximum size in bytes of an attachment received from a peer. Fetching larger
+    /// attachments fails.
+    pub max_attachment_size: usize,
+}
+
+impl Default for SyncLimits {
+    fn default() -> Self {
+        Self {
+            max_causal_size: 16 * 1024 * 1024,
+            max_requests_per_sec: 256,
+            max_buffered_lens_requests: 16,
+            max_attachment_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Traffic of a peer that was dropped because it exceeded the [`SyncLimits`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RejectedTraffic {
+    /// Number of messages larger than [`SyncLimits::max_causal_size`].
+    pub oversized: u64,
+    /// Number of requests and broadcasts exceeding [`SyncLimits::max_requests_per_sec`].
+    pub rate_limited: u64,
+    /// Number of changes not buffered because of
+    /// [`SyncLimits::max_buffered_lens_requests`].
+    pub lens_requests: u64,
+}
+
+/// Sync status of a document with a peer.
+#[derive(Clone, Debug)]
+pub struct PeerSyncStatus {
+    /// The peer.
+    pub peer: PeerId,
+    /// Last time an unjoin of the document was exchanged with the peer.
+    pub last_sync: Option<Instant>,
+    /// Number of dots the peer lacks, as far as we know, including dots it isn't allowed to
+    /// read. `None` until the peer requested an unjoin.
+    pub ahead: Option<usize>,
+    /// The peer is connected and subscribed to the document, so our broadcasts reach it.
+    pub delivering: bool,
+}
+
+/// What is known about a document at a peer.
+#[derive(Default)]
+struct SyncState {
+    last_sync: Option<Instant>,
+    /// Dots the peer has, known once it requested an unjoin.
+    ctx: Option<CausalContext>,
+}
+
+/// Priority of a document when scheduling unjoins and broadcasts.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    /// Synced after all other documents.
+    Low,
+    /// Default priority.
+    Normal,
+    /// Synced before all other documents.
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::Normal
+    }
+}
+
+/// Queue ordered by priority and insertion order.
+struct PriorityQueue<T> {
+    queue: BTreeMap<(Reverse<Priority>, u64), (DocId, T)>,
+    seq: u64,
+}
+
+impl<T> Default for PriorityQueue<T> {
+    fn default() -> Self {
+        Self {
+            queue: Default::default(),
+            seq: 0,
+        }
+    }
+}
+
+impl<T> PriorityQueue<T> {
+    fn push(&mut self, prio: Priority, doc: DocId, item: T) {
+        self.seq += 1;
+        self.queue.insert((Reverse(prio), self.seq), (doc, item));
+    }
+
+    fn pop(&mut self) -> Option<(DocId, T)> {
+        let key = *self.queue.keys().next()?;
+        self.queue.remove(&key)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    fn remove(&mut self, doc: &DocId) {
+        self.queue.retain(|_, (d, _)| d != doc);
+    }
+
+    /// Removes the queued items matching `f` and returns their documents.
+    fn drain_filter(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<DocId> {
+        let mut docs = vec![];
+        self.queue.retain(|_, (doc, item)| {
+            if f(item) {
+                docs.push(*doc);
+                false
+            } else {
+                true
+            }
+        });
+        docs
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &(DocId, T)> {
+        self.queue.values()
+    }
+
+    /// Moves the queued items of `doc` to `prio` keeping their relative order.
+    fn reprioritize(&mut self, doc: &DocId, prio: Priority) {
+        let (items, rest): (BTreeMap<_, _>, BTreeMap<_, _>) = std::mem::take(&mut self.queue)
+            .into_iter()
+            .partition(|(_, (d, _))| d == doc);
+        self.queue = rest;
+        for ((_, seq), item) in items {
+            self.queue.insert((Reverse(prio), seq), item);
+        }
+    }
+}
+
+impl PeerInfo {
+    /// Returns if the last ping to the peer succeeded.
+    pub fn is_reachable(&self) -> bool {
+        self.last_seen.is_some() && self.failures == 0
+    }
+}
+
+#[derive(Debug, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct InviteRequest {
+    pub doc: DocId,
+    pub schema: String,
+    pub message: Option<String>,
+    pub permission: Option<Permission>,
+}
+
+/// Tells the sender of a change that part of it was dropped because it lacked `permission`.
+#[derive(Debug, Archive, Deserialize, Serialize)]
+#[archive_attr(derive(Debug, CheckBytes))]
+#[repr(C)]
+pub struct DeniedNotice {
+    prefix: Vec<u8>,
+    permission: Permission,
 }
 
 /// Invitation to collaborate on a document.
@@ -88,11 +426,46 @@ pub struct Invite {
     pub doc: DocId,
     /// Schema of the document.
     pub schema: String,
+    /// Peer that sent the invitation, authenticated by the transport.
+    pub inviter: PeerId,
+    /// Message of the inviter.
+    pub message: Option<String>,
+    /// Permission the inviter says the invitee has on the document. It is only a preview,
+    /// the actual permissions are enforced once the document is synced.
+    pub permission: Option<Permission>,
 }
 
-#[derive(Clone, Default)]
+/// Codec of the sync protocol. Messages are only read up to `max_size` bytes.
+#[derive(Clone)]
 pub struct SyncCodec {
     buffer: Vec<u8>,
+    max_size: usize,
+}
+
+impl SyncCodec {
+    fn new(max_size: usize) -> Self {
+        Self {
+            buffer: Default::default(),
+            max_size,
+        }
+    }
+
+    async fn read<T>(&mut self, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.buffer.clear();
+        io.take(self.max_size as u64 + 1)
+            .read_to_end(&mut self.buffer)
+            .await?;
+        if self.buffer.len() > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message exceeds {} bytes", self.max_size),
+            ));
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -105,8 +478,7 @@ impl RequestResponseCodec for SyncCodec {
     where
         T: AsyncRead + Unpin + Send,
     {
-        self.buffer.clear();
-        io.read_to_end(&mut self.buffer).await?;
+        self.read(io).await?;
         Ref::checked(&self.buffer).map_err(|err| {
             io::Error::new(
                 io::ErrorKind::Other,
@@ -119,8 +491,7 @@ impl RequestResponseCodec for SyncCodec {
     where
         T: AsyncRead + Unpin + Send,
     {
-        self.buffer.clear();
-        io.read_to_end(&mut self.buffer).await?;
+        self.read(io).await?;
         Ref::checked(&self.buffer).map_err(|err| {
             io::Error::new(
                 io::ErrorKind::Other,
@@ -158,8 +529,262 @@ impl RequestResponseCodec for SyncCodec {
     }
 }
 
-pub(crate) fn notify(subs: &mut Vec<mpsc::Sender<()>>) {
-    subs.retain(|tx| match tx.clone().try_send(()) {
+/// Codec of the attachment protocol. Messages are only read up to `max_size` bytes, which is
+/// larger than the limit of the [`SyncCodec`] since attachments are large.
+#[derive(Clone)]
+pub struct AttachmentCodec {
+    buffer: Vec<u8>,
+    max_size: usize,
+}
+
+impl AttachmentCodec {
+    fn new(max_size: usize) -> Self {
+        Self {
+            buffer: Default::default(),
+            max_size,
+        }
+    }
+
+    async fn read<T>(&mut self, io: &mut T) -> io::Result<()>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.buffer.clear();
+        io.take(self.max_size as u64 + 1)
+            .read_to_end(&mut self.buffer)
+            .await?;
+        if self.buffer.len() > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("attachment exceeds {} bytes", self.max_size),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RequestResponseCodec for AttachmentCodec {
+    type Protocol = AttachmentProtocol;
+    type Request = Ref<AttachmentRequest>;
+    type Response = Ref<AttachmentResponse>;
+
+    async fn read_request<T>(
+        &mut self,
+        _: &AttachmentProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Request>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read(io).await?;
+        Ref::checked(&self.buffer)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("read_request: {}", err)))
+    }
+
+    async fn read_response<T>(
+        &mut self,
+        _: &AttachmentProtocol,
+        io: &mut T,
+    ) -> io::Result<Self::Response>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        self.read(io).await?;
+        Ref::checked(&self.buffer)
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, format!("read_response: {}", err)))
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &AttachmentProtocol,
+        io: &mut T,
+        req: Self::Request,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(req.as_bytes()).await?;
+        io.close().await?;
+        Ok(())
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &AttachmentProtocol,
+        io: &mut T,
+        res: Self::Response,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        io.write_all(res.as_bytes()).await?;
+        io.close().await?;
+        Ok(())
+    }
+}
+
+/// Time after which a peer that didn't send a requested attachment is considered failed.
+const ATTACHMENT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum number of unjoin requests in flight.
+const MAX_UNJOINS: usize = 16;
+
+/// Changes received are decompressed to at most this multiple of
+/// [`SyncLimits::max_causal_size`].
+const MAX_DECOMPRESSION_RATIO: usize = 8;
+
+/// Number of peers whose [`RejectedTraffic`] is kept.
+const MAX_REJECTED_PEERS: usize = 1024;
+
+/// Feature flag of peers that send broadcasts to the sub-topics of top-level fields.
+const FEATURE_SHARDING: u32 = 1;
+
+/// Feature flag of peers that accept notices about changes they lacked permission for.
+const FEATURE_DENIED: u32 = 2;
+
+/// Feature flag of peers that answer unjoins in pages.
+const FEATURE_PAGED_UNJOIN: u32 = 4;
+
+/// Feature flag of peers that accept acknowledgements of their broadcasts.
+const FEATURE_ACK: u32 = 8;
+
+/// Feature flag of peers that accept [`SyncRequest::Synced`].
+const FEATURE_SYNCED: u32 = 64;
+
+/// Protocol features supported by this peer, exchanged when connecting.
+const FEATURES: u32 =
+    FEATURE_SHARDING | FEATURE_DENIED | FEATURE_PAGED_UNJOIN | FEATURE_ACK | FEATURE_SYNCED;
+
+/// Maximum number of capability tokens presented to the peers of a document.
+const MAX_DOC_TOKENS: usize = 16;
+
+/// Maximum size in bytes of the paths sent in a page of an unjoin. Large documents are
+/// transferred in several request-response round trips, the next page is only requested
+/// once the previous one was applied.
+const UNJOIN_PAGE_SIZE: usize = 256 * 1024;
+
+/// Maximum number of permission denials kept until they are cleared.
+const MAX_DENIED: usize = 1024;
+
+/// Returns the broadcast sub-topic of a top-level field of a document. Paths not below a
+/// top-level field are sent to the sub-topic without a field.
+fn shard_topic(doc: &DocId, field: Option<&str>) -> Topic {
+    let mut topic = [0; 64];
+    topic[..32].copy_from_slice(doc.as_ref());
+    if let Some(field) = field {
+        topic[32..].copy_from_slice(blake3::hash(field.as_bytes()).as_bytes());
+    }
+    Topic::new(&topic)
+}
+
+/// Returns the document of a broadcast topic or sub-topic.
+fn topic_doc(topic: &Topic) -> Result<DocId> {
+    match topic.as_ref().len() {
+        32 | 64 => Ok(DocId::new(topic.as_ref()[..32].try_into()?)),
+        _ => bail!("invalid topic"),
+    }
+}
+
+/// Time after which changes waiting for lenses no peer could provide are dropped.
+const PENDING_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Time after the last connection until relayed and dialed addresses are forgotten.
+const ADDRESS_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Peers asked for the lenses of a schema hash.
+struct LensRequest {
+    since: Instant,
+    tried: BTreeSet<PeerId>,
+}
+
+/// Peers asked for an attachment and the callers waiting for it.
+#[derive(Default)]
+struct AttachmentFetch {
+    tried: BTreeSet<PeerId>,
+    waiting: Vec<oneshot::Sender<Result<Vec<u8>>>>,
+}
+
+/// Bounded set of recently seen message hashes.
+#[derive(Default)]
+struct RecentSet {
+    order: VecDeque<[u8; 32]>,
+    set: BTreeSet<[u8; 32]>,
+}
+
+impl RecentSet {
+    const CAPACITY: usize = 1024;
+
+    /// Inserts the hash of `msg`. Returns `false` if it was seen recently.
+    fn insert(&mut self, msg: &[u8]) -> bool {
+        let hash = *blake3::hash(msg).as_bytes();
+        if !self.set.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > Self::CAPACITY {
+            if let Some(hash) = self.order.pop_front() {
+                self.set.remove(&hash);
+            }
+        }
+        true
+    }
+}
+
+/// Dots of recent local changes and of their broadcasts. A change is delivered to the peers
+/// known to have all of it's dots.
+#[derive(Default)]
+struct Deliveries {
+    order: VecDeque<Dot>,
+    /// Documents and dots of the local changes by transaction id.
+    changes: FnvHashMap<Dot, (DocId, CausalContext)>,
+    /// Transaction ids and dots of the broadcast messages by hash.
+    deltas: FnvHashMap<[u8; 32], (Dot, CausalContext)>,
+}
+
+impl Deliveries {
+    const CAPACITY: usize = 1024;
+
+    /// Tracks the local change `id` of `doc` containing the dots of `ctx`.
+    fn track(&mut self, id: Dot, doc: DocId, ctx: CausalContext) {
+        if self.changes.insert(id, (doc, ctx)).is_none() {
+            self.order.push_back(id);
+            if self.order.len() > Self::CAPACITY {
+                if let Some(old) = self.order.pop_front() {
+                    self.changes.remove(&old);
+                    self.deltas.retain(|_, (id, _)| *id != old);
+                }
+            }
+        }
+    }
+
+    /// Tracks acknowledgements of the broadcast `msg` of the change `id` containing the dots
+    /// of `ctx`.
+    fn insert(&mut self, id: Dot, msg: &[u8], ctx: CausalContext) {
+        if self.changes.contains_key(&id) {
+            self.deltas.insert(*blake3::hash(msg).as_bytes(), (id, ctx));
+        }
+    }
+
+    /// Returns the document, the id of the change and the dots of the broadcast with `hash`.
+    /// Acknowledgements naming a different document than the change are ignored.
+    fn ack(&self, doc: &DocId, hash: &[u8; 32]) -> Option<(DocId, Dot, &CausalContext)> {
+        let (id, ctx) = self.deltas.get(hash)?;
+        let (change_doc, _) = self.changes.get(id)?;
+        if change_doc != doc {
+            return None;
+        }
+        Some((*change_doc, *id, ctx))
+    }
+
+    fn change(&self, id: &Dot) -> Option<&(DocId, CausalContext)> {
+        self.changes.get(id)
+    }
+}
+
+/// Sends an event to the subscribers. Events are dropped for subscribers that fell behind.
+fn emit<T: Clone>(subs: &mut Vec<mpsc::Sender<T>>, ev: T) {
+    subs.retain(|tx| match tx.clone().try_send(ev.clone()) {
         Ok(()) => true,
         Err(err) if err.is_full() => true,
         Err(_) => false,
@@ -169,45 +794,140 @@ pub(crate) fn notify(subs: &mut Vec<mpsc::Sender<()>>) {
 type RequestResponseEvent =
     request_response::RequestResponseEvent<Ref<SyncRequest>, Ref<SyncResponse>>;
 
+type AttachmentEvent =
+    request_response::RequestResponseEvent<Ref<AttachmentRequest>, Ref<AttachmentResponse>>;
+
 #[derive(NetworkBehaviour)]
 #[behaviour(event_process = true, poll_method = "poll_dial")]
 pub struct Behaviour {
     req: RequestResponse<SyncCodec>,
+    attachments: RequestResponse<AttachmentCodec>,
     broadcast: Broadcast,
     ping: ping::Behaviour,
-    #[cfg(not(target_family = "wasm"))]
-    mdns: mdns::Mdns,
+    #[cfg(all(feature = "mdns", not(target_family = "wasm")))]
+    mdns: Toggle<mdns::Mdns>,
     #[behaviour(ignore)]
-    unjoin_req: FnvHashMap<RequestId, DocId>,
-    #[behaviour(ignore)]
-    buffer: Vec<(Hash, DocId, PeerId, Causal)>,
+    unjoin_req: FnvHashMap<RequestId, (PeerId, DocId)>,
     #[behaviour(ignore)]
     backend: Backend,
     #[behaviour(ignore)]
-    sub_local_peers: Vec<mpsc::Sender<()>>,
+    sub_events: Vec<mpsc::Sender<SdkEvent>>,
     #[behaviour(ignore)]
-    sub_invites: Vec<mpsc::Sender<()>>,
+    sub_connections: Vec<mpsc::Sender<ConnectionEvent>>,
     #[behaviour(ignore)]
     invites: Vec<Invite>,
     #[behaviour(ignore)]
     dial: VecDeque<PeerId>,
+    #[behaviour(ignore)]
+    peer_info: FnvHashMap<PeerId, PeerInfo>,
+    /// Peers discovered, added or dialed with their addresses.
+    #[behaviour(ignore)]
+    peers: FnvHashMap<PeerId, PeerEntry>,
+    /// When relayed and dialed addresses were last used.
+    #[behaviour(ignore)]
+    address_seen: FnvHashMap<(PeerId, Multiaddr, PeerSource), Instant>,
+    #[behaviour(ignore)]
+    unjoin_redundancy: usize,
+    #[behaviour(ignore)]
+    unjoin_failed: FnvHashMap<DocId, BTreeSet<PeerId>>,
+    #[behaviour(ignore)]
+    recent_deltas: RecentSet,
+    /// Capability tokens presented to the peers of a document.
+    #[behaviour(ignore)]
+    tokens: FnvHashMap<DocId, Vec<Token>>,
+    #[behaviour(ignore)]
+    token_req: FnvHashMap<RequestId, (DocId, [u8; 32])>,
+    /// Ids of the tokens connected peers accepted, which aren't presented to them again.
+    #[behaviour(ignore)]
+    tokens_accepted: FnvHashMap<PeerId, BTreeSet<[u8; 32]>>,
+    #[behaviour(ignore)]
+    remote_changes: BTreeSet<DocId>,
+    /// Changes dropped because the peer lacked permission, both ours and remote ones.
+    #[behaviour(ignore)]
+    denied: VecDeque<Denied>,
+    #[behaviour(ignore)]
+    sync_once: Vec<(BTreeSet<RequestId>, oneshot::Sender<()>)>,
+    #[behaviour(ignore)]
+    priorities: FnvHashMap<DocId, Priority>,
+    #[behaviour(ignore)]
+    unjoin_queue: PriorityQueue<PeerId>,
+    /// Deltas to broadcast.
+    #[behaviour(ignore)]
+    broadcast_queue: PriorityQueue<(Topic, Vec<u8>)>,
+    /// Documents connected peers are subscribed to.
+    #[behaviour(ignore)]
+    peer_docs: FnvHashMap<PeerId, BTreeSet<DocId>>,
+    /// Documents shared with peers that disconnected, which need to be unjoined again once the
+    /// peer reconnects.
+    #[behaviour(ignore)]
+    stale: FnvHashMap<PeerId, BTreeSet<DocId>>,
+    #[behaviour(ignore)]
+    lens_req: FnvHashMap<RequestId, (PeerId, Hash)>,
+    #[behaviour(ignore)]
+    lens_requests: FnvHashMap<Hash, LensRequest>,
+    #[behaviour(ignore)]
+    attachment_req: FnvHashMap<RequestId, (PeerId, DocId, Hash)>,
+    #[behaviour(ignore)]
+    attachment_fetches: FnvHashMap<(DocId, Hash), AttachmentFetch>,
+    /// Protocol features of connected peers.
+    #[behaviour(ignore)]
+    features: FnvHashMap<PeerId, u32>,
+    #[behaviour(ignore)]
+    features_req: FnvHashMap<RequestId, PeerId>,
+    /// Top-level fields of documents that only the sub-topics are subscribed to.
+    #[behaviour(ignore)]
+    shards: FnvHashMap<DocId, BTreeSet<String>>,
+    /// Sharded documents that are subscribed to fully, because a peer doesn't support sharding.
+    #[behaviour(ignore)]
+    shard_fallback: BTreeSet<DocId>,
+    #[behaviour(ignore)]
+    sync_state: FnvHashMap<(DocId, PeerId), SyncState>,
+    /// Peers whose requests and changes are dropped.
+    #[behaviour(ignore)]
+    blocked: BTreeSet<PeerId>,
+    #[behaviour(ignore)]
+    deliveries: Deliveries,
+    #[behaviour(ignore)]
+    limits: SyncLimits,
+    /// Start of the current rate limiting window and the messages received in it per peer.
+    #[behaviour(ignore)]
+    rate: FnvHashMap<PeerId, (Instant, u32)>,
+    #[behaviour(ignore)]
+    rejected: FnvHashMap<PeerId, RejectedTraffic>,
 }
 
 impl Behaviour {
-    pub async fn new(backend: Backend) -> Result<Self> {
+    /// Creates the behaviour. Local peer discovery is only enabled if `mdns` is set and the
+    /// `mdns` feature is enabled.
+    pub async fn new(backend: Backend, mdns: bool, limits: SyncLimits) -> Result<Self> {
+        #[cfg(not(all(feature = "mdns", not(target_family = "wasm"))))]
+        let _ = mdns;
+        let mut attachment_config = RequestResponseConfig::default();
+        attachment_config.set_request_timeout(ATTACHMENT_TIMEOUT);
         let mut me = Self {
             backend,
             req: RequestResponse::new(
-                SyncCodec::default(),
+                SyncCodec::new(limits.max_causal_size),
                 vec![(SyncProtocol, ProtocolSupport::Full)],
                 RequestResponseConfig::default(),
             ),
-            #[cfg(not(target_family = "wasm"))]
-            mdns: mdns::Mdns::new(mdns::MdnsConfig {
-                query_interval: Duration::from_secs(10),
-                ..Default::default()
-            })
-            .await?,
+            attachments: RequestResponse::new(
+                AttachmentCodec::new(limits.max_attachment_size),
+                vec![(AttachmentProtocol, ProtocolSupport::Full)],
+                attachment_config,
+            ),
+            #[cfg(all(feature = "mdns", not(target_family = "wasm")))]
+            mdns: Toggle::from(if mdns {
+                Some(
+                    mdns::Mdns::new(mdns::MdnsConfig {
+                        query_interval: Duration::from_secs(10),
+                        ..Default::default()
+                    })
+                    .await?,
+                )
+            } else {
+                None
+            }),
             ping: ping::Behaviour::new(
                 ping::Config::new()
                     .with_keep_alive(true)
@@ -215,106 +935,1010 @@ impl Behaviour {
                     .with_timeout(Duration::from_secs(1)),
             ),
             unjoin_req: Default::default(),
-            buffer: Default::default(),
             broadcast: Broadcast::new(BroadcastConfig::default()),
-            sub_local_peers: Default::default(),
-            sub_invites: Default::default(),
+            sub_events: Default::default(),
+            sub_connections: Default::default(),
             invites: Default::default(),
             dial: Default::default(),
+            peer_info: Default::default(),
+            peers: Default::default(),
+            address_seen: Default::default(),
+            unjoin_redundancy: 2,
+            unjoin_failed: Default::default(),
+            recent_deltas: Default::default(),
+            tokens: Default::default(),
+            token_req: Default::default(),
+            tokens_accepted: Default::default(),
+            remote_changes: Default::default(),
+            denied: Default::default(),
+            sync_once: Default::default(),
+            priorities: Default::default(),
+            unjoin_queue: Default::default(),
+            broadcast_queue: Default::default(),
+            peer_docs: Default::default(),
+            stale: Default::default(),
+            lens_req: Default::default(),
+            lens_requests: Default::default(),
+            attachment_req: Default::default(),
+            attachment_fetches: Default::default(),
+            features: Default::default(),
+            features_req: Default::default(),
+            shards: Default::default(),
+            shard_fallback: Default::default(),
+            sync_state: Default::default(),
+            blocked: Default::default(),
+            deliveries: Default::default(),
+            limits,
+            rate: Default::default(),
+            rejected: Default::default(),
         };
         for res in me.backend.frontend().docs() {
             let doc = res?;
             me.subscribe(&doc);
         }
-        Ok(me)
+        for (schema, peer) in me.backend.pending() {
+            me.request_lenses(&peer, schema);
+        }
+        // local changes of a previous run that no peer received yet. they were applied before
+        // they were journaled, so the paths are read from the store.
+        let journal = me.backend.frontend().journal().collect::<Vec<_>>();
+        for res in journal {
+            let (doc, entry, causal) = match res {
+                Ok(res) => res,
+                Err(err) => {
+                    tracing::error!("{}", err);
+                    continue;
+                }
+            };
+            tracing::info!("sending journaled change {} of {}", entry, doc);
+            if let Err(err) = me.broadcast(&doc, causal, Some(entry)) {
+                tracing::error!("{}", err);
+            }
+        }
+        Ok(me)
+    }
+
+    pub fn poll_backend(&mut self, cx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.backend).poll(cx)
+    }
+
+    pub fn add_address(&mut self, peer: &PeerId, addr: Multiaddr) {
+        self.req
+            .add_address(&peer.to_libp2p().to_peer_id(), addr.clone());
+        self.learn_address(peer, addr, PeerSource::Manual);
+    }
+
+    pub fn remove_address(&mut self, peer: &PeerId, addr: &Multiaddr) {
+        self.req
+            .remove_address(&peer.to_libp2p().to_peer_id(), addr);
+        self.forget_address(peer, addr, PeerSource::Manual);
+    }
+
+    /// Records the address of an outbound connection in the peer table.
+    pub fn dialed_address(&mut self, peer: &PeerId, addr: Multiaddr) {
+        let relayed = addr.iter().any(|p| matches!(p, Protocol::P2pCircuit));
+        let source = if relayed {
+            PeerSource::Relay
+        } else {
+            PeerSource::Connection
+        };
+        self.learn_address(peer, addr, source);
+    }
+
+    fn learn_address(&mut self, peer: &PeerId, addr: Multiaddr, source: PeerSource) {
+        if matches!(source, PeerSource::Relay | PeerSource::Connection) {
+            self.address_seen
+                .insert((*peer, addr.clone(), source), Instant::now());
+        }
+        let entry = self.peers.entry(*peer).or_default();
+        if entry.addresses.insert((addr, source)) {
+            self.emit(SdkEvent::LocalPeersChanged);
+        }
+    }
+
+    fn forget_address(&mut self, peer: &PeerId, addr: &Multiaddr, source: PeerSource) {
+        self.address_seen.remove(&(*peer, addr.clone(), source));
+        if let Some(entry) = self.peers.get_mut(peer) {
+            if entry.addresses.remove(&(addr.clone(), source)) {
+                if entry.addresses.is_empty() {
+                    self.peers.remove(peer);
+                }
+                self.emit(SdkEvent::LocalPeersChanged);
+            }
+        }
+    }
+
+    /// Forgets relayed and dialed addresses of peers that weren't connected within
+    /// [`ADDRESS_TTL`].
+    pub fn expire_addresses(&mut self, connected: &BTreeSet<PeerId>) {
+        let now = Instant::now();
+        let expired = self
+            .address_seen
+            .iter()
+            .filter(|((peer, _, _), seen)| {
+                !connected.contains(peer) && now.duration_since(**seen) > ADDRESS_TTL
+            })
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for (peer, addr, source) in expired {
+            self.forget_address(&peer, &addr, source);
+        }
+    }
+
+    pub fn local_peers(&self) -> BTreeSet<PeerId> {
+        self.peers.keys().copied().collect()
+    }
+
+    pub fn peers(&self) -> BTreeMap<PeerId, PeerEntry> {
+        self.peers
+            .iter()
+            .map(|(peer, entry)| (*peer, entry.clone()))
+            .collect()
+    }
+
+    pub fn subscribe_events(&mut self, ch: mpsc::Sender<SdkEvent>) {
+        self.sub_events.push(ch);
+    }
+
+    pub fn emit(&mut self, ev: SdkEvent) {
+        emit(&mut self.sub_events, ev);
+    }
+
+    pub fn subscribe_connections(&mut self, ch: mpsc::Sender<ConnectionEvent>) {
+        self.sub_connections.push(ch);
+    }
+
+    pub fn emit_connection(&mut self, ev: ConnectionEvent) {
+        emit(&mut self.sub_connections, ev);
+    }
+
+    pub fn clear_denied(&mut self) -> Vec<Denied> {
+        self.denied.drain(..).collect()
+    }
+
+    fn push_denied(&mut self, denied: Denied) {
+        if self.denied.len() >= MAX_DENIED {
+            self.denied.pop_front();
+        }
+        let doc = denied.doc;
+        self.denied.push_back(denied);
+        self.emit(SdkEvent::PermissionDenied(doc));
+    }
+
+    /// Records changes dropped during a join and tells the peers that sent them.
+    fn inject_denied(&mut self, denied: Vec<Denied>) {
+        let mut notices = BTreeMap::<_, Vec<_>>::new();
+        for denied in denied {
+            notices
+                .entry((denied.peer, denied.doc))
+                .or_default()
+                .push(DeniedNotice {
+                    prefix: denied.prefix.as_ref().to_vec(),
+                    permission: denied.permission,
+                });
+            self.push_denied(denied);
+        }
+        for ((peer, doc), notices) in notices {
+            if self.features.get(&peer).copied().unwrap_or_default() & FEATURE_DENIED == 0 {
+                continue;
+            }
+            let req = SyncRequest::Denied(doc, notices);
+            self.req
+                .send_request(&peer.to_libp2p().to_peer_id(), Ref::archive(&req));
+        }
+    }
+
+    pub fn clear_remote_changes(&mut self) -> Vec<DocId> {
+        std::mem::take(&mut self.remote_changes)
+            .into_iter()
+            .collect()
+    }
+
+    /// Requests an unjoin for every document and completes `tx` once all requests were
+    /// answered or failed.
+    pub fn sync_once(&mut self, tx: oneshot::Sender<()>) {
+        let mut reqs = BTreeSet::new();
+        for res in self.backend.frontend().docs() {
+            let doc = unwrap!(res);
+            let mut peers = self.topic_peers(&doc);
+            self.rank_peers(&mut peers);
+            peers.truncate(self.unjoin_redundancy);
+            for peer in peers {
+                reqs.insert(unwrap!(self.request_unjoin(&peer, doc)));
+            }
+        }
+        if reqs.is_empty() {
+            tx.send(()).ok();
+        } else {
+            self.sync_once.push((reqs, tx));
+        }
+    }
+
+    /// Waits for the request of the next page instead of `id` before completing a sync.
+    fn continue_sync_once(&mut self, id: &RequestId, next: RequestId) {
+        for (reqs, _) in &mut self.sync_once {
+            if reqs.remove(id) {
+                reqs.insert(next);
+            }
+        }
+    }
+
+    fn complete_sync_once(&mut self, id: &RequestId) {
+        for (reqs, _) in &mut self.sync_once {
+            reqs.remove(id);
+        }
+        let (done, pending): (Vec<_>, Vec<_>) = std::mem::take(&mut self.sync_once)
+            .into_iter()
+            .partition(|(reqs, _)| reqs.is_empty());
+        self.sync_once = pending;
+        for (_, tx) in done {
+            tx.send(()).ok();
+        }
+    }
+
+    pub fn peer_info(&self, peer: &PeerId) -> Option<PeerInfo> {
+        self.peer_info.get(peer).copied()
+    }
+
+    /// Returns the sync status of a document with the peers it was exchanged with or that
+    /// are subscribed to it.
+    pub fn sync_status(&self, doc: &DocId) -> Result<Vec<PeerSyncStatus>> {
+        let mut peers = self
+            .sync_state
+            .keys()
+            .filter(|(d, _)| d == doc)
+            .map(|(_, peer)| *peer)
+            .collect::<BTreeSet<_>>();
+        peers.extend(
+            self.peer_docs
+                .iter()
+                .filter(|(_, docs)| docs.contains(doc))
+                .map(|(peer, _)| *peer),
+        );
+        let ctx = self.backend.frontend().ctx(doc)?;
+        let mut status = Vec::with_capacity(peers.len());
+        for peer in peers {
+            let state = self.sync_state.get(&(*doc, peer));
+            let ahead = state
+                .and_then(|state| state.ctx.as_ref())
+                .map(|known| ctx.missing(known));
+            status.push(PeerSyncStatus {
+                peer,
+                last_sync: state.and_then(|state| state.last_sync),
+                ahead,
+                delivering: self
+                    .peer_docs
+                    .get(&peer)
+                    .map(|docs| docs.contains(doc))
+                    .unwrap_or_default(),
+            });
+        }
+        Ok(status)
+    }
+
+    /// Records an unjoin exchanged with `peer`. `ctx` is the context the peer requested the
+    /// unjoin with. The changes sent in the response aren't known to be applied by the peer
+    /// until it requests the next unjoin.
+    fn record_unjoin(&mut self, peer: PeerId, doc: DocId, ctx: Option<CausalContext>) {
+        let state = self.sync_state.entry((doc, peer)).or_default();
+        state.last_sync = Some(Instant::now());
+        if ctx.is_some() {
+            state.ctx = ctx;
+        }
+        self.emit(SdkEvent::SyncStatusChanged(doc));
+    }
+
+    /// Records that `peer` has the dots of `ctx`.
+    fn record_delta(&mut self, peer: PeerId, doc: DocId, ctx: &CausalContext) {
+        let state = self.sync_state.get_mut(&(doc, peer));
+        if let Some(known) = state.and_then(|state| state.ctx.as_mut()) {
+            known.union(ctx);
+        }
+    }
+
+    fn seen(&mut self, peer: PeerId) {
+        let info = self.peer_info.entry(peer).or_default();
+        info.last_seen = Some(Instant::now());
+        info.failures = 0;
+        self.emit(SdkEvent::PeerInfoChanged(peer));
+    }
+
+    /// Sets the number of peers an unjoin is requested from when subscribing to a document.
+    pub fn set_unjoin_redundancy(&mut self, redundancy: usize) {
+        self.unjoin_redundancy = redundancy.max(1);
+    }
+
+    /// Orders peers by reachability and latency.
+    fn rank_peers(&self, peers: &mut [PeerId]) {
+        peers.sort_by_key(|peer| {
+            let info = self.peer_info(peer).unwrap_or_default();
+            (
+                !info.is_reachable(),
+                info.failures,
+                info.rtt.unwrap_or(Duration::MAX),
+            )
+        });
+    }
+
+    /// Returns the peers subscribed to any topic of a document. Every peer sharding the
+    /// document is subscribed to the sub-topic without a field.
+    fn topic_peers(&self, doc: &DocId) -> Vec<PeerId> {
+        let mut topics = self.doc_topics(doc);
+        for topic in [Topic::new(doc.as_ref()), shard_topic(doc, None)] {
+            if !topics.contains(&topic) {
+                topics.push(topic);
+            }
+        }
+        let mut peers = vec![];
+        for topic in &topics {
+            if let Some(iter) = self.broadcast.peers(topic) {
+                for peer in iter {
+                    if let Ok(peer) = libp2p_peer_id(peer) {
+                        if !peers.contains(&peer) {
+                            peers.push(peer);
+                        }
+                    }
+                }
+            }
+        }
+        peers
+    }
+
+    fn pending_unjoins(&self, doc: &DocId) -> usize {
+        let queued = self.unjoin_queue.iter().filter(|(d, _)| d == doc).count();
+        self.unjoin_req.values().filter(|(_, d)| d == doc).count() + queued
+    }
+
+    fn priority(&self, doc: &DocId) -> Priority {
+        self.priorities.get(doc).copied().unwrap_or_default()
+    }
+
+    /// Sets the priority of a document. Unjoins and broadcasts of documents with a higher
+    /// priority are sent first.
+    pub fn set_priority(&mut self, doc: &DocId, prio: Priority) {
+        if prio == Priority::default() {
+            self.priorities.remove(doc);
+        } else {
+            self.priorities.insert(*doc, prio);
+        }
+        self.unjoin_queue.reprioritize(doc, prio);
+        self.broadcast_queue.reprioritize(doc, prio);
+    }
+
+    /// Queues an unjoin request. At most `MAX_UNJOINS` unjoins are in flight at a time.
+    fn schedule_unjoin(&mut self, peer: PeerId, doc: DocId) {
+        let prio = self.priority(&doc);
+        self.unjoin_queue.push(prio, doc, peer);
+    }
+
+    /// Requests an unjoin from the best ranked peer that didn't fail yet.
+    fn request_unjoin_fallback(&mut self, doc: DocId) {
+        let failed = self.unjoin_failed.get(&doc).cloned().unwrap_or_default();
+        let pending = self
+            .unjoin_req
+            .values()
+            .filter(|(_, d)| *d == doc)
+            .map(|(peer, _)| *peer)
+            .collect::<BTreeSet<_>>();
+        let mut peers = self.topic_peers(&doc);
+        peers.retain(|peer| !failed.contains(peer) && !pending.contains(peer));
+        self.rank_peers(&mut peers);
+        if let Some(peer) = peers.first() {
+            unwrap!(self.request_unjoin(peer, doc));
+        }
+    }
+
+    /// Requests the lenses of `hash` from `peer_id` unless a request is already in flight.
+    pub fn request_lenses(&mut self, peer_id: &PeerId, hash: Hash) -> Option<RequestId> {
+        if self.lens_req.values().any(|(_, h)| *h == hash) {
+            return None;
+        }
+        tracing::debug!("request_lenses {} {}", peer_id, hash);
+        self.lens_requests
+            .entry(hash)
+            .or_insert_with(|| LensRequest {
+                since: Instant::now(),
+                tried: Default::default(),
+            })
+            .tried
+            .insert(*peer_id);
+        let libp2p_peer = peer_id.to_libp2p().to_peer_id();
+        let req = SyncRequest::Lenses(hash.into());
+        let id = self.req.send_request(&libp2p_peer, Ref::archive(&req));
+        self.lens_req.insert(id, (*peer_id, hash));
+        Some(id)
+    }
+
+    /// Asks another peer that sent changes for `hash` for the lenses. If every peer was tried
+    /// the buffered changes are dropped.
+    fn request_lenses_fallback(&mut self, hash: Hash) {
+        let tried = self
+            .lens_requests
+            .get(&hash)
+            .map(|req| req.tried.clone())
+            .unwrap_or_default();
+        let peer = self
+            .backend
+            .pending()
+            .into_iter()
+            .find(|(schema, peer)| *schema == hash && !tried.contains(peer))
+            .map(|(_, peer)| peer);
+        if let Some(peer) = peer {
+            self.request_lenses(&peer, hash);
+        } else {
+            self.evict_pending(hash);
+        }
+    }
+
+    /// Sends the attachment with `hash` of `doc` to `tx`. Attachments that aren't stored
+    /// locally are fetched from the connected peers, one peer at a time starting with the
+    /// best ranked peers subscribed to the document.
+    pub fn fetch_attachment(
+        &mut self,
+        doc: DocId,
+        hash: Hash,
+        tx: oneshot::Sender<Result<Vec<u8>>>,
+    ) {
+        match self.backend.frontend().attachments().get(&hash) {
+            Ok(Some(data)) => {
+                tx.send(Ok(data.to_vec())).ok();
+                return;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                tx.send(Err(err)).ok();
+                return;
+            }
+        }
+        let in_flight = self.attachment_fetches.contains_key(&(doc, hash));
+        let fetch = self.attachment_fetches.entry((doc, hash)).or_default();
+        fetch.waiting.push(tx);
+        if !in_flight {
+            self.request_attachment_fallback(doc, hash);
+        }
+    }
+
+    /// Asks the best ranked connected peer that wasn't asked yet for the attachment. If every
+    /// peer was asked the callers waiting for it fail.
+    fn request_attachment_fallback(&mut self, doc: DocId, hash: Hash) {
+        let tried = match self.attachment_fetches.get(&(doc, hash)) {
+            Some(fetch) => fetch.tried.clone(),
+            None => return,
+        };
+        let mut peers = self
+            .features
+            .keys()
+            .filter(|peer| !tried.contains(peer))
+            .copied()
+            .collect::<Vec<_>>();
+        self.rank_peers(&mut peers);
+        // the sort is stable, so subscribed peers stay ordered by rank.
+        peers.sort_by_key(|peer| {
+            let subscribed = self.peer_docs.get(peer).map(|docs| docs.contains(&doc));
+            !subscribed.unwrap_or_default()
+        });
+        if let Some(peer) = peers.first() {
+            tracing::debug!("request_attachment {} {} {}", peer, doc, hash);
+            if let Some(fetch) = self.attachment_fetches.get_mut(&(doc, hash)) {
+                fetch.tried.insert(*peer);
+            }
+            let libp2p_peer = peer.to_libp2p().to_peer_id();
+            let req = AttachmentRequest {
+                doc,
+                hash: hash.into(),
+            };
+            let id = self
+                .attachments
+                .send_request(&libp2p_peer, Ref::archive(&req));
+            self.attachment_req.insert(id, (*peer, doc, hash));
+        } else if let Some(fetch) = self.attachment_fetches.remove(&(doc, hash)) {
+            for tx in fetch.waiting {
+                let err = anyhow::anyhow!("no connected peer has attachment {}", hash);
+                tx.send(Err(err)).ok();
+            }
+        }
+    }
+
+    /// Returns if `peer` can read `doc`. Unknown documents can't be read.
+    fn can_read(&self, peer: &PeerId, doc: &DocId) -> Result<bool> {
+        if !self.backend.contains(doc)? {
+            return Ok(false);
+        }
+        self.backend.frontend().doc(*doc)?.cursor().can_read(peer)
+    }
+
+    fn evict_pending(&mut self, hash: Hash) {
+        self.lens_requests.remove(&hash);
+        let evicted = unwrap!(self.backend.evict_pending(&hash));
+        tracing::warn!(
+            "dropped {} pending changes for unknown schema {} {:?}",
+            evicted,
+            hash,
+            self.backend.pending_stats()
+        );
+    }
+
+    /// Drops buffered changes whose lenses couldn't be retrieved within [`PENDING_TIMEOUT`].
+    pub fn expire_pending(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .lens_requests
+            .iter()
+            .filter(|(_, req)| now.duration_since(req.since) > PENDING_TIMEOUT)
+            .map(|(hash, _)| *hash)
+            .collect::<Vec<_>>();
+        for hash in expired {
+            self.lens_req.retain(|_, (_, h)| *h != hash);
+            self.evict_pending(hash);
+        }
+    }
+
+    pub fn request_unjoin(&mut self, peer: &PeerId, doc: DocId) -> Result<RequestId> {
+        tracing::debug!("request_unjoin {} {}", peer, doc);
+        let accepted = self.tokens_accepted.get(peer);
+        let tokens = self
+            .tokens
+            .get(&doc)
+            .into_iter()
+            .flatten()
+            .filter(|token| {
+                !accepted
+                    .map(|ids| ids.contains(&token.id()))
+                    .unwrap_or_default()
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for token in tokens {
+            self.send_token(peer, doc, token);
+        }
+        self.request_unjoin_page(peer, doc, None)
+    }
+
+    /// Requests the page of an unjoin at the cursor `after`. The request carries the
+    /// current [`CausalContext`], so an interrupted transfer resumes without resending the
+    /// pages that were already applied.
+    fn request_unjoin_page(
+        &mut self,
+        peer: &PeerId,
+        doc: DocId,
+        after: Option<Vec<u8>>,
+    ) -> Result<RequestId> {
+        let peer_id = peer.to_libp2p().to_peer_id();
+        let ctx = self.backend.frontend().ctx(&doc)?;
+        let req =
+            if self.features.get(peer).copied().unwrap_or_default() & FEATURE_PAGED_UNJOIN == 0 {
+                SyncRequest::Unjoin(doc, ctx)
+            } else {
+                SyncRequest::UnjoinPage(doc, ctx, after)
+            };
+        let id = self.req.send_request(&peer_id, Ref::archive(&req));
+        self.unjoin_req.insert(id, (*peer, doc));
+        Ok(id)
+    }
+
+    /// Returns the topics subscribed to for a document.
+    fn doc_topics(&self, doc: &DocId) -> Vec<Topic> {
+        let mut topics = vec![];
+        if let Some(fields) = self.shards.get(doc) {
+            topics.push(shard_topic(doc, None));
+            topics.extend(fields.iter().map(|field| shard_topic(doc, Some(field))));
+            if !self.shard_fallback.contains(doc) {
+                return topics;
+            }
+        }
+        topics.push(Topic::new(doc.as_ref()));
+        topics
     }
 
-    pub fn poll_backend(&mut self, cx: &mut Context) -> Poll<Result<()>> {
-        Pin::new(&mut self.backend).poll(cx)
+    pub fn subscribe(&mut self, doc: &DocId) {
+        for topic in self.doc_topics(doc) {
+            self.broadcast.subscribe(topic);
+        }
+        let mut peers = self.topic_peers(doc);
+        self.rank_peers(&mut peers);
+        peers.truncate(self.unjoin_redundancy);
+        for peer in peers {
+            self.schedule_unjoin(peer, *doc);
+        }
     }
 
-    pub fn add_address(&mut self, peer: &PeerId, addr: Multiaddr) {
-        self.req.add_address(&peer.to_libp2p().to_peer_id(), addr);
+    pub fn unsubscribe(&mut self, doc: &DocId) {
+        for topic in self.doc_topics(doc) {
+            self.broadcast.unsubscribe(&topic);
+        }
+        self.shards.remove(doc);
+        self.shard_fallback.remove(doc);
+        self.tokens.remove(doc);
+        self.unjoin_queue.remove(doc);
+        self.priorities.remove(doc);
+        self.sync_state.retain(|(d, _), _| d != doc);
     }
 
-    pub fn remove_address(&mut self, peer: &PeerId, addr: &Multiaddr) {
-        self.req
-            .remove_address(&peer.to_libp2p().to_peer_id(), addr);
+    /// Handles the first connection to a peer being established. Topics shared before the
+    /// peer disconnected are subscribed again.
+    pub fn peer_connected(&mut self, peer: &PeerId) {
+        for failed in self.unjoin_failed.values_mut() {
+            failed.remove(peer);
+        }
+        for doc in self.stale.get(peer).cloned().unwrap_or_default() {
+            if self.backend.contains(&doc).unwrap_or_default() {
+                for topic in self.doc_topics(&doc) {
+                    self.broadcast.subscribe(topic);
+                }
+            }
+        }
+        let libp2p_peer = peer.to_libp2p().to_peer_id();
+        let req = SyncRequest::Features(FEATURES);
+        let id = self.req.send_request(&libp2p_peer, Ref::archive(&req));
+        self.features_req.insert(id, *peer);
     }
 
-    pub fn local_peers(&self) -> BTreeSet<PeerId> {
-        #[cfg(not(target_family = "wasm"))]
-        return self
-            .mdns
-            .discovered_nodes()
-            .filter_map(|peer| libp2p_peer_id(peer).ok())
-            .collect();
-        #[cfg(target_family = "wasm")]
-        return Default::default();
+    /// Handles the last connection to a peer being closed. Queued unjoins are requested from
+    /// other peers and the shared documents are unjoined again when the peer reconnects.
+    pub fn peer_disconnected(&mut self, peer: &PeerId) {
+        let now = Instant::now();
+        for ((p, _, _), seen) in self.address_seen.iter_mut() {
+            if p == peer {
+                *seen = now;
+            }
+        }
+        self.features.remove(peer);
+        self.tokens_accepted.remove(peer);
+        self.rate.remove(peer);
+        self.sync_state.retain(|(_, p), _| p != peer);
+        if let Some(docs) = self.peer_docs.remove(peer) {
+            for doc in &docs {
+                self.emit(SdkEvent::SyncStatusChanged(*doc));
+            }
+            self.stale.entry(*peer).or_default().extend(docs);
+        }
+        for doc in self.unjoin_queue.drain_filter(|p| p == peer) {
+            self.request_unjoin_fallback(doc);
+        }
+        for doc in self.shards.keys().copied().collect::<Vec<_>>() {
+            self.update_shard_fallback(&doc);
+        }
     }
 
-    pub fn subscribe_local_peers(&mut self, ch: mpsc::Sender<()>) {
-        self.sub_local_peers.push(ch);
+    /// Drops requests and changes of `peer`. The swarm is expected to disconnect the peer.
+    pub fn block_peer(&mut self, peer: PeerId) {
+        tracing::info!("blocking {}", peer);
+        self.blocked.insert(peer);
+        self.dial.retain(|p| *p != peer);
+        self.peer_disconnected(&peer);
     }
 
-    pub fn subscribe_invites(&mut self, ch: mpsc::Sender<()>) {
-        self.sub_invites.push(ch);
+    /// Accepts requests and changes of a blocked `peer` again.
+    pub fn unblock_peer(&mut self, peer: &PeerId) {
+        self.blocked.remove(peer);
     }
 
-    pub fn request_lenses(&mut self, peer_id: &PeerId, hash: Hash) -> RequestId {
-        tracing::debug!("request_lenses {} {}", peer_id, hash);
-        let peer_id = peer_id.to_libp2p().to_peer_id();
-        let req = SyncRequest::Lenses(hash.into());
-        self.req.send_request(&peer_id, Ref::archive(&req))
+    /// Returns if `peer` is blocked.
+    pub fn is_blocked(&self, peer: &PeerId) -> bool {
+        self.blocked.contains(peer)
     }
 
-    pub fn request_unjoin(&mut self, peer_id: &PeerId, doc: DocId) -> Result<RequestId> {
-        tracing::debug!("request_unjoin {} {}", peer_id, doc);
-        let peer_id = peer_id.to_libp2p().to_peer_id();
-        let ctx = self.backend.frontend().ctx(&doc)?;
-        let req = SyncRequest::Unjoin(doc, ctx);
-        let id = self.req.send_request(&peer_id, Ref::archive(&req));
-        self.unjoin_req.insert(id, doc);
-        Ok(id)
+    /// Returns the traffic dropped per peer because it exceeded the [`SyncLimits`].
+    pub fn rejected_traffic(&self) -> BTreeMap<PeerId, RejectedTraffic> {
+        self.rejected.iter().map(|(peer, r)| (*peer, *r)).collect()
     }
 
-    pub fn subscribe(&mut self, doc: &DocId) {
-        let topic = Topic::new(doc.as_ref());
-        self.broadcast.subscribe(topic);
-        let mut peers = vec![];
-        if let Some(iter) = self.broadcast.peers(&topic) {
-            for peer in iter {
-                if let Ok(peer) = libp2p_peer_id(peer) {
-                    peers.push(peer);
-                }
+    /// Returns the metrics of the backend.
+    #[cfg(feature = "metrics")]
+    pub fn metrics_snapshot(&self) -> tlfs_crdt::MetricsSnapshot {
+        self.backend.metrics_snapshot()
+    }
+
+    /// Counts a request or broadcast of `peer`. Returns `false` if the peer exceeded
+    /// [`SyncLimits::max_requests_per_sec`].
+    fn admit(&mut self, peer: PeerId) -> bool {
+        let now = Instant::now();
+        let (start, count) = self.rate.entry(peer).or_insert((now, 0));
+        if now.duration_since(*start) >= Duration::from_secs(1) {
+            *start = now;
+            *count = 0;
+        }
+        *count += 1;
+        if *count <= self.limits.max_requests_per_sec {
+            return true;
+        }
+        if *count == self.limits.max_requests_per_sec + 1 {
+            tracing::warn!("rate limiting {}", peer);
+        }
+        self.rejected_mut(peer).rate_limited += 1;
+        false
+    }
+
+    /// Maximum size of the paths a received change decompresses to.
+    fn max_decompressed_size(&self) -> usize {
+        self.limits
+            .max_causal_size
+            .saturating_mul(MAX_DECOMPRESSION_RATIO)
+    }
+
+    /// Returns the rejected traffic of `peer`. Entries of disconnected peers are pruned once
+    /// [`MAX_REJECTED_PEERS`] peers had traffic rejected.
+    fn rejected_mut(&mut self, peer: PeerId) -> &mut RejectedTraffic {
+        if !self.rejected.contains_key(&peer) && self.rejected.len() >= MAX_REJECTED_PEERS {
+            let features = &self.features;
+            self.rejected.retain(|peer, _| features.contains_key(peer));
+            if self.rejected.len() >= MAX_REJECTED_PEERS {
+                let evict = *self.rejected.keys().next().unwrap();
+                self.rejected.remove(&evict);
             }
         }
-        for peer in peers {
-            unwrap!(self.request_unjoin(&peer, *doc));
+        self.rejected.entry(peer).or_default()
+    }
+
+    /// Returns `false` if a message of `size` bytes exceeds [`SyncLimits::max_causal_size`].
+    fn admit_size(&mut self, peer: PeerId, size: usize) -> bool {
+        if size <= self.limits.max_causal_size {
+            return true;
+        }
+        tracing::warn!("dropping message of {} bytes from {}", size, peer);
+        self.rejected_mut(peer).oversized += 1;
+        false
+    }
+
+    /// Presents a capability token to the peers of the document. Peers accepting the token
+    /// are asked for an unjoin once. At most [`MAX_DOC_TOKENS`] unexpired tokens are kept per
+    /// document and presented to peers connecting later.
+    pub fn present_token(&mut self, token: Token) -> Result<()> {
+        let doc = token.doc()?;
+        let tokens = self.tokens.entry(doc).or_default();
+        tokens.retain(|t| !t.is_expired() && *t != token);
+        if tokens.len() >= MAX_DOC_TOKENS {
+            tokens.remove(0);
+        }
+        tokens.push(token.clone());
+        for peer in self.topic_peers(&doc) {
+            tracing::debug!("present_token {} {}", peer, doc);
+            self.send_token(&peer, doc, token.clone());
+        }
+        Ok(())
+    }
+
+    /// Answers a rejected request of `peer` with the reason.
+    fn reject(
+        &mut self,
+        peer: &PeerId,
+        channel: ResponseChannel<Ref<SyncResponse>>,
+        err: anyhow::Error,
+    ) {
+        tracing::warn!("rejected request of {}: {}", peer, err);
+        let resp = Ref::archive(&SyncResponse::Error(err.to_string()));
+        self.req.send_response(channel, resp).ok();
+    }
+
+    fn send_token(&mut self, peer: &PeerId, doc: DocId, token: Token) {
+        let peer_id = peer.to_libp2p().to_peer_id();
+        let id = token.id();
+        let req = SyncRequest::Token(token);
+        let req_id = self.req.send_request(&peer_id, Ref::archive(&req));
+        self.token_req.insert(req_id, (doc, id));
+    }
+
+    /// Adds a capability token presented by `peer`. If the local peer can delegate the
+    /// permission it is recorded as a policy statement and broadcast.
+    fn accept_token(&mut self, peer: &PeerId, token: Token) -> Result<DocId> {
+        // verifies the token before it is used.
+        self.backend.frontend().add_token(token.clone())?;
+        let doc = token.doc()?;
+        if self.backend.contains(&doc)? {
+            let handle = self.backend.frontend().doc(doc)?;
+            if let Some(causal) = handle.redeem_token(&token, peer)? {
+                let entry = handle.apply_journaled(&causal)?;
+                self.broadcast(&doc, causal, Some(entry))?;
+            }
+        }
+        Ok(doc)
+    }
+
+    /// Only subscribes to the broadcasts of the top-level `fields` of a document. Passing `None`
+    /// subscribes to all broadcasts again. While a peer sharing the document doesn't support
+    /// sharding all broadcasts are received.
+    pub fn set_shards(&mut self, doc: &DocId, fields: Option<BTreeSet<String>>) {
+        let prev = self.doc_topics(doc);
+        match fields {
+            Some(fields) => {
+                self.shards.insert(*doc, fields);
+            }
+            None => {
+                self.shards.remove(doc);
+                self.shard_fallback.remove(doc);
+            }
+        }
+        self.update_topics(doc, prev);
+    }
+
+    /// Subscribes to the full topic of a sharded document while a peer sharing it doesn't
+    /// support sharding. Peers are assumed not to support it until they told us otherwise.
+    fn update_shard_fallback(&mut self, doc: &DocId) {
+        if !self.shards.contains_key(doc) {
+            return;
+        }
+        let fallback = self.peer_docs.iter().any(|(peer, docs)| {
+            docs.contains(doc)
+                && self.features.get(peer).copied().unwrap_or_default() & FEATURE_SHARDING == 0
+        });
+        let prev = self.doc_topics(doc);
+        if fallback {
+            self.shard_fallback.insert(*doc);
+        } else {
+            self.shard_fallback.remove(doc);
+        }
+        self.update_topics(doc, prev);
+    }
+
+    fn update_topics(&mut self, doc: &DocId, prev: Vec<Topic>) {
+        if !self.backend.contains(doc).unwrap_or_default() {
+            return;
+        }
+        let topics = self.doc_topics(doc);
+        for topic in prev.iter().filter(|topic| !topics.contains(topic)) {
+            self.broadcast.unsubscribe(topic);
+        }
+        for topic in topics.into_iter().filter(|topic| !prev.contains(topic)) {
+            self.broadcast.subscribe(topic);
         }
     }
 
-    pub fn invite(&mut self, peer_id: &PeerId, doc: DocId, schema: String) -> RequestId {
-        tracing::debug!("invite {} {}", peer_id, doc);
+    pub fn invite(&mut self, peer_id: &PeerId, invite: InviteRequest) -> RequestId {
+        tracing::debug!("invite {} {}", peer_id, invite.doc);
         let peer_id = peer_id.to_libp2p().to_peer_id();
-        let req = SyncRequest::Invite(doc, schema);
+        let req = SyncRequest::Invite(invite);
         self.req.send_request(&peer_id, Ref::archive(&req))
     }
 
+    /// Returns the schema of a document and the changes a peer with `ctx` is missing, without
+    /// going through the network.
+    pub fn export_delta(
+        &self,
+        peer: &PeerId,
+        doc: &DocId,
+        ctx: &CausalContext,
+    ) -> Result<(Hash, Causal)> {
+        let schema = self.backend.frontend().schema(doc)?.as_ref().hash();
+        let causal = self.backend.unjoin(peer, doc, Ref::archive(ctx).as_ref())?;
+        Ok((schema, causal))
+    }
+
+    /// Applies changes received from a peer outside of the network. Changes are validated and
+    /// checked against the acl like changes received through a broadcast.
+    pub fn import_delta(
+        &mut self,
+        peer: PeerId,
+        doc: DocId,
+        schema: Hash,
+        causal: Causal,
+    ) -> Result<()> {
+        if !self.backend.contains(&doc)? {
+            bail!("unknown doc {}", doc);
+        }
+        self.inject_causal(peer, doc, schema, causal)?;
+        Ok(())
+    }
+
+    /// Serializes the full state of a document for backups.
+    pub fn export_snapshot(&self, doc: &DocId) -> Result<Vec<u8>> {
+        self.backend.export_snapshot(doc)
+    }
+
+    /// Restores a document from a snapshot and subscribes to it.
+    pub fn import_snapshot(&mut self, snapshot: &[u8]) -> Result<DocId> {
+        let doc = self.backend.import_snapshot(snapshot)?;
+        self.subscribe(&doc);
+        Ok(doc)
+    }
+
+    /// Registers a lens package and applies the buffered changes that were waiting for it.
+    pub fn add_package(&mut self, package: &[u8]) -> Result<Hash> {
+        let hash = self.backend.add_package(package)?;
+        let denied = self.backend.join_pending()?;
+        self.inject_denied(denied);
+        Ok(hash)
+    }
+
     pub fn clear_invites(&mut self) -> Vec<Invite> {
         std::mem::take(&mut self.invites)
     }
 
-    pub fn broadcast(&mut self, doc: &DocId, causal: Causal) -> Result<()> {
+    /// Queues a broadcast of a local change. The journal `entry` of the change is removed once
+    /// a peer acknowledged the broadcast.
+    pub fn broadcast(&mut self, doc: &DocId, mut causal: Causal, entry: Option<Dot>) -> Result<()> {
+        let id = causal.id();
+        self.backend.remove_local(doc, &mut causal)?;
+        self.deliveries.track(id, *doc, causal.ctx());
+        if causal.is_empty() {
+            if let Some(entry) = entry {
+                self.backend.frontend().journal_remove(doc, &entry)?;
+            }
+            return Ok(());
+        }
         let topic = Topic::new(doc.as_ref());
         let hash = self.backend.frontend().schema(doc)?.as_ref().hash();
         let delta = Delta {
             schema: hash.into(),
-            causal,
+            causal: CompressedCausal::from(&causal),
         };
         let delta = Ref::archive(&delta);
-        tracing::debug!("sending broadcast");
-        self.broadcast.broadcast(&topic, delta.as_bytes().into());
+        self.recent_deltas.insert(delta.as_bytes());
+        self.deliveries.insert(id, delta.as_bytes(), causal.ctx());
+        let prio = self.priority(doc);
+        self.broadcast_queue
+            .push(prio, *doc, (topic, delta.as_bytes().to_vec()));
+        // peers subscribed to sub-topics only receive the paths of their fields. Paths not below
+        // a field, like policies, are sent with every field so that they are applied first.
+        let (root, fields) = causal.split_fields();
+        let mut shards = vec![(shard_topic(doc, None), root.clone())];
+        shards.extend(fields.into_iter().map(|(field, mut causal)| {
+            causal.join(&root);
+            (shard_topic(doc, Some(&field)), causal)
+        }));
+        for (topic, causal) in shards {
+            let subscribed = self
+                .broadcast
+                .peers(&topic)
+                .map(|mut peers| peers.next().is_some())
+                .unwrap_or_default();
+            if !subscribed || causal.is_empty() {
+                continue;
+            }
+            let delta = Delta {
+                schema: hash.into(),
+                causal: CompressedCausal::from(&causal),
+            };
+            let delta = Ref::archive(&delta);
+            self.recent_deltas.insert(delta.as_bytes());
+            self.deliveries.insert(id, delta.as_bytes(), causal.ctx());
+            self.broadcast_queue
+                .push(prio, *doc, (topic, delta.as_bytes().to_vec()));
+        }
+        Ok(())
+    }
+
+    /// Returns the peers known to have applied the local change with transaction id `id`. The
+    /// peers report what they have with acknowledgements of broadcasts and after unjoins.
+    pub fn delivered(&self, id: &Dot) -> Result<BTreeSet<PeerId>> {
+        let (doc, ctx) = self
+            .deliveries
+            .change(id)
+            .ok_or_else(|| anyhow::anyhow!("change {:?} isn't tracked", id))?;
+        Ok(self
+            .sync_state
+            .iter()
+            .filter(|((d, _), _)| d == doc)
+            .filter(|(_, state)| {
+                state
+                    .ctx
+                    .as_ref()
+                    .map(|known| ctx.missing(known) == 0)
+                    .unwrap_or_default()
+            })
+            .map(|((_, peer), _)| *peer)
+            .collect())
+    }
+
+    /// Acknowledges a broadcast that was applied, if the peer that sent it supports it.
+    fn ack(&mut self, peer: PeerId, doc: DocId, msg: &[u8]) {
+        if self.features.get(&peer).copied().unwrap_or_default() & FEATURE_ACK == 0 {
+            return;
+        }
+        let req = SyncRequest::Ack(doc, *blake3::hash(msg).as_bytes());
+        self.req
+            .send_request(&peer.to_libp2p().to_peer_id(), Ref::archive(&req));
+    }
+
+    /// Reports the context of a document to `peer` after applying an unjoin from it, so that
+    /// it knows which changes arrived.
+    fn report_synced(&mut self, peer: PeerId, doc: DocId) -> Result<()> {
+        if self.features.get(&peer).copied().unwrap_or_default() & FEATURE_SYNCED == 0 {
+            return Ok(());
+        }
+        let ctx = self.backend.frontend().ctx(&doc)?;
+        let req = SyncRequest::Synced(doc, ctx);
+        self.req
+            .send_request(&peer.to_libp2p().to_peer_id(), Ref::archive(&req));
         Ok(())
     }
 
@@ -323,20 +1947,99 @@ impl Behaviour {
         peer: PeerId,
         doc: DocId,
         schema: Hash,
-        causal: Causal,
-    ) -> Result<()> {
-        if self.backend.registry().contains(&schema) {
-            self.backend.join(&peer, &doc, &schema, causal)?;
+        mut causal: Causal,
+    ) -> Result<bool> {
+        tracing::debug!("{:?} from {} for {}", causal, peer, doc);
+        if self.is_blocked(&peer) {
+            tracing::debug!("dropping change of blocked peer {}", peer);
+            return Ok(false);
+        }
+        // changes of blocked peers are dropped when relayed by other peers as well.
+        causal.remove_signed_by(&self.blocked);
+        let res = if self.backend.registry().contains(&schema) {
+            self.join_causal(peer, doc, schema, causal)
         } else {
-            self.buffer.push((schema, doc, peer, causal));
-            self.request_lenses(&peer, schema);
+            let buffered = self
+                .backend
+                .pending()
+                .into_iter()
+                .filter(|(s, p)| *p == peer && *s != schema)
+                .count();
+            if buffered >= self.limits.max_buffered_lens_requests {
+                self.rejected_mut(peer).lens_requests += 1;
+                return Err(anyhow::anyhow!(
+                    "not buffering change of {} waiting for {} lenses",
+                    peer,
+                    buffered
+                ));
+            }
+            let res = self.backend.buffer(&peer, &doc, &schema, &causal);
+            if res.is_ok() {
+                self.request_lenses(&peer, schema);
+            }
+            res.map(|_| false)
+        };
+        if let Some(reason) = res.as_ref().err().and_then(|err| err.downcast_ref()) {
+            tracing::warn!("rejected change of {} to {}: {}", peer, doc, reason);
+            self.emit(SdkEvent::ChangeRejected(peer, doc, *reason));
+        }
+        res
+    }
+
+    /// Joins a change of `peer`. Returns `false` if parts of it were denied.
+    fn join_causal(
+        &mut self,
+        peer: PeerId,
+        doc: DocId,
+        schema: Hash,
+        causal: Causal,
+    ) -> Result<bool> {
+        let members = causal.members().count() > 0;
+        let changed = !causal.is_empty();
+        let denied = self.backend.join(&peer, &doc, &schema, causal)?;
+        let applied = denied.is_empty();
+        self.inject_denied(denied);
+        if members {
+            self.subscribe_members(&doc)?;
+        }
+        if changed {
+            self.remote_changes.insert(doc);
+            self.emit(SdkEvent::DocChanged(doc));
+        }
+        Ok(applied)
+    }
+
+    /// Retries the failed request `id` with other peers.
+    fn request_failed(&mut self, id: &RequestId) {
+        if let Some((peer, doc)) = self.unjoin_req.remove(id) {
+            self.complete_sync_once(id);
+            self.unjoin_failed.entry(doc).or_default().insert(peer);
+            self.request_unjoin_fallback(doc);
+        }
+        if let Some((_, hash)) = self.lens_req.remove(id) {
+            self.request_lenses_fallback(hash);
+        }
+        self.features_req.remove(id);
+    }
+
+    /// Adds and subscribes to the members of a workspace that aren't known locally.
+    fn subscribe_members(&mut self, workspace: &DocId) -> Result<()> {
+        let frontend = self.backend.frontend();
+        let peer = frontend.peer_id(workspace)?;
+        for (member, schema) in frontend.doc(*workspace)?.members() {
+            if frontend.schema(&member).is_ok() {
+                continue;
+            }
+            tracing::debug!("subscribing to member {} of {}", member, workspace);
+            frontend.add_doc(member, &peer, &schema)?;
+            self.subscribe(&member);
         }
         Ok(())
     }
 
     fn poll_dial(
         &mut self,
-        _cx: &mut Context,
+        cx: &mut Context,
         _params: &mut impl PollParameters,
     ) -> Poll<
         NetworkBehaviourAction<
@@ -344,6 +2047,25 @@ impl Behaviour {
             <Self as NetworkBehaviour>::ProtocolsHandler,
         >,
     > {
+        let mut progress = !self.broadcast_queue.is_empty();
+        while let Some((_, (topic, delta))) = self.broadcast_queue.pop() {
+            tracing::debug!("sending broadcast");
+            self.broadcast.broadcast(&topic, delta.into());
+            self.backend.metrics().record_broadcast();
+        }
+        while self.unjoin_req.len() < MAX_UNJOINS {
+            if let Some((doc, peer)) = self.unjoin_queue.pop() {
+                if let Err(err) = self.request_unjoin(&peer, doc) {
+                    tracing::error!("{}", err);
+                }
+                progress = true;
+            } else {
+                break;
+            }
+        }
+        if progress {
+            cx.waker().wake_by_ref();
+        }
         if let Some(peer) = self.dial.pop_front() {
             Poll::Ready(NetworkBehaviourAction::Dial {
                 opts: DialOpts::peer_id(peer.to_libp2p().to_peer_id())
@@ -363,23 +2085,67 @@ impl NetworkBehaviourEventProcess<BroadcastEvent> for Behaviour {
         match ev {
             Subscribed(peer, topic) => {
                 let peer = unwrap!(libp2p_peer_id(&peer));
-                let doc = DocId::new(topic.as_ref().try_into().unwrap());
+                let doc = unwrap!(topic_doc(&topic));
                 tracing::debug!("{} subscribed to {}", peer, doc);
-                if unwrap!(self.backend.contains(&doc)) {
-                    unwrap!(self.request_unjoin(&peer, doc));
+                for (schema, pending) in self.backend.pending() {
+                    if pending == peer {
+                        self.request_lenses(&peer, schema);
+                    }
+                }
+                self.peer_docs.entry(peer).or_default().insert(doc);
+                self.update_shard_fallback(&doc);
+                self.emit(SdkEvent::SyncStatusChanged(doc));
+                // broadcasts sent while disconnected were missed, so a reconnecting peer is
+                // always asked for an unjoin.
+                let stale = self
+                    .stale
+                    .get_mut(&peer)
+                    .map(|docs| docs.remove(&doc))
+                    .unwrap_or_default();
+                if unwrap!(self.backend.contains(&doc))
+                    && (stale || self.pending_unjoins(&doc) < self.unjoin_redundancy)
+                {
+                    self.schedule_unjoin(peer, doc);
                 }
             }
             Received(peer, topic, msg) => {
                 tracing::debug!("received broadcast");
                 let peer = unwrap!(libp2p_peer_id(&peer));
-                let doc = DocId::new(topic.as_ref().try_into().unwrap());
-                let delta = unwrap!(unwrap!(Ref::<Delta>::checked(&msg)).to_owned());
-                unwrap!(self.inject_causal(peer, doc, delta.schema.into(), delta.causal));
+                if self.is_blocked(&peer) {
+                    tracing::debug!("dropping broadcast of blocked peer {}", peer);
+                    return;
+                }
+                self.seen(peer);
+                if !self.admit(peer) || !self.admit_size(peer, msg.len()) {
+                    return;
+                }
+                if !self.recent_deltas.insert(&msg) {
+                    tracing::debug!("dropping duplicate broadcast");
+                    return;
+                }
+                let doc = unwrap!(topic_doc(&topic));
+                let delta = unwrap!(Ref::<Delta>::checked(&msg));
+                let causal = unwrap!(delta
+                    .as_ref()
+                    .causal
+                    .decompress(self.max_decompressed_size()));
+                let schema: Hash = delta.as_ref().schema.into();
+                self.record_delta(peer, doc, &causal.ctx());
+                // changes waiting for lenses or denied in parts aren't acknowledged.
+                let applied = unwrap!(self.inject_causal(peer, doc, schema, causal));
+                if applied {
+                    self.ack(peer, doc, &msg);
+                }
             }
             Unsubscribed(peer, topic) => {
                 let peer = unwrap!(libp2p_peer_id(&peer));
-                let doc = DocId::new(topic.as_ref().try_into().unwrap());
+                let doc = unwrap!(topic_doc(&topic));
                 tracing::debug!("{} unsubscribed from {}", peer, doc);
+                if let Some(docs) = self.peer_docs.get_mut(&peer) {
+                    docs.remove(&doc);
+                }
+                self.update_shard_fallback(&doc);
+                self.emit(SdkEvent::SyncStatusChanged(doc));
             }
         }
     }
@@ -388,6 +2154,41 @@ impl NetworkBehaviourEventProcess<BroadcastEvent> for Behaviour {
 impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
     fn inject_event(&mut self, ev: RequestResponseEvent) {
         use request_response::{RequestResponseEvent::*, RequestResponseMessage::*};
+        if let Message { peer, message } = &ev {
+            let peer = unwrap!(libp2p_peer_id(peer));
+            if self.is_blocked(&peer) {
+                tracing::debug!("dropping message of blocked peer {}", peer);
+                return;
+            }
+            self.seen(peer);
+            let (admitted, size, request_id) = match message {
+                Request { request, .. } => (self.admit(peer), request.as_bytes().len(), None),
+                Response {
+                    request_id,
+                    response,
+                } => (true, response.as_bytes().len(), Some(*request_id)),
+            };
+            let sized = admitted && self.admit_size(peer, size);
+            if !sized {
+                // rejected requests are answered with the reason, dropped responses are
+                // requested from other peers.
+                if let Message {
+                    message: Request { channel, .. },
+                    ..
+                } = ev
+                {
+                    let err = if admitted {
+                        anyhow::anyhow!("request exceeds {} bytes", self.limits.max_causal_size)
+                    } else {
+                        anyhow::anyhow!("rate limited")
+                    };
+                    self.reject(&peer, channel, err);
+                } else if let Some(request_id) = request_id {
+                    self.request_failed(&request_id);
+                }
+                return;
+            }
+        }
         match ev {
             Message { peer, message } => match message {
                 Request {
@@ -398,12 +2199,15 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
                     tracing::debug!("req {:?}", request.as_ref());
                     use ArchivedSyncRequest as SyncRequest;
                     match request.as_ref() {
-                        SyncRequest::Invite(doc, schema) => {
+                        SyncRequest::Invite(invite) => {
                             self.invites.push(Invite {
-                                doc: *doc,
-                                schema: schema.to_string(),
+                                doc: invite.doc,
+                                schema: invite.schema.to_string(),
+                                inviter: unwrap!(libp2p_peer_id(&peer)),
+                                message: invite.message.as_ref().map(|msg| msg.to_string()),
+                                permission: invite.permission.as_ref().copied(),
                             });
-                            notify(&mut self.sub_invites);
+                            self.emit(SdkEvent::InviteReceived);
                             let resp = SyncResponse::Invite;
                             let resp = Ref::archive(&resp);
                             self.req.send_response(channel, resp).ok();
@@ -421,10 +2225,95 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
                             let schema =
                                 unwrap!(self.backend.frontend().schema(doc)).as_ref().hash();
                             let causal = unwrap!(self.backend.unjoin(&peer, doc, ctx));
-                            let resp = SyncResponse::Unjoin(schema.into(), causal);
+                            let ctx = unwrap!(ctx.deserialize(&mut rkyv::Infallible));
+                            self.record_unjoin(peer, *doc, Some(ctx));
+                            let resp = SyncResponse::Unjoin(
+                                schema.into(),
+                                CompressedCausal::from(&causal),
+                            );
+                            let resp = Ref::archive(&resp);
+                            self.req.send_response(channel, resp).ok();
+                        }
+                        SyncRequest::UnjoinPage(doc, ctx, after) => {
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            let schema =
+                                unwrap!(self.backend.frontend().schema(doc)).as_ref().hash();
+                            let after = after.as_ref().map(|after| after.as_slice());
+                            let (page, next) = unwrap!(self.backend.unjoin_page(
+                                &peer,
+                                doc,
+                                ctx,
+                                after,
+                                UNJOIN_PAGE_SIZE
+                            ));
+                            let ctx = unwrap!(ctx.deserialize(&mut rkyv::Infallible));
+                            self.record_unjoin(peer, *doc, Some(ctx));
+                            let resp = SyncResponse::UnjoinPage(
+                                schema.into(),
+                                CompressedCausal::from(&page),
+                                next,
+                            );
                             let resp = Ref::archive(&resp);
                             self.req.send_response(channel, resp).ok();
                         }
+                        SyncRequest::Token(token) => {
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            let token: Token = unwrap!(token.deserialize(&mut rkyv::Infallible));
+                            match self.accept_token(&peer, token) {
+                                Ok(doc) => {
+                                    let resp = Ref::archive(&SyncResponse::Token(doc));
+                                    self.req.send_response(channel, resp).ok();
+                                }
+                                Err(err) => self.reject(&peer, channel, err),
+                            }
+                        }
+                        SyncRequest::Features(_) => {
+                            let resp = Ref::archive(&SyncResponse::Features(FEATURES));
+                            self.req.send_response(channel, resp).ok();
+                        }
+                        SyncRequest::Ack(doc, hash) => {
+                            let resp = Ref::archive(&SyncResponse::Ack);
+                            self.req.send_response(channel, resp).ok();
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            if let Some((doc, id, ctx)) = self.deliveries.ack(doc, hash) {
+                                let ctx = ctx.clone();
+                                // the journal entry of a local change is its id.
+                                if let Err(err) = self.backend.frontend().journal_remove(&doc, &id)
+                                {
+                                    tracing::error!("{}", err);
+                                }
+                                self.record_delta(peer, doc, &ctx);
+                                self.emit(SdkEvent::Delivered(doc, id));
+                            }
+                        }
+                        SyncRequest::Synced(doc, ctx) => {
+                            let resp = Ref::archive(&SyncResponse::Ack);
+                            self.req.send_response(channel, resp).ok();
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            let ctx = unwrap!(ctx.deserialize(&mut rkyv::Infallible));
+                            self.record_unjoin(peer, *doc, Some(ctx));
+                        }
+                        SyncRequest::Denied(doc, notices) => {
+                            let resp = Ref::archive(&SyncResponse::Denied);
+                            self.req.send_response(channel, resp).ok();
+                            let local = unwrap!(self.backend.frontend().peer_id(doc));
+                            for notice in notices.iter() {
+                                // notices come from the remote peer, drop malformed paths.
+                                let prefix = match Path::checked(&notice.prefix) {
+                                    Ok(prefix) => prefix.to_owned(),
+                                    Err(err) => {
+                                        tracing::debug!("invalid denied notice: {}", err);
+                                        continue;
+                                    }
+                                };
+                                self.push_denied(Denied {
+                                    doc: *doc,
+                                    prefix,
+                                    peer: local,
+                                    permission: notice.permission,
+                                });
+                            }
+                        }
                     }
                 }
                 Response {
@@ -434,31 +2323,84 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
                     tracing::debug!("resp {:?}", response.as_ref());
                     use ArchivedSyncResponse::*;
                     match response.as_ref() {
-                        Invite => {}
+                        Invite | ArchivedSyncResponse::Denied | Ack => {}
                         Lenses(lenses) => {
-                            let schema2 = unwrap!(self.backend.registry().register(lenses));
-                            self.buffer.retain(|(schema, doc, peer, causal)| {
-                                if *schema == schema2 {
-                                    if let Err(err) =
-                                        self.backend.join(peer, doc, schema, causal.clone())
-                                    {
-                                        tracing::error!("{}", err);
-                                    }
-                                    false
-                                } else {
-                                    true
-                                }
-                            });
+                            let (_, hash) = unwrap!(self.lens_req.remove(&request_id).ok_or_else(
+                                || anyhow::anyhow!("received response without request")
+                            ));
+                            if blake3::hash(lenses) != hash {
+                                tracing::warn!("{} sent lenses not matching {}", peer, hash);
+                                self.request_lenses_fallback(hash);
+                                return;
+                            }
+                            self.lens_requests.remove(&hash);
+                            unwrap!(self.backend.registry().register(lenses));
+                            let denied = unwrap!(self.backend.join_pending());
+                            self.inject_denied(denied);
                         }
                         Unjoin(schema, causal) => {
                             let schema = Hash::from(*schema);
                             let peer = unwrap!(libp2p_peer_id(&peer));
-                            let causal = unwrap!(causal.deserialize(&mut rkyv::Infallible));
+                            let causal = unwrap!(causal.decompress(self.max_decompressed_size()));
                             let res = self.unjoin_req.remove(&request_id).ok_or_else(|| {
                                 anyhow::anyhow!("received response without request")
                             });
-                            let doc = unwrap!(res);
+                            let (_, doc) = unwrap!(res);
+                            self.complete_sync_once(&request_id);
+                            self.unjoin_failed.remove(&doc);
+                            self.record_unjoin(peer, doc, None);
+                            self.record_delta(peer, doc, &causal.ctx());
                             unwrap!(self.inject_causal(peer, doc, schema, causal));
+                            unwrap!(self.report_synced(peer, doc));
+                            self.emit(SdkEvent::SyncCompleted(doc));
+                        }
+                        UnjoinPage(schema, causal, next) => {
+                            let schema = Hash::from(*schema);
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            let causal = unwrap!(causal.decompress(self.max_decompressed_size()));
+                            let res = self.unjoin_req.remove(&request_id).ok_or_else(|| {
+                                anyhow::anyhow!("received response without request")
+                            });
+                            let (_, doc) = unwrap!(res);
+                            self.unjoin_failed.remove(&doc);
+                            self.record_unjoin(peer, doc, None);
+                            self.record_delta(peer, doc, &causal.ctx());
+                            let res = self.inject_causal(peer, doc, schema, causal);
+                            match next.as_ref() {
+                                Some(next) if res.is_ok() => {
+                                    let next = Some(next.to_vec());
+                                    let res = self.request_unjoin_page(&peer, doc, next);
+                                    let next = unwrap!(res);
+                                    self.continue_sync_once(&request_id, next);
+                                }
+                                _ => {
+                                    self.complete_sync_once(&request_id);
+                                    unwrap!(res);
+                                    unwrap!(self.report_synced(peer, doc));
+                                    self.emit(SdkEvent::SyncCompleted(doc));
+                                }
+                            }
+                        }
+                        Token(_) => {
+                            // the unjoin sent with the token may have been answered before
+                            // the token was added, so unjoin once more.
+                            let peer = unwrap!(libp2p_peer_id(&peer));
+                            if let Some((doc, id)) = self.token_req.remove(&request_id) {
+                                self.tokens_accepted.entry(peer).or_default().insert(id);
+                                unwrap!(self.request_unjoin_page(&peer, doc, None));
+                            }
+                        }
+                        Error(err) => {
+                            tracing::warn!("{} rejected request: {}", peer, err);
+                            self.token_req.remove(&request_id);
+                        }
+                        Features(features) => {
+                            if let Some(peer) = self.features_req.remove(&request_id) {
+                                self.features.insert(peer, *features);
+                                for doc in self.shards.keys().copied().collect::<Vec<_>>() {
+                                    self.update_shard_fallback(&doc);
+                                }
+                            }
                         }
                     }
                 }
@@ -468,8 +2410,98 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
                 request_id,
                 error,
             } => {
-                self.unjoin_req.remove(&request_id);
                 tracing::error!("{}", error);
+                self.request_failed(&request_id);
+            }
+            InboundFailure {
+                peer: _,
+                request_id: _,
+                error,
+            } => {
+                tracing::error!("{}", error);
+            }
+            ResponseSent {
+                peer: _,
+                request_id: _,
+            } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<AttachmentEvent> for Behaviour {
+    fn inject_event(&mut self, ev: AttachmentEvent) {
+        use request_response::{RequestResponseEvent::*, RequestResponseMessage::*};
+        match ev {
+            Message { peer, message } => {
+                let peer = unwrap!(libp2p_peer_id(&peer));
+                if self.is_blocked(&peer) {
+                    tracing::debug!("dropping message of blocked peer {}", peer);
+                    return;
+                }
+                self.seen(peer);
+                match message {
+                    Request {
+                        request_id: _,
+                        request,
+                        channel,
+                    } => {
+                        if !self.admit(peer) {
+                            return;
+                        }
+                        let doc = request.as_ref().doc;
+                        let hash = Hash::from(request.as_ref().hash);
+                        tracing::debug!("{} requested attachment {} of {}", peer, hash, doc);
+                        let frontend = self.backend.frontend();
+                        let data = if frontend.attachments().is_attached(&doc, &hash)
+                            && unwrap!(self.can_read(&peer, &doc))
+                        {
+                            unwrap!(frontend.attachments().get(&hash))
+                        } else {
+                            None
+                        };
+                        let resp = AttachmentResponse(data.map(|data| data.to_vec()));
+                        let resp = Ref::archive(&resp);
+                        self.attachments.send_response(channel, resp).ok();
+                    }
+                    Response {
+                        request_id,
+                        response,
+                    } => {
+                        let res = self
+                            .attachment_req
+                            .remove(&request_id)
+                            .ok_or_else(|| anyhow::anyhow!("received response without request"));
+                        let (_, doc, hash) = unwrap!(res);
+                        let data = match response.as_ref().0.as_ref() {
+                            Some(data) => data,
+                            None => {
+                                self.request_attachment_fallback(doc, hash);
+                                return;
+                            }
+                        };
+                        let attachments = self.backend.frontend().attachments().clone();
+                        if let Err(err) = attachments.insert_verified(&doc, &hash, data) {
+                            tracing::warn!("{} sent an invalid attachment: {}", peer, err);
+                            self.request_attachment_fallback(doc, hash);
+                            return;
+                        }
+                        if let Some(fetch) = self.attachment_fetches.remove(&(doc, hash)) {
+                            for tx in fetch.waiting {
+                                tx.send(Ok(data.to_vec())).ok();
+                            }
+                        }
+                    }
+                }
+            }
+            OutboundFailure {
+                peer: _,
+                request_id,
+                error,
+            } => {
+                tracing::error!("{}", error);
+                if let Some((_, doc, hash)) = self.attachment_req.remove(&request_id) {
+                    self.request_attachment_fallback(doc, hash);
+                }
             }
             InboundFailure {
                 peer: _,
@@ -487,24 +2519,47 @@ impl NetworkBehaviourEventProcess<RequestResponseEvent> for Behaviour {
 }
 
 impl NetworkBehaviourEventProcess<ping::Event> for Behaviour {
-    fn inject_event(&mut self, _event: ping::Event) {}
+    fn inject_event(&mut self, event: ping::Event) {
+        let peer = unwrap!(libp2p_peer_id(&event.peer));
+        match event.result {
+            Ok(ping::Success::Ping { rtt }) => {
+                self.peer_info.entry(peer).or_default().rtt = Some(rtt);
+                self.seen(peer);
+            }
+            Ok(ping::Success::Pong) => self.seen(peer),
+            Err(err) => {
+                tracing::debug!("ping {} failed: {}", peer, err);
+                self.peer_info.entry(peer).or_default().failures += 1;
+                self.emit(SdkEvent::PeerInfoChanged(peer));
+            }
+        }
+    }
 }
 
-#[cfg(not(target_family = "wasm"))]
+#[cfg(all(feature = "mdns", not(target_family = "wasm")))]
 impl NetworkBehaviourEventProcess<mdns::MdnsEvent> for Behaviour {
     fn inject_event(&mut self, event: mdns::MdnsEvent) {
-        if let mdns::MdnsEvent::Discovered(iter) = event {
-            for (peer, _) in iter {
-                if let Ok(peer) = libp2p_peer_id(&peer) {
-                    // TODO: handle becomes active after discovery
-                    if self.backend.active_peer(&peer) {
-                        tracing::info!("dialing active peer {}", peer);
-                        self.dial.push_back(peer);
+        match event {
+            mdns::MdnsEvent::Discovered(iter) => {
+                for (peer, addr) in iter {
+                    if let Ok(peer) = libp2p_peer_id(&peer) {
+                        self.learn_address(&peer, addr, PeerSource::Mdns);
+                        // TODO: handle becomes active after discovery
+                        if self.backend.active_peer(&peer) {
+                            tracing::info!("dialing active peer {}", peer);
+                            self.dial.push_back(peer);
+                        }
+                    }
+                }
+            }
+            mdns::MdnsEvent::Expired(iter) => {
+                for (peer, addr) in iter {
+                    if let Ok(peer) = libp2p_peer_id(&peer) {
+                        self.forget_address(&peer, &addr, PeerSource::Mdns);
                     }
                 }
             }
         }
-        notify(&mut self.sub_local_peers);
     }
 }
 
@@ -559,3 +2614,57 @@ pub fn libp2p_peer_id(peer_id: &libp2p::PeerId) -> Result<PeerId> {
         Err(err) => bail!(err),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_info() {
+        let mut info = PeerInfo::default();
+        assert!(!info.is_reachable());
+        info.last_seen = Some(Instant::now());
+        assert!(info.is_reachable());
+        info.failures = 1;
+        assert!(!info.is_reachable());
+    }
+
+    #[test]
+    fn test_priority_queue() {
+        let (a, b, c) = (
+            DocId::new([1; 32]),
+            DocId::new([2; 32]),
+            DocId::new([3; 32]),
+        );
+        let mut queue = PriorityQueue::default();
+        queue.push(Priority::Normal, a, 1);
+        queue.push(Priority::Low, b, 2);
+        queue.push(Priority::High, c, 3);
+        queue.push(Priority::Normal, a, 4);
+        queue.push(Priority::Low, b, 5);
+
+        queue.reprioritize(&b, Priority::High);
+        queue.remove(&c);
+        let mut items = vec![];
+        while let Some(item) = queue.pop() {
+            items.push(item);
+        }
+        assert_eq!(items, vec![(b, 2), (b, 5), (a, 1), (a, 4)]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn test_deliveries_ack() {
+        let (a, b) = (DocId::new([1; 32]), DocId::new([2; 32]));
+        let id = Dot::new([1; 32]);
+        let mut deliveries = Deliveries::default();
+        deliveries.track(id, a, CausalContext::new());
+        deliveries.insert(id, b"delta", CausalContext::new());
+        let hash = *blake3::hash(b"delta").as_bytes();
+
+        assert!(deliveries.ack(&b, &hash).is_none());
+        assert!(deliveries.ack(&a, &[0; 32]).is_none());
+        let (doc, acked, _) = deliveries.ack(&a, &hash).unwrap();
+        assert_eq!((doc, acked), (a, id));
+    }
+}
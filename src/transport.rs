@@ -4,15 +4,23 @@ use libp2p::{
     identity, PeerId,
 };
 
-pub fn transport(keypair: identity::Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+/// Builds the transport. The webrtc transport is only included if `webrtc` is set and the
+/// `transport-webrtc` feature is enabled.
+pub fn transport(
+    keypair: identity::Keypair,
+    webrtc: bool,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
     #[cfg(target_arch = "wasm32")]
-    return wasm_transport(keypair);
+    return wasm_transport(keypair, webrtc);
     #[cfg(not(target_arch = "wasm32"))]
-    return native_transport(keypair);
+    return native_transport(keypair, webrtc);
 }
 
 #[cfg(not(target_arch = "wasm32"))]
-fn native_transport(keypair: identity::Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+fn native_transport(
+    keypair: identity::Keypair,
+    webrtc: bool,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
     use std::time::Duration;
 
     use libp2p::{
@@ -23,13 +31,27 @@ fn native_transport(keypair: identity::Keypair) -> Result<Boxed<(PeerId, StreamM
         yamux::YamuxConfig,
         Transport,
     };
-    use libp2p_webrtc::WebRtcTransport;
 
-    let peer_id = PeerId::from(keypair.public());
-    let webrtc = WebRtcTransport::new(peer_id, vec!["stun:stun.l.google.com:19302"]);
     let tcp = TcpConfig::new().nodelay(true);
     let dns = TokioDnsConfig::custom(tcp, ResolverConfig::cloudflare(), Default::default())?;
-    let transport = core::transport::OrTransport::new(webrtc, dns);
+    #[cfg(feature = "transport-webrtc")]
+    let transport = {
+        let peer_id = PeerId::from(keypair.public());
+        let webrtc = if webrtc {
+            core::transport::OptionalTransport::some(libp2p_webrtc::WebRtcTransport::new(
+                peer_id,
+                vec!["stun:stun.l.google.com:19302"],
+            ))
+        } else {
+            core::transport::OptionalTransport::none()
+        };
+        core::transport::OrTransport::new(webrtc, dns)
+    };
+    #[cfg(not(feature = "transport-webrtc"))]
+    let transport = {
+        let _ = webrtc;
+        dns
+    };
     let key = noise::Keypair::<X25519Spec>::new().into_authentic(&keypair)?;
     Ok(transport
         .upgrade(Version::V1)
@@ -40,7 +62,10 @@ fn native_transport(keypair: identity::Keypair) -> Result<Boxed<(PeerId, StreamM
 }
 
 #[cfg(target_arch = "wasm32")]
-fn wasm_transport(identity: identity::Keypair) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
+fn wasm_transport(
+    identity: identity::Keypair,
+    webrtc: bool,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>> {
     use std::time::Duration;
 
     use libp2p::{
@@ -49,12 +74,26 @@ fn wasm_transport(identity: identity::Keypair) -> Result<Boxed<(PeerId, StreamMu
         wasm_ext::{ffi, ExtTransport},
         yamux, Transport,
     };
-    use libp2p_webrtc::WebRtcTransport;
 
-    let peer_id = PeerId::from(identity.public());
-    let webrtc = WebRtcTransport::new(peer_id, vec!["stun:stun.l.google.com:19302"]);
     let ws = ExtTransport::new(ffi::websocket_transport());
-    let base = core::transport::OrTransport::new(webrtc, ws);
+    #[cfg(feature = "transport-webrtc")]
+    let base = {
+        let peer_id = PeerId::from(identity.public());
+        let webrtc = if webrtc {
+            core::transport::OptionalTransport::some(libp2p_webrtc::WebRtcTransport::new(
+                peer_id,
+                vec!["stun:stun.l.google.com:19302"],
+            ))
+        } else {
+            core::transport::OptionalTransport::none()
+        };
+        core::transport::OrTransport::new(webrtc, ws)
+    };
+    #[cfg(not(feature = "transport-webrtc"))]
+    let base = {
+        let _ = webrtc;
+        ws
+    };
     let noise_keys = noise::Keypair::<noise::X25519Spec>::new().into_authentic(&identity)?;
 
     Ok(base
@@ -0,0 +1,231 @@
+// Generated by tlfsc from the schema, do not edit.
+
+/// Latest version of the `todoapp` schema.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Todoapp {
+    pub title: Option<String>,
+    pub todos: ::std::collections::BTreeMap<u64, TodoappTodos>,
+}
+
+impl Todoapp {
+    /// Reads the document.
+    pub fn load(doc: &tlfs::Doc) -> ::anyhow::Result<Self> {
+        Self::read(&doc.cursor())
+    }
+
+    /// Applies the changes needed for the document to match `value`.
+    pub fn apply_changes(doc: &tlfs::Doc, value: &Self) -> ::anyhow::Result<()> {
+        let mut causal = tlfs::Causal::default();
+        value.write(&doc.cursor(), &mut causal)?;
+        if !causal.is_empty() {
+            doc.apply(causal)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the value at `cursor`.
+    pub fn read(cursor: &tlfs::Cursor<'_>) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            title: {
+                let cursor = cursor.at_field("title")?;
+                cursor.strs()?.next().transpose()?
+            },
+            todos: {
+                let cursor = cursor.at_field("todos")?;
+                {
+                    let mut entries = ::std::collections::BTreeMap::new();
+                    for key in cursor.keys_u64()?.collect::<::std::collections::BTreeSet<_>>() {
+                        let cursor = cursor.at_key_u64(key)?;
+                        entries.insert(key, {
+                            TodoappTodos::read(&cursor)?
+                        });
+                    }
+                    entries
+                }
+            },
+        })
+    }
+
+    /// Joins the changes needed for the value at `cursor` to match `self` into `causal`.
+    pub fn write(&self, cursor: &tlfs::Cursor<'_>, causal: &mut tlfs::Causal) -> ::anyhow::Result<()> {
+        {
+            let cursor = cursor.at_field("title")?;
+            let value = &self.title;
+            if cursor.strs()?.next().transpose()?.as_ref() != value.as_ref() {
+                match value {
+                    Some(value) => causal.join(&cursor.assign_str(value)?),
+                    None => causal.join(&cursor.remove()?),
+                }
+            }
+        }
+        {
+            let cursor = cursor.at_field("todos")?;
+            let value = &self.todos;
+            let keys = cursor.keys_u64()?.collect::<::std::collections::BTreeSet<_>>();
+            for (key, value) in value {
+                let cursor = cursor.at_key_u64(*key)?;
+                value.write(&cursor, causal)?;
+            }
+            for key in keys {
+                if !value.contains_key(&key) {
+                    causal.join(&cursor.at_key_u64(key)?.remove()?);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Nested struct of [`TodoappTodos`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TodoappTodos {
+    pub complete: bool,
+    pub estimate: Option<f64>,
+    pub notes: Vec<String>,
+    pub priority: Option<u64>,
+    pub tags: ::std::collections::BTreeSet<String>,
+    pub thumbnail: Option<Vec<u8>>,
+    pub title: Option<String>,
+    pub votes: i64,
+}
+
+impl TodoappTodos {
+    /// Reads the value at `cursor`.
+    pub fn read(cursor: &tlfs::Cursor<'_>) -> ::anyhow::Result<Self> {
+        Ok(Self {
+            complete: {
+                let cursor = cursor.at_field("complete")?;
+                cursor.enabled()?
+            },
+            estimate: {
+                let cursor = cursor.at_field("estimate")?;
+                cursor.f64s()?.next().transpose()?
+            },
+            notes: {
+                let cursor = cursor.at_field("notes")?;
+                {
+                    let mut elements = Vec::new();
+                    for cursor in cursor.iter_array()? {
+                        elements.push({
+                            cursor.text()?
+                        });
+                    }
+                    elements
+                }
+            },
+            priority: {
+                let cursor = cursor.at_field("priority")?;
+                cursor.merged_u64()?
+            },
+            tags: {
+                let cursor = cursor.at_field("tags")?;
+                cursor.elements_str()?.collect::<::std::collections::BTreeSet<_>>()
+            },
+            thumbnail: {
+                let cursor = cursor.at_field("thumbnail")?;
+                cursor.bytes()?.next().transpose()?
+            },
+            title: {
+                let cursor = cursor.at_field("title")?;
+                cursor.strs()?.next().transpose()?
+            },
+            votes: {
+                let cursor = cursor.at_field("votes")?;
+                cursor.counter_value()?
+            },
+        })
+    }
+
+    /// Joins the changes needed for the value at `cursor` to match `self` into `causal`.
+    pub fn write(&self, cursor: &tlfs::Cursor<'_>, causal: &mut tlfs::Causal) -> ::anyhow::Result<()> {
+        {
+            let cursor = cursor.at_field("complete")?;
+            let value = &self.complete;
+            if cursor.enabled()? != *value {
+                causal.join(&if *value { cursor.enable()? } else { cursor.disable()? });
+            }
+        }
+        {
+            let cursor = cursor.at_field("estimate")?;
+            let value = &self.estimate;
+            if cursor.f64s()?.next().transpose()?.as_ref() != value.as_ref() {
+                match value {
+                    Some(value) => causal.join(&cursor.assign_f64(*value)?),
+                    None => causal.join(&cursor.remove()?),
+                }
+            }
+        }
+        {
+            let cursor = cursor.at_field("notes")?;
+            let value = &self.notes;
+            let len = cursor.len()? as usize;
+            let appended = cursor.append(value.len().saturating_sub(len))?;
+            // elements that didn't write anything aren't inserted
+            for (value, cursor) in value.iter().zip(cursor.iter_array()?.chain(appended)) {
+                let current = cursor.text()?;
+                if current != *value {
+                    let len = current.chars().count();
+                    if len > 0 {
+                        causal.join(&cursor.delete_range(0, len)?);
+                    }
+                    if !value.is_empty() {
+                        causal.join(&cursor.insert_str(0, value)?);
+                    }
+                }
+            }
+            for ix in (value.len()..len).rev() {
+                causal.join(&cursor.at_index(ix)?.delete()?);
+            }
+        }
+        {
+            let cursor = cursor.at_field("priority")?;
+            let value = &self.priority;
+            if cursor.merged_u64()?.as_ref() != value.as_ref() {
+                match value {
+                    Some(value) => causal.join(&cursor.assign_u64(*value)?),
+                    None => causal.join(&cursor.remove()?),
+                }
+            }
+        }
+        {
+            let cursor = cursor.at_field("tags")?;
+            let value = &self.tags;
+            let current = cursor.elements_str()?.collect::<::std::collections::BTreeSet<_>>();
+            for value in value.difference(&current) {
+                causal.join(&cursor.add_str(value)?);
+            }
+            for value in current.difference(value) {
+                causal.join(&cursor.remove_str(value)?);
+            }
+        }
+        {
+            let cursor = cursor.at_field("thumbnail")?;
+            let value = &self.thumbnail;
+            if cursor.bytes()?.next().transpose()?.as_ref() != value.as_ref() {
+                match value {
+                    Some(value) => causal.join(&cursor.assign_bytes(value)?),
+                    None => causal.join(&cursor.remove()?),
+                }
+            }
+        }
+        {
+            let cursor = cursor.at_field("title")?;
+            let value = &self.title;
+            if cursor.strs()?.next().transpose()?.as_ref() != value.as_ref() {
+                match value {
+                    Some(value) => causal.join(&cursor.assign_str(value)?),
+                    None => causal.join(&cursor.remove()?),
+                }
+            }
+        }
+        {
+            let cursor = cursor.at_field("votes")?;
+            let value = &self.votes;
+            let delta = *value - cursor.counter_value()?;
+            if delta != 0 {
+                causal.join(&cursor.increment(delta)?);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,26 +1,86 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use fnv::FnvHashMap;
 use pest::iterators::Pair;
 use pest::Parser;
 use pest_derive::Parser;
-use std::path::Path;
-use tlfs_crdt::{Kind, Lens, Lenses, Package, PrimitiveKind, Ref, Schema};
+use std::path::{Path, PathBuf};
+use tlfs_crdt::{
+    is_reserved, Invariant, Kind, Lens, Lenses, Merge, Package, PrimitiveKind, Ref, Schema,
+};
+
+mod rust;
+
+pub use rust::compile_to_rust;
 
 #[derive(Parser)]
 #[grammar = "grammar.pest"]
 struct GrammarParser;
 
+/// Includes a package compiled by [`build`] from a build script. The argument is the file stem
+/// of the schema, so `tlfsc::build("schema.tlfs")` is included with `include_package!("schema")`.
+#[macro_export]
+macro_rules! include_package {
+    ($name:literal) => {
+        include_bytes!(concat!(env!("OUT_DIR"), "/", $name, ".tlfs.rkyv"))
+    };
+}
+
 pub fn compile<P: AsRef<Path>>(input: P, output: P) -> Result<()> {
     let input = std::fs::read(input)?;
     let input = std::str::from_utf8(&input)?;
-    let lenses = compile_lenses(input)?;
-    let lenses = Ref::archive(&lenses);
-    std::fs::write(output, lenses.as_bytes())?;
+    std::fs::write(output, compile_to_bytes(input)?)?;
     Ok(())
 }
 
+/// Compiles a schema into the archived packages expected by the sdk.
+pub fn compile_to_bytes(input: &str) -> Result<Vec<u8>> {
+    let lenses = compile_lenses(input)?;
+    Ok(Ref::archive(&lenses).into())
+}
+
+/// Compiles a schema from a build script into `OUT_DIR` so it can be embedded with
+/// [`include_package!`]. Cargo reruns the build script when the schema changes.
+pub fn build<P: AsRef<Path>>(input: P) -> Result<()> {
+    let input = input.as_ref();
+    println!("cargo:rerun-if-changed={}", input.display());
+    let out_dir = std::env::var_os("OUT_DIR").context("OUT_DIR not set, not in a build script")?;
+    let name = input
+        .file_stem()
+        .and_then(|name| name.to_str())
+        .context("invalid schema path")?;
+    let mut output = PathBuf::from(out_dir);
+    output.push(format!("{}.tlfs.rkyv", name));
+    compile(input, &output)
+}
+
 pub fn compile_lenses(input: &str) -> Result<Vec<Package>> {
+    Ok(interpret(input)?.into_packages())
+}
+
+fn interpret(input: &str) -> Result<Interpreter> {
     let root = GrammarParser::parse(Rule::root, input)?;
+    for pair in root.clone().flatten() {
+        if pair.as_rule() == Rule::ty {
+            let mut idents = pair.clone().into_inner().map(|ident| ident.as_str());
+            if let (Some("Table"), Some(key @ ("f64" | "Bytes"))) = (idents.next(), idents.next()) {
+                let (line, col) = pair.as_span().start_pos().line_col();
+                bail!("{}:{}: cannot key a table by {}", line, col, key);
+            }
+        }
+        if pair.as_rule() == Rule::invocation {
+            for ident in pair.into_inner() {
+                if is_reserved(ident.as_str()) {
+                    let (line, col) = ident.as_span().start_pos().line_col();
+                    bail!(
+                        "{}:{}: field name {} is reserved",
+                        line,
+                        col,
+                        ident.as_str()
+                    );
+                }
+            }
+        }
+    }
     let mut interpreter = Interpreter::default();
     for pair in root {
         for pair in pair.into_inner() {
@@ -29,7 +89,7 @@ pub fn compile_lenses(input: &str) -> Result<Vec<Package>> {
             }
         }
     }
-    Ok(interpreter.into_packages())
+    Ok(interpreter)
 }
 
 #[derive(Debug, Default)]
@@ -143,9 +203,12 @@ impl SchemaBuilder {
         self.lenses.push(lens);
     }
 
-    fn kind_of(&mut self, segments: &[Segment]) -> Kind {
+    fn schema_of(&self, segments: &[Segment]) -> &Schema {
         let mut schema = &self.schema;
         for seg in segments {
+            if let Schema::Local(inner) = schema {
+                schema = inner;
+            }
             match (seg, schema) {
                 (Segment::Field(field), Schema::Struct(fields)) => {
                     schema = fields.get(field).unwrap();
@@ -159,50 +222,171 @@ impl SchemaBuilder {
                 (seg, schema) => panic!("invalid segment {:?} {:?}", seg, schema),
             }
         }
+        schema
+    }
+
+    fn kind_of(&self, segments: &[Segment]) -> Kind {
+        let mut schema = self.schema_of(segments);
+        if let Schema::Local(inner) = schema {
+            schema = inner;
+        }
         match schema {
             Schema::Flag => Kind::Flag,
-            Schema::Reg(kind) => Kind::Reg(*kind),
+            Schema::Reg(kind) | Schema::MergeReg(kind, _) | Schema::CheckedReg(kind, _) => {
+                Kind::Reg(*kind)
+            }
+            Schema::LwwReg(kind) => Kind::LwwReg(*kind),
+            Schema::Encrypted(kind) => Kind::Encrypted(*kind),
             Schema::Table(kind, _) => Kind::Table(*kind),
             Schema::Struct(_) => Kind::Struct,
             Schema::Array(_) => Kind::Array,
+            Schema::Set(kind) => Kind::Set(*kind),
+            Schema::Dynamic => Kind::Dynamic,
+            Schema::Counter => Kind::Counter,
+            Schema::Text => Kind::Text,
+            Schema::Local(_) => panic!("unexpected schema local"),
             Schema::Null => panic!("unexpected schema null"),
         }
     }
 
+    fn has_field(&self, segments: &[Segment], field: &str) -> bool {
+        let mut schema = self.schema_of(segments);
+        if let Schema::Local(inner) = schema {
+            schema = inner;
+        }
+        matches!(schema, Schema::Struct(fields) if fields.contains_key(field))
+    }
+
+    /// Converts the register at `segments` between a MVReg and a LwwReg.
+    fn convert(&mut self, segments: &[Segment], kind: Kind) {
+        let lens = match (self.kind_of(segments), kind) {
+            (Kind::Reg(from), Kind::LwwReg(to)) if from == to => Lens::MakeLww,
+            (Kind::LwwReg(from), Kind::Reg(to)) if from == to => Lens::MakeMultiValue,
+            (from, to) => panic!("cannot change {:?} to {:?}", from, to),
+        };
+        self.add_lens(segments, lens);
+    }
+
     fn rule(&mut self, pair: Pair<Rule>) {
         let mut segments = None;
         let mut kind = None;
+        let mut local = false;
+        let mut merge = None;
+        let mut check = None;
         for pair in pair.into_inner() {
             match pair.as_rule() {
                 Rule::path => {
                     segments = Some(self.path(pair));
                 }
+                Rule::local => {
+                    local = true;
+                }
                 Rule::ty => {
                     kind = Some(self.ty(pair));
                 }
+                Rule::merge => {
+                    merge = Some(self.merge(pair));
+                }
+                Rule::check => {
+                    check = Some(pair);
+                }
                 _ => {}
             }
         }
         let mut segments = segments.unwrap();
         if let Some(kind) = kind {
+            let merge = merge.flatten();
             match segments.pop() {
+                Some(Segment::Field(field)) if self.has_field(&segments, &field) => {
+                    if local || merge.is_some() || check.is_some() {
+                        panic!("only the type of an existing field can be changed");
+                    }
+                    segments.push(Segment::Field(field));
+                    self.convert(&segments, kind);
+                }
                 Some(Segment::Field(field)) => {
                     self.add_lens(&segments, Lens::AddProperty(field.clone()));
                     segments.push(Segment::Field(field));
                     self.add_lens(&segments, Lens::Make(kind));
+                    if let Some(merge) = merge {
+                        self.add_lens(&segments, Lens::AddMerge(merge));
+                    }
+                    if let Some(check) = check {
+                        let invariant = self.check(check, kind);
+                        self.add_lens(&segments, Lens::AddInvariant(invariant));
+                    }
+                    if local {
+                        self.add_lens(&segments, Lens::MakeLocal);
+                    }
                 }
                 Some(seg) => {
+                    if local {
+                        panic!("only fields can be local");
+                    }
                     segments.push(seg);
                     self.add_lens(&segments, Lens::Make(kind));
+                    if let Some(merge) = merge {
+                        self.add_lens(&segments, Lens::AddMerge(merge));
+                    }
+                    if let Some(check) = check {
+                        let invariant = self.check(check, kind);
+                        self.add_lens(&segments, Lens::AddInvariant(invariant));
+                    }
                 }
                 None => {
+                    if local {
+                        panic!("only fields can be local");
+                    }
                     self.add_lens(&segments, Lens::Make(kind));
+                    if let Some(merge) = merge {
+                        self.add_lens(&segments, Lens::AddMerge(merge));
+                    }
+                    if let Some(check) = check {
+                        let invariant = self.check(check, kind);
+                        self.add_lens(&segments, Lens::AddInvariant(invariant));
+                    }
                 }
             }
+        } else if let Some(merge) = merge {
+            let strategy = match self.schema_of(&segments) {
+                Schema::MergeReg(_, strategy) => Some(*strategy),
+                Schema::Local(inner) => match inner.as_ref() {
+                    Schema::MergeReg(_, strategy) => Some(*strategy),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some(strategy) = strategy {
+                self.add_lens(&segments, Lens::RemoveMerge(strategy));
+            }
+            if let Some(merge) = merge {
+                self.add_lens(&segments, Lens::AddMerge(merge));
+            }
         } else {
             match segments.pop() {
                 Some(Segment::Remove) => {
                     let kind = self.kind_of(&segments);
+                    let (is_local, schema) = match self.schema_of(&segments) {
+                        Schema::Local(inner) => (true, inner.as_ref()),
+                        schema => (false, schema),
+                    };
+                    let strategy = match schema {
+                        Schema::MergeReg(_, strategy) => Some(*strategy),
+                        _ => None,
+                    };
+                    let invariant = match schema {
+                        Schema::CheckedReg(_, invariant) => Some(*invariant),
+                        _ => None,
+                    };
+                    if is_local {
+                        self.add_lens(&segments, Lens::MakeShared);
+                    }
+                    if let Some(strategy) = strategy {
+                        self.add_lens(&segments, Lens::RemoveMerge(strategy));
+                    }
+                    if let Some(invariant) = invariant {
+                        self.add_lens(&segments, Lens::RemoveInvariant(invariant));
+                    }
                     self.add_lens(&segments, Lens::Destroy(kind));
                     if let Some(Segment::Field(field)) = segments.pop() {
                         self.add_lens(&segments, Lens::RemoveProperty(field));
@@ -288,6 +472,36 @@ impl SchemaBuilder {
         segment.unwrap()
     }
 
+    fn merge(&mut self, pair: Pair<Rule>) -> Option<Merge> {
+        let ident = pair.into_inner().next().unwrap();
+        Some(match ident.as_str() {
+            "max" => Merge::Max,
+            "min" => Merge::Min,
+            "sum" => Merge::Sum,
+            "custom" => Merge::Custom,
+            "none" => return None,
+            _ => panic!("unexpected merge {}", ident.as_str()),
+        })
+    }
+
+    fn check(&mut self, pair: Pair<Rule>, kind: Kind) -> Invariant {
+        let mut pairs = pair.into_inner();
+        let ident = pairs.next().unwrap();
+        let args: Vec<&str> = pairs.map(|pair| pair.as_str()).collect();
+        match (ident.as_str(), kind, args.as_slice()) {
+            ("range", Kind::Reg(PrimitiveKind::U64), [min, max]) => {
+                Invariant::U64Range(min.parse().unwrap(), max.parse().unwrap())
+            }
+            ("range", Kind::Reg(PrimitiveKind::I64), [min, max]) => {
+                Invariant::I64Range(min.parse().unwrap(), max.parse().unwrap())
+            }
+            ("maxlen", Kind::Reg(PrimitiveKind::Str), [len]) => {
+                Invariant::MaxLen(len.parse().unwrap())
+            }
+            _ => panic!("unexpected check {} on {:?}", ident.as_str(), kind),
+        }
+    }
+
     fn ty(&mut self, pair: Pair<Rule>) -> Kind {
         let mut prim_kind = None;
         let mut kind = None;
@@ -297,12 +511,20 @@ impl SchemaBuilder {
                     (None, "bool") => prim_kind = Some(PrimitiveKind::Bool),
                     (None, "u64") => prim_kind = Some(PrimitiveKind::U64),
                     (None, "i64") => prim_kind = Some(PrimitiveKind::I64),
+                    (None, "f64") => prim_kind = Some(PrimitiveKind::F64),
+                    (None, "Bytes") => prim_kind = Some(PrimitiveKind::Bytes),
                     (None, "String") => prim_kind = Some(PrimitiveKind::Str),
                     (None, "EWFlag") => kind = Some(Kind::Flag),
                     (None, "Struct") => kind = Some(Kind::Struct),
                     (None, "Array") => kind = Some(Kind::Array),
+                    (None, "Dynamic") => kind = Some(Kind::Dynamic),
+                    (None, "PNCounter") => kind = Some(Kind::Counter),
+                    (None, "Text") => kind = Some(Kind::Text),
                     (Some(prim_kind), "MVReg") => kind = Some(Kind::Reg(prim_kind)),
+                    (Some(prim_kind), "LwwReg") => kind = Some(Kind::LwwReg(prim_kind)),
+                    (Some(prim_kind), "Encrypted") => kind = Some(Kind::Encrypted(prim_kind)),
                     (Some(prim_kind), "Table") => kind = Some(Kind::Table(prim_kind)),
+                    (Some(prim_kind), "Set") => kind = Some(Kind::Set(prim_kind)),
                     _ => panic!("unexpected type {}", pair.as_str()),
                 }
             }
@@ -325,8 +547,19 @@ todoapp {
     .todos.{}: Struct
     .todos.{}.title: MVReg<String>
     .todos.{}.complete: EWFlag
+    .todos.{}.tags: Set<String>
+    .todos.{}.draft: local MVReg<String>
+    .todos.{}.priority: MVReg<u64> merge(max)
+    .todos.{}.estimate: local MVReg<i64> merge(sum)
+    .todos.{}.rating: MVReg<i64> check(range(-5, 5))
+    .todos.{}.label: MVReg<String> check(maxlen(32))
+    .todos.{}.opened: MVReg<u64>
+    .todos.{}.location: LwwReg<f64>
   }
   0.1.1 {
+    .todos.{}.opened: LwwReg<u64>
+    .todos.{}.location: MVReg<f64>
+    .todos.{}.priority: merge(none)
     .todos.rename(tasks)
     .tasks.{}.attrs: Struct
     .tasks.{}.title.plunge(attrs)
@@ -334,10 +567,38 @@ todoapp {
     .tasks.{}.attrs.obsolete: Struct
     .tasks.{}.attrs.obsolete.remove()
     .tasks.{}.attrs.remove()
+    .tasks.{}.draft.remove()
+    .tasks.{}.estimate.remove()
+    .tasks.{}.label.remove()
   }
 }
     "#;
         compile_lenses(lenses)?;
+        let bytes = compile_to_bytes(lenses)?;
+        let packages = Ref::<Vec<Package>>::new(bytes.into());
+        assert_eq!(packages.as_ref()[0].name(), "todoapp");
         Ok(())
     }
+
+    #[test]
+    fn test_reserved() {
+        for schema in [
+            "todoapp { 0.1.0 { .: Struct .VALUES: MVReg<u64> } }",
+            "todoapp { 0.1.0 { .: Struct .title: MVReg<u64> } 0.1.1 { .title.rename(META) } }",
+        ] {
+            let err = compile_lenses(schema).unwrap_err();
+            assert!(err.to_string().contains("is reserved"));
+        }
+    }
+
+    #[test]
+    fn test_table_key() {
+        for schema in [
+            "todoapp { 0.1.0 { .: Table<f64> } }",
+            "todoapp { 0.1.0 { .: Struct .todos: Table<Bytes> } }",
+        ] {
+            let err = compile_lenses(schema).unwrap_err();
+            assert!(err.to_string().contains("cannot key a table"));
+        }
+    }
 }
@@ -1,6 +1,15 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 use std::path::PathBuf;
+use std::time::Duration;
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Emit {
+    /// Archived lens packages loaded by the sdk.
+    Package,
+    /// Rust structs for the latest schema versions.
+    Rust,
+}
 
 #[derive(Parser)]
 struct Cli {
@@ -8,10 +17,43 @@ struct Cli {
     input: PathBuf,
     #[clap(short, long)]
     output: PathBuf,
+    /// Recompile whenever the input changes.
+    #[clap(short, long)]
+    watch: bool,
+    /// Output format.
+    #[clap(long, arg_enum, default_value = "package")]
+    emit: Emit,
+}
+
+fn compile(cli: &Cli) -> Result<()> {
+    match cli.emit {
+        Emit::Package => tlfsc::compile(&cli.input, &cli.output),
+        Emit::Rust => {
+            let input = std::fs::read_to_string(&cli.input)?;
+            std::fs::write(&cli.output, tlfsc::compile_to_rust(&input)?)?;
+            Ok(())
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    tlfsc::compile(&cli.input, &cli.output)?;
-    Ok(())
+    if !cli.watch {
+        compile(&cli)?;
+        return Ok(());
+    }
+    let mut last_modified = None;
+    loop {
+        let modified = std::fs::metadata(&cli.input)
+            .and_then(|m| m.modified())
+            .ok();
+        if modified.is_some() && modified != last_modified {
+            last_modified = modified;
+            match compile(&cli) {
+                Ok(()) => eprintln!("compiled {}", cli.output.display()),
+                Err(err) => eprintln!("error: {:?}", err),
+            }
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
 }
@@ -0,0 +1,473 @@
+use crate::interpret;
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use tlfs_crdt::{Merge, PrimitiveKind, Schema};
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "box", "break", "const", "continue", "crate", "dyn", "else", "enum",
+    "extern", "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move",
+    "mut", "pub", "ref", "return", "static", "struct", "trait", "true", "try", "type", "unsafe",
+    "use", "where", "while", "yield",
+];
+
+/// Compiles a schema into rust structs mirroring the latest version of each schema. Every
+/// root struct gets a `load` function reading it from a document and an `apply_changes`
+/// function writing the differences back as a single transaction. The generated code refers
+/// to the sdk as `tlfs`. Fails for tables whose keys can't be read from a cursor.
+pub fn compile_to_rust(input: &str) -> Result<String> {
+    let interpreter = interpret(input)?;
+    let mut schemas = interpreter.schemas.iter().collect::<Vec<_>>();
+    schemas.sort_by_key(|(name, _)| *name);
+    let mut gen = Generator::default();
+    gen.out
+        .push_str("// Generated by tlfsc from the schema, do not edit.\n");
+    for (name, builder) in schemas {
+        let fields = match &builder.schema {
+            Schema::Struct(fields) => fields,
+            _ => bail!("the root of schema {} is not a Struct", name),
+        };
+        gen.structure(&camel_case(name), fields, Some(name))?;
+        while let Some((name, fields)) = gen.queue.pop() {
+            gen.structure(&name, fields, None)?;
+        }
+    }
+    Ok(gen.out)
+}
+
+#[derive(Default)]
+struct Generator<'a> {
+    out: String,
+    queue: Vec<(String, &'a BTreeMap<String, Schema>)>,
+}
+
+impl<'a> Generator<'a> {
+    fn structure(
+        &mut self,
+        name: &str,
+        fields: &'a BTreeMap<String, Schema>,
+        root: Option<&str>,
+    ) -> Result<()> {
+        let mut decls = String::new();
+        let mut reads = String::new();
+        let mut writes = String::new();
+        for (field, schema) in fields {
+            let ty_name = format!("{}{}", name, camel_case(field));
+            let ty = if let Some(ty) = self.ty(schema, &ty_name)? {
+                ty
+            } else {
+                continue;
+            };
+            let ident = ident(field);
+            decls.push_str(&format!("    pub {}: {},\n", ident, ty));
+            reads.push_str(&format!(
+                "            {}: {{\n                let cursor = cursor.at_field({:?})?;\n{}\n            }},\n",
+                ident,
+                field,
+                indent(&read(schema, &ty_name), 16),
+            ));
+            writes.push_str(&format!(
+                "        {{\n            let cursor = cursor.at_field({:?})?;\n            let value = &self.{};\n{}\n        }}\n",
+                field,
+                ident,
+                indent(&write(schema), 12),
+            ));
+        }
+        self.out.push('\n');
+        match root {
+            Some(schema) => self
+                .out
+                .push_str(&format!("/// Latest version of the `{}` schema.\n", schema)),
+            None => self
+                .out
+                .push_str(&format!("/// Nested struct of [`{}`].\n", name)),
+        }
+        // f64s are only PartialEq.
+        let eq = if fields.values().any(has_f64) {
+            ""
+        } else {
+            " Eq,"
+        };
+        self.out.push_str(&format!(
+            "#[derive(Clone, Debug, Default,{} PartialEq)]\npub struct {} {{\n{}}}\n\nimpl {} {{\n",
+            eq, name, decls, name
+        ));
+        if root.is_some() {
+            self.out.push_str(concat!(
+                "    /// Reads the document.\n",
+                "    pub fn load(doc: &tlfs::Doc) -> ::anyhow::Result<Self> {\n",
+                "        Self::read(&doc.cursor())\n",
+                "    }\n\n",
+                "    /// Applies the changes needed for the document to match `value`.\n",
+                "    pub fn apply_changes(doc: &tlfs::Doc, value: &Self) -> ::anyhow::Result<()> {\n",
+                "        let mut causal = tlfs::Causal::default();\n",
+                "        value.write(&doc.cursor(), &mut causal)?;\n",
+                "        if !causal.is_empty() {\n",
+                "            doc.apply(causal)?;\n",
+                "        }\n",
+                "        Ok(())\n",
+                "    }\n\n",
+            ));
+        }
+        self.out.push_str(&format!(
+            concat!(
+                "    /// Reads the value at `cursor`.\n",
+                "    pub fn read(cursor: &tlfs::Cursor<'_>) -> ::anyhow::Result<Self> {{\n",
+                "        Ok(Self {{\n{}        }})\n",
+                "    }}\n\n",
+                "    /// Joins the changes needed for the value at `cursor` to match `self` into `causal`.\n",
+                "    pub fn write(&self, cursor: &tlfs::Cursor<'_>, causal: &mut tlfs::Causal) -> ::anyhow::Result<()> {{\n",
+                "{}        Ok(())\n",
+                "    }}\n",
+                "}}\n",
+            ),
+            reads, writes
+        ));
+        Ok(())
+    }
+
+    /// Returns the rust type of `schema` or `None` if the field is skipped. Fails if no cursor
+    /// methods exist to read or write `schema`.
+    fn ty(&mut self, schema: &'a Schema, name: &str) -> Result<Option<String>> {
+        Ok(Some(match schema {
+            Schema::Null | Schema::Dynamic => return Ok(None),
+            Schema::Flag => "bool".into(),
+            Schema::Counter => "i64".into(),
+            Schema::Text => "String".into(),
+            Schema::Reg(kind)
+            | Schema::MergeReg(kind, _)
+            | Schema::CheckedReg(kind, _)
+            | Schema::LwwReg(kind)
+            | Schema::Encrypted(kind) => {
+                format!("Option<{}>", prim_ty(*kind))
+            }
+            // f64s aren't Ord, so they can't be elements of a BTreeSet.
+            Schema::Set(PrimitiveKind::F64) => return Ok(None),
+            Schema::Table(kind @ (PrimitiveKind::F64 | PrimitiveKind::Bytes), _) => {
+                bail!(
+                    "{}: Table<{:?}> keys can't be read from a cursor",
+                    name,
+                    kind
+                )
+            }
+            Schema::Set(kind) => format!("::std::collections::BTreeSet<{}>", prim_ty(*kind)),
+            Schema::Table(kind, value) => format!(
+                "::std::collections::BTreeMap<{}, {}>",
+                prim_ty(*kind),
+                match self.ty(value, name)? {
+                    Some(ty) => ty,
+                    None => return Ok(None),
+                }
+            ),
+            Schema::Array(value) => match self.ty(value, name)? {
+                Some(ty) => format!("Vec<{}>", ty),
+                None => return Ok(None),
+            },
+            Schema::Local(schema) => return self.ty(schema, name),
+            Schema::Struct(fields) => {
+                self.queue.push((name.into(), fields));
+                name.into()
+            }
+        }))
+    }
+}
+
+/// Expression reading the value of `schema` at `cursor`, `name` is the type of nested structs.
+fn read(schema: &Schema, name: &str) -> String {
+    match schema {
+        Schema::Null | Schema::Dynamic => unreachable!(),
+        Schema::Flag => "cursor.enabled()?".into(),
+        Schema::Counter => "cursor.counter_value()?".into(),
+        Schema::Text => "cursor.text()?".into(),
+        Schema::MergeReg(kind, merge) if *merge != Merge::Custom => {
+            format!("cursor.merged_{}()?", prim_suffix(*kind))
+        }
+        Schema::Reg(kind)
+        | Schema::MergeReg(kind, _)
+        | Schema::CheckedReg(kind, _)
+        | Schema::LwwReg(kind)
+        | Schema::Encrypted(kind) => {
+            format!("cursor.{}()?.next().transpose()?", prim_reader(*kind))
+        }
+        Schema::Set(kind) => format!(
+            "cursor.elements_{}()?.collect::<::std::collections::BTreeSet<_>>()",
+            prim_suffix(*kind)
+        ),
+        Schema::Table(kind, value) => format!(
+            concat!(
+                "{{\n",
+                "    let mut entries = ::std::collections::BTreeMap::new();\n",
+                "    for key in cursor.keys_{0}()?.collect::<::std::collections::BTreeSet<_>>() {{\n",
+                "        let cursor = cursor.at_key_{0}({1})?;\n",
+                "        entries.insert(key, {{\n{2}\n        }});\n",
+                "    }}\n",
+                "    entries\n",
+                "}}",
+            ),
+            prim_suffix(*kind),
+            if *kind == PrimitiveKind::Str {
+                "&key"
+            } else {
+                "key"
+            },
+            indent(&read(value, name), 12),
+        ),
+        Schema::Array(value) => format!(
+            concat!(
+                "{{\n",
+                "    let mut elements = Vec::new();\n",
+                "    for cursor in cursor.iter_array()? {{\n",
+                "        elements.push({{\n{}\n        }});\n",
+                "    }}\n",
+                "    elements\n",
+                "}}",
+            ),
+            indent(&read(value, name), 12),
+        ),
+        Schema::Local(schema) => read(schema, name),
+        Schema::Struct(_) => format!("{}::read(&cursor)?", name),
+    }
+}
+
+/// Statements writing the differences between the value at `cursor` and `value`.
+fn write(schema: &Schema) -> String {
+    match schema {
+        Schema::Null | Schema::Dynamic => unreachable!(),
+        Schema::Flag => concat!(
+            "if cursor.enabled()? != *value {\n",
+            "    causal.join(&if *value { cursor.enable()? } else { cursor.disable()? });\n",
+            "}",
+        )
+        .into(),
+        Schema::Counter => concat!(
+            "let delta = *value - cursor.counter_value()?;\n",
+            "if delta != 0 {\n",
+            "    causal.join(&cursor.increment(delta)?);\n",
+            "}",
+        )
+        .into(),
+        Schema::Text => concat!(
+            "let current = cursor.text()?;\n",
+            "if current != *value {\n",
+            "    let len = current.chars().count();\n",
+            "    if len > 0 {\n",
+            "        causal.join(&cursor.delete_range(0, len)?);\n",
+            "    }\n",
+            "    if !value.is_empty() {\n",
+            "        causal.join(&cursor.insert_str(0, value)?);\n",
+            "    }\n",
+            "}",
+        )
+        .into(),
+        Schema::Reg(kind)
+        | Schema::MergeReg(kind, _)
+        | Schema::CheckedReg(kind, _)
+        | Schema::LwwReg(kind)
+        | Schema::Encrypted(kind) => format!(
+            concat!(
+                "if {}.as_ref() != value.as_ref() {{\n",
+                "    match value {{\n",
+                "        Some(value) => causal.join(&cursor.assign_{}({})?),\n",
+                "        None => causal.join(&cursor.remove()?),\n",
+                "    }}\n",
+                "}}",
+            ),
+            read(schema, ""),
+            prim_suffix(*kind),
+            prim_arg(*kind, "value"),
+        ),
+        Schema::Set(kind) => format!(
+            concat!(
+                "let current = {0};\n",
+                "for value in value.difference(&current) {{\n",
+                "    causal.join(&cursor.add_{1}({2})?);\n",
+                "}}\n",
+                "for value in current.difference(value) {{\n",
+                "    causal.join(&cursor.remove_{1}({2})?);\n",
+                "}}",
+            ),
+            read(schema, ""),
+            prim_suffix(*kind),
+            prim_arg(*kind, "value"),
+        ),
+        Schema::Table(kind, schema) => format!(
+            concat!(
+                "let keys = cursor.keys_{0}()?.collect::<::std::collections::BTreeSet<_>>();\n",
+                "for (key, value) in value {{\n",
+                "    let cursor = cursor.at_key_{0}({1})?;\n",
+                "{2}\n",
+                "}}\n",
+                "for key in keys {{\n",
+                "    if !value.contains_key(&key) {{\n",
+                "        causal.join(&cursor.at_key_{0}({3})?.remove()?);\n",
+                "    }}\n",
+                "}}",
+            ),
+            prim_suffix(*kind),
+            prim_arg(*kind, "key"),
+            indent(&write(schema), 4),
+            if *kind == PrimitiveKind::Str {
+                "&key"
+            } else {
+                "key"
+            },
+        ),
+        Schema::Array(schema) => format!(
+            concat!(
+                "let len = cursor.len()? as usize;\n",
+                "let appended = cursor.append(value.len().saturating_sub(len))?;\n",
+                "// elements that didn't write anything aren't inserted\n",
+                "for (value, cursor) in value.iter().zip(cursor.iter_array()?.chain(appended)) {{\n",
+                "{}\n",
+                "}}\n",
+                "for ix in (value.len()..len).rev() {{\n",
+                "    causal.join(&cursor.at_index(ix)?.delete()?);\n",
+                "}}",
+            ),
+            indent(&write(schema), 4),
+        ),
+        Schema::Local(schema) => write(schema),
+        Schema::Struct(_) => "value.write(&cursor, causal)?;".into(),
+    }
+}
+
+fn prim_ty(kind: PrimitiveKind) -> &'static str {
+    match kind {
+        PrimitiveKind::Bool => "bool",
+        PrimitiveKind::U64 => "u64",
+        PrimitiveKind::I64 => "i64",
+        PrimitiveKind::F64 => "f64",
+        PrimitiveKind::Str => "String",
+        PrimitiveKind::Bytes => "Vec<u8>",
+    }
+}
+
+fn prim_suffix(kind: PrimitiveKind) -> &'static str {
+    match kind {
+        PrimitiveKind::Bool => "bool",
+        PrimitiveKind::U64 => "u64",
+        PrimitiveKind::I64 => "i64",
+        PrimitiveKind::F64 => "f64",
+        PrimitiveKind::Str => "str",
+        PrimitiveKind::Bytes => "bytes",
+    }
+}
+
+/// Name of the cursor method iterating over the values of a register.
+fn prim_reader(kind: PrimitiveKind) -> &'static str {
+    match kind {
+        PrimitiveKind::Bool => "bools",
+        PrimitiveKind::U64 => "u64s",
+        PrimitiveKind::I64 => "i64s",
+        PrimitiveKind::F64 => "f64s",
+        PrimitiveKind::Str => "strs",
+        PrimitiveKind::Bytes => "bytes",
+    }
+}
+
+/// Returns if a field of `schema` holds a f64.
+fn has_f64(schema: &Schema) -> bool {
+    match schema {
+        Schema::Reg(kind)
+        | Schema::MergeReg(kind, _)
+        | Schema::CheckedReg(kind, _)
+        | Schema::LwwReg(kind)
+        | Schema::Encrypted(kind) => *kind == PrimitiveKind::F64,
+        Schema::Table(_, value) | Schema::Array(value) => has_f64(value),
+        Schema::Local(schema) => has_f64(schema),
+        Schema::Struct(fields) => fields.values().any(has_f64),
+        _ => false,
+    }
+}
+
+/// Converts a reference named `name` into the argument of a cursor method.
+fn prim_arg(kind: PrimitiveKind, name: &str) -> String {
+    match kind {
+        PrimitiveKind::Str | PrimitiveKind::Bytes => name.into(),
+        _ => format!("*{}", name),
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|c| c.to_ascii_uppercase())
+        .into_iter()
+        .chain(chars)
+        .collect()
+}
+
+fn ident(name: &str) -> String {
+    if KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.into()
+    }
+}
+
+fn indent(code: &str, n: usize) -> String {
+    code.lines()
+        .map(|line| format!("{:n$}{}", "", line, n = n))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Schema of `fixtures/todoapp.rs`, which the crdt tests compile and run.
+    const TODOAPP: &str = r#"
+todoapp {
+  0.1.0 {
+    .: Struct
+    .title: MVReg<String>
+    .todos: Table<u64>
+    .todos.{}: Struct
+    .todos.{}.title: MVReg<String>
+    .todos.{}.complete: EWFlag
+    .todos.{}.tags: Set<String>
+    .todos.{}.priority: MVReg<u64> merge(max)
+    .todos.{}.estimate: MVReg<f64>
+    .todos.{}.thumbnail: MVReg<Bytes>
+    .todos.{}.votes: PNCounter
+    .todos.{}.notes: Array
+    .todos.{}.notes.[]: Text
+  }
+}
+"#;
+
+    #[test]
+    fn test_compile_to_rust() -> Result<()> {
+        let rust = compile_to_rust(TODOAPP)?;
+        assert_eq!(rust, include_str!("../fixtures/todoapp.rs"));
+        assert!(rust.contains("pub struct Todoapp {"));
+        assert!(rust.contains("pub todos: ::std::collections::BTreeMap<u64, TodoappTodos>,"));
+        assert!(
+            rust.contains("#[derive(Clone, Debug, Default, PartialEq)]\npub struct TodoappTodos {")
+        );
+        assert!(rust.contains("pub estimate: Option<f64>,"));
+        assert!(rust.contains("pub thumbnail: Option<Vec<u8>>,"));
+        assert!(rust.contains("cursor.assign_bytes(value)?"));
+        assert!(rust.contains("cursor.bytes()?.next().transpose()?"));
+        assert!(!rust.contains("bytess"));
+        assert!(rust.contains("pub complete: bool,"));
+        assert!(rust.contains("pub tags: ::std::collections::BTreeSet<String>,"));
+        assert!(rust.contains("pub notes: Vec<String>,"));
+        assert!(rust.contains("cursor.merged_u64()?"));
+        assert!(rust.contains("pub fn load(doc: &tlfs::Doc)"));
+        assert!(rust.contains("pub fn apply_changes(doc: &tlfs::Doc, value: &Self)"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsupported_table_keys() {
+        for kind in ["f64", "Bytes"] {
+            let schema = format!(
+                "app {{ 0.1.0 {{ .: Struct .by_key: Table<{}> .by_key.{{}}: EWFlag }} }}",
+                kind
+            );
+            assert!(compile_to_rust(&schema).is_err());
+        }
+    }
+}